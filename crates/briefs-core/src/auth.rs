@@ -0,0 +1,305 @@
+//! API tokens and the roles they grant. A connection logs in by sending a
+//! `Command::Login` with its token; the stream handler then checks the
+//! role of the caller before running every command that changes a stream.
+//! Reading streams is open to anyone, unless the server is configured with
+//! `private_reads`, see `crate::config::BriefsConfig`.
+//!
+//! Tokens are random and only their SHA-256 hash is stored, so a leaked Db
+//! does not leak any token. A token is shown once, when it is created.
+
+use std::{fmt::Display, path::Path, str::FromStr, time::SystemTime};
+
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlite::Connection;
+
+use crate::{constant::MAX_TOKEN_NAME, db, post::time_in_sec, BriefsError, BriefsResult, Command};
+
+/// Prefix of every token, which makes leaked tokens easy to spot.
+pub const TOKEN_PREFIX: &str = "brf_";
+
+/// Roles are ordered; every role may run the commands of the roles below
+/// it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Reads posts, metadata and feeds, and subscribes to streams. Only
+    /// needed with `private_reads`; anyone may read otherwise.
+    Reader,
+    /// Creates, updates and deletes posts.
+    Publisher,
+    /// Creates, renames and archives streams.
+    Admin,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Reader => write!(f, "reader"),
+            Role::Publisher => write!(f, "publisher"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = BriefsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "publisher" => Ok(Role::Publisher),
+            "admin" => Ok(Role::Admin),
+            _ => Err(BriefsError::InvalidMessage {
+                msg: format!("unknown role '{s}'"),
+            }),
+        }
+    }
+}
+
+/// Who sent a command, as established by logging in.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Identity {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Metadata of a token; the token itself is only known to its holder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub name: String,
+    pub role: Role,
+    pub created: u64,
+}
+
+/// Returns the role needed to run the command, or `None` if anyone may
+/// run it. Reads only need `Role::Reader` with `private_reads`.
+pub fn required_role(cmd: &Command, private_reads: bool) -> Option<Role> {
+    match cmd {
+        Command::Login { .. } => None,
+        Command::Catchup { .. }
+        | Command::Get { .. }
+        | Command::Metadata { .. }
+        | Command::ListStreams {}
+        | Command::Subscribe { .. }
+        | Command::Feed { .. }
+        | Command::History { .. }
+        | Command::Search { .. } => private_reads.then_some(Role::Reader),
+        Command::Create { .. }
+        | Command::UpdateMsg { .. }
+        | Command::UpdateTitle { .. }
+        | Command::Delete { .. }
+        | Command::Update { .. }
+        | Command::Restore { .. }
+        | Command::Undelete { .. } => Some(Role::Publisher),
+        Command::CreateStream { .. }
+        | Command::RenameStream { .. }
+        | Command::ArchiveStream { .. } => Some(Role::Admin),
+    }
+}
+
+/// Checks that the caller may run the command, see `required_role`.
+///
+/// # Errors
+///
+/// This function will return `BriefsError::Unauthenticated` if the
+/// command needs a role and the caller has not logged in, and
+/// `BriefsError::Forbidden` if the role of the caller is not enough.
+pub fn authorize(
+    caller: Option<&Identity>,
+    cmd: &Command,
+    private_reads: bool,
+) -> BriefsResult<()> {
+    let Some(required) = required_role(cmd, private_reads) else {
+        return Ok(());
+    };
+    let caller = caller.ok_or(BriefsError::Unauthenticated)?;
+    if caller.role < required {
+        return Err(BriefsError::Forbidden {
+            role: caller.role.to_string(),
+            required: required.to_string(),
+            cmd: cmd.name().to_owned(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Generates a new random token.
+pub fn generate_token() -> String {
+    let mut buffer = [0u8; 32];
+    thread_rng().fill_bytes(&mut buffer);
+    let secret: String = buffer.iter().map(|val| format!("{val:02x}")).collect();
+    format!("{TOKEN_PREFIX}{secret}")
+}
+
+/// Hash of the token, as stored in the Db.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|val| format!("{val:02x}"))
+        .collect()
+}
+
+/// Token names are shown on the command line, hence only lowercase
+/// letters, digits, '-' and '_' are allowed.
+pub fn verify_token_name(name: &str) -> BriefsResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_TOKEN_NAME as usize
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '-' || ch == '_');
+    if !valid {
+        return Err(BriefsError::InvalidTokenName {
+            name: name.into(),
+            max_size: MAX_TOKEN_NAME as usize,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The API tokens stored in a sqlite Db, which is expected to be set up
+/// using `db::setup_db`.
+pub struct TokenStore {
+    conn: Connection,
+}
+
+impl TokenStore {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> BriefsResult<Self> {
+        Ok(Self::new(sqlite::open(path)?))
+    }
+
+    /// Creates a new token with the given role and returns it. The token
+    /// cannot be recovered later on.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the name is invalid or is
+    /// already taken by another token.
+    pub fn create(&mut self, name: &str, role: Role) -> BriefsResult<String> {
+        verify_token_name(name)?;
+        if db::query_token_by_name(&self.conn, name)?.is_some() {
+            return Err(BriefsError::TokenExists { name: name.into() }.into());
+        }
+        let token = generate_token();
+        let info = TokenInfo {
+            name: name.to_owned(),
+            role,
+            created: time_in_sec(SystemTime::now())?,
+        };
+        db::insert_token(&mut self.conn, &info, &hash_token(&token))?;
+        Ok(token)
+    }
+
+    /// Returns the metadata of all the tokens, oldest first.
+    pub fn list(&self) -> BriefsResult<Vec<TokenInfo>> {
+        db::query_tokens(&self.conn)
+    }
+
+    /// Revokes the token with the given name. Connections already logged
+    /// in with it keep their identity until they are closed.
+    pub fn revoke(&mut self, name: &str) -> BriefsResult<()> {
+        if !db::delete_token(&mut self.conn, name)? {
+            return Err(BriefsError::UnknownToken { name: name.into() }.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the identity the token was created for.
+    pub fn login(&self, token: &str) -> BriefsResult<Identity> {
+        let info = db::query_token_by_hash(&self.conn, &hash_token(token))?
+            .ok_or(BriefsError::InvalidToken)?;
+        Ok(Identity {
+            name: info.name,
+            role: info.role,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test::{cleanup_db, setup_mock_db};
+
+    #[test]
+    fn test_tokens_are_hashed_at_rest() {
+        let path = setup_mock_db();
+        let mut tokens = TokenStore::open(&path).unwrap();
+
+        let token = tokens.create("ci", Role::Publisher).unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        let identity = tokens.login(&token).unwrap();
+        assert_eq!(identity.name, "ci");
+        assert_eq!(identity.role, Role::Publisher);
+
+        {
+            let conn = sqlite::open(&path).unwrap();
+            let mut stmt = conn
+                .prepare(format!("SELECT hash FROM {}", db::TOKENS_TABLE))
+                .unwrap();
+            stmt.next().unwrap();
+            let hash: String = stmt.read(0).unwrap();
+            assert_ne!(hash, token);
+            assert_eq!(hash, hash_token(&token));
+        }
+
+        assert!(tokens.create("ci", Role::Admin).is_err());
+        assert!(tokens.create("Not valid", Role::Admin).is_err());
+        assert!(tokens.login("brf_unknown").is_err());
+
+        tokens.revoke("ci").unwrap();
+        assert!(tokens.login(&token).is_err());
+        assert!(tokens.revoke("ci").is_err());
+        assert!(tokens.list().unwrap().is_empty());
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let reader = Identity {
+            name: "dash".into(),
+            role: Role::Reader,
+        };
+        let publisher = Identity {
+            name: "ci".into(),
+            role: Role::Publisher,
+        };
+        let create = Command::Create {
+            stream: "ops".into(),
+            title: "Hi".into(),
+            msg: "There".into(),
+        };
+        let list = Command::ListStreams {};
+        let login = Command::Login {
+            token: "brf_".into(),
+        };
+
+        assert!(authorize(None, &login, true).is_ok());
+        // Reads are open, unless they are private
+        assert!(authorize(None, &list, false).is_ok());
+        let err = authorize(None, &list, true).unwrap_err();
+        assert_eq!(err.downcast_ref::<BriefsError>().unwrap().code(), 400);
+        assert!(authorize(Some(&reader), &list, true).is_ok());
+        let err = authorize(None, &create, false).unwrap_err();
+        assert_eq!(err.downcast_ref::<BriefsError>().unwrap().code(), 400);
+        let err = authorize(Some(&reader), &create, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Role 'reader' is not allowed to run Create; requires 'publisher'"
+        );
+        assert!(authorize(Some(&publisher), &create, false).is_ok());
+        let archive = Command::ArchiveStream {
+            stream: "ops".into(),
+        };
+        assert!(authorize(Some(&publisher), &archive, false).is_err());
+    }
+}
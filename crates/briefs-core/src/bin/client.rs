@@ -1,6 +1,8 @@
-use briefs_core::Command;
-use serde_json;
-use tokio::io::AsyncWriteExt;
+use briefs_core::{
+    codec::{read_frame, write_frame},
+    constant::DEFAULT_STREAM,
+    Command, Envelope, Request,
+};
 use tokio::net::TcpStream;
 
 #[tokio::main]
@@ -9,29 +11,30 @@ async fn main() {
     let mut stream = TcpStream::connect(server_addr).await.unwrap();
     println!("Connected with '{server_addr}'");
 
-    let request = Command::Create {
-        title: String::from("First Post"),
-        msg: String::from(
-            "My/Our first post. This is a demo post to test the proper \
-            functioning of my/our new stream. Follow to keep yourself updated \
-            with the latest updates.",
-        ),
-    };
+    let request = Request::new(
+        1,
+        Command::Create {
+            stream: DEFAULT_STREAM.into(),
+            title: String::from("First Post"),
+            msg: String::from(
+                "My/Our first post. This is a demo post to test the proper \
+                functioning of my/our new stream. Follow to keep yourself updated \
+                with the latest updates.",
+            ),
+        },
+    );
 
-    let bytes = stream
-        .write(&serde_json::to_vec(&request).unwrap()[..])
-        .await
-        .unwrap();
-    println!("Written {bytes} bytes");
+    let payload = serde_json::to_vec(&request).unwrap();
+    write_frame(&mut stream, &payload).await.unwrap();
+    println!("Written {} bytes", payload.len());
 
-    let mut kb_buffer = [0u8; 10240];
-    stream.readable().await.unwrap();
-    match stream.try_read(&mut kb_buffer) {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            let response = String::from_utf8(kb_buffer[..bytes].to_vec()).unwrap();
-            println!("{}", response);
+    match read_frame(&mut stream).await {
+        Ok(Some(response)) => {
+            println!("Read {} bytes", response.len());
+            let envelope = serde_json::from_slice::<Envelope>(&response).unwrap();
+            println!("{:#?}", envelope);
         }
-        Err(e) => eprintln!("Error reading into buffer: {:?}", e),
+        Ok(None) => eprintln!("Connection closed without a response"),
+        Err(e) => eprintln!("Error reading frame: {:?}", e),
     }
 }
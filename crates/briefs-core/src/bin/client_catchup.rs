@@ -1,6 +1,8 @@
-use briefs_core::Command;
-use serde_json;
-use tokio::io::AsyncWriteExt;
+use briefs_core::{
+    codec::{read_frame, write_frame},
+    constant::DEFAULT_STREAM,
+    Command, Envelope, Request,
+};
 use tokio::net::TcpStream;
 
 #[tokio::main]
@@ -9,22 +11,28 @@ async fn main() {
     let mut tcp_stream = TcpStream::connect(server_addr).await.unwrap();
     println!("Connected with '{server_addr}'");
 
-    let request = Command::Catchup { last_fetch_id: 0 };
-    let bytes = tcp_stream
-        .write(&serde_json::to_vec(&request).unwrap()[..])
-        .await
-        .unwrap();
-    println!("Written {bytes} bytes");
+    let request = Request::new(
+        1,
+        Command::Catchup {
+            stream: DEFAULT_STREAM.into(),
+            last_fetch_id: 0,
+            cursor: None,
+            since: None,
+            until: None,
+            limit: None,
+        },
+    );
+    let payload = serde_json::to_vec(&request).unwrap();
+    write_frame(&mut tcp_stream, &payload).await.unwrap();
+    println!("Written {} bytes", payload.len());
 
-    // 10MB buffer
-    let mut kb_buffer = [0u8; 10240];
-    tcp_stream.readable().await.unwrap();
-    match tcp_stream.try_read(&mut kb_buffer) {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            let response = String::from_utf8(kb_buffer[..bytes].to_vec()).unwrap();
-            println!("{}", response);
+    match read_frame(&mut tcp_stream).await {
+        Ok(Some(response)) => {
+            println!("Read {} bytes", response.len());
+            let envelope = serde_json::from_slice::<Envelope>(&response).unwrap();
+            println!("{:#?}", envelope);
         }
-        Err(e) => eprintln!("Error reading into buffer: {:?}", e),
+        Ok(None) => eprintln!("Connection closed without a response"),
+        Err(e) => eprintln!("Error reading frame: {:?}", e),
     }
 }
@@ -0,0 +1,147 @@
+//! Framing of the wire protocol, shared by the server and the clients.
+//!
+//! Every message is sent as a frame: a 4 byte big-endian length followed by
+//! that many bytes of payload. Since the end of a message no longer depends
+//! on the peer closing its write half, one connection can carry any number
+//! of request/response pairs.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{BriefsError, BriefsResult};
+
+/// Size of the length prefix of every frame.
+pub const FRAME_HEADER_SIZE: usize = 4;
+/// 1Mb; larger frames are refused by both ends.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Writes the payload as a single frame and flushes the writer.
+///
+/// # Errors
+///
+/// This function will return an error if the payload is larger than
+/// `MAX_FRAME_SIZE`, or if writing fails.
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> BriefsResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    verify_frame_size(payload.len())?;
+    let len = u32::try_from(payload.len())?;
+
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Reads the next frame and returns its payload. Returns `None` if the peer
+/// closed the connection in between frames.
+///
+/// # Errors
+///
+/// This function will return an error if the connection is closed in the
+/// middle of a frame, or if the announced length exceeds `MAX_FRAME_SIZE`.
+pub async fn read_frame<R>(reader: &mut R) -> BriefsResult<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < FRAME_HEADER_SIZE {
+        let bytes = reader.read(&mut header[filled..]).await?;
+        if bytes == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(BriefsError::IncompleteFrame.into());
+        }
+        filled += bytes;
+    }
+
+    let len = u32::from_be_bytes(header) as usize;
+    verify_frame_size(len)?;
+
+    let mut payload = vec![0u8; len];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(|_| BriefsError::IncompleteFrame)?;
+
+    Ok(Some(payload))
+}
+
+fn verify_frame_size(len: usize) -> BriefsResult<()> {
+    if len > MAX_FRAME_SIZE {
+        return Err(BriefsError::InvalidFrameLength {
+            max_size: MAX_FRAME_SIZE,
+            curr_size: len,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_many_frames_on_one_connection() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let payloads: Vec<Vec<u8>> = vec![
+            b"first".to_vec(),
+            Vec::new(),
+            "नमस्ते 😃".as_bytes().to_vec(),
+            vec![7u8; 1000],
+        ];
+        let expected = payloads.clone();
+        let writer = tokio::spawn(async move {
+            for payload in payloads {
+                write_frame(&mut client, &payload).await.unwrap();
+            }
+        });
+
+        for payload in expected {
+            assert_eq!(read_frame(&mut server).await.unwrap(), Some(payload));
+        }
+        writer.await.unwrap();
+        // The writer is dropped in between frames
+        assert_eq!(read_frame(&mut server).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_frame() {
+        for bytes in [vec![0u8, 0], vec![0, 0, 0, 5, b'a', b'b']] {
+            let (mut client, mut server) = tokio::io::duplex(64);
+            client.write_all(&bytes).await.unwrap();
+            drop(client);
+
+            assert!(matches!(
+                read_frame(&mut server)
+                    .await
+                    .unwrap_err()
+                    .downcast_ref::<BriefsError>(),
+                Some(BriefsError::IncompleteFrame)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let payload = vec![0u8; MAX_FRAME_SIZE + 1];
+        assert!(write_frame(&mut client, &payload).await.is_err());
+
+        let len = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        client.write_all(&len).await.unwrap();
+        assert!(matches!(
+            read_frame(&mut server)
+                .await
+                .unwrap_err()
+                .downcast_ref::<BriefsError>(),
+            Some(BriefsError::InvalidFrameLength { .. })
+        ));
+    }
+}
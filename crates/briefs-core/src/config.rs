@@ -8,7 +8,7 @@ use std::{
 };
 
 use crate::{
-    constant::{CONFIG_DIR, CONFIG_ENV, CONFIG_FILE},
+    constant::{CONFIG_DIR, CONFIG_ENV, CONFIG_FILE, DRAIN_TIMEOUT, TRASH_RETENTION},
     BriefsError, BriefsResult,
 };
 
@@ -17,13 +17,29 @@ pub struct BriefsConfig {
     /// Socket address used to serve. Should be <ip>:<port>
     /// Example: 127.0.0.1:8080
     pub socket: SocketAddr,
+    /// Socket address of the HTTP gateway; the gateway is only served
+    /// when set, over HTTPS with `cert` and `pkey`. Example: 127.0.0.1:8081
+    pub http: Option<SocketAddr>,
     /// Server Certificate file; Should be <name>.pem file
     pub cert: PathBuf,
     /// Server private key used with certificate; Should be <name>.pem file
     pub pkey: PathBuf,
+    /// CA bundle used to verify client certificates; Should be <name>.pem
+    /// file. Optional; client certificates are only accepted when set.
+    pub client_ca: Option<PathBuf>,
     /// Path to sqlite Db.
     /// Optional
     pub db: PathBuf,
+    /// Seconds given to the connections to send the replies of their
+    /// pending requests when the server shuts down; queued commands are
+    /// always handled and the streams persisted. Example: 10
+    pub drain_timeout: u64,
+    /// Days deleted posts are kept in the trash before they are purged.
+    /// Example: 30
+    pub trash_retention: u64,
+    /// Whether reading streams needs a token with the reader role; anyone
+    /// may read otherwise. Example: false
+    pub private_reads: bool,
     /// Path of the config file directory; eg $HOME/.config/
     pub dirpath: PathBuf,
     /// Path of the config file; eg $HOME/.config/briefs.toml
@@ -37,8 +53,13 @@ impl Default for BriefsConfig {
         });
         Self {
             socket: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080),
+            http: None,
             cert: PathBuf::new(),
             pkey: PathBuf::new(),
+            client_ca: None,
+            drain_timeout: DRAIN_TIMEOUT,
+            trash_retention: TRASH_RETENTION,
+            private_reads: false,
             db: home_dir.join(CONFIG_DIR),
             dirpath: home_dir.join(CONFIG_DIR),
             filepath: home_dir.join(CONFIG_DIR).join(CONFIG_FILE),
@@ -65,17 +86,32 @@ impl BriefsConfig {
         // • Create config file
         let filepath = self.dirpath.join(CONFIG_FILE);
         let mut fptr = std::fs::File::create(filepath)?;
-        let config = String::from(format!(
+        let mut config = format!(
             "[config]\
             \nsocket = \"{}\"\
             \ncert = \"{}\"\
             \npkey = \"{}\"\
-            \ndb = \"{}\"\n",
-            self.socket.to_string(),
+            \ndb = \"{}\"\
+            \ndrain_timeout = {}\
+            \ntrash_retention = {}\
+            \nprivate_reads = {}\n",
+            self.socket,
             self.cert.to_str().unwrap_or_default(),
             self.pkey.to_str().unwrap_or_default(),
-            self.db.to_str().unwrap_or_default()
-        ));
+            self.db.to_str().unwrap_or_default(),
+            self.drain_timeout,
+            self.trash_retention,
+            self.private_reads
+        );
+        if let Some(http) = self.http {
+            config.push_str(&format!("http = \"{}\"\n", http));
+        }
+        if let Some(client_ca) = &self.client_ca {
+            config.push_str(&format!(
+                "client_ca = \"{}\"\n",
+                client_ca.to_str().unwrap_or_default()
+            ));
+        }
         fptr.write_all(config.as_bytes())?;
         Ok(())
     }
@@ -86,16 +122,18 @@ impl BriefsConfig {
         let mut buf = String::new();
         let mut fptr = std::fs::File::open(file.clone())?;
         let _ = fptr.read_to_string(&mut buf)?;
-        let mut config = BriefsConfig::default();
-        config.dirpath = file
-            .canonicalize()?
-            .parent()
-            .ok_or(BriefsError::config_error("filepath has no parent".into()))?
-            .to_path_buf();
-        config.filepath = file;
+        let mut config = BriefsConfig {
+            dirpath: file
+                .canonicalize()?
+                .parent()
+                .ok_or(BriefsError::config_error("filepath has no parent".into()))?
+                .to_path_buf(),
+            filepath: file,
+            ..Default::default()
+        };
 
         let buf: Vec<&str> = buf.split("\n").collect();
-        if buf.len() == 0 {
+        if buf.is_empty() {
             return Err(BriefsError::config_error("Config file is empty".to_string()).into());
         }
         if !buf.first().unwrap().trim().contains("[config]") {
@@ -113,13 +151,34 @@ impl BriefsConfig {
                             matches.name("val").map_or("", |val| val.as_str()),
                         )?
                     }
+                    "http" => {
+                        config.http = Some(SocketAddr::from_str(
+                            matches.name("val").map_or("", |val| val.as_str()),
+                        )?)
+                    }
                     "cert" => {
                         config.cert = matches.name("val").map_or("", |val| val.as_str()).into()
                     }
                     "pkey" => {
                         config.pkey = matches.name("val").map_or("", |val| val.as_str()).into()
                     }
+                    "client_ca" => {
+                        config.client_ca =
+                            Some(matches.name("val").map_or("", |val| val.as_str()).into())
+                    }
                     "db" => config.db = matches.name("val").map_or("", |val| val.as_str()).into(),
+                    "drain_timeout" => {
+                        config.drain_timeout =
+                            matches.name("val").map_or("", |val| val.as_str()).parse()?
+                    }
+                    "trash_retention" => {
+                        config.trash_retention =
+                            matches.name("val").map_or("", |val| val.as_str()).parse()?
+                    }
+                    "private_reads" => {
+                        config.private_reads =
+                            matches.name("val").map_or("", |val| val.as_str()).parse()?
+                    }
                     _ => {
                         return Err(BriefsError::config_error(
                             "Parsing error: key not found".into(),
@@ -136,7 +195,7 @@ impl BriefsConfig {
 
 pub fn fetch_config_from_env() -> BriefsResult<PathBuf> {
     let result = std::env::var(CONFIG_ENV)?;
-    let dirpath = PathBuf::try_from(result)?;
+    let dirpath = PathBuf::from(result);
     if !dirpath.is_dir() {
         return Err(BriefsError::config_error(format!("{} is not a dir path", CONFIG_ENV)).into());
     }
@@ -148,7 +207,7 @@ pub fn fetch_config_from_env() -> BriefsResult<PathBuf> {
         ))
         .into());
     }
-    return Ok(filepath);
+    Ok(filepath)
 }
 
 pub fn fallback_config_dir() -> BriefsResult<PathBuf> {
@@ -165,7 +224,7 @@ pub fn fallback_config_dir() -> BriefsResult<PathBuf> {
         )
         .into());
     }
-    return Ok(filepath);
+    Ok(filepath)
 }
 
 #[cfg(test)]
@@ -229,10 +288,20 @@ mod tests {
 
         config.cert = std::env::temp_dir().join(CONFIG_DIR);
         config.db = crate::db::generate_temp_db();
+        config.http = Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8081));
+        config.client_ca = Some(std::env::temp_dir().join("ca.pem"));
+        config.drain_timeout = 3;
+        config.trash_retention = 7;
+        config.private_reads = true;
         config.save().unwrap();
 
         let saved_config = BriefsConfig::from_file(config.filepath.clone()).unwrap();
         assert_eq!(config.socket, saved_config.socket);
+        assert_eq!(config.http, saved_config.http);
+        assert_eq!(config.client_ca, saved_config.client_ca);
+        assert_eq!(config.drain_timeout, saved_config.drain_timeout);
+        assert_eq!(config.trash_retention, saved_config.trash_retention);
+        assert_eq!(config.private_reads, saved_config.private_reads);
         assert_eq!(config.cert, saved_config.cert);
         assert_eq!(config.pkey, saved_config.pkey);
         assert_eq!(config.db, saved_config.db);
@@ -0,0 +1,74 @@
+//! Opaque cursors, handed out to clients to continue where a page of
+//! results ended. Clients pass a cursor back as is and must not rely on
+//! its format.
+
+use crate::{BriefsError, BriefsResult};
+
+/// Encodes the position of the next page within the results of `scope`.
+pub fn encode(scope: &str, position: u64) -> String {
+    format!("{scope}:{position}")
+        .bytes()
+        .map(|val| format!("{val:02x}"))
+        .collect()
+}
+
+/// Decodes a cursor made by `encode` for the same scope.
+///
+/// # Errors
+///
+/// This function will return `BriefsError::InvalidCursor` if the cursor is
+/// malformed or was made for another scope.
+pub fn decode(scope: &str, cursor: &str) -> BriefsResult<u64> {
+    let invalid = || BriefsError::InvalidCursor {
+        cursor: cursor.to_owned(),
+    };
+    if !cursor.is_ascii() || !cursor.len().is_multiple_of(2) {
+        return Err(invalid().into());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&cursor[idx..idx + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid())?;
+    let position = String::from_utf8(bytes)
+        .ok()
+        .and_then(|val| {
+            val.strip_prefix(scope)?
+                .strip_prefix(':')?
+                .parse::<u64>()
+                .ok()
+        })
+        .ok_or_else(invalid)?;
+
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        for position in [0, 20, u64::MAX] {
+            let cursor = encode("search", position);
+            assert!(cursor.bytes().all(|val| val.is_ascii_hexdigit()));
+            assert_eq!(decode("search", &cursor).unwrap(), position);
+        }
+
+        for cursor in [
+            String::new(),
+            "not a cursor".into(),
+            "abc".into(),
+            "ünï".into(),
+            encode("catchup", 20),
+            encode("search", 20).replace("32", "2d"),
+        ] {
+            assert!(matches!(
+                decode("search", &cursor)
+                    .unwrap_err()
+                    .downcast_ref::<BriefsError>(),
+                Some(BriefsError::InvalidCursor { .. })
+            ));
+        }
+    }
+}
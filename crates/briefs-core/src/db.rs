@@ -1,38 +1,63 @@
-use crate::{constant::STREAM_CACHE_SIZE, post::Post, BriefsError, BriefsResult};
+use crate::{
+    auth::{Role, TokenInfo},
+    constant::{DEFAULT_STREAM, STREAM_CACHE_SIZE},
+    migrations,
+    post::Post,
+    revision::Revision,
+    search::{ELLIPSIS, HIGHLIGHT, SNIPPET_WORDS, TITLE_WEIGHT},
+    state::{SearchHit, StreamInfo},
+    store::PageQuery,
+    BriefsError, BriefsResult,
+};
+// Provides the sqlite library the sqlite crate binds to, see Cargo.toml
+use libsqlite3_sys as _;
 use rand::{thread_rng, Rng};
-use sqlite::Connection;
-use std::{path::PathBuf, process};
+use sqlite::{Connection, Statement};
+use std::path::PathBuf;
 
 const DB_NAME: &str = "briefs-dev.db";
 pub const POSTS_TABLE: &str = "posts";
 pub const CACHE_VIEW: &str = "cache";
 pub const COUNT_VIEW: &str = "post_count";
-
-pub trait DbInsertString {
-    /// A trait for converting the underlying data into Db friendly
-    /// syntax. Currently, only implemented for `Post`.
+pub const STREAMS_TABLE: &str = "streams";
+pub const TOKENS_TABLE: &str = "tokens";
+pub const REVISIONS_TABLE: &str = "revisions";
+pub const SEARCH_INDEX: &str = "posts_fts";
+/// ID of the default stream; posts of Dbs created before named streams
+/// belong to it.
+pub const DEFAULT_STREAM_ID: u32 = 1;
+
+pub trait FromRow: Sized {
+    /// A trait for converting a sqlite row into the underlying data.
+    /// Currently, implemented for `Post`, `StreamInfo`, `TokenInfo`,
+    /// `Revision` and `SearchHit`.
     ///
     /// # Errors
     ///
-    /// This function will return an error if .
-    fn db_insert_string(&self) -> BriefsResult<String>;
-}
-
-impl DbInsertString for Post {
-    fn db_insert_string(&self) -> BriefsResult<String> {
-        // \"\" are needed, otherwise the insertion will fail.
-        Ok(format!(
-            "{},\"{}\",\"{}\",{},{}",
-            self.id()
-                .map_err(|_| BriefsError::custom_error("Unable to load post ID".into()))?,
-            self.title,
-            self.msg,
-            self.date,
-            self.edited
-        ))
+    /// This function will return an error if a column is missing or
+    /// holds a value of an unexpected type.
+    fn from_row(row: &sqlite::Row) -> BriefsResult<Self>;
+}
+
+/// Steps through the statement and maps every returned row into `T`.
+fn query_rows<T: FromRow>(mut stmt: Statement) -> BriefsResult<Vec<T>> {
+    let mut result = Vec::new();
+    for row in stmt.iter() {
+        result.push(T::from_row(&row?)?);
     }
+
+    Ok(result)
+}
+
+/// Converts an unsigned value into a sqlite integer.
+fn to_sql_int<T: TryInto<i64>>(val: T) -> BriefsResult<i64> {
+    val.try_into().map_err(|_| {
+        BriefsError::custom_error("Value does not fit into a sqlite integer".into()).into()
+    })
 }
 
+/// Creates the posts table as of schema version 1. Later changes to the
+/// schema are applied by `migrations`.
 pub fn setup_tables(conn: &mut Connection) -> BriefsResult<()> {
     let statement = format!(
         "
@@ -50,6 +75,22 @@ pub fn setup_tables(conn: &mut Connection) -> BriefsResult<()> {
     Ok(())
 }
 
+pub fn setup_streams_table(conn: &mut Connection) -> BriefsResult<()> {
+    let statement = format!(
+        "\
+        CREATE TABLE IF NOT EXISTS {STREAMS_TABLE} \
+        (id INTEGER PRIMARY KEY, \
+        last_updated INTEGER NOT NULL, \
+        date_of_inception INTEGER NOT NULL);\
+        "
+    );
+
+    conn.execute(statement)?;
+
+    Ok(())
+}
+
+/// Creates the views as of schema version 1.
 pub fn setup_views(conn: &mut Connection) -> BriefsResult<()> {
     let statement = format!(
         "\
@@ -73,98 +114,610 @@ pub fn setup_views(conn: &mut Connection) -> BriefsResult<()> {
     Ok(())
 }
 
-pub fn query_table_info(
-    conn: &mut Connection,
-    table_name: &str,
-) -> BriefsResult<Vec<sqlite::Row>> {
-    let statement = format!("PRAGMA table_info({table_name});");
+/// Gives every stream a unique name and scopes the posts by stream. Posts
+/// of a Db hosting a single stream are moved to the default stream.
+pub fn scope_posts_by_stream(conn: &mut Connection) -> BriefsResult<()> {
+    let statement = format!(
+        "\
+        CREATE TABLE {STREAMS_TABLE}_new \
+        (id INTEGER PRIMARY KEY, \
+        name TEXT NOT NULL UNIQUE, \
+        archived BOOLEAN NOT NULL DEFAULT 0, \
+        last_updated INTEGER NOT NULL, \
+        date_of_inception INTEGER NOT NULL); \
+        INSERT INTO {STREAMS_TABLE}_new (id, name, last_updated, date_of_inception) \
+        SELECT id, CASE id WHEN {DEFAULT_STREAM_ID} THEN '{DEFAULT_STREAM}' ELSE 'stream-' || id END, \
+        last_updated, date_of_inception FROM {STREAMS_TABLE}; \
+        DROP TABLE {STREAMS_TABLE}; \
+        ALTER TABLE {STREAMS_TABLE}_new RENAME TO {STREAMS_TABLE};\
+        "
+    );
 
-    let mut stmt = conn.prepare(statement)?;
+    conn.execute(statement)?;
+
+    let statement = format!(
+        "\
+        DROP VIEW IF EXISTS {CACHE_VIEW}; \
+        DROP VIEW IF EXISTS {COUNT_VIEW}; \
+        CREATE TABLE {POSTS_TABLE}_new \
+        (stream_id INTEGER NOT NULL, \
+        id INTEGER NOT NULL, \
+        title TEXT NOT NULL, \
+        msg TEXT NOT NULL, \
+        date INTEGER NOT NULL, \
+        edited BOOLEAN, \
+        PRIMARY KEY (stream_id, id)); \
+        INSERT INTO {POSTS_TABLE}_new (stream_id, id, title, msg, date, edited) \
+        SELECT {DEFAULT_STREAM_ID}, id, title, msg, date, edited FROM {POSTS_TABLE}; \
+        DROP TABLE {POSTS_TABLE}; \
+        ALTER TABLE {POSTS_TABLE}_new RENAME TO {POSTS_TABLE}; \
+        CREATE VIEW {CACHE_VIEW} AS \
+        SELECT * FROM \
+        (SELECT *, ROW_NUMBER() OVER (PARTITION BY stream_id ORDER BY id DESC) AS row_num \
+        FROM {POSTS_TABLE}) \
+        WHERE row_num <= {STREAM_CACHE_SIZE}; \
+        CREATE VIEW {COUNT_VIEW} AS \
+        SELECT stream_id, COUNT(*) AS count FROM {POSTS_TABLE} GROUP BY stream_id;\
+        "
+    );
+
+    conn.execute(statement)?;
+
+    Ok(())
+}
+
+/// Creates the table of API tokens. Only the hash of a token is stored.
+pub fn setup_tokens_table(conn: &mut Connection) -> BriefsResult<()> {
+    let statement = format!(
+        "\
+        CREATE TABLE IF NOT EXISTS {TOKENS_TABLE} \
+        (name TEXT PRIMARY KEY, \
+        hash TEXT NOT NULL UNIQUE, \
+        role TEXT NOT NULL, \
+        created INTEGER NOT NULL);\
+        "
+    );
+
+    conn.execute(statement)?;
+
+    Ok(())
+}
+
+/// Creates the table of post revisions. Every revision stores the title
+/// and message before and after the edit.
+pub fn setup_revisions_table(conn: &mut Connection) -> BriefsResult<()> {
+    let statement = format!(
+        "\
+        CREATE TABLE IF NOT EXISTS {REVISIONS_TABLE} \
+        (stream_id INTEGER NOT NULL, \
+        post_id INTEGER NOT NULL, \
+        revision INTEGER NOT NULL, \
+        author TEXT NOT NULL, \
+        date INTEGER NOT NULL, \
+        old_title TEXT NOT NULL, \
+        old_msg TEXT NOT NULL, \
+        new_title TEXT NOT NULL, \
+        new_msg TEXT NOT NULL, \
+        PRIMARY KEY (stream_id, post_id, revision));\
+        "
+    );
+
+    conn.execute(statement)?;
+
+    Ok(())
+}
+
+/// Turns deletes into tombstones: a deleted post keeps its row, with the
+/// time of deletion in `deleted`, until it is purged. The views only see
+/// live posts.
+pub fn add_post_tombstones(conn: &mut Connection) -> BriefsResult<()> {
+    let statement = format!(
+        "\
+        ALTER TABLE {POSTS_TABLE} ADD COLUMN deleted INTEGER; \
+        DROP VIEW IF EXISTS {CACHE_VIEW}; \
+        DROP VIEW IF EXISTS {COUNT_VIEW}; \
+        CREATE VIEW {CACHE_VIEW} AS \
+        SELECT * FROM \
+        (SELECT *, ROW_NUMBER() OVER (PARTITION BY stream_id ORDER BY id DESC) AS row_num \
+        FROM {POSTS_TABLE} WHERE deleted IS NULL) \
+        WHERE row_num <= {STREAM_CACHE_SIZE}; \
+        CREATE VIEW {COUNT_VIEW} AS \
+        SELECT stream_id, COUNT(*) AS count FROM {POSTS_TABLE} \
+        WHERE deleted IS NULL GROUP BY stream_id;\
+        "
+    );
+
+    conn.execute(statement)?;
 
-    let result: Vec<sqlite::Row> = stmt.iter().filter_map(|val| val.ok()).collect();
+    Ok(())
+}
+
+/// Gives every stream a sequence of post IDs, starting after the latest
+/// post. The sequence only moves forward, so the IDs of deleted posts are
+/// never reused.
+pub fn add_post_id_sequence(conn: &mut Connection) -> BriefsResult<()> {
+    let statement = format!(
+        "\
+        ALTER TABLE {STREAMS_TABLE} ADD COLUMN next_post_id INTEGER NOT NULL DEFAULT 0; \
+        UPDATE {STREAMS_TABLE} SET next_post_id = \
+        (SELECT COALESCE(MAX(id) + 1, 0) FROM {POSTS_TABLE} \
+        WHERE {POSTS_TABLE}.stream_id = {STREAMS_TABLE}.id);\
+        "
+    );
+
+    conn.execute(statement)?;
+
+    Ok(())
+}
+
+/// Creates the FTS5 index of the title and message of the live posts,
+/// kept in sync by triggers. The rowid of a post in the index is its
+/// stream ID in the upper 32 bits and its ID in the lower ones.
+pub fn setup_search_index(conn: &mut Connection) -> BriefsResult<()> {
+    let new_rowid = "(new.stream_id << 32) | new.id";
+    let old_rowid = "(old.stream_id << 32) | old.id";
+    let statement = format!(
+        "\
+        CREATE VIRTUAL TABLE {SEARCH_INDEX} USING fts5(title, msg); \
+        CREATE TRIGGER {SEARCH_INDEX}_insert AFTER INSERT ON {POSTS_TABLE} \
+        WHEN new.deleted IS NULL BEGIN \
+        INSERT INTO {SEARCH_INDEX} (rowid, title, msg) \
+        VALUES ({new_rowid}, new.title, new.msg); \
+        END; \
+        CREATE TRIGGER {SEARCH_INDEX}_delete AFTER DELETE ON {POSTS_TABLE} \
+        WHEN old.deleted IS NULL BEGIN \
+        DELETE FROM {SEARCH_INDEX} WHERE rowid = {old_rowid}; \
+        END; \
+        CREATE TRIGGER {SEARCH_INDEX}_update \
+        AFTER UPDATE OF title, msg, deleted ON {POSTS_TABLE} BEGIN \
+        DELETE FROM {SEARCH_INDEX} WHERE rowid = {old_rowid}; \
+        INSERT INTO {SEARCH_INDEX} (rowid, title, msg) \
+        SELECT {new_rowid}, new.title, new.msg WHERE new.deleted IS NULL; \
+        END; \
+        INSERT INTO {SEARCH_INDEX} (rowid, title, msg) \
+        SELECT (stream_id << 32) | id, title, msg FROM {POSTS_TABLE} \
+        WHERE deleted IS NULL;\
+        "
+    );
+
+    conn.execute(statement)?;
+
+    Ok(())
+}
+
+pub fn query_table_info(conn: &mut Connection, table_name: &str) -> BriefsResult<Vec<sqlite::Row>> {
+    let mut stmt = conn.prepare("SELECT * FROM pragma_table_info(:table)")?;
+    stmt.bind((":table", table_name))?;
+
+    let result = stmt.iter().collect::<Result<Vec<sqlite::Row>, _>>()?;
 
     Ok(result)
 }
 
 pub fn create_db(path: PathBuf) -> BriefsResult<Connection> {
-    let conn = sqlite::open(path.as_path())?;
+    let conn = sqlite::open(path.as_path()).map_err(|e| BriefsError::SqliteError {
+        msg: format!("Unable to open db at {path:?}: {e}"),
+    })?;
 
     Ok(conn)
 }
 
-pub fn insert_post(conn: &mut Connection, data: &Post) -> BriefsResult<()> {
-    let value_string = data.db_insert_string()?;
-    let statement = format!("INSERT INTO {} VALUES ({})", POSTS_TABLE, value_string);
+/// Runs `f` inside a transaction. The transaction is committed if `f`
+/// succeeds and rolled back otherwise.
+pub fn transaction<T, F>(conn: &mut Connection, f: F) -> BriefsResult<T>
+where
+    F: FnOnce(&mut Connection) -> BriefsResult<T>,
+{
+    conn.execute("BEGIN")?;
+    match f(conn) {
+        Ok(val) => {
+            conn.execute("COMMIT")?;
+            Ok(val)
+        }
+        Err(e) => {
+            conn.execute("ROLLBACK")?;
+            Err(e)
+        }
+    }
+}
+
+/// Stores a new stream. Its ID sequence starts after the posts already
+/// stored for it, if any.
+pub fn insert_stream(conn: &mut Connection, info: &StreamInfo) -> BriefsResult<()> {
+    let statement = format!(
+        "INSERT INTO {STREAMS_TABLE} \
+        (id, name, archived, last_updated, date_of_inception, next_post_id) \
+        VALUES (:id, :name, :archived, :last_updated, :date_of_inception, \
+        (SELECT COALESCE(MAX(id) + 1, 0) FROM {POSTS_TABLE} WHERE stream_id = :id))"
+    );
 
-    conn.execute(statement)?;
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":id", i64::from(info.id).into()),
+        (":name", info.name.as_str().into()),
+        (":archived", i64::from(info.archived).into()),
+        (":last_updated", to_sql_int(info.last_updated)?.into()),
+        (
+            ":date_of_inception",
+            to_sql_int(info.date_of_inception)?.into(),
+        ),
+    ])?;
+    stmt.next()?;
 
     Ok(())
 }
 
-pub fn delete_post_by_id(conn: &mut Connection, post_id: u32) -> BriefsResult<()> {
-    let statement = format!("DELETE FROM {} WHERE id={}", POSTS_TABLE, post_id);
+/// Returns the ID to be used by the next stream.
+pub fn next_stream_id(conn: &Connection) -> BriefsResult<u32> {
+    let statement = format!("SELECT COALESCE(MAX(id), 0) + 1 AS next_id FROM {STREAMS_TABLE}");
 
-    conn.execute(statement)?;
+    let mut stmt = conn.prepare(statement)?;
+    stmt.next()?;
+
+    Ok(stmt.read::<i64, _>("next_id")?.try_into()?)
+}
+
+pub fn query_streams(conn: &Connection) -> BriefsResult<Vec<StreamInfo>> {
+    let statement = format!("SELECT * FROM {STREAMS_TABLE} ORDER BY id");
+
+    let stmt = conn.prepare(statement)?;
+
+    query_rows(stmt)
+}
+
+pub fn query_stream_by_id(conn: &Connection, stream_id: u32) -> BriefsResult<Option<StreamInfo>> {
+    let statement = format!("SELECT * FROM {STREAMS_TABLE} WHERE id = :id");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":id", i64::from(stream_id)))?;
+
+    let mut result: Vec<StreamInfo> = query_rows(stmt)?;
+
+    Ok(result.pop())
+}
+
+pub fn update_stream_last_updated(
+    conn: &mut Connection,
+    stream_id: u32,
+    last_updated: u64,
+) -> BriefsResult<()> {
+    let statement =
+        format!("UPDATE {STREAMS_TABLE} SET last_updated = :last_updated WHERE id = :id");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":last_updated", to_sql_int(last_updated)?))?;
+    stmt.bind((":id", i64::from(stream_id)))?;
+    stmt.next()?;
 
     Ok(())
 }
 
-pub fn update_post_title_by_id(
+/// Returns the ID to be used by the next post of the stream.
+pub fn query_next_post_id(conn: &Connection, stream_id: u32) -> BriefsResult<u32> {
+    let statement = format!("SELECT next_post_id FROM {STREAMS_TABLE} WHERE id = :id");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":id", i64::from(stream_id)))?;
+
+    match stmt.next()? {
+        sqlite::State::Row => Ok(stmt.read::<i64, _>("next_post_id")?.try_into()?),
+        sqlite::State::Done => Err(BriefsError::custom_error(format!(
+            "BROKEN Db: No stream found with ID {stream_id}"
+        ))
+        .into()),
+    }
+}
+
+/// Moves the ID sequence of the stream past `post_id`; IDs already handed
+/// out are never given out again.
+pub fn advance_post_id_sequence(
     conn: &mut Connection,
+    stream_id: u32,
     post_id: u32,
-    title: String,
 ) -> BriefsResult<()> {
     let statement = format!(
-        "UPDATE {} SET title = \"{}\" WHERE id={}",
-        POSTS_TABLE, title, post_id
+        "UPDATE {STREAMS_TABLE} SET next_post_id = MAX(next_post_id, :post_id + 1) \
+        WHERE id = :id"
     );
 
-    conn.execute(statement)?;
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":post_id", i64::from(post_id)))?;
+    stmt.bind((":id", i64::from(stream_id)))?;
+    stmt.next()?;
+
+    Ok(())
+}
+
+pub fn rename_stream(conn: &mut Connection, stream_id: u32, name: &str) -> BriefsResult<()> {
+    let statement = format!("UPDATE {STREAMS_TABLE} SET name = :name WHERE id = :id");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":name", name))?;
+    stmt.bind((":id", i64::from(stream_id)))?;
+    stmt.next()?;
+
+    Ok(())
+}
+
+pub fn archive_stream(conn: &mut Connection, stream_id: u32) -> BriefsResult<()> {
+    let statement = format!("UPDATE {STREAMS_TABLE} SET archived = 1 WHERE id = :id");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":id", i64::from(stream_id)))?;
+    stmt.next()?;
+
+    Ok(())
+}
+
+pub fn insert_post(conn: &mut Connection, stream_id: u32, data: &Post) -> BriefsResult<()> {
+    let statement = format!(
+        "INSERT INTO {POSTS_TABLE} (stream_id, id, title, msg, date, edited) \
+        VALUES (:stream_id, :id, :title, :msg, :date, :edited)"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":id", to_sql_int(data.id()?)?.into()),
+        (":title", data.title.as_str().into()),
+        (":msg", data.msg.as_str().into()),
+        (":date", to_sql_int(data.date)?.into()),
+        (":edited", i64::from(data.edited).into()),
+    ])?;
+    stmt.next()?;
+
+    Ok(())
+}
+
+/// Removes the post for good, whether it is live or a tombstone.
+pub fn delete_post_by_id(conn: &mut Connection, stream_id: u32, post_id: u32) -> BriefsResult<()> {
+    let statement = format!("DELETE FROM {POSTS_TABLE} WHERE stream_id = :stream_id AND id = :id");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":id", i64::from(post_id)))?;
+    stmt.next()?;
 
     Ok(())
 }
 
-pub fn update_post_msg_by_id(
+/// Marks the live post as deleted at `deleted`. Does not touch any row if
+/// there is no such live post.
+pub fn tombstone_post_by_id(
     conn: &mut Connection,
+    stream_id: u32,
     post_id: u32,
-    msg: String,
+    deleted: u64,
 ) -> BriefsResult<()> {
     let statement = format!(
-        "UPDATE {} SET msg = \"{}\" WHERE id={}",
-        POSTS_TABLE, msg, post_id
+        "UPDATE {POSTS_TABLE} SET deleted = :deleted \
+        WHERE stream_id = :stream_id AND id = :id AND deleted IS NULL"
     );
 
-    conn.execute(statement)?;
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":id", i64::from(post_id).into()),
+        (":deleted", to_sql_int(deleted)?.into()),
+    ])?;
+    stmt.next()?;
 
     Ok(())
 }
 
-pub fn query_posts(
+/// Brings back the deleted post. Does not touch any row if there is no
+/// such tombstone.
+pub fn undelete_post_by_id(
     conn: &mut Connection,
-    posts_limit: Option<u32>,
-) -> BriefsResult<Vec<sqlite::Row>> {
+    stream_id: u32,
+    post_id: u32,
+) -> BriefsResult<()> {
     let statement = format!(
-        "SELECT * FROM {} LIMIT {};",
-        POSTS_TABLE,
-        posts_limit.unwrap_or(20)
+        "UPDATE {POSTS_TABLE} SET deleted = NULL \
+        WHERE stream_id = :stream_id AND id = :id AND deleted IS NOT NULL"
     );
 
     let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":id", i64::from(post_id)))?;
+    stmt.next()?;
 
-    let result: Vec<sqlite::Row> = stmt.iter().filter_map(|val| val.ok()).collect();
+    Ok(())
+}
+
+/// Returns the IDs of at most `limit` posts with `id <= until` that were
+/// deleted at or after `since`, in ascending order. When there are more,
+/// the most recently deleted ones are returned.
+pub fn query_tombstones(
+    conn: &Connection,
+    stream_id: u32,
+    until: u32,
+    since: u64,
+    limit: u32,
+) -> BriefsResult<Vec<u32>> {
+    let statement = format!(
+        "SELECT id FROM (SELECT id FROM {POSTS_TABLE} \
+        WHERE stream_id = :stream_id AND id <= :until AND deleted >= :since \
+        ORDER BY deleted DESC, id DESC LIMIT :limit) ORDER BY id"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":until", i64::from(until).into()),
+        (":since", to_sql_int(since)?.into()),
+        (":limit", i64::from(limit).into()),
+    ])?;
+
+    let mut result = Vec::new();
+    while let sqlite::State::Row = stmt.next()? {
+        result.push(stmt.read::<i64, _>("id")?.try_into()?);
+    }
 
     Ok(result)
 }
 
-pub fn query_post_by_id(conn: &Connection, post_id: u32) -> BriefsResult<sqlite::Row> {
-    let statement = format!("SELECT * FROM {} WHERE id={}", POSTS_TABLE, post_id);
+/// Removes the posts deleted before `before` for good, along with their
+/// revisions. Returns the number of purged posts.
+pub fn purge_deleted(conn: &mut Connection, stream_id: u32, before: u64) -> BriefsResult<usize> {
+    let statement = format!(
+        "DELETE FROM {REVISIONS_TABLE} WHERE stream_id = :stream_id AND post_id IN \
+        (SELECT id FROM {POSTS_TABLE} \
+        WHERE stream_id = :stream_id AND deleted IS NOT NULL AND deleted < :before)"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":before", to_sql_int(before)?))?;
+    stmt.next()?;
+
+    let statement = format!(
+        "DELETE FROM {POSTS_TABLE} \
+        WHERE stream_id = :stream_id AND deleted IS NOT NULL AND deleted < :before"
+    );
 
     let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":before", to_sql_int(before)?))?;
+    stmt.next()?;
 
-    let mut result: Vec<sqlite::Row> = stmt.iter().filter_map(|val| val.ok()).collect();
+    Ok(conn.change_count())
+}
 
-    if result.len() == 0 {
-        return Err(BriefsError::custom_error("Post not found with the given ID".into()).into());
+/// Replaces both the title and the message of the post and marks it as
+/// edited.
+pub fn update_post_by_id(
+    conn: &mut Connection,
+    stream_id: u32,
+    post_id: u32,
+    title: &str,
+    msg: &str,
+) -> BriefsResult<()> {
+    let statement = format!(
+        "UPDATE {POSTS_TABLE} SET title = :title, msg = :msg, edited = 1 \
+        WHERE stream_id = :stream_id AND id = :id AND deleted IS NULL"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":id", i64::from(post_id).into()),
+        (":title", title.into()),
+        (":msg", msg.into()),
+    ])?;
+    stmt.next()?;
+
+    Ok(())
+}
+
+pub fn insert_revision(
+    conn: &mut Connection,
+    stream_id: u32,
+    post_id: u32,
+    data: &Revision,
+) -> BriefsResult<()> {
+    let statement = format!(
+        "INSERT INTO {REVISIONS_TABLE} \
+        (stream_id, post_id, revision, author, date, old_title, old_msg, new_title, new_msg) \
+        VALUES (:stream_id, :post_id, :revision, :author, :date, \
+        :old_title, :old_msg, :new_title, :new_msg)"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":post_id", i64::from(post_id).into()),
+        (":revision", i64::from(data.revision).into()),
+        (":author", data.author.as_str().into()),
+        (":date", to_sql_int(data.date)?.into()),
+        (":old_title", data.old_title.as_str().into()),
+        (":old_msg", data.old_msg.as_str().into()),
+        (":new_title", data.new_title.as_str().into()),
+        (":new_msg", data.new_msg.as_str().into()),
+    ])?;
+    stmt.next()?;
+
+    Ok(())
+}
+
+/// Returns the revisions of the post, oldest first.
+pub fn query_revisions(
+    conn: &Connection,
+    stream_id: u32,
+    post_id: u32,
+) -> BriefsResult<Vec<Revision>> {
+    let statement = format!(
+        "SELECT * FROM {REVISIONS_TABLE} \
+        WHERE stream_id = :stream_id AND post_id = :post_id ORDER BY revision"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":post_id", i64::from(post_id)))?;
+
+    query_rows(stmt)
+}
+
+/// Returns at most `limit` of the live posts with `id < before` whose
+/// latest revision is dated at or after `since`, in ascending order of ID.
+/// When there are more, the most recently edited ones are returned.
+pub fn query_edited(
+    conn: &Connection,
+    stream_id: u32,
+    before: u32,
+    since: u64,
+    limit: u32,
+) -> BriefsResult<Vec<Post>> {
+    let statement = format!(
+        "SELECT * FROM (SELECT p.* FROM {POSTS_TABLE} p JOIN \
+        (SELECT post_id, MAX(date) AS edited_at FROM {REVISIONS_TABLE} \
+        WHERE stream_id = :stream_id AND post_id < :before GROUP BY post_id) r \
+        ON p.id = r.post_id \
+        WHERE p.stream_id = :stream_id AND p.deleted IS NULL AND r.edited_at >= :since \
+        ORDER BY r.edited_at DESC, p.id DESC LIMIT :limit) ORDER BY id"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":before", i64::from(before).into()),
+        (":since", to_sql_int(since)?.into()),
+        (":limit", i64::from(limit).into()),
+    ])?;
+
+    query_rows(stmt)
+}
+
+pub fn query_posts(
+    conn: &Connection,
+    stream_id: u32,
+    posts_limit: Option<u32>,
+) -> BriefsResult<Vec<Post>> {
+    let statement = format!(
+        "SELECT * FROM {POSTS_TABLE} WHERE stream_id = :stream_id AND deleted IS NULL \
+        ORDER BY id LIMIT :limit"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":limit", i64::from(posts_limit.unwrap_or(20))))?;
+
+    query_rows(stmt)
+}
+
+pub fn query_post_by_id(conn: &Connection, stream_id: u32, post_id: u32) -> BriefsResult<Post> {
+    let statement = format!(
+        "SELECT * FROM {POSTS_TABLE} WHERE stream_id = :stream_id AND id = :id AND deleted IS NULL"
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":id", i64::from(post_id)))?;
+
+    let mut result: Vec<Post> = query_rows(stmt)?;
+
+    if result.is_empty() {
+        return Err(BriefsError::InvalidId {}.into());
     } else if result.len() > 1 {
         return Err(BriefsError::custom_error(
             "BROKEN Db: Multiple posts found with the same ID".into(),
@@ -175,116 +728,311 @@ pub fn query_post_by_id(conn: &Connection, post_id: u32) -> BriefsResult<sqlite:
     Ok(result.remove(0))
 }
 
-pub fn query_post_count(conn: &Connection) -> BriefsResult<sqlite::Row> {
-    let statement = format!("SELECT count FROM {}", COUNT_VIEW);
+pub fn query_post_count(conn: &Connection, stream_id: u32) -> BriefsResult<u64> {
+    let statement = format!("SELECT count FROM {COUNT_VIEW} WHERE stream_id = :stream_id");
 
     let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+
+    match stmt.next()? {
+        sqlite::State::Row => Ok(stmt
+            .read::<i64, _>("count")?
+            .try_into()
+            .map_err(|_| BriefsError::SqliteValueParseError)?),
+        // Streams without posts have no row in the view
+        sqlite::State::Done => Ok(0),
+    }
+}
 
-    let mut result: Vec<sqlite::Row> = stmt.iter().filter_map(|val| val.ok()).collect();
+pub fn query_cache(conn: &Connection, stream_id: u32) -> BriefsResult<Vec<Post>> {
+    let statement =
+        format!("SELECT * FROM {CACHE_VIEW} WHERE stream_id = :stream_id ORDER BY id DESC");
 
-    if result.len() == 0 || result.len() > 1 {
-        return Err(BriefsError::custom_error("Multiple rows in posts count".into()).into());
-    };
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
 
-    Ok(result.remove(0))
+    query_rows(stmt)
 }
 
-pub fn query_cache(conn: &mut Connection) -> BriefsResult<Vec<sqlite::Row>> {
-    let statement = format!("SELECT * FROM {} ", CACHE_VIEW);
+pub fn query_last_n(conn: &Connection, stream_id: u32, n: u32) -> BriefsResult<Vec<Post>> {
+    let statement = format!(
+        "SELECT * FROM {POSTS_TABLE} WHERE stream_id = :stream_id AND deleted IS NULL \
+        ORDER BY id DESC LIMIT :limit"
+    );
 
     let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":stream_id", i64::from(stream_id)))?;
+    stmt.bind((":limit", i64::from(n)))?;
 
-    let result: Vec<sqlite::Row> = stmt.iter().filter_map(|val| val.ok()).collect();
-
-    Ok(result)
+    query_rows(stmt)
 }
 
-pub fn query_last_n(conn: &mut Connection, n: u32) -> BriefsResult<Vec<sqlite::Row>> {
-    let statement = format!("SELECT * FROM {} ORDER BY id DESC LIMIT {}", POSTS_TABLE, n);
+/// Returns at most `limit` of the live posts selected by the query, in
+/// ascending order of ID. Pages are bounded by IDs rather than offsets, so
+/// gaps left by deleted posts do not shift them.
+pub fn catchup(
+    conn: &Connection,
+    stream_id: u32,
+    query: &PageQuery,
+    limit: u32,
+) -> BriefsResult<Vec<Post>> {
+    let order = if query.backward { "DESC" } else { "ASC" };
+    let statement = format!(
+        "SELECT * FROM {POSTS_TABLE} \
+        WHERE stream_id = :stream_id AND deleted IS NULL \
+        AND id >= :from_id AND (:to_id IS NULL OR id < :to_id) \
+        AND (:since IS NULL OR date >= :since) AND (:until IS NULL OR date < :until) \
+        ORDER BY id {order} LIMIT :limit"
+    );
 
     let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":from_id", i64::from(query.from_id).into()),
+        (":to_id", query.to_id.map(i64::from).into()),
+        (":since", query.since.map(to_sql_int).transpose()?.into()),
+        (":until", query.until.map(to_sql_int).transpose()?.into()),
+        (":limit", i64::from(limit).into()),
+    ])?;
+
+    let mut posts: Vec<Post> = query_rows(stmt)?;
+    if query.backward {
+        posts.reverse();
+    }
 
-    let result: Vec<sqlite::Row> = stmt.iter().filter_map(|val| val.ok()).collect();
+    Ok(posts)
+}
 
-    Ok(result)
+/// Returns the live posts of the stream matching the FTS5 query, best
+/// match first; see `search::fts_query`.
+pub fn search_posts(
+    conn: &Connection,
+    stream_id: u32,
+    fts_query: &str,
+    limit: u32,
+    offset: u64,
+) -> BriefsResult<Vec<SearchHit>> {
+    let statement = format!(
+        "\
+        SELECT {POSTS_TABLE}.*, \
+        highlight({SEARCH_INDEX}, 0, '{HIGHLIGHT}', '{HIGHLIGHT}') AS highlighted_title, \
+        snippet({SEARCH_INDEX}, 1, '{HIGHLIGHT}', '{HIGHLIGHT}', '{ELLIPSIS}', {SNIPPET_WORDS}) \
+        AS snippet, \
+        -bm25({SEARCH_INDEX}, {TITLE_WEIGHT:.1}, 1.0) AS score \
+        FROM {SEARCH_INDEX} JOIN {POSTS_TABLE} \
+        ON {POSTS_TABLE}.stream_id = :stream_id \
+        AND {POSTS_TABLE}.id = ({SEARCH_INDEX}.rowid & 0xffffffff) \
+        WHERE {SEARCH_INDEX} MATCH :query \
+        AND {SEARCH_INDEX}.rowid BETWEEN (:stream_id << 32) AND ((:stream_id << 32) | 0xffffffff) \
+        ORDER BY score DESC, {POSTS_TABLE}.id DESC LIMIT :limit OFFSET :offset\
+        "
+    );
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":stream_id", i64::from(stream_id).into()),
+        (":query", fts_query.into()),
+        (":limit", i64::from(limit).into()),
+        (":offset", to_sql_int(offset)?.into()),
+    ])?;
+
+    query_rows(stmt)
 }
 
-pub fn catchup(conn: &Connection, sid: u64, eid: u64, limit: u32) -> BriefsResult<Vec<sqlite::Row>> {
+pub fn insert_token(conn: &mut Connection, info: &TokenInfo, hash: &str) -> BriefsResult<()> {
     let statement = format!(
-        "SELECT * FROM {} WHERE id >= {} AND id <= {} LIMIT {}",
-        POSTS_TABLE, sid, eid, limit
+        "INSERT INTO {TOKENS_TABLE} (name, hash, role, created) \
+        VALUES (:name, :hash, :role, :created)"
     );
 
     let mut stmt = conn.prepare(statement)?;
+    stmt.bind::<&[(_, sqlite::Value)]>(&[
+        (":name", info.name.as_str().into()),
+        (":hash", hash.into()),
+        (":role", info.role.to_string().into()),
+        (":created", to_sql_int(info.created)?.into()),
+    ])?;
+    stmt.next()?;
 
-    let result: Vec<sqlite::Row> = stmt.iter().filter_map(|val| val.ok()).collect();
+    Ok(())
+}
 
-    Ok(result)
+pub fn query_tokens(conn: &Connection) -> BriefsResult<Vec<TokenInfo>> {
+    let statement = format!("SELECT * FROM {TOKENS_TABLE} ORDER BY created, name");
+
+    let stmt = conn.prepare(statement)?;
+
+    query_rows(stmt)
 }
 
-pub fn sqlite_to_post(records: Vec<sqlite::Row>) -> BriefsResult<Vec<Post>> {
-    let mut result = Vec::with_capacity(records.len());
-    for row in records.into_iter() {
-        let post = Post::parse_sqlite_row(row)?;
-        result.push(post);
-    }
+pub fn query_token_by_name(conn: &Connection, name: &str) -> BriefsResult<Option<TokenInfo>> {
+    let statement = format!("SELECT * FROM {TOKENS_TABLE} WHERE name = :name");
 
-    Ok(result)
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":name", name))?;
+
+    let mut result: Vec<TokenInfo> = query_rows(stmt)?;
+
+    Ok(result.pop())
+}
+
+pub fn query_token_by_hash(conn: &Connection, hash: &str) -> BriefsResult<Option<TokenInfo>> {
+    let statement = format!("SELECT * FROM {TOKENS_TABLE} WHERE hash = :hash");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":hash", hash))?;
+
+    let mut result: Vec<TokenInfo> = query_rows(stmt)?;
+
+    Ok(result.pop())
+}
+
+/// Removes the token with the given name. Returns false if there is none.
+pub fn delete_token(conn: &mut Connection, name: &str) -> BriefsResult<bool> {
+    let statement = format!("DELETE FROM {TOKENS_TABLE} WHERE name = :name");
+
+    let mut stmt = conn.prepare(statement)?;
+    stmt.bind((":name", name))?;
+    stmt.next()?;
+
+    Ok(conn.change_count() > 0)
 }
 
 /// path - Can be either a complete file path(with .db suffix) or
 ///        a directory name which will then be appended with default
 ///        db name.
 ///
-/// # Panics
+/// The bundled sqlite library is used, so no external `sqlite3`
+/// installation is needed.
+///
+/// # Errors
 ///
-/// Panics if sqlite3 is not installed.
+/// This function will return `BriefsError::SqliteError` if the Db cannot be
+/// opened or its schema cannot be migrated.
 pub fn setup_db(path: Option<PathBuf>) -> BriefsResult<()> {
-    // Check if sqlite3 is installed
-    let sqlite3_check = process::Command::new("sqlite3")
-        .arg("-version")
-        .output()
-        .expect("sqlite3 not installed");
-
-    if !sqlite3_check.status.success() {
-        return Err(BriefsError::SqliteError {
-            msg: String::from_utf8(sqlite3_check.stderr)
-                .expect("Unable to parse sqlite3 error to string"),
-        }
-        .into());
+    // Setup Db
+    let db_path = resolve_db_path(path)?;
+    if !db_path.try_exists()? {
+        println!("{db_path:?} does not exist; creating a new db");
+    }
+    let mut conn = create_db(db_path)?;
+
+    migrations::migrate_up(&mut conn).map_err(|e| match e.downcast::<BriefsError>() {
+        Ok(e) => e,
+        Err(e) => BriefsError::SqliteError { msg: e.to_string() },
+    })?;
+
+    Ok(())
+}
+
+/// Resolves the sqlite file used by `setup_db`. A directory, or a path that
+/// does not exist and lacks the `.db` suffix, is appended with the default db
+/// name. When no path is given, the default db inside the temp dir is used.
+pub fn resolve_db_path(path: Option<PathBuf>) -> BriefsResult<PathBuf> {
+    let Some(inner_path) = path else {
+        return Ok(std::env::temp_dir().join(DB_NAME));
     };
 
-    // println!(
-    //     "Found sqlite3: {}",
-    //     String::from_utf8(sqlite3_check.stdout).expect("Unable to parse sqlite3 stdout")
-    // );
+    if inner_path.try_exists()? && !inner_path.is_dir() {
+        return Ok(inner_path);
+    }
 
-    // Setup Db
-    let mut conn: Connection;
-    match path {
-        Some(inner_path) => {
-            if !inner_path.try_exists()? || inner_path.is_dir() {
-                println!("{inner_path:?} does not exist or is a directory; creating a new db");
-
-                if !inner_path.to_str().unwrap().ends_with(".db") {
-                    conn = create_db(inner_path.join(DB_NAME))?;
-                } else {
-                    conn = create_db(inner_path)?;
-                }
-            } else {
-                conn = create_db(inner_path)?;
-            }
-        }
-        None => {
-            let db_path = std::env::temp_dir().join(DB_NAME);
-            conn = create_db(db_path)?;
-        }
+    if inner_path.extension().is_some_and(|ext| ext == "db") && !inner_path.is_dir() {
+        Ok(inner_path)
+    } else {
+        Ok(inner_path.join(DB_NAME))
     }
+}
 
-    setup_tables(&mut conn)?;
-    setup_views(&mut conn)?;
+impl FromRow for StreamInfo {
+    fn from_row(row: &sqlite::Row) -> BriefsResult<Self> {
+        let id: i64 = row
+            .try_read("id")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let name: &str = row
+            .try_read("name")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let archived: i64 = row
+            .try_read("archived")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let last_updated: i64 = row
+            .try_read("last_updated")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let date_of_inception: i64 = row
+            .try_read("date_of_inception")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+
+        Ok(StreamInfo {
+            id: id.try_into()?,
+            name: name.to_owned(),
+            archived: archived != 0,
+            last_updated: last_updated.try_into()?,
+            date_of_inception: date_of_inception.try_into()?,
+        })
+    }
+}
 
-    Ok(())
+impl FromRow for TokenInfo {
+    fn from_row(row: &sqlite::Row) -> BriefsResult<Self> {
+        let name: &str = row
+            .try_read("name")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let role: &str = row
+            .try_read("role")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let created: i64 = row
+            .try_read("created")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+
+        Ok(TokenInfo {
+            name: name.to_owned(),
+            role: role
+                .parse::<Role>()
+                .map_err(|_| BriefsError::SqliteValueParseError)?,
+            created: created.try_into()?,
+        })
+    }
+}
+
+impl FromRow for SearchHit {
+    fn from_row(row: &sqlite::Row) -> BriefsResult<Self> {
+        let title: &str = row
+            .try_read("highlighted_title")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let snippet: &str = row
+            .try_read("snippet")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let score: f64 = row
+            .try_read("score")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+
+        Ok(SearchHit {
+            post: Post::from_row(row)?,
+            score,
+            title: title.to_owned(),
+            snippet: snippet.to_owned(),
+        })
+    }
+}
+
+/// Version of the linked sqlite library, eg `3.45.3`.
+pub fn sqlite_version() -> String {
+    let version = sqlite::version();
+    format!(
+        "{}.{}.{}",
+        version / 1_000_000,
+        version / 1000 % 1000,
+        version % 1000
+    )
+}
+
+/// Whether the linked sqlite library was compiled with FTS5, which the
+/// search index of the posts needs.
+pub fn fts5_enabled(conn: &Connection) -> BriefsResult<bool> {
+    let mut stmt = conn.prepare("SELECT sqlite_compileoption_used('ENABLE_FTS5')")?;
+    stmt.next()?;
+
+    Ok(stmt.read::<i64, _>(0)? == 1)
 }
 
 /// Generates a random db name with four 16-bit fields, such that when generating
@@ -307,8 +1055,7 @@ pub fn generate_random_db_name() -> String {
 
 pub fn generate_temp_db() -> PathBuf {
     let random_db_name = generate_random_db_name();
-    let temp_dir = std::env::temp_dir().join(random_db_name);
-    temp_dir
+    std::env::temp_dir().join(random_db_name)
 }
 
 #[cfg(test)]
@@ -357,6 +1104,34 @@ pub mod test {
         cleanup_db(path);
     }
 
+    #[test]
+    fn test_setup_db_invalid_path() {
+        let path = std::env::temp_dir()
+            .join(generate_random_db_name())
+            .join("missing-dir")
+            .join("briefs.db");
+
+        let result = setup_db(Some(path));
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<BriefsError>(),
+            Some(BriefsError::SqliteError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sqlite_version() {
+        let version = sqlite_version();
+        let parts: Vec<u32> = version.split('.').map(|val| val.parse().unwrap()).collect();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0], 3);
+    }
+
+    #[test]
+    fn test_fts5_enabled() {
+        let conn = sqlite::open(":memory:").unwrap();
+        assert!(fts5_enabled(&conn).unwrap());
+    }
+
     #[test]
     fn test_setup_tables() {
         let db_name = generate_random_db_name();
@@ -371,13 +1146,15 @@ pub mod test {
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
         //----- Expected values
-        let expected_rows = 5u8;
+        let expected_rows = 7u8;
         let expected_columns = [
+            Value::String("stream_id".into()),
             Value::String("id".into()),
             Value::String("title".into()),
             Value::String("msg".into()),
             Value::String("date".into()),
             Value::String("edited".into()),
+            Value::String("deleted".into()),
         ];
         //-----
 
@@ -414,39 +1191,25 @@ pub mod test {
             "Hello there, this is my first post".into(),
         )
         .unwrap();
-        let result = insert_post(&mut conn, &post);
+        let result = insert_post(&mut conn, DEFAULT_STREAM_ID, &post);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
-        let result = query_posts(&mut conn, None);
+        let result = query_posts(&conn, DEFAULT_STREAM_ID, None);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
         //----- Expected values
         let expected_rows = 1;
-        let expected_columns = [
-            Value::Integer(0),
-            Value::String(post.title),
-            Value::String(post.msg),
-            Value::Integer(
-                post.date
-                    .try_into()
-                    .expect("Error: Unable to convert timestamp to sqlite integer"),
-            ),
-            Value::Integer(0),
-        ];
         //-----
 
-        let mut row_data = result.unwrap();
+        let row_data = result.unwrap();
         let actual_rows = row_data.len();
         assert_eq!(actual_rows, expected_rows);
 
-        let actual_columns = [
-            row_data[0].take(0),
-            row_data[0].take(1),
-            row_data[0].take(2),
-            row_data[0].take(3),
-            row_data[0].take(4),
-        ];
-        assert_eq!(actual_columns, expected_columns);
+        assert_eq!(row_data[0].id().unwrap(), 0);
+        assert_eq!(row_data[0].title, post.title);
+        assert_eq!(row_data[0].msg, post.msg);
+        assert_eq!(row_data[0].date, post.date);
+        assert!(!row_data[0].edited);
 
         cleanup_db(path);
     }
@@ -471,10 +1234,10 @@ pub mod test {
             "Hello there, this is my first post".into(),
         )
         .unwrap();
-        let result = insert_post(&mut conn, &post);
+        let result = insert_post(&mut conn, DEFAULT_STREAM_ID, &post);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
-        let result = query_posts(&mut conn, None);
+        let result = query_posts(&conn, DEFAULT_STREAM_ID, None);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
         //----- Expected values
@@ -487,10 +1250,10 @@ pub mod test {
 
         println!("{:?}", row_data);
 
-        let result = delete_post_by_id(&mut conn, 0);
+        let result = delete_post_by_id(&mut conn, DEFAULT_STREAM_ID, 0);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
-        let result = query_posts(&mut conn, None);
+        let result = query_posts(&conn, DEFAULT_STREAM_ID, None);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
         //----- Expected values
@@ -505,7 +1268,7 @@ pub mod test {
     }
 
     #[test]
-    fn test_update_post_title() {
+    fn test_posts_count() {
         let db_name = generate_random_db_name();
         let path = std::env::temp_dir().join(db_name.clone());
         setup_db(Some(path.clone())).unwrap();
@@ -524,43 +1287,41 @@ pub mod test {
             "Hello there, this is my first post".into(),
         )
         .unwrap();
-        let result = insert_post(&mut conn, &post);
+        let result = insert_post(&mut conn, DEFAULT_STREAM_ID, &post);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
-        let result = query_posts(&mut conn, None);
+        let result = query_post_count(&conn, DEFAULT_STREAM_ID);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
         //----- Expected values
-        let expected_rows = 1;
+        let expected_count = 1;
         //-----
+        let post_count = result.unwrap();
+        assert_eq!(post_count, expected_count);
 
-        let row_data = result.unwrap();
-        let actual_rows = row_data.len();
-        assert_eq!(actual_rows, expected_rows);
-        println!("{:?}", row_data);
-
-        let new_title = String::from("Updated Title!");
-        let result = update_post_title_by_id(&mut conn, 0, new_title.clone());
+        let post = Post::new(
+            1,
+            "Post #2".into(),
+            "Hello there, this is my second post".into(),
+        )
+        .unwrap();
+        let result = insert_post(&mut conn, DEFAULT_STREAM_ID, &post);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
-        let result = query_posts(&mut conn, None);
+        let result = query_post_count(&conn, DEFAULT_STREAM_ID);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
         //----- Expected values
-        let expected_rows = 1;
-        let expected_title = sqlite::Value::String(new_title);
+        let expected_count = 2;
         //-----
-        let mut row_data = result.unwrap();
-        assert_eq!(row_data.len(), expected_rows);
-
-        let post_title = row_data[0].take("title");
-        assert_eq!(post_title, expected_title);
+        let post_count = result.unwrap();
+        assert_eq!(post_count, expected_count);
 
         cleanup_db(path);
     }
 
     #[test]
-    fn test_update_post_msg() {
+    fn test_query_cache() {
         let db_name = generate_random_db_name();
         let path = std::env::temp_dir().join(db_name.clone());
         setup_db(Some(path.clone())).unwrap();
@@ -572,6 +1333,8 @@ pub mod test {
         let result = query_table_info(&mut conn, POSTS_TABLE);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
+        let mut posts = Vec::new();
+
         let mut conn = sqlite::open(path.clone()).unwrap();
         let post = Post::new(
             0,
@@ -579,151 +1342,322 @@ pub mod test {
             "Hello there, this is my first post".into(),
         )
         .unwrap();
-        let result = insert_post(&mut conn, &post);
+        let result = insert_post(&mut conn, DEFAULT_STREAM_ID, &post);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        posts.push(post);
 
-        let result = query_posts(&mut conn, None);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-
-        //----- Expected values
-        let expected_rows = 1;
-        //-----
-
-        let row_data = result.unwrap();
-        let actual_rows = row_data.len();
-        assert_eq!(actual_rows, expected_rows);
-        println!("{:?}", row_data);
-
-        let new_msg = String::from("This is a new updated msg. Interesting?");
-        let result = update_post_msg_by_id(&mut conn, 0, new_msg.clone());
+        let post = Post::new(
+            1,
+            "Post #2".into(),
+            "Hello there, this is my second post".into(),
+        )
+        .unwrap();
+        let result = insert_post(&mut conn, DEFAULT_STREAM_ID, &post);
         assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        posts.push(post);
 
-        let result = query_posts(&mut conn, None);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        let cache_posts = query_cache(&conn, DEFAULT_STREAM_ID).unwrap();
 
-        //----- Expected values
-        let expected_rows = 1;
-        let expected_msg = sqlite::Value::String(new_msg);
-        //-----
-        let mut row_data = result.unwrap();
-        assert_eq!(row_data.len(), expected_rows);
+        assert_eq!(cache_posts.len(), 2);
+        assert_eq!(posts[0].id().unwrap(), cache_posts[1].id().unwrap());
+        assert_eq!(posts[0].msg, cache_posts[1].msg);
+        assert_eq!(posts[0].title, cache_posts[1].title);
+        assert_eq!(posts[0].edited, cache_posts[1].edited);
+        assert_eq!(posts[0].date, cache_posts[1].date);
 
-        let post_msg = row_data[0].take("msg");
-        assert_eq!(post_msg, expected_msg);
+        assert_eq!(posts[1].id().unwrap(), cache_posts[0].id().unwrap());
+        assert_eq!(posts[1].msg, cache_posts[0].msg);
+        assert_eq!(posts[1].title, cache_posts[0].title);
+        assert_eq!(posts[1].edited, cache_posts[0].edited);
+        assert_eq!(posts[1].date, cache_posts[0].date);
 
         cleanup_db(path);
     }
 
     #[test]
-    fn test_posts_count() {
-        let db_name = generate_random_db_name();
-        let path = std::env::temp_dir().join(db_name.clone());
-        setup_db(Some(path.clone())).unwrap();
-
-        assert!(path.exists(), "Db creation failed at expected path");
-
+    fn test_stream_info() {
+        let path = setup_mock_db();
         let mut conn = sqlite::open(path.clone()).unwrap();
 
-        let result = query_table_info(&mut conn, POSTS_TABLE);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert!(query_stream_by_id(&conn, DEFAULT_STREAM_ID)
+            .unwrap()
+            .is_none());
+        assert_eq!(next_stream_id(&conn).unwrap(), DEFAULT_STREAM_ID);
+
+        let info = StreamInfo {
+            id: DEFAULT_STREAM_ID,
+            name: DEFAULT_STREAM.into(),
+            archived: false,
+            last_updated: 10,
+            date_of_inception: 5,
+        };
+        insert_stream(&mut conn, &info).unwrap();
+        assert_eq!(
+            query_stream_by_id(&conn, DEFAULT_STREAM_ID).unwrap(),
+            Some(info.clone())
+        );
+        assert_eq!(next_stream_id(&conn).unwrap(), DEFAULT_STREAM_ID + 1);
+        // Names are unique
+        let duplicate = StreamInfo {
+            id: DEFAULT_STREAM_ID + 1,
+            ..info
+        };
+        assert!(insert_stream(&mut conn, &duplicate).is_err());
+
+        update_stream_last_updated(&mut conn, DEFAULT_STREAM_ID, 20).unwrap();
+        rename_stream(&mut conn, DEFAULT_STREAM_ID, "ops").unwrap();
+        archive_stream(&mut conn, DEFAULT_STREAM_ID).unwrap();
+        let info = query_stream_by_id(&conn, DEFAULT_STREAM_ID)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.last_updated, 20);
+        assert_eq!(info.date_of_inception, 5);
+        assert_eq!(info.name, "ops");
+        assert!(info.archived);
+        assert_eq!(query_streams(&conn).unwrap(), vec![info]);
 
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_posts_are_scoped_by_stream() {
+        let path = setup_mock_db();
         let mut conn = sqlite::open(path.clone()).unwrap();
-        let post = Post::new(
-            0,
-            "Post #1".into(),
-            "Hello there, this is my first post".into(),
+        let other_stream = DEFAULT_STREAM_ID + 1;
+
+        // Both streams have their own ID sequence
+        for stream_id in [DEFAULT_STREAM_ID, other_stream] {
+            for id in 0..2 {
+                let title = format!("Stream #{stream_id} post #{id}");
+                let post = Post::new(id, title, "Hello there".into()).unwrap();
+                insert_post(&mut conn, stream_id, &post).unwrap();
+            }
+        }
+        insert_post(
+            &mut conn,
+            other_stream,
+            &Post::new(2, "Post".into(), "Hello there".into()).unwrap(),
         )
         .unwrap();
-        let result = insert_post(&mut conn, &post);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
 
-        let result = query_post_count(&mut conn);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert_eq!(query_post_count(&conn, DEFAULT_STREAM_ID).unwrap(), 2);
+        assert_eq!(query_post_count(&conn, other_stream).unwrap(), 3);
+        assert_eq!(query_post_count(&conn, other_stream + 1).unwrap(), 0);
+        assert_eq!(query_last_n(&conn, DEFAULT_STREAM_ID, 5).unwrap().len(), 2);
+        let all = PageQuery::default();
+        assert_eq!(catchup(&conn, other_stream, &all, 10).unwrap().len(), 3);
+
+        delete_post_by_id(&mut conn, other_stream, 0).unwrap();
+        update_post_by_id(&mut conn, other_stream, 1, "Updated", "Hello there").unwrap();
+        let post = query_post_by_id(&conn, DEFAULT_STREAM_ID, 0).unwrap();
+        assert_eq!(post.title, format!("Stream #{DEFAULT_STREAM_ID} post #0"));
+        let post = query_post_by_id(&conn, DEFAULT_STREAM_ID, 1).unwrap();
+        assert!(!post.edited);
+        assert!(query_post_by_id(&conn, other_stream, 0).is_err());
 
-        //----- Expected values
-        let expected_count = sqlite::Value::Integer(1);
-        //-----
-        let mut row_data = result.unwrap();
-        println!("{:?}", row_data);
+        cleanup_db(path);
+    }
 
-        let post_count = row_data.take("count");
-        assert_eq!(post_count, expected_count);
+    #[test]
+    fn test_tombstones() {
+        let path = setup_mock_db();
+        let mut conn = sqlite::open(path.clone()).unwrap();
 
-        let post = Post::new(
-            1,
-            "Post #2".into(),
-            "Hello there, this is my second post".into(),
-        )
-        .unwrap();
-        let result = insert_post(&mut conn, &post);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        for id in 0..3 {
+            let post = Post::new(id, "Post".into(), "Hello there".into()).unwrap();
+            insert_post(&mut conn, DEFAULT_STREAM_ID, &post).unwrap();
+        }
+        let revision = Revision {
+            revision: 1,
+            author: "ci".into(),
+            date: 5,
+            old_title: "Post".into(),
+            old_msg: "Hello there".into(),
+            new_title: "Post".into(),
+            new_msg: "Hello".into(),
+        };
+        insert_revision(&mut conn, DEFAULT_STREAM_ID, 1, &revision).unwrap();
+
+        tombstone_post_by_id(&mut conn, DEFAULT_STREAM_ID, 1, 10).unwrap();
+        tombstone_post_by_id(&mut conn, DEFAULT_STREAM_ID, 2, 20).unwrap();
+        // Tombstones are hidden from every query of live posts
+        assert_eq!(query_post_count(&conn, DEFAULT_STREAM_ID).unwrap(), 1);
+        assert_eq!(query_cache(&conn, DEFAULT_STREAM_ID).unwrap().len(), 1);
+        assert_eq!(query_last_n(&conn, DEFAULT_STREAM_ID, 5).unwrap().len(), 1);
+        assert_eq!(
+            catchup(&conn, DEFAULT_STREAM_ID, &PageQuery::default(), 10)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(query_post_by_id(&conn, DEFAULT_STREAM_ID, 1).is_err());
+        assert_eq!(
+            query_tombstones(&conn, DEFAULT_STREAM_ID, 5, 0, 10).unwrap(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            query_tombstones(&conn, DEFAULT_STREAM_ID, 1, 0, 10).unwrap(),
+            vec![1]
+        );
+        // Only deletes from `since` on, the most recent ones first
+        assert_eq!(
+            query_tombstones(&conn, DEFAULT_STREAM_ID, 5, 11, 10).unwrap(),
+            vec![2]
+        );
+        assert_eq!(
+            query_tombstones(&conn, DEFAULT_STREAM_ID, 5, 0, 1).unwrap(),
+            vec![2]
+        );
+
+        undelete_post_by_id(&mut conn, DEFAULT_STREAM_ID, 2).unwrap();
+        assert_eq!(
+            query_post_by_id(&conn, DEFAULT_STREAM_ID, 2)
+                .unwrap()
+                .id()
+                .unwrap(),
+            2
+        );
+        undelete_post_by_id(&mut conn, DEFAULT_STREAM_ID, 0).unwrap();
+        assert_eq!(conn.change_count(), 0);
+
+        // Only tombstones older than the cutoff are purged, with their revisions
+        assert_eq!(purge_deleted(&mut conn, DEFAULT_STREAM_ID, 10).unwrap(), 0);
+        assert_eq!(purge_deleted(&mut conn, DEFAULT_STREAM_ID, 11).unwrap(), 1);
+        assert!(query_tombstones(&conn, DEFAULT_STREAM_ID, 5, 0, 10)
+            .unwrap()
+            .is_empty());
+        assert!(query_revisions(&conn, DEFAULT_STREAM_ID, 1)
+            .unwrap()
+            .is_empty());
+        assert_eq!(query_post_count(&conn, DEFAULT_STREAM_ID).unwrap(), 2);
 
-        let result = query_post_count(&mut conn);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        cleanup_db(path);
+    }
 
-        //----- Expected values
-        let expected_count = sqlite::Value::Integer(2);
-        //-----
-        let mut row_data = result.unwrap();
-        println!("{:?}", row_data);
+    #[test]
+    fn test_post_id_sequence() {
+        let path = setup_mock_db();
+        let mut conn = sqlite::open(path.clone()).unwrap();
 
-        let post_count = row_data.take("count");
-        assert_eq!(post_count, expected_count);
+        // Posts stored before the stream start its sequence
+        let post = Post::new(4, "Post".into(), "Hello there".into()).unwrap();
+        insert_post(&mut conn, DEFAULT_STREAM_ID, &post).unwrap();
+        assert!(query_next_post_id(&conn, DEFAULT_STREAM_ID).is_err());
+        let info = StreamInfo {
+            id: DEFAULT_STREAM_ID,
+            name: DEFAULT_STREAM.into(),
+            archived: false,
+            last_updated: 0,
+            date_of_inception: 0,
+        };
+        insert_stream(&mut conn, &info).unwrap();
+        assert_eq!(query_next_post_id(&conn, DEFAULT_STREAM_ID).unwrap(), 5);
+
+        // The sequence never moves back, not even once the posts are purged
+        advance_post_id_sequence(&mut conn, DEFAULT_STREAM_ID, 7).unwrap();
+        advance_post_id_sequence(&mut conn, DEFAULT_STREAM_ID, 2).unwrap();
+        assert_eq!(query_next_post_id(&conn, DEFAULT_STREAM_ID).unwrap(), 8);
+        tombstone_post_by_id(&mut conn, DEFAULT_STREAM_ID, 4, 10).unwrap();
+        purge_deleted(&mut conn, DEFAULT_STREAM_ID, 11).unwrap();
+        assert_eq!(query_next_post_id(&conn, DEFAULT_STREAM_ID).unwrap(), 8);
 
         cleanup_db(path);
     }
 
     #[test]
-    fn test_query_cache() {
-        let db_name = generate_random_db_name();
-        let path = std::env::temp_dir().join(db_name.clone());
-        setup_db(Some(path.clone())).unwrap();
+    fn test_transaction_rollback() {
+        let path = setup_mock_db();
+        let mut conn = sqlite::open(path.clone()).unwrap();
 
-        assert!(path.exists(), "Db creation failed at expected path");
+        let post = Post::new(0, "Post #1".into(), "Hello there".into()).unwrap();
+        let result = transaction(&mut conn, |conn| {
+            insert_post(conn, DEFAULT_STREAM_ID, &post)?;
+            // Duplicate ID; fails the whole transaction
+            insert_post(conn, DEFAULT_STREAM_ID, &post)
+        });
+        assert!(result.is_err());
+        assert_eq!(query_post_count(&conn, DEFAULT_STREAM_ID).unwrap(), 0);
+
+        transaction(&mut conn, |conn| {
+            insert_post(conn, DEFAULT_STREAM_ID, &post)
+        })
+        .unwrap();
+        assert_eq!(query_post_count(&conn, DEFAULT_STREAM_ID).unwrap(), 1);
 
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_special_characters_roundtrip() {
+        let path = setup_mock_db();
         let mut conn = sqlite::open(path.clone()).unwrap();
 
-        let result = query_table_info(&mut conn, POSTS_TABLE);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        let samples = [
+            (r#"He said "hello""#, r#"Quotes "inside" the 'message'"#),
+            ("'); DROP TABLE posts; --", r#""); DELETE FROM posts; --"#),
+            ("Semi;colons;everywhere;", "SELECT * FROM posts; SELECT 1;"),
+            ("नमस्ते 😃", "Unicode ►→℞+ formatting 😃😃 ünïcödé"),
+        ];
 
-        let mut posts = Vec::new();
+        for (idx, (title, msg)) in samples.iter().enumerate() {
+            let post = Post::new(idx as u32, title.to_string(), msg.to_string()).unwrap();
+            let result = insert_post(&mut conn, DEFAULT_STREAM_ID, &post);
+            assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        }
+
+        assert_eq!(
+            query_post_count(&conn, DEFAULT_STREAM_ID).unwrap(),
+            samples.len() as u64
+        );
+
+        for (idx, (title, msg)) in samples.iter().enumerate() {
+            let post = query_post_by_id(&conn, DEFAULT_STREAM_ID, idx as u32).unwrap();
+            assert_eq!(post.title, *title);
+            assert_eq!(post.msg, *msg);
+            assert!(!post.edited);
+        }
+
+        cleanup_db(path);
+    }
 
+    #[test]
+    fn test_special_characters_update() {
+        let path = setup_mock_db();
         let mut conn = sqlite::open(path.clone()).unwrap();
-        let post = Post::new(
-            0,
-            "Post #1".into(),
-            "Hello there, this is my first post".into(),
-        )
-        .unwrap();
-        let result = insert_post(&mut conn, &post);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        posts.push(post);
 
-        let post = Post::new(
-            1,
-            "Post #2".into(),
-            "Hello there, this is my second post".into(),
-        )
-        .unwrap();
-        let result = insert_post(&mut conn, &post);
-        assert!(result.is_ok(), "{:?}", result.unwrap_err());
-        posts.push(post);
+        for id in 0..2 {
+            let post = Post::new(id, "Post".into(), "Hello there".into()).unwrap();
+            insert_post(&mut conn, DEFAULT_STREAM_ID, &post).unwrap();
+        }
 
-        let result = query_cache(&mut conn).unwrap();
-        let cache_posts = sqlite_to_post(result).unwrap();
+        let new_title = r#"Title" WHERE 1=1; --"#;
+        let new_msg = "'; UPDATE posts SET msg = 'pwned'; -- 😃";
+        update_post_by_id(&mut conn, DEFAULT_STREAM_ID, 0, new_title, new_msg).unwrap();
 
-        assert_eq!(cache_posts.len(), 2);
-        assert_eq!(posts[0].id().unwrap(), cache_posts[1].id().unwrap());
-        assert_eq!(posts[0].msg, cache_posts[1].msg);
-        assert_eq!(posts[0].title, cache_posts[1].title);
-        assert_eq!(posts[0].edited, cache_posts[1].edited);
-        assert_eq!(posts[0].date, cache_posts[1].date);
+        let post = query_post_by_id(&conn, DEFAULT_STREAM_ID, 0).unwrap();
+        assert_eq!(post.title, new_title);
+        assert_eq!(post.msg, new_msg);
+        assert!(post.edited);
 
-        assert_eq!(posts[1].id().unwrap(), cache_posts[0].id().unwrap());
-        assert_eq!(posts[1].msg, cache_posts[0].msg);
-        assert_eq!(posts[1].title, cache_posts[0].title);
-        assert_eq!(posts[1].edited, cache_posts[0].edited);
-        assert_eq!(posts[1].date, cache_posts[0].date);
+        // The other post must be untouched.
+        let post = query_post_by_id(&conn, DEFAULT_STREAM_ID, 1).unwrap();
+        assert_eq!(post.title, "Post");
+        assert_eq!(post.msg, "Hello there");
+        assert!(!post.edited);
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_query_post_by_invalid_id() {
+        let path = setup_mock_db();
+        let conn = sqlite::open(path.clone()).unwrap();
+
+        let result = query_post_by_id(&conn, DEFAULT_STREAM_ID, 42);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<BriefsError>(),
+            Some(BriefsError::InvalidId {})
+        ));
 
         cleanup_db(path);
     }
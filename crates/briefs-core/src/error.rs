@@ -31,10 +31,71 @@ pub enum BriefsError {
     /// The requested/specified ID does not exist.
     #[error("Post does not exist with the given ID")]
     InvalidId {},
+    /// No stream exists with the given name.
+    #[error("Stream '{name}' does not exist")]
+    UnknownStream { name: String },
+    /// A stream already exists with the given name.
+    #[error("Stream '{name}' already exists")]
+    StreamExists { name: String },
+    /// The stream is archived and no longer accepts changes.
+    #[error("Stream '{name}' is archived")]
+    StreamArchived { name: String },
+    /// The stream name is empty, too long or contains characters other
+    /// than lowercase letters, digits, '-' and '_'.
+    #[error("Invalid stream name '{name}'; max allowed size: {max_size}, allowed chars: a-z 0-9 - _")]
+    InvalidStreamName { name: String, max_size: usize },
+    /// The token name is empty, too long or contains characters other
+    /// than lowercase letters, digits, '-' and '_'.
+    #[error("Invalid token name '{name}'; max allowed size: {max_size}, allowed chars: a-z 0-9 - _")]
+    InvalidTokenName { name: String, max_size: usize },
+    /// The search query has no word to search for.
+    #[error("Invalid search query '{query}'; expected at least one word")]
+    InvalidQuery { query: String },
+    /// The cursor was not handed out by the server, or not for this kind
+    /// of results.
+    #[error("Invalid cursor '{cursor}'")]
+    InvalidCursor { cursor: String },
+    /// A token already exists with the given name.
+    #[error("Token '{name}' already exists")]
+    TokenExists { name: String },
+    /// No token exists with the given name.
+    #[error("Token '{name}' does not exist")]
+    UnknownToken { name: String },
+    /// The post has no revision with the given number.
+    #[error("Post {id} has no revision {revision}")]
+    UnknownRevision { id: u32, revision: u32 },
+    /// The command was sent before logging in.
+    #[error("Not logged in; send a Login command with an API token first")]
+    Unauthenticated,
+    /// The API token is unknown or was revoked.
+    #[error("Invalid or revoked API token")]
+    InvalidToken,
+    /// The role of the caller does not allow the command.
+    #[error("Role '{role}' is not allowed to run {cmd}; requires '{required}'")]
+    Forbidden {
+        role: String,
+        required: String,
+        cmd: String,
+    },
+    /// The frame length exceeds the maximum length.
+    #[error("Max allowed size of frame: {max_size}, current size: {curr_size}")]
+    InvalidFrameLength {
+        max_size: usize,
+        curr_size: usize,
+    },
+    /// The connection was closed in the middle of a frame.
+    #[error("Connection closed in the middle of a frame")]
+    IncompleteFrame,
+    /// A message received from a peer could not be decoded.
+    #[error("Invalid message: {msg}")]
+    InvalidMessage { msg: String },
     /// An error occured in a sqlite operation. This is just
     /// a wrapper around the error message.
     #[error("ERROR: {msg}")]
     SqliteError { msg: String },
+    /// The Db was migrated by a newer binary and cannot be opened.
+    #[error("Db schema version({found}) is newer than the supported version({supported})")]
+    IncompatibleSchema { found: u32, supported: u32 },
     /// Parsing of sqlite::Value into required type failed.
     #[error("ERROR: Unable to parse input sqlite `Value` into required type")]
     SqliteValueParseError,
@@ -51,7 +112,47 @@ pub enum BriefsError {
     },
 }
 
+/// Code sent for errors that are not a `BriefsError`.
+pub const INTERNAL_ERROR_CODE: u16 = 500;
+
 impl BriefsError {
+    /// Stable numeric code of the error, sent to clients along with the
+    /// message. Codes are never reused: 1xx are invalid input, 2xx are
+    /// requests that conflict with the state of a stream, 3xx are protocol
+    /// errors, 4xx are authentication failures and 5xx are server failures.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::EmptyTitle => 100,
+            Self::EmptyPost => 101,
+            Self::InvalidTitleLength { .. } => 102,
+            Self::InvalidPostLength { .. } => 103,
+            Self::InvalidStreamName { .. } => 104,
+            Self::InvalidTokenName { .. } => 105,
+            Self::InvalidQuery { .. } => 106,
+            Self::InvalidCursor { .. } => 107,
+            Self::InvalidIndex { .. } => 200,
+            Self::InvalidId {} => 201,
+            Self::UnknownStream { .. } => 202,
+            Self::StreamExists { .. } => 203,
+            Self::StreamArchived { .. } => 204,
+            Self::TokenExists { .. } => 205,
+            Self::UnknownToken { .. } => 206,
+            Self::UnknownRevision { .. } => 207,
+            Self::InvalidFrameLength { .. } => 300,
+            Self::IncompleteFrame => 301,
+            Self::InvalidMessage { .. } => 302,
+            Self::Unauthenticated => 400,
+            Self::InvalidToken => 401,
+            Self::Forbidden { .. } => 402,
+            Self::SqliteError { .. } => 501,
+            Self::SqliteValueParseError => 502,
+            Self::IncompatibleSchema { .. } => 503,
+            Self::ConfigError { .. } => 504,
+            Self::UtilsError { .. } => 505,
+            Self::CustomError { .. } => 506,
+        }
+    }
+
     pub fn custom_error(msg: String) -> Self {
         Self::CustomError { msg }
     }
@@ -0,0 +1,272 @@
+//! Feed documents of a stream, for consumers that follow it with a feed
+//! reader. A feed holds the latest posts of the stream, newest first, in
+//! Atom, RSS 2.0 or JSON Feed 1.1.
+//!
+//! Entries are identified by `urn:briefs:<stream id>:<post id>`, which
+//! survives renaming the stream. Edited posts are tagged with an `edited`
+//! category, or the `_briefs` extension of JSON Feed.
+
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+
+use crate::{post::Post, state::StreamInfo, BriefsError, BriefsResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+    Json,
+}
+
+impl FeedFormat {
+    /// Media type of the documents in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "application/atom+xml",
+            FeedFormat::Rss => "application/rss+xml",
+            FeedFormat::Json => "application/feed+json",
+        }
+    }
+}
+
+impl Display for FeedFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeedFormat::Atom => write!(f, "atom"),
+            FeedFormat::Rss => write!(f, "rss"),
+            FeedFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for FeedFormat {
+    type Err = BriefsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "atom" => Ok(FeedFormat::Atom),
+            "rss" => Ok(FeedFormat::Rss),
+            "json" => Ok(FeedFormat::Json),
+            _ => Err(BriefsError::InvalidMessage {
+                msg: format!("unknown feed format '{s}'"),
+            }),
+        }
+    }
+}
+
+/// Renders the posts of the stream as a feed. The feed is updated at the
+/// `last_updated` of the stream.
+pub fn render(format: FeedFormat, info: &StreamInfo, posts: &[Post]) -> BriefsResult<String> {
+    let mut posts: Vec<&Post> = posts.iter().collect();
+    posts.sort_by_key(|post| std::cmp::Reverse(post.id().unwrap_or_default()));
+    match format {
+        FeedFormat::Atom => render_atom(info, &posts),
+        FeedFormat::Rss => render_rss(info, &posts),
+        FeedFormat::Json => render_json(info, &posts),
+    }
+}
+
+fn render_atom(info: &StreamInfo, posts: &[&Post]) -> BriefsResult<String> {
+    let mut doc = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    doc.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    doc.push_str(&format!("  <id>{}</id>\n", feed_id(info)));
+    doc.push_str(&format!("  <title>{}</title>\n", escape(&info.name)));
+    doc.push_str(&format!(
+        "  <updated>{}</updated>\n",
+        rfc3339(info.last_updated)?
+    ));
+    doc.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        escape(&info.name)
+    ));
+    for post in posts {
+        let date = rfc3339(post.date)?;
+        doc.push_str("  <entry>\n");
+        doc.push_str(&format!("    <id>{}</id>\n", entry_id(info, post)?));
+        doc.push_str(&format!("    <title>{}</title>\n", escape(&post.title)));
+        doc.push_str(&format!("    <published>{date}</published>\n"));
+        doc.push_str(&format!("    <updated>{date}</updated>\n"));
+        doc.push_str(&format!(
+            "    <content type=\"text\">{}</content>\n",
+            escape(&post.msg)
+        ));
+        if post.edited {
+            doc.push_str("    <category term=\"edited\"/>\n");
+        }
+        doc.push_str("  </entry>\n");
+    }
+    doc.push_str("</feed>\n");
+    Ok(doc)
+}
+
+fn render_rss(info: &StreamInfo, posts: &[&Post]) -> BriefsResult<String> {
+    let mut doc = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    doc.push_str("<rss version=\"2.0\">\n<channel>\n");
+    doc.push_str(&format!("  <title>{}</title>\n", escape(&info.name)));
+    doc.push_str(&format!(
+        "  <description>Posts of the stream '{}'</description>\n",
+        escape(&info.name)
+    ));
+    doc.push_str(&format!(
+        "  <lastBuildDate>{}</lastBuildDate>\n",
+        rfc2822(info.last_updated)?
+    ));
+    for post in posts {
+        doc.push_str("  <item>\n");
+        doc.push_str(&format!(
+            "    <guid isPermaLink=\"false\">{}</guid>\n",
+            entry_id(info, post)?
+        ));
+        doc.push_str(&format!("    <title>{}</title>\n", escape(&post.title)));
+        doc.push_str(&format!(
+            "    <description>{}</description>\n",
+            escape(&post.msg)
+        ));
+        doc.push_str(&format!("    <pubDate>{}</pubDate>\n", rfc2822(post.date)?));
+        if post.edited {
+            doc.push_str("    <category>edited</category>\n");
+        }
+        doc.push_str("  </item>\n");
+    }
+    doc.push_str("</channel>\n</rss>\n");
+    Ok(doc)
+}
+
+fn render_json(info: &StreamInfo, posts: &[&Post]) -> BriefsResult<String> {
+    let items = posts
+        .iter()
+        .map(|post| {
+            Ok(json!({
+                "id": entry_id(info, post)?,
+                "title": post.title,
+                "content_text": post.msg,
+                "date_published": rfc3339(post.date)?,
+                "_briefs": { "edited": post.edited },
+            }))
+        })
+        .collect::<BriefsResult<Vec<_>>>()?;
+    let feed = json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": info.name,
+        "_briefs": {
+            "id": feed_id(info),
+            "updated": rfc3339(info.last_updated)?,
+        },
+        "items": items,
+    });
+    Ok(serde_json::to_string_pretty(&feed)?)
+}
+
+fn feed_id(info: &StreamInfo) -> String {
+    format!("urn:briefs:{}", info.id)
+}
+
+fn entry_id(info: &StreamInfo, post: &Post) -> BriefsResult<String> {
+    Ok(format!("urn:briefs:{}:{}", info.id, post.id()?))
+}
+
+fn timestamp(secs: u64) -> BriefsResult<DateTime<Utc>> {
+    DateTime::from_timestamp(secs.try_into()?, 0).ok_or_else(|| {
+        BriefsError::custom_error(format!("Timestamp {secs} is out of range")).into()
+    })
+}
+
+fn rfc3339(secs: u64) -> BriefsResult<String> {
+    Ok(timestamp(secs)?.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn rfc2822(secs: u64) -> BriefsResult<String> {
+    Ok(timestamp(secs)?.to_rfc2822())
+}
+
+/// Escapes text for XML elements and attributes.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mocks() -> (StreamInfo, Vec<Post>) {
+        let info = StreamInfo {
+            id: 3,
+            name: "ops".into(),
+            archived: false,
+            last_updated: 1_700_000_100,
+            date_of_inception: 1_700_000_000,
+        };
+        let posts = vec![
+            Post::from_parts(
+                0,
+                "Up".into(),
+                "All <good> & well".into(),
+                1_700_000_000,
+                false,
+            ),
+            Post::from_parts(
+                1,
+                "Down".into(),
+                "Investigating".into(),
+                1_700_000_050,
+                true,
+            ),
+        ];
+        (info, posts)
+    }
+
+    #[test]
+    fn test_atom() {
+        let (info, posts) = mocks();
+        let doc = render(FeedFormat::Atom, &info, &posts).unwrap();
+        assert!(doc.contains("<updated>2023-11-14T22:15:00Z</updated>"));
+        assert!(doc.contains("<id>urn:briefs:3:1</id>"));
+        assert!(doc.contains("All &lt;good&gt; &amp; well"));
+        assert_eq!(doc.matches("<category term=\"edited\"/>").count(), 1);
+        // Newest first
+        assert!(doc.find("urn:briefs:3:1").unwrap() < doc.find("urn:briefs:3:0").unwrap());
+    }
+
+    #[test]
+    fn test_rss() {
+        let (info, posts) = mocks();
+        let doc = render(FeedFormat::Rss, &info, &posts).unwrap();
+        assert!(doc.contains("<lastBuildDate>Tue, 14 Nov 2023 22:15:00 +0000</lastBuildDate>"));
+        assert!(doc.contains("<guid isPermaLink=\"false\">urn:briefs:3:0</guid>"));
+        assert!(doc.contains("<pubDate>Tue, 14 Nov 2023 22:13:20 +0000</pubDate>"));
+    }
+
+    #[test]
+    fn test_json_feed() {
+        let (info, posts) = mocks();
+        let doc = render(FeedFormat::Json, &info, &posts).unwrap();
+        let feed: serde_json::Value = serde_json::from_str(&doc).unwrap();
+        assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+        assert_eq!(feed["_briefs"]["updated"], "2023-11-14T22:15:00Z");
+        assert_eq!(feed["items"][0]["id"], "urn:briefs:3:1");
+        assert_eq!(feed["items"][0]["_briefs"]["edited"], true);
+        assert_eq!(feed["items"][1]["content_text"], "All <good> & well");
+    }
+
+    #[test]
+    fn test_format_from_str() {
+        for format in [FeedFormat::Atom, FeedFormat::Rss, FeedFormat::Json] {
+            assert_eq!(format.to_string().parse::<FeedFormat>().unwrap(), format);
+        }
+        assert!("xml".parse::<FeedFormat>().is_err());
+    }
+}
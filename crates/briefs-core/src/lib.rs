@@ -2,21 +2,33 @@
 //! all that you need within these docs. However, if need be, join
 //! the discord(link in github) and post your question.
 mod error;
+pub mod cursor;
 pub mod post;
+pub mod revision;
+pub mod search;
 pub mod state;
+pub mod store;
 pub mod stream;
+pub mod feed;
+pub mod registry;
+pub mod codec;
 pub mod db;
+pub mod migrations;
 pub mod config;
+pub mod auth;
 pub mod utils;
 
 use std::fmt::Display;
 
-pub use error::{BriefsError, BriefsResult};
+pub use error::{BriefsError, BriefsResult, INTERNAL_ERROR_CODE};
 
 pub mod constant {
     pub const MAX_POST_LEN: u16 = 300;
     pub const MAX_POST_TITLE: u16 = 100;
     pub const STREAM_CACHE_SIZE: u16 = 10;
+    pub const MAX_STREAM_NAME: u16 = 32;
+    pub const MAX_TOKEN_NAME: u16 = 32;
+    pub const DEFAULT_STREAM: &str = "default";
     pub const CONFIG_DIR: &str = ".briefs";
     pub const CONFIG_FILE: &str = "briefs.toml";
     pub const CONFIG_ENV: &str = "BRIEFSCONF";
@@ -24,28 +36,277 @@ pub mod constant {
     pub const DATA_FILE: &str = "stream";
     pub const PAGINATION_LIMIT: u32 = 40;
     pub const PAGINATION_DEFAULT: u32 = 20;
+    pub const FEED_DEFAULT: u32 = 50;
+    pub const FEED_LIMIT: u32 = 1000;
+    pub const SEARCH_DEFAULT: u32 = 10;
+    pub const SEARCH_LIMIT: u32 = 50;
+    /// Seconds given to the server to drain queued commands on shutdown.
+    pub const DRAIN_TIMEOUT: u64 = 10;
+    /// Days a deleted post is kept in the trash before it is purged.
+    pub const TRASH_RETENTION: u64 = 30;
+    /// Seconds between two purges of the trash.
+    pub const PURGE_INTERVAL: u64 = 3600;
 }
 
 /// Used to send acknowledgements to the connection handler.
 pub type Responder<T> = tokio::sync::oneshot::Sender<T>;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Commands understood by the stream handler. Post commands carry the
+/// name of the stream they target; requests without one target the
+/// default stream.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Command {
-    Catchup { last_fetch_id: u32 },
-    Create { title: String, msg: String },
-    UpdateMsg { id: u32, msg: String },
-    UpdateTitle { id: u32, title: String },
-    Delete { id: u32 },
-    Get { id: u32 },
-    Metadata {},
+    /// Returns a page of posts starting at `last_fetch_id`, or where the
+    /// page that handed out `cursor` ended. `since` and `until` only keep
+    /// the posts dated within `since <= date < until`.
+    Catchup {
+        #[serde(default = "default_stream")]
+        stream: String,
+        #[serde(default)]
+        last_fetch_id: u32,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        since: Option<u64>,
+        #[serde(default)]
+        until: Option<u64>,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
+    Create {
+        #[serde(default = "default_stream")]
+        stream: String,
+        title: String,
+        msg: String,
+    },
+    UpdateMsg {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+        msg: String,
+    },
+    UpdateTitle {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+        title: String,
+    },
+    /// Moves the post to the trash; `Catchup` reports it as deleted to
+    /// readers that fetched it before, until it is purged.
+    Delete {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+    },
+    Get {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+    },
+    Metadata {
+        #[serde(default = "default_stream")]
+        stream: String,
+    },
+    CreateStream { name: String },
+    ListStreams {},
+    RenameStream { stream: String, new_name: String },
+    ArchiveStream { stream: String },
+    /// Keeps pushing the events of the stream on the connection. Posts
+    /// created after `last_seen_id` are replayed first; without it, only
+    /// new events are pushed.
+    Subscribe {
+        #[serde(default = "default_stream")]
+        stream: String,
+        #[serde(default)]
+        last_seen_id: Option<u32>,
+    },
+    /// Logs the connection in with an API token; the following commands
+    /// run with the role of the token. Answered with the `Identity`.
+    Login { token: String },
+    /// Renders the latest `limit` posts of the stream as a feed document.
+    Feed {
+        #[serde(default = "default_stream")]
+        stream: String,
+        format: feed::FeedFormat,
+        #[serde(default)]
+        limit: Option<u32>,
+    },
+    /// Returns the revisions of the post, one for every edit.
+    History {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+    },
+    /// Restores the title and message of the post as of the revision,
+    /// which is stored as a new revision. Revision 0 is the post as it
+    /// was created. Answered with the restored post.
+    Restore {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+        revision: u32,
+    },
+    /// Brings back a post from the trash. Answered with the restored post.
+    Undelete {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+    },
+    /// Searches the title and message of the posts, see `search`. Pass
+    /// the `next_cursor` of a page as `cursor` to get the next one.
+    Search {
+        #[serde(default = "default_stream")]
+        stream: String,
+        query: String,
+        #[serde(default)]
+        limit: Option<u32>,
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+    /// Replaces the title, the message or both in a single edit, which is
+    /// stored as one revision. Answered with the updated post.
+    Update {
+        #[serde(default = "default_stream")]
+        stream: String,
+        id: u32,
+        #[serde(default)]
+        title: Option<String>,
+        #[serde(default)]
+        msg: Option<String>,
+    },
 }
 
+fn default_stream() -> String {
+    constant::DEFAULT_STREAM.into()
+}
+
+impl Command {
+    /// Name of the command, as in the JSON of a request.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Catchup { .. } => "Catchup",
+            Command::Create { .. } => "Create",
+            Command::UpdateMsg { .. } => "UpdateMsg",
+            Command::UpdateTitle { .. } => "UpdateTitle",
+            Command::Delete { .. } => "Delete",
+            Command::Get { .. } => "Get",
+            Command::Metadata { .. } => "Metadata",
+            Command::CreateStream { .. } => "CreateStream",
+            Command::ListStreams {} => "ListStreams",
+            Command::RenameStream { .. } => "RenameStream",
+            Command::ArchiveStream { .. } => "ArchiveStream",
+            Command::Subscribe { .. } => "Subscribe",
+            Command::Login { .. } => "Login",
+            Command::Feed { .. } => "Feed",
+            Command::History { .. } => "History",
+            Command::Restore { .. } => "Restore",
+            Command::Undelete { .. } => "Undelete",
+            Command::Search { .. } => "Search",
+            Command::Update { .. } => "Update",
+        }
+    }
+}
+
+/// A command along with an ID chosen by the client, which is echoed in
+/// the reply. Requests without an ID get 0.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Request {
+    #[serde(default)]
+    pub id: u64,
+    #[serde(flatten)]
+    pub cmd: Command,
+}
+
+impl Request {
+    pub fn new(id: u64, cmd: Command) -> Self {
+        Self { id, cmd }
+    }
+}
+
+/// A command for the stream handler, along with the identity the
+/// connection logged in with, if any.
 pub struct StreamCommand {
     pub cmd: Command,
-    pub resp: Option<Responder<Vec<u8>>>,
+    pub caller: Option<auth::Identity>,
+    pub resp: Option<Responder<BriefsResult<Response>>>,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Every reply of the server: the ID of the request it answers, and either
+/// the response or an error.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Envelope {
+    pub id: u64,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Data(Response),
+    Error(ErrorReply),
+}
+
+impl Envelope {
+    pub fn new(id: u64, result: BriefsResult<Response>) -> Self {
+        let outcome = match result {
+            Ok(response) => Outcome::Data(response),
+            Err(e) => Outcome::Error(ErrorReply::from(&e)),
+        };
+        Self { id, outcome }
+    }
+
+    /// Returns the response, or the error sent by the server.
+    pub fn into_result(self) -> BriefsResult<Response> {
+        match self.outcome {
+            Outcome::Data(response) => Ok(response),
+            Outcome::Error(error) => Err(error.into()),
+        }
+    }
+}
+
+/// An error as sent to clients. The code identifies the `BriefsError`
+/// variant, see `BriefsError::code`.
+#[derive(Debug, PartialEq, thiserror::Error, serde::Serialize, serde::Deserialize)]
+#[error("{msg} (code {code})")]
+pub struct ErrorReply {
+    pub code: u16,
+    pub msg: String,
+}
+
+impl From<&anyhow::Error> for ErrorReply {
+    fn from(e: &anyhow::Error) -> Self {
+        let code = match e.downcast_ref::<BriefsError>() {
+            Some(e) => e.code(),
+            None => INTERNAL_ERROR_CODE,
+        };
+        Self {
+            code,
+            msg: e.to_string(),
+        }
+    }
+}
+
+/// Responses of the stream handler. The connection handler encodes them
+/// using the encoding negotiated with the client; in JSON the variant is
+/// named by `type`, and its value is sent as `data`, eg
+/// `{"type":"Streams","data":[]}`.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Response {
+    Post(post::Post),
+    CatchUp(state::CatchUpResponse),
+    Metadata(state::StreamMetadata),
+    Streams(Vec<state::StreamInfo>),
+    Event(state::StreamEvent),
+    Feed(state::Feed),
+    History(state::PostHistory),
+    Search(state::SearchResults),
+    Identity(auth::Identity),
+    Message(StreamResponse),
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct StreamResponse {
     msg: String,
 }
@@ -54,6 +315,10 @@ impl StreamResponse {
      pub fn new(msg: String) -> Self {
         Self { msg }
     }
+
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
 }
 
 impl Display for StreamResponse {
@@ -69,15 +334,6 @@ pub mod prelude {
 
     use crate::BriefsResult;
 
-    /// This is a potential Response type to be used by stream handler
-    /// to respond with to the client. This is needed because in current
-    /// state, the cli will be unable to deserialize the data, if there
-    /// is an error, as it references a different data struct for serde.
-    pub struct StreamResponse<D, E> {
-        data: D,
-        error: E
-    }
-
     pub trait CatchupStream {
         fn insert_post(&mut self, post: post::Post) -> BriefsResult<()>;
         fn delete_post(&mut self, id: usize) -> BriefsResult<()>;
@@ -88,27 +344,107 @@ pub mod prelude {
     }
 
     pub trait CatchupPost {
-        fn new(id: usize, title: String, msg: String) -> BriefsResult<()>;
+        fn new(id: usize, title: String, msg: String) -> BriefsResult<Self>
+        where
+            Self: Sized;
         fn update_msg(id: usize, msg: String) -> BriefsResult<()>;
         fn update_title(id: usize, title: String) -> BriefsResult<()>;
-        fn verify_title(title: &String) -> BriefsResult<()>;
-        fn verify_msg(msg: &String) -> BriefsResult<()>;
-    }
-
-    pub trait DataBase {
-        fn insert_post(&self, post: &post::Post) -> BriefsResult<()>;
-        fn delete_post(&self, id: usize) -> BriefsResult<()>;
-        fn modify_post(&self, post: &post::Post) -> BriefsResult<()>;
-        /// Used to retrieve the latest `N` Posts. Needed by the refresh_cache
-        /// functionality
-        ///
-        /// # Errors
-        ///
-        /// This function will return an error if .
-        fn get_n_posts(&self, n: usize) -> BriefsResult<Vec<post::Post>>;
+        fn verify_title(title: &str) -> BriefsResult<()>;
+        fn verify_msg(msg: &str) -> BriefsResult<()>;
     }
 
     pub trait Cache {
         fn refresh_cache(&mut self) -> BriefsResult<()>;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id_is_optional() {
+        let request: Request = serde_json::from_str(r#"{"Metadata":{}}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::new(
+                0,
+                Command::Metadata {
+                    stream: constant::DEFAULT_STREAM.into()
+                }
+            )
+        );
+
+        let request = Request::new(7, Command::ListStreams {});
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(json, r#"{"id":7,"ListStreams":{}}"#);
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+    }
+
+    #[test]
+    fn test_command_name() {
+        let cmd = Command::Undelete {
+            stream: "ops".into(),
+            id: 2,
+        };
+        assert_eq!(cmd.name(), "Undelete");
+        let json = serde_json::to_value(&cmd).unwrap();
+        assert!(json.get(cmd.name()).is_some());
+    }
+
+    #[test]
+    fn test_envelope() {
+        let envelope = Envelope::new(3, Ok(Response::Streams(vec![])));
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(json, r#"{"id":3,"data":{"type":"Streams","data":[]}}"#);
+        assert_eq!(serde_json::from_str::<Envelope>(&json).unwrap(), envelope);
+
+        let envelope = Envelope::new(
+            4,
+            Err(BriefsError::UnknownStream { name: "ops".into() }.into()),
+        );
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":4,"error":{"code":202,"msg":"Stream 'ops' does not exist"}}"#
+        );
+        let err = serde_json::from_str::<Envelope>(&json)
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<ErrorReply>().unwrap().code, 202);
+
+        // Empty responses of different kinds stay apart
+        let responses = [
+            Response::Streams(vec![]),
+            Response::CatchUp(state::CatchUpResponse {
+                posts: vec![],
+                next_cursor: None,
+                prev_cursor: None,
+                deleted: vec![],
+                edited: vec![],
+            }),
+            Response::History(state::PostHistory {
+                id: 1,
+                revisions: vec![],
+            }),
+            Response::Search(state::SearchResults {
+                hits: vec![],
+                next_cursor: None,
+            }),
+        ];
+        for response in responses {
+            let json = serde_json::to_string(&response).unwrap();
+            assert_eq!(serde_json::from_str::<Response>(&json).unwrap(), response);
+        }
+
+        let envelope = Envelope::new(5, Err(anyhow::anyhow!("boom")));
+        assert_eq!(
+            envelope.outcome,
+            Outcome::Error(ErrorReply {
+                code: INTERNAL_ERROR_CODE,
+                msg: "boom".into()
+            })
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Versioned schema migrations for the sqlite Db. The schema version is
+//! recorded inside the sqlite file itself using `PRAGMA user_version`, so
+//! every deployment knows which migrations have already been applied.
+
+use sqlite::Connection;
+
+use crate::{db, BriefsError, BriefsResult};
+
+/// A single, ordered step of the schema.
+pub struct Migration {
+    /// Schema version after this migration is applied. Versions start
+    /// at 1 and must increase by one with every migration.
+    pub version: u32,
+    /// Short human readable summary; shown by `migrate status`.
+    pub description: &'static str,
+    /// Applies the migration. Always run inside a transaction.
+    pub up: fn(&mut Connection) -> BriefsResult<()>,
+}
+
+/// All the migrations known to this binary, in the order they are applied.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create posts table with cache and post_count views",
+        up: |conn| {
+            db::setup_tables(conn)?;
+            db::setup_views(conn)
+        },
+    },
+    Migration {
+        version: 2,
+        description: "Create streams table for stream metadata",
+        up: db::setup_streams_table,
+    },
+    Migration {
+        version: 3,
+        description: "Add stream names and scope posts by stream",
+        up: db::scope_posts_by_stream,
+    },
+    Migration {
+        version: 4,
+        description: "Create tokens table for API tokens",
+        up: db::setup_tokens_table,
+    },
+    Migration {
+        version: 5,
+        description: "Create revisions table for post edits",
+        up: db::setup_revisions_table,
+    },
+    Migration {
+        version: 6,
+        description: "Keep deleted posts as tombstones until purged",
+        up: db::add_post_tombstones,
+    },
+    Migration {
+        version: 7,
+        description: "Add per stream post ID sequences",
+        up: db::add_post_id_sequence,
+    },
+    Migration {
+        version: 8,
+        description: "Create full-text search index of posts",
+        up: db::setup_search_index,
+    },
+];
+
+/// Summary of the schema of a Db, as reported by `migrate status`.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    /// Version recorded inside the Db.
+    pub current: u32,
+    /// Latest version known to this binary.
+    pub latest: u32,
+    /// Versions and descriptions of the migrations yet to be applied.
+    pub pending: Vec<(u32, &'static str)>,
+}
+
+/// The latest schema version known to this binary.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |val| val.version)
+}
+
+/// Returns the schema version recorded inside the Db.
+pub fn schema_version(conn: &Connection) -> BriefsResult<u32> {
+    let mut stmt = conn.prepare("PRAGMA user_version")?;
+    stmt.next()?;
+    let version = stmt.read::<i64, _>(0)?;
+
+    Ok(version.try_into()?)
+}
+
+pub fn status(conn: &Connection) -> BriefsResult<MigrationStatus> {
+    let current = schema_version(conn)?;
+    let pending = MIGRATIONS
+        .iter()
+        .filter(|val| val.version > current)
+        .map(|val| (val.version, val.description))
+        .collect();
+
+    Ok(MigrationStatus {
+        current,
+        latest: latest_version(),
+        pending,
+    })
+}
+
+/// Applies all pending migrations and returns the resulting schema version.
+///
+/// # Errors
+///
+/// This function will return an error if the Db was created by a newer
+/// binary, if the linked sqlite lacks FTS5, or if any migration fails. A
+/// failed migration is rolled back, leaving the Db at the last
+/// successfully applied version.
+pub fn migrate_up(conn: &mut Connection) -> BriefsResult<u32> {
+    if !db::fts5_enabled(conn)? {
+        return Err(BriefsError::SqliteError {
+            msg: format!(
+                "sqlite {} was built without FTS5, which the search index needs",
+                db::sqlite_version()
+            ),
+        }
+        .into());
+    }
+    apply(conn, MIGRATIONS)
+}
+
+fn apply(conn: &mut Connection, migrations: &[Migration]) -> BriefsResult<u32> {
+    let mut current = schema_version(conn)?;
+    let latest = migrations.last().map_or(0, |val| val.version);
+    if current > latest {
+        return Err(BriefsError::IncompatibleSchema {
+            found: current,
+            supported: latest,
+        }
+        .into());
+    }
+
+    let pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|val| val.version > current)
+        .collect();
+    for migration in pending {
+        db::transaction(conn, |conn| {
+            (migration.up)(conn)?;
+            conn.execute(format!("PRAGMA user_version = {}", migration.version))?;
+            Ok(())
+        })
+        .map_err(|e| BriefsError::SqliteError {
+            msg: format!("Migration to version {} failed: {e}", migration.version),
+        })?;
+        current = migration.version;
+    }
+
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{
+            self,
+            test::{cleanup_db, setup_mock_db},
+        },
+        store::{PostStore, SqliteStore},
+        stream::Stream,
+    };
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (idx, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, idx + 1);
+        }
+    }
+
+    #[test]
+    fn test_fresh_db_is_latest() {
+        let path = setup_mock_db();
+        let mut conn = sqlite::open(&path).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert!(status(&conn).unwrap().pending.is_empty());
+        // Running again is a no-op
+        assert_eq!(migrate_up(&mut conn).unwrap(), latest_version());
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_legacy_db_is_migrated() {
+        let path = db::generate_temp_db();
+        let mut conn = sqlite::open(&path).unwrap();
+        // Dbs created before migrations have the tables but no version
+        db::setup_tables(&mut conn).unwrap();
+        db::setup_views(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO posts (id, title, msg, date, edited) VALUES (0, 'Title', 'Message', 0, 0)",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        assert_eq!(migrate_up(&mut conn).unwrap(), latest_version());
+        // Posts of a single stream Db belong to the default stream
+        assert_eq!(
+            db::query_post_count(&conn, db::DEFAULT_STREAM_ID).unwrap(),
+            1
+        );
+        let post = db::query_post_by_id(&conn, db::DEFAULT_STREAM_ID, 0).unwrap();
+        assert_eq!(post.title, "Title");
+        // Existing posts are added to the search index
+        let hits = db::search_posts(&conn, db::DEFAULT_STREAM_ID, "\"message\"", 10, 0).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].post, post);
+        // New posts of the default stream follow the legacy ones
+        let stream = Stream::open(SqliteStore::new(conn)).unwrap();
+        assert_eq!(stream.store().next_post_id().unwrap(), 1);
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_newer_db_is_refused() {
+        let path = setup_mock_db();
+        let mut conn = sqlite::open(&path).unwrap();
+        conn.execute(format!("PRAGMA user_version = {}", latest_version() + 1))
+            .unwrap();
+
+        let result = migrate_up(&mut conn);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<BriefsError>(),
+            Some(BriefsError::IncompatibleSchema { .. })
+        ));
+        assert!(db::setup_db(Some(path.clone())).is_err());
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let path = db::generate_temp_db();
+        let mut conn = sqlite::open(&path).unwrap();
+        let migrations = [
+            Migration {
+                version: 1,
+                description: "Create demo table",
+                up: |conn| Ok(conn.execute("CREATE TABLE demo (id INTEGER)")?),
+            },
+            Migration {
+                version: 2,
+                description: "Broken migration",
+                up: |conn| {
+                    conn.execute("ALTER TABLE demo ADD COLUMN title TEXT")?;
+                    Ok(conn.execute("THIS IS NOT SQL")?)
+                },
+            },
+        ];
+
+        assert!(apply(&mut conn, &migrations).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+        let columns = db::query_table_info(&mut conn, "demo").unwrap();
+        assert_eq!(columns.len(), 1);
+
+        cleanup_db(path);
+    }
+}
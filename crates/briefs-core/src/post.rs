@@ -1,6 +1,6 @@
 //! This module defines the `Post` struct which is the heart of CatchUP!
 
-use crate::{constant, BriefsError, BriefsResult};
+use crate::{constant, db::FromRow, BriefsError, BriefsResult};
 use std::fmt::{Display, Formatter};
 use std::time::SystemTime;
 use textwrap::core::display_width;
@@ -8,7 +8,7 @@ use textwrap::{self, wrap};
 
 /// Every time a new post is created by the admin,
 /// this is the struct that stores all the necessary data.
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Post {
     id: u32,
     pub title: String,
@@ -32,6 +32,18 @@ impl Post {
         })
     }
 
+    /// Assemble a post received from a peer as is. Unlike `new`, neither
+    /// the title nor the message are validated.
+    pub fn from_parts(id: u32, title: String, msg: String, date: u64, edited: bool) -> Self {
+        Post {
+            id,
+            title,
+            msg,
+            date,
+            edited,
+        }
+    }
+
     /// Update the message of an existing post.
     pub fn update_msg(&mut self, new_msg: String) -> BriefsResult<()> {
         verify_msg(&new_msg)?;
@@ -51,42 +63,34 @@ impl Post {
     pub fn id(&self) -> BriefsResult<u32> {
         Ok(self.id)
     }
+}
 
-    pub fn parse_sqlite_row(mut record: sqlite::Row) -> BriefsResult<Self> {
-        let mut post = Post {
-            id: 0,
-            title: String::new(),
-            msg: String::new(),
-            date: 0,
-            edited: false,
-        };
-
-        match record.take("id") {
-            sqlite::Value::Integer(val) => post.id = val.try_into()?,
-            _ => return Err(BriefsError::SqliteValueParseError.into()),
-        };
-
-        match record.take("title") {
-            sqlite::Value::String(val) => post.title = val,
-            _ => return Err(BriefsError::SqliteValueParseError.into()),
-        };
-
-        match record.take("msg") {
-            sqlite::Value::String(val) => post.msg = val,
-            _ => return Err(BriefsError::SqliteValueParseError.into()),
-        };
-
-        match record.take("date") {
-            sqlite::Value::Integer(val) => post.date = val.try_into()?,
-            _ => return Err(BriefsError::SqliteValueParseError.into()),
-        };
-
-        match record.take("edited") {
-            sqlite::Value::Integer(val) => post.edited = val != 0, // 0: false, 1: true; in sqlite
-            _ => return Err(BriefsError::SqliteValueParseError.into()),
-        };
-
-        Ok(post)
+impl FromRow for Post {
+    fn from_row(row: &sqlite::Row) -> BriefsResult<Self> {
+        let id: i64 = row
+            .try_read("id")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let title: &str = row
+            .try_read("title")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let msg: &str = row
+            .try_read("msg")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let date: i64 = row
+            .try_read("date")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        // 0: false, 1: true; in sqlite
+        let edited: i64 = row
+            .try_read("edited")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+
+        Ok(Post {
+            id: id.try_into()?,
+            title: title.to_owned(),
+            msg: msg.to_owned(),
+            date: date.try_into()?,
+            edited: edited != 0,
+        })
     }
 }
 
@@ -95,7 +99,7 @@ pub(crate) fn time_in_sec(time: SystemTime) -> BriefsResult<u64> {
 }
 
 /// Some necessary checks for post's title.
-fn verify_title(title: &String) -> BriefsResult<()> {
+pub(crate) fn verify_title(title: &str) -> BriefsResult<()> {
     if title.is_empty() {
         return Err(BriefsError::EmptyTitle.into());
     }
@@ -110,7 +114,7 @@ fn verify_title(title: &String) -> BriefsResult<()> {
 }
 
 /// Some necessary checks for post's message.
-fn verify_msg(msg: &String) -> BriefsResult<()> {
+pub(crate) fn verify_msg(msg: &str) -> BriefsResult<()> {
     // check min/max length of post
     if msg.is_empty() {
         return Err(BriefsError::EmptyPost.into());
@@ -127,30 +131,27 @@ fn verify_msg(msg: &String) -> BriefsResult<()> {
 
 impl Display for Post {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:-<54}\n", "")?;
-        write!(f, "\\ {:^50} /\n/ {:50} \\\n", self.title, "")?;
-        let mut count = 0u8;
+        writeln!(f, "{:-<54}", "")?;
+        writeln!(f, "\\ {:^50} /\n/ {:50} \\", self.title, "")?;
         let content_width = 50;
         let wrapping_config = textwrap::Options::new(content_width).break_words(true);
-        for line in wrap(&format!("{}\n", self.msg), wrapping_config) {
-            let (left_closure, right_closure) = if count % 2 == 0 {
+        for (count, line) in wrap(&format!("{}\n", self.msg), wrapping_config)
+            .into_iter()
+            .enumerate()
+        {
+            let (left_closure, right_closure) = if count.is_multiple_of(2) {
                 ("\\ ", " /")
             } else {
                 ("/ ", " \\")
             };
             let text_width = display_width(&line);
-            let whitespace = if content_width >= text_width {
-                content_width - text_width
-            } else {
-                0
-            };
-            write!(
+            let whitespace = content_width.saturating_sub(text_width);
+            writeln!(
                 f,
-                "{left_closure}{}{}{right_closure}\n",
+                "{left_closure}{}{}{right_closure}",
                 line,
                 " ".repeat(whitespace)
             )?;
-            count += 1;
         }
         write!(f, "{:-<54}", "")
     }
@@ -0,0 +1,386 @@
+//! Registry of the named streams hosted by a server. Every stream has its
+//! own posts, ID sequence, cache and metadata, while all of them share the
+//! same sqlite Db.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use sqlite::Connection;
+
+use crate::{
+    constant::MAX_STREAM_NAME, db, post::time_in_sec, state::StreamInfo, store::SqliteStore,
+    stream::Stream, BriefsError, BriefsResult,
+};
+
+/// All the streams of a Db, archived ones included, keyed by name.
+pub struct StreamRegistry {
+    db_path: PathBuf,
+    conn: Connection,
+    streams: HashMap<String, Stream<SqliteStore>>,
+}
+
+impl StreamRegistry {
+    /// Opens every stream stored in the Db at `db_path`. The default stream
+    /// is created if the Db has no streams yet.
+    pub fn open<P: AsRef<Path>>(db_path: P) -> BriefsResult<Self> {
+        let db_path = db_path.as_ref().to_path_buf();
+        let conn = sqlite::open(&db_path)?;
+
+        let mut streams = HashMap::new();
+        let infos = db::query_streams(&conn)?;
+        if infos.is_empty() {
+            let stream = Stream::open(SqliteStore::open(&db_path)?)?;
+            streams.insert(stream.name().to_owned(), stream);
+        }
+        for info in infos {
+            let store = SqliteStore::open_stream(&db_path, info.id)?;
+            streams.insert(info.name, Stream::open(store)?);
+        }
+
+        Ok(Self {
+            db_path,
+            conn,
+            streams,
+        })
+    }
+
+    /// Returns the stream with the given name.
+    pub fn get(&self, name: &str) -> BriefsResult<&Stream<SqliteStore>> {
+        Ok(self
+            .streams
+            .get(name)
+            .ok_or_else(|| BriefsError::UnknownStream { name: name.into() })?)
+    }
+
+    /// Returns the stream with the given name for modification.
+    pub fn get_mut(&mut self, name: &str) -> BriefsResult<&mut Stream<SqliteStore>> {
+        Ok(self
+            .streams
+            .get_mut(name)
+            .ok_or_else(|| BriefsError::UnknownStream { name: name.into() })?)
+    }
+
+    /// Returns the metadata of all the streams, in the order they were
+    /// created.
+    pub fn list(&self) -> Vec<StreamInfo> {
+        let mut infos: Vec<StreamInfo> = self
+            .streams
+            .values()
+            .map(|stream| stream.info().clone())
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Creates a new, empty stream.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the name is invalid or is
+    /// already taken by another stream.
+    pub fn create(&mut self, name: &str) -> BriefsResult<StreamInfo> {
+        self.verify_new_name(name)?;
+        let now = time_in_sec(SystemTime::now())?;
+        let info = StreamInfo {
+            id: db::next_stream_id(&self.conn)?,
+            name: name.to_owned(),
+            archived: false,
+            last_updated: now,
+            date_of_inception: now,
+        };
+        db::insert_stream(&mut self.conn, &info)?;
+
+        let store = SqliteStore::open_stream(&self.db_path, info.id)?;
+        self.streams.insert(info.name.clone(), Stream::open(store)?);
+
+        Ok(info)
+    }
+
+    /// Renames an existing stream; archived streams can be renamed too.
+    pub fn rename(&mut self, name: &str, new_name: &str) -> BriefsResult<StreamInfo> {
+        self.verify_new_name(new_name)?;
+        let id = self.get(name)?.info().id;
+        db::rename_stream(&mut self.conn, id, new_name)?;
+
+        let mut stream = self
+            .streams
+            .remove(name)
+            .ok_or_else(|| BriefsError::UnknownStream { name: name.into() })?;
+        stream.set_name(new_name.to_owned());
+        let info = stream.info().clone();
+        self.streams.insert(new_name.to_owned(), stream);
+
+        Ok(info)
+    }
+
+    /// Archives a stream. Archived streams can still be read, but no longer
+    /// accept changes.
+    pub fn archive(&mut self, name: &str) -> BriefsResult<StreamInfo> {
+        let info = self.get(name)?.info();
+        if info.archived {
+            return Err(BriefsError::StreamArchived { name: name.into() }.into());
+        }
+        let id = info.id;
+        db::archive_stream(&mut self.conn, id)?;
+
+        let stream = self.get_mut(name)?;
+        stream.set_archived();
+        Ok(stream.info().clone())
+    }
+
+    /// Removes the posts deleted before `before` for good, from every stream
+    /// including archived ones. Returns the number of purged posts.
+    pub fn purge_deleted(&mut self, before: u64) -> BriefsResult<usize> {
+        let mut purged = 0;
+        for stream in self.streams.values_mut() {
+            purged += stream.purge_deleted(before)?;
+        }
+        Ok(purged)
+    }
+
+    /// Persists the state of every stream and closes all the connections to
+    /// the Db; used when the server shuts down. Returns the number of
+    /// streams persisted.
+    ///
+    /// Post mutations already store `last_updated` along with every change,
+    /// so this only catches up on changes made by other connections to the
+    /// Db in the meantime.
+    pub fn close(mut self) -> BriefsResult<usize> {
+        let infos = self.list();
+        db::transaction(&mut self.conn, |conn| {
+            for info in &infos {
+                db::update_stream_last_updated(conn, info.id, info.last_updated)?;
+            }
+            Ok(())
+        })?;
+        // Dropping the streams closes their stores
+        Ok(infos.len())
+    }
+
+    fn verify_new_name(&self, name: &str) -> BriefsResult<()> {
+        verify_stream_name(name)?;
+        if self.streams.contains_key(name) {
+            return Err(BriefsError::StreamExists { name: name.into() }.into());
+        }
+        Ok(())
+    }
+}
+
+impl Debug for StreamRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamRegistry")
+            .field("db_path", &self.db_path)
+            .field("streams", &self.streams)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Stream names are used in URLs and on the command line, hence only
+/// lowercase letters, digits, '-' and '_' are allowed.
+pub fn verify_stream_name(name: &str) -> BriefsResult<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_STREAM_NAME as usize
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '-' || ch == '_');
+    if !valid {
+        return Err(BriefsError::InvalidStreamName {
+            name: name.into(),
+            max_size: MAX_STREAM_NAME as usize,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constant::DEFAULT_STREAM,
+        db::test::{cleanup_db, setup_mock_db},
+        post::Post,
+        store::PostStore,
+    };
+
+    fn new_post(id: u32) -> Post {
+        Post::new(id, format!("Post #{id}"), format!("Message of post #{id}")).unwrap()
+    }
+
+    #[test]
+    fn test_verify_stream_name() {
+        for name in ["ops", "releases-2024", "hr_team"] {
+            assert!(verify_stream_name(name).is_ok(), "{name}");
+        }
+        let too_long = "a".repeat(MAX_STREAM_NAME as usize + 1);
+        for name in [
+            "",
+            "Ops",
+            "with space",
+            "dots.not.allowed",
+            too_long.as_str(),
+        ] {
+            assert!(matches!(
+                verify_stream_name(name)
+                    .unwrap_err()
+                    .downcast_ref::<BriefsError>(),
+                Some(BriefsError::InvalidStreamName { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_streams_are_independent() {
+        let path = setup_mock_db();
+        let mut registry = StreamRegistry::open(&path).unwrap();
+        assert_eq!(registry.list().len(), 1);
+        assert_eq!(registry.list()[0].name, DEFAULT_STREAM);
+
+        registry.create("ops").unwrap();
+        registry.create("releases").unwrap();
+        assert!(matches!(
+            registry
+                .create("ops")
+                .unwrap_err()
+                .downcast_ref::<BriefsError>(),
+            Some(BriefsError::StreamExists { .. })
+        ));
+
+        for id in 0..3 {
+            registry
+                .get_mut("ops")
+                .unwrap()
+                .add_post(new_post(id))
+                .unwrap();
+        }
+        registry
+            .get_mut("releases")
+            .unwrap()
+            .add_post(new_post(0))
+            .unwrap();
+
+        assert_eq!(registry.get("ops").unwrap().nposts(), 3);
+        assert_eq!(registry.get("releases").unwrap().nposts(), 1);
+        assert_eq!(registry.get(DEFAULT_STREAM).unwrap().nposts(), 0);
+        assert!(matches!(
+            registry
+                .get("hr")
+                .unwrap_err()
+                .downcast_ref::<BriefsError>(),
+            Some(BriefsError::UnknownStream { .. })
+        ));
+
+        // Everything survives a restart
+        drop(registry);
+        let registry = StreamRegistry::open(&path).unwrap();
+        let names: Vec<String> = registry.list().into_iter().map(|val| val.name).collect();
+        assert_eq!(names, vec![DEFAULT_STREAM, "ops", "releases"]);
+        assert_eq!(registry.get("ops").unwrap().nposts(), 3);
+        assert_eq!(
+            registry.get("releases").unwrap().get_post(0).unwrap().title,
+            "Post #0"
+        );
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_rename_and_archive() {
+        let path = setup_mock_db();
+        let mut registry = StreamRegistry::open(&path).unwrap();
+        registry.create("ops").unwrap();
+        registry
+            .get_mut("ops")
+            .unwrap()
+            .add_post(new_post(0))
+            .unwrap();
+
+        assert!(registry.rename("ops", DEFAULT_STREAM).is_err());
+        let info = registry.rename("ops", "operations").unwrap();
+        assert_eq!(info.name, "operations");
+        assert!(registry.get("ops").is_err());
+        assert_eq!(registry.get("operations").unwrap().nposts(), 1);
+
+        let info = registry.archive("operations").unwrap();
+        assert!(info.archived);
+        assert!(registry.archive("operations").is_err());
+        let stream = registry.get_mut("operations").unwrap();
+        assert!(matches!(
+            stream
+                .add_post(new_post(1))
+                .unwrap_err()
+                .downcast_ref::<BriefsError>(),
+            Some(BriefsError::StreamArchived { .. })
+        ));
+        // Archived streams can still be read
+        assert!(stream.get_post(0).is_some());
+
+        drop(registry);
+        let registry = StreamRegistry::open(&path).unwrap();
+        let stream = registry.get("operations").unwrap();
+        assert!(stream.info().archived);
+        assert_eq!(stream.nposts(), 1);
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_close_persists_last_updated() {
+        let path = setup_mock_db();
+        let mut registry = StreamRegistry::open(&path).unwrap();
+        registry.create("ops").unwrap();
+        registry
+            .get_mut("ops")
+            .unwrap()
+            .add_post(new_post(0))
+            .unwrap();
+        let last_updated = registry.get("ops").unwrap().last_updated();
+
+        // Another connection rewinds the stream in the meantime
+        let mut conn = sqlite::open(&path).unwrap();
+        let id = registry.get("ops").unwrap().info().id;
+        db::update_stream_last_updated(&mut conn, id, 0).unwrap();
+        drop(conn);
+
+        assert_eq!(registry.close().unwrap(), 2);
+        let registry = StreamRegistry::open(&path).unwrap();
+        assert_eq!(registry.get("ops").unwrap().last_updated(), last_updated);
+        assert_eq!(registry.get("ops").unwrap().nposts(), 1);
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_purge_deleted_from_every_stream() {
+        let path = setup_mock_db();
+        let mut registry = StreamRegistry::open(&path).unwrap();
+        registry.create("ops").unwrap();
+        for name in [DEFAULT_STREAM, "ops"] {
+            let stream = registry.get_mut(name).unwrap();
+            stream.add_post(new_post(0)).unwrap();
+            stream.add_post(new_post(1)).unwrap();
+            stream.remove_post(0).unwrap();
+        }
+        registry.archive("ops").unwrap();
+
+        // Nothing was deleted before the epoch
+        assert_eq!(registry.purge_deleted(0).unwrap(), 0);
+        let now = time_in_sec(SystemTime::now()).unwrap();
+        assert_eq!(registry.purge_deleted(now + 1).unwrap(), 2);
+        for name in [DEFAULT_STREAM, "ops"] {
+            let stream = registry.get(name).unwrap();
+            assert!(stream
+                .store()
+                .tombstones(u32::MAX, 0, u32::MAX)
+                .unwrap()
+                .is_empty());
+            assert_eq!(stream.nposts(), 1);
+        }
+
+        cleanup_db(path);
+    }
+}
@@ -0,0 +1,240 @@
+//! Revisions of posts. Every edit of a post is stored as a revision holding
+//! the title and message before and after the edit, so that readers can
+//! see what changed and an edit can be undone, see `Command::Restore`.
+//!
+//! Revisions of a post are numbered from 1 in the order of the edits.
+//! Revision 0 stands for the post as it was created.
+
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, SecondsFormat};
+
+use crate::{db::FromRow, BriefsError, BriefsResult};
+
+/// A single edit of a post.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Revision {
+    pub revision: u32,
+    /// Name of the identity that made the edit.
+    pub author: String,
+    pub date: u64,
+    pub old_title: String,
+    pub old_msg: String,
+    pub new_title: String,
+    pub new_msg: String,
+}
+
+impl Revision {
+    /// Word diff of the edit, see `diff`.
+    pub fn diff(&self) -> String {
+        render_diff(
+            (&self.old_title, &self.old_msg),
+            (&self.new_title, &self.new_msg),
+        )
+    }
+}
+
+impl Display for Revision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let date = i64::try_from(self.date)
+            .ok()
+            .and_then(|val| DateTime::from_timestamp(val, 0))
+            .map_or_else(
+                || self.date.to_string(),
+                |val| val.to_rfc3339_opts(SecondsFormat::Secs, true),
+            );
+        writeln!(
+            f,
+            "revision {} by {} at {}",
+            self.revision, self.author, date
+        )?;
+        write!(f, "{}", self.diff())
+    }
+}
+
+impl FromRow for Revision {
+    fn from_row(row: &sqlite::Row) -> BriefsResult<Self> {
+        let revision: i64 = row
+            .try_read("revision")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let author: &str = row
+            .try_read("author")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let date: i64 = row
+            .try_read("date")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let old_title: &str = row
+            .try_read("old_title")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let old_msg: &str = row
+            .try_read("old_msg")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let new_title: &str = row
+            .try_read("new_title")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+        let new_msg: &str = row
+            .try_read("new_msg")
+            .map_err(|_| BriefsError::SqliteValueParseError)?;
+
+        Ok(Revision {
+            revision: revision.try_into()?,
+            author: author.to_owned(),
+            date: date.try_into()?,
+            old_title: old_title.to_owned(),
+            old_msg: old_msg.to_owned(),
+            new_title: new_title.to_owned(),
+            new_msg: new_msg.to_owned(),
+        })
+    }
+}
+
+/// Returns the title and message of the post as of the given revision.
+///
+/// # Errors
+///
+/// This function will return `BriefsError::UnknownRevision` if the post
+/// has no such revision. A post that was never edited has no revisions,
+/// not even 0.
+pub fn content_at(id: u32, revisions: &[Revision], revision: u32) -> BriefsResult<(&str, &str)> {
+    let content = if revision == 0 {
+        revisions
+            .first()
+            .map(|val| (val.old_title.as_str(), val.old_msg.as_str()))
+    } else {
+        revisions
+            .iter()
+            .find(|val| val.revision == revision)
+            .map(|val| (val.new_title.as_str(), val.new_msg.as_str()))
+    };
+    Ok(content.ok_or(BriefsError::UnknownRevision { id, revision })?)
+}
+
+/// Word diff between two revisions of a post, in the style of
+/// `git diff --word-diff`: removed words are marked as `[-words-]` and
+/// added words as `{+words+}`.
+pub fn diff(id: u32, revisions: &[Revision], from: u32, to: u32) -> BriefsResult<String> {
+    let old = content_at(id, revisions, from)?;
+    let new = content_at(id, revisions, to)?;
+    Ok(format!(
+        "--- revision {from}\n+++ revision {to}\n{}",
+        render_diff(old, new)
+    ))
+}
+
+fn render_diff(old: (&str, &str), new: (&str, &str)) -> String {
+    format!(
+        "title: {}\nmsg: {}",
+        word_diff(old.0, new.0),
+        word_diff(old.1, new.1)
+    )
+}
+
+/// Diffs the words of both texts using their longest common subsequence.
+fn word_diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.split_whitespace().collect();
+    let new: Vec<&str> = new.split_whitespace().collect();
+
+    // lcs[i][j]: length of the LCS of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut words = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let flush = |words: &mut Vec<String>, removed: &mut Vec<&str>, added: &mut Vec<&str>| {
+        if !removed.is_empty() {
+            words.push(format!("[-{}-]", removed.join(" ")));
+            removed.clear();
+        }
+        if !added.is_empty() {
+            words.push(format!("{{+{}+}}", added.join(" ")));
+            added.clear();
+        }
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            flush(&mut words, &mut removed, &mut added);
+            words.push(old[i].to_owned());
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            removed.push(old[i]);
+            i += 1;
+        } else {
+            added.push(new[j]);
+            j += 1;
+        }
+    }
+    flush(&mut words, &mut removed, &mut added);
+
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revision(revision: u32, old: (&str, &str), new: (&str, &str)) -> Revision {
+        Revision {
+            revision,
+            author: "ci".into(),
+            date: 1700000000,
+            old_title: old.0.into(),
+            old_msg: old.1.into(),
+            new_title: new.0.into(),
+            new_msg: new.1.into(),
+        }
+    }
+
+    #[test]
+    fn test_word_diff() {
+        assert_eq!(word_diff("a b c", "a b c"), "a b c");
+        assert_eq!(word_diff("a b c", "a x c"), "a [-b-] {+x+} c");
+        assert_eq!(word_diff("a b", "a b c d"), "a b {+c d+}");
+        assert_eq!(word_diff("a b c d", "c d"), "[-a b-] c d");
+        assert_eq!(word_diff("", "a"), "{+a+}");
+    }
+
+    #[test]
+    fn test_content_and_diff_between_revisions() {
+        let revisions = vec![
+            revision(1, ("v1", "Deploy is done"), ("v2", "Deploy is done")),
+            revision(2, ("v2", "Deploy is done"), ("v2", "Deploy is not done")),
+        ];
+
+        assert_eq!(
+            content_at(3, &revisions, 0).unwrap(),
+            ("v1", "Deploy is done")
+        );
+        assert_eq!(
+            content_at(3, &revisions, 2).unwrap(),
+            ("v2", "Deploy is not done")
+        );
+        assert!(matches!(
+            content_at(3, &revisions, 3)
+                .unwrap_err()
+                .downcast_ref::<BriefsError>(),
+            Some(BriefsError::UnknownRevision { id: 3, revision: 3 })
+        ));
+        assert!(content_at(3, &[], 0).is_err());
+
+        assert_eq!(
+            diff(3, &revisions, 0, 2).unwrap(),
+            "--- revision 0\n+++ revision 2\n\
+            title: [-v1-] {+v2+}\nmsg: Deploy is {+not+} done"
+        );
+        assert_eq!(
+            revisions[0].diff(),
+            "title: [-v1-] {+v2+}\nmsg: Deploy is done"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! Full-text search over the title and message of posts. The sqlite Db
+//! keeps a FTS5 index of the live posts, see `db::setup_search_index`.
+//!
+//! A query is a list of words; a post matches when it holds every one of
+//! them, in any letter case. A word ending with `*` matches any word
+//! starting with it, and words joined by punctuation, eg `v1.2`, must
+//! appear next to each other. Queries never use the FTS5 query syntax, so
+//! every query is valid as long as it holds a word.
+
+use crate::{post::Post, state::SearchHit, BriefsError, BriefsResult};
+
+/// Marks the matching words in the titles and snippets of the hits.
+pub const HIGHLIGHT: &str = "**";
+/// Stands for the text cut off around a snippet.
+pub const ELLIPSIS: &str = "…";
+/// Maximum number of words in a snippet.
+pub const SNIPPET_WORDS: u32 = 24;
+/// Weight of a match in the title, relative to one in the message.
+pub const TITLE_WEIGHT: f64 = 10.0;
+
+/// A word of the query, split into tokens the way FTS5 does.
+#[derive(Debug, PartialEq)]
+struct Term {
+    tokens: Vec<String>,
+    prefix: bool,
+}
+
+impl Term {
+    /// Whether the tokens of the text match the term; only the last one
+    /// may be longer for a prefix term.
+    fn matches(&self, tokens: &[(usize, usize, String)]) -> bool {
+        let last = self.tokens.len() - 1;
+        tokens
+            .iter()
+            .zip(&self.tokens)
+            .enumerate()
+            .all(|(idx, (token, word))| match self.prefix && idx == last {
+                true => token.2.starts_with(word.as_str()),
+                false => token.2 == *word,
+            })
+    }
+}
+
+/// Splits the query into terms.
+///
+/// # Errors
+///
+/// This function will return `BriefsError::InvalidQuery` if the query
+/// holds no word.
+fn terms(query: &str) -> BriefsResult<Vec<Term>> {
+    let terms: Vec<Term> = query
+        .split_whitespace()
+        .filter_map(|word| {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(val) => (val, true),
+                None => (word, false),
+            };
+            let tokens: Vec<String> = tokenize(word).into_iter().map(|val| val.2).collect();
+            (!tokens.is_empty()).then_some(Term { tokens, prefix })
+        })
+        .collect();
+
+    if terms.is_empty() {
+        return Err(BriefsError::InvalidQuery {
+            query: query.to_owned(),
+        }
+        .into());
+    }
+
+    Ok(terms)
+}
+
+/// Start, end and lowercase text of the alphanumeric tokens of the text.
+fn tokenize(text: &str) -> Vec<(usize, usize, String)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (idx, ch) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (ch.is_alphanumeric(), start) {
+            (true, None) => start = Some(idx),
+            (false, Some(sidx)) => {
+                tokens.push((sidx, idx, text[sidx..idx].to_lowercase()));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    tokens
+}
+
+/// Translates the query into a FTS5 query matching the same posts.
+///
+/// # Errors
+///
+/// This function will return `BriefsError::InvalidQuery` if the query
+/// holds no word.
+pub fn fts_query(query: &str) -> BriefsResult<String> {
+    let terms = terms(query)?;
+    // Tokens are alphanumeric, so they never need escaping
+    let phrases: Vec<String> = terms
+        .iter()
+        .map(|term| {
+            let star = if term.prefix { "*" } else { "" };
+            format!("\"{}\"{star}", term.tokens.join(" "))
+        })
+        .collect();
+
+    Ok(phrases.join(" "))
+}
+
+/// Searches the posts without an index, for stores that keep their posts
+/// in memory. Hits are ranked by the number of matches, see
+/// `TITLE_WEIGHT`, and the snippet is the whole message.
+///
+/// # Errors
+///
+/// This function will return `BriefsError::InvalidQuery` if the query
+/// holds no word.
+pub fn search_posts<'a>(
+    posts: impl Iterator<Item = &'a Post>,
+    query: &str,
+    limit: u32,
+    offset: u64,
+) -> BriefsResult<Vec<SearchHit>> {
+    let terms = terms(query)?;
+
+    let mut hits: Vec<SearchHit> = posts
+        .filter_map(|post| {
+            let title = Matches::find(&post.title, &terms);
+            let msg = Matches::find(&post.msg, &terms);
+            let all_found = (0..terms.len()).all(|idx| title.found[idx] || msg.found[idx]);
+            all_found.then(|| SearchHit {
+                score: TITLE_WEIGHT * title.spans.len() as f64 + msg.spans.len() as f64,
+                title: title.highlight(&post.title),
+                snippet: msg.highlight(&post.msg),
+                post: post.clone(),
+            })
+        })
+        .collect();
+    hits.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| b.post.id().ok().cmp(&a.post.id().ok()))
+    });
+
+    Ok(hits
+        .into_iter()
+        .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+        .take(limit as usize)
+        .collect())
+}
+
+/// Matches of the terms within a text.
+struct Matches {
+    /// Byte ranges of the matches, in order and without overlaps.
+    spans: Vec<(usize, usize)>,
+    /// Whether each term matched at least once.
+    found: Vec<bool>,
+}
+
+impl Matches {
+    fn find(text: &str, terms: &[Term]) -> Self {
+        let tokens = tokenize(text);
+        let mut spans = Vec::new();
+        let mut found = vec![false; terms.len()];
+        for (tidx, term) in terms.iter().enumerate() {
+            let len = term.tokens.len();
+            for window in tokens.windows(len) {
+                if term.matches(window) {
+                    spans.push((window[0].0, window[len - 1].1));
+                    found[tidx] = true;
+                }
+            }
+        }
+
+        spans.sort_unstable();
+        spans.dedup_by(|next, prev| next.0 < prev.1);
+        Self { spans, found }
+    }
+
+    /// Wraps every match of the text within `HIGHLIGHT`.
+    fn highlight(&self, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut last = 0;
+        for (start, end) in &self.spans {
+            result.push_str(&text[last..*start]);
+            result.push_str(HIGHLIGHT);
+            result.push_str(&text[*start..*end]);
+            result.push_str(HIGHLIGHT);
+            last = *end;
+        }
+        result.push_str(&text[last..]);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(fts_query("deploy").unwrap(), "\"deploy\"");
+        assert_eq!(
+            fts_query("  Deploy  v1.2 rel* ").unwrap(),
+            "\"deploy\" \"v1 2\" \"rel\"*"
+        );
+        // FTS5 operators and quotes are taken as plain words
+        assert_eq!(
+            fts_query("\"a\" OR title:b NEAR(c)").unwrap(),
+            "\"a\" \"or\" \"title b\" \"near c\""
+        );
+
+        for query in ["", "   ", "* - \"\""] {
+            assert!(matches!(
+                fts_query(query).unwrap_err().downcast_ref::<BriefsError>(),
+                Some(BriefsError::InvalidQuery { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_search_posts() {
+        let posts = [
+            Post::new(0, "Deploy".into(), "Deploying v1.2 to prod".into()).unwrap(),
+            Post::new(1, "Lunch".into(), "The deploy is done, lunch?".into()).unwrap(),
+            Post::new(2, "Lunch".into(), "Pizza".into()).unwrap(),
+        ];
+
+        let hits = search_posts(posts.iter(), "deploy*", 10, 0).unwrap();
+        assert_eq!(
+            hits.iter()
+                .map(|val| val.post.id().unwrap())
+                .collect::<Vec<u32>>(),
+            [0, 1]
+        );
+        assert_eq!(hits[0].title, "**Deploy**");
+        assert_eq!(hits[0].snippet, "**Deploying** v1.2 to prod");
+        assert_eq!(hits[1].title, "Lunch");
+        assert_eq!(hits[1].snippet, "The **deploy** is done, lunch?");
+
+        // Every word must match, in the title or the message
+        let hits = search_posts(posts.iter(), "LUNCH deploy", 10, 0).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].title, "**Lunch**");
+        assert_eq!(hits[0].snippet, "The **deploy** is done, **lunch**?");
+
+        let hits = search_posts(posts.iter(), "v1.2", 10, 0).unwrap();
+        assert_eq!(hits[0].snippet, "Deploying **v1.2** to prod");
+        assert!(search_posts(posts.iter(), "v2.1", 10, 0)
+            .unwrap()
+            .is_empty());
+
+        // Paging skips the hits of the previous pages
+        let hits = search_posts(posts.iter(), "lunch", 1, 1).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].post.id().unwrap(), 2);
+    }
+}
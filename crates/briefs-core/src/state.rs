@@ -1,18 +1,104 @@
 use serde::{Deserialize, Serialize};
 
-use crate::post::Post;
+use crate::{feed::FeedFormat, post::Post, revision::Revision};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct CatchUpResponse {
     pub posts: Vec<Post>,
-    pub caught_up: bool,
+    /// Cursor of the page of newer posts; unset once caught up.
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+    /// Cursor of the page of older posts; unset at the oldest post.
+    #[serde(default)]
+    pub prev_cursor: Option<String>,
+    /// IDs of the posts up to the last returned one that were deleted since
+    /// the reader fetched them, so that readers can drop them from their
+    /// caches. See `Stream::catchup_page`.
+    #[serde(default)]
+    pub deleted: Vec<u32>,
+    /// Posts older than the page that were edited since the reader fetched
+    /// them, as they are now.
+    #[serde(default)]
+    pub edited: Vec<Post>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct StreamMetadata {
+    pub name: String,
+    pub archived: bool,
     pub latest_post_id: Option<u32>,
     pub last_updated: u64,
     pub posts_count: u32,
+    /// Version of the sqlite library linked into the server.
+    pub sqlite_version: String,
+}
+
+/// Metadata of a stream persisted alongside its posts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct StreamInfo {
+    pub id: u32,
+    pub name: String,
+    /// Archived streams can still be read, but no longer accept changes.
+    pub archived: bool,
+    pub last_updated: u64,
+    pub date_of_inception: u64,
+}
+
+/// A change to the posts of a stream, pushed to its subscribers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct StreamEvent {
+    pub stream: String,
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum EventKind {
+    Created(Post),
+    Updated(Post),
+    Deleted { id: u32 },
+    Undeleted(Post),
+}
+
+/// A feed document of a stream, see `crate::feed`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct Feed {
+    pub format: FeedFormat,
+    pub document: String,
+}
+
+/// The revisions of a post, oldest first; see `crate::revision`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct PostHistory {
+    pub id: u32,
+    pub revisions: Vec<Revision>,
+}
+
+/// A post matching a search, see `crate::search`. The matching words in
+/// `title` and `snippet` are marked as `**word**`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct SearchHit {
+    pub post: Post,
+    /// Relevance of the post, higher is better. Scores are only
+    /// comparable within the same search.
+    pub score: f64,
+    pub title: String,
+    /// Excerpt of the message around the matching words.
+    pub snippet: String,
+}
+
+/// A page of search hits, best first. Pass `next_cursor` back to get the
+/// next page; it is unset on the last page.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    #[serde(default)]
+    pub next_cursor: Option<String>,
 }
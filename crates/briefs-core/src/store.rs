@@ -0,0 +1,872 @@
+//! Storage backends for the posts of a `Stream`. The sqlite backend is used
+//! by the server, while the in-memory backend is handy for unit tests and
+//! ephemeral demo servers where nothing needs to survive a restart.
+
+use std::{collections::BTreeMap, fmt::Debug, path::Path};
+
+use sqlite::Connection;
+
+use crate::{
+    db,
+    post::Post,
+    revision::{self, Revision},
+    search,
+    state::{SearchHit, StreamInfo},
+    BriefsError, BriefsResult,
+};
+
+/// Persistent storage of posts and stream metadata, used by `Stream` for
+/// everything that is not served from its cache.
+///
+/// Every post mutation takes `updated_at`, which is stored as the
+/// `last_updated` of the stream atomically with the mutation itself. Edits
+/// also store a `Revision` made by `author`, see `crate::revision`.
+///
+/// Deleted posts are kept as tombstones until they are purged, so that
+/// readers can be told about the delete and the post can be brought back.
+/// Apart from `tombstones` and `undelete_post`, the store behaves as if
+/// deleted posts did not exist.
+pub trait PostStore {
+    /// Returns the metadata of the stream, or `None` if the stream has
+    /// not been created yet.
+    fn stream_info(&self) -> BriefsResult<Option<StreamInfo>>;
+
+    /// Stores the metadata of a new stream. Stores scoped to a stream ID
+    /// create the stream under that ID, ignoring the ID of `info`.
+    fn create_stream(&mut self, info: &StreamInfo) -> BriefsResult<()>;
+
+    /// Returns the ID to be used by the next post. IDs only ever grow, so
+    /// the IDs of deleted posts are never given out again.
+    fn next_post_id(&self) -> BriefsResult<u32>;
+
+    /// Stores a new post and moves the ID sequence past its ID.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a post with the same ID
+    /// already exists.
+    fn insert_post(&mut self, post: &Post, updated_at: u64) -> BriefsResult<()>;
+
+    /// Deletes the post with the given ID, leaving a tombstone dated
+    /// `updated_at`. Its revisions are kept until the post is purged.
+    fn delete_post(&mut self, id: u32, updated_at: u64) -> BriefsResult<()>;
+
+    /// Brings back the deleted post with the given ID and returns it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return `BriefsError::InvalidId` if there is no
+    /// deleted post with the given ID.
+    fn undelete_post(&mut self, id: u32, updated_at: u64) -> BriefsResult<Post>;
+
+    /// Returns the IDs of at most `limit` posts with `id <= until` that were
+    /// deleted at or after `since`, in ascending order. When there are more,
+    /// the most recently deleted ones are returned.
+    fn tombstones(&self, until: u32, since: u64, limit: u32) -> BriefsResult<Vec<u32>>;
+
+    /// Removes the posts deleted before `before` for good, along with their
+    /// revisions, and returns how many were purged.
+    fn purge_deleted(&mut self, before: u64) -> BriefsResult<usize>;
+
+    /// Replaces the title of the post with the given ID and marks it as edited.
+    fn update_post_title(
+        &mut self,
+        id: u32,
+        title: &str,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<()>;
+
+    /// Replaces the message of the post with the given ID and marks it as edited.
+    fn update_post_msg(
+        &mut self,
+        id: u32,
+        msg: &str,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<()>;
+
+    /// Replaces the title, the message or both of the post with the given
+    /// ID in a single edit, marks it as edited and returns the updated post.
+    fn update_post(
+        &mut self,
+        id: u32,
+        title: Option<&str>,
+        msg: Option<&str>,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<Post>;
+
+    /// Restores the title and message of the post as of the given revision
+    /// and returns the restored post.
+    fn restore_post(
+        &mut self,
+        id: u32,
+        revision: u32,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<Post>;
+
+    /// Returns the revisions of the post with the given ID, oldest first.
+    fn revisions(&self, id: u32) -> BriefsResult<Vec<Revision>>;
+
+    /// Returns at most `limit` of the posts with `id < before` that were
+    /// last edited at or after `since`, in ascending order of ID. When
+    /// there are more, the most recently edited ones are returned.
+    fn edited(&self, before: u32, since: u64, limit: u32) -> BriefsResult<Vec<Post>>;
+
+    /// Returns the post with the given ID.
+    fn get_post(&self, id: u32) -> BriefsResult<Post>;
+
+    /// Returns at most `limit` of the posts selected by the query, in
+    /// ascending order of ID.
+    fn page(&self, query: &PageQuery, limit: u32) -> BriefsResult<Vec<Post>>;
+
+    /// Returns the total number of posts.
+    fn count(&self) -> BriefsResult<u64>;
+
+    /// Returns the latest `n` posts, in ascending order of ID. Needed by
+    /// the refresh_cache functionality.
+    fn last_n(&self, n: u32) -> BriefsResult<Vec<Post>>;
+
+    /// Returns at most `limit` posts matching the query, best match first,
+    /// after skipping the first `offset` ones; see `crate::search`.
+    fn search(&self, query: &str, limit: u32, offset: u64) -> BriefsResult<Vec<SearchHit>>;
+}
+
+/// Selects the posts of a page, see `PostStore::page`. Every bound is
+/// inclusive at the start and exclusive at the end; unset ones are open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageQuery {
+    /// Only posts with `from_id <= id`.
+    pub from_id: u32,
+    /// Only posts with `id < to_id`.
+    pub to_id: Option<u32>,
+    /// Only posts with `since <= date`.
+    pub since: Option<u64>,
+    /// Only posts with `date < until`.
+    pub until: Option<u64>,
+    /// Take the posts with the highest IDs first, rather than the lowest.
+    pub backward: bool,
+}
+
+impl PageQuery {
+    /// Whether the query selects the post.
+    pub fn matches(&self, post: &Post) -> bool {
+        let id = post.id().unwrap_or_default();
+        id >= self.from_id
+            && self.to_id.is_none_or(|val| id < val)
+            && self.since.is_none_or(|val| post.date >= val)
+            && self.until.is_none_or(|val| post.date < val)
+    }
+}
+
+/// Posts of a single stream stored in a sqlite Db. The Db is expected to be
+/// set up using `db::setup_db`.
+pub struct SqliteStore {
+    conn: Connection,
+    stream_id: u32,
+}
+
+impl SqliteStore {
+    /// Creates a store for the default stream.
+    pub fn new(conn: Connection) -> Self {
+        Self::with_stream_id(conn, db::DEFAULT_STREAM_ID)
+    }
+
+    /// Creates a store for the stream with the given ID.
+    pub fn with_stream_id(conn: Connection, stream_id: u32) -> Self {
+        Self { conn, stream_id }
+    }
+
+    /// Opens a connection to the sqlite Db at `path` for the default stream.
+    pub fn open<P: AsRef<Path>>(path: P) -> BriefsResult<Self> {
+        Ok(Self::new(sqlite::open(path)?))
+    }
+
+    /// Opens a connection to the sqlite Db at `path` for the stream with
+    /// the given ID.
+    pub fn open_stream<P: AsRef<Path>>(path: P, stream_id: u32) -> BriefsResult<Self> {
+        Ok(Self::with_stream_id(sqlite::open(path)?, stream_id))
+    }
+
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Runs the post mutation `f` and updates `last_updated` of the stream
+    /// in a single transaction. Fails with `InvalidId` when `f` did not
+    /// touch any row.
+    fn mutate<F>(&mut self, updated_at: u64, f: F) -> BriefsResult<()>
+    where
+        F: FnOnce(&mut Connection) -> BriefsResult<()>,
+    {
+        let stream_id = self.stream_id;
+        db::transaction(&mut self.conn, |conn| {
+            f(conn)?;
+            if conn.change_count() == 0 {
+                return Err(BriefsError::InvalidId {}.into());
+            }
+            db::update_stream_last_updated(conn, stream_id, updated_at)
+        })
+    }
+
+    /// Replaces the title and message of the post with the ones returned
+    /// by `f`, stores the revision and updates `last_updated` of the stream
+    /// in a single transaction.
+    fn revise<F>(&mut self, id: u32, author: &str, updated_at: u64, f: F) -> BriefsResult<Post>
+    where
+        F: FnOnce(&Post, &[Revision]) -> BriefsResult<(String, String)>,
+    {
+        let stream_id = self.stream_id;
+        db::transaction(&mut self.conn, |conn| {
+            let mut post = db::query_post_by_id(conn, stream_id, id)?;
+            let revisions = db::query_revisions(conn, stream_id, id)?;
+            let (title, msg) = f(&post, &revisions)?;
+            let revision = revise(&mut post, &revisions, title, msg, author, updated_at);
+            db::update_post_by_id(conn, stream_id, id, &post.title, &post.msg)?;
+            db::insert_revision(conn, stream_id, id, &revision)?;
+            db::update_stream_last_updated(conn, stream_id, updated_at)?;
+            Ok(post)
+        })
+    }
+}
+
+/// Applies the new title and message to the post and returns the revision
+/// recording the edit.
+fn revise(
+    post: &mut Post,
+    revisions: &[Revision],
+    title: String,
+    msg: String,
+    author: &str,
+    updated_at: u64,
+) -> Revision {
+    let revision = Revision {
+        revision: revisions.last().map_or(1, |val| val.revision + 1),
+        author: author.to_owned(),
+        date: updated_at,
+        old_title: std::mem::replace(&mut post.title, title),
+        old_msg: std::mem::replace(&mut post.msg, msg),
+        new_title: post.title.clone(),
+        new_msg: post.msg.clone(),
+    };
+    post.edited = true;
+    revision
+}
+
+impl Debug for SqliteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteStore")
+            .field("stream_id", &self.stream_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PostStore for SqliteStore {
+    fn stream_info(&self) -> BriefsResult<Option<StreamInfo>> {
+        db::query_stream_by_id(&self.conn, self.stream_id)
+    }
+
+    fn create_stream(&mut self, info: &StreamInfo) -> BriefsResult<()> {
+        let info = StreamInfo {
+            id: self.stream_id,
+            ..info.clone()
+        };
+        db::insert_stream(&mut self.conn, &info)
+    }
+
+    fn next_post_id(&self) -> BriefsResult<u32> {
+        db::query_next_post_id(&self.conn, self.stream_id)
+    }
+
+    fn insert_post(&mut self, post: &Post, updated_at: u64) -> BriefsResult<()> {
+        let stream_id = self.stream_id;
+        self.mutate(updated_at, |conn| {
+            // The post goes last, as `mutate` checks its change count
+            db::advance_post_id_sequence(conn, stream_id, post.id()?)?;
+            db::insert_post(conn, stream_id, post)
+        })
+    }
+
+    fn delete_post(&mut self, id: u32, updated_at: u64) -> BriefsResult<()> {
+        let stream_id = self.stream_id;
+        self.mutate(updated_at, |conn| {
+            db::tombstone_post_by_id(conn, stream_id, id, updated_at)
+        })
+    }
+
+    fn undelete_post(&mut self, id: u32, updated_at: u64) -> BriefsResult<Post> {
+        let stream_id = self.stream_id;
+        self.mutate(updated_at, |conn| {
+            db::undelete_post_by_id(conn, stream_id, id)
+        })?;
+        self.get_post(id)
+    }
+
+    fn tombstones(&self, until: u32, since: u64, limit: u32) -> BriefsResult<Vec<u32>> {
+        db::query_tombstones(&self.conn, self.stream_id, until, since, limit)
+    }
+
+    fn purge_deleted(&mut self, before: u64) -> BriefsResult<usize> {
+        let stream_id = self.stream_id;
+        db::transaction(&mut self.conn, |conn| {
+            db::purge_deleted(conn, stream_id, before)
+        })
+    }
+
+    fn update_post_title(
+        &mut self,
+        id: u32,
+        title: &str,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<()> {
+        self.revise(id, author, updated_at, |post, _| {
+            Ok((title.to_owned(), post.msg.clone()))
+        })?;
+        Ok(())
+    }
+
+    fn update_post_msg(
+        &mut self,
+        id: u32,
+        msg: &str,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<()> {
+        self.revise(id, author, updated_at, |post, _| {
+            Ok((post.title.clone(), msg.to_owned()))
+        })?;
+        Ok(())
+    }
+
+    fn update_post(
+        &mut self,
+        id: u32,
+        title: Option<&str>,
+        msg: Option<&str>,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<Post> {
+        self.revise(id, author, updated_at, |post, _| {
+            Ok((
+                title.unwrap_or(&post.title).to_owned(),
+                msg.unwrap_or(&post.msg).to_owned(),
+            ))
+        })
+    }
+
+    fn restore_post(
+        &mut self,
+        id: u32,
+        revision: u32,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<Post> {
+        self.revise(id, author, updated_at, |_, revisions| {
+            let (title, msg) = revision::content_at(id, revisions, revision)?;
+            Ok((title.to_owned(), msg.to_owned()))
+        })
+    }
+
+    fn revisions(&self, id: u32) -> BriefsResult<Vec<Revision>> {
+        db::query_revisions(&self.conn, self.stream_id, id)
+    }
+
+    fn edited(&self, before: u32, since: u64, limit: u32) -> BriefsResult<Vec<Post>> {
+        db::query_edited(&self.conn, self.stream_id, before, since, limit)
+    }
+
+    fn get_post(&self, id: u32) -> BriefsResult<Post> {
+        db::query_post_by_id(&self.conn, self.stream_id, id)
+    }
+
+    fn page(&self, query: &PageQuery, limit: u32) -> BriefsResult<Vec<Post>> {
+        db::catchup(&self.conn, self.stream_id, query, limit)
+    }
+
+    fn count(&self) -> BriefsResult<u64> {
+        db::query_post_count(&self.conn, self.stream_id)
+    }
+
+    fn last_n(&self, n: u32) -> BriefsResult<Vec<Post>> {
+        let mut posts = db::query_last_n(&self.conn, self.stream_id, n)?;
+        posts.reverse();
+        Ok(posts)
+    }
+
+    fn search(&self, query: &str, limit: u32, offset: u64) -> BriefsResult<Vec<SearchHit>> {
+        let query = search::fts_query(query)?;
+        db::search_posts(&self.conn, self.stream_id, &query, limit, offset)
+    }
+}
+
+/// Posts kept in memory; everything is lost once the store is dropped.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    info: Option<StreamInfo>,
+    posts: BTreeMap<u32, Post>,
+    revisions: BTreeMap<u32, Vec<Revision>>,
+    /// Deleted posts along with the time they were deleted.
+    trash: BTreeMap<u32, (Post, u64)>,
+    next_id: u32,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MemoryStore {
+    fn touch(&mut self, updated_at: u64) {
+        if let Some(info) = self.info.as_mut() {
+            info.last_updated = updated_at;
+        }
+    }
+
+    fn revise<F>(&mut self, id: u32, author: &str, updated_at: u64, f: F) -> BriefsResult<Post>
+    where
+        F: FnOnce(&Post, &[Revision]) -> BriefsResult<(String, String)>,
+    {
+        let post = self.posts.get_mut(&id).ok_or(BriefsError::InvalidId {})?;
+        let revisions = self.revisions.entry(id).or_default();
+        let (title, msg) = f(post, revisions)?;
+        let revision = revise(post, revisions, title, msg, author, updated_at);
+        revisions.push(revision);
+        let post = post.clone();
+        self.touch(updated_at);
+        Ok(post)
+    }
+}
+
+impl PostStore for MemoryStore {
+    fn stream_info(&self) -> BriefsResult<Option<StreamInfo>> {
+        Ok(self.info.clone())
+    }
+
+    fn create_stream(&mut self, info: &StreamInfo) -> BriefsResult<()> {
+        if self.info.is_some() {
+            return Err(BriefsError::custom_error("Stream already exists".into()).into());
+        }
+        self.info = Some(info.clone());
+        Ok(())
+    }
+
+    fn next_post_id(&self) -> BriefsResult<u32> {
+        Ok(self.next_id)
+    }
+
+    fn insert_post(&mut self, post: &Post, updated_at: u64) -> BriefsResult<()> {
+        let id = post.id()?;
+        if self.posts.contains_key(&id) || self.trash.contains_key(&id) {
+            return Err(
+                BriefsError::custom_error("Post already exists with the given ID".into()).into(),
+            );
+        }
+        self.posts.insert(id, post.clone());
+        self.next_id = self.next_id.max(id.saturating_add(1));
+        self.touch(updated_at);
+        Ok(())
+    }
+
+    fn delete_post(&mut self, id: u32, updated_at: u64) -> BriefsResult<()> {
+        let post = self.posts.remove(&id).ok_or(BriefsError::InvalidId {})?;
+        self.trash.insert(id, (post, updated_at));
+        self.touch(updated_at);
+        Ok(())
+    }
+
+    fn undelete_post(&mut self, id: u32, updated_at: u64) -> BriefsResult<Post> {
+        let (post, _) = self.trash.remove(&id).ok_or(BriefsError::InvalidId {})?;
+        self.posts.insert(id, post.clone());
+        self.touch(updated_at);
+        Ok(post)
+    }
+
+    fn tombstones(&self, until: u32, since: u64, limit: u32) -> BriefsResult<Vec<u32>> {
+        let mut deletes: Vec<(u64, u32)> = self
+            .trash
+            .range(..=until)
+            .filter(|(_, (_, deleted))| *deleted >= since)
+            .map(|(id, (_, deleted))| (*deleted, *id))
+            .collect();
+        deletes.sort_unstable_by(|a, b| b.cmp(a));
+        deletes.truncate(limit as usize);
+        let mut ids: Vec<u32> = deletes.into_iter().map(|(_, id)| id).collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn purge_deleted(&mut self, before: u64) -> BriefsResult<usize> {
+        let purged: Vec<u32> = self
+            .trash
+            .iter()
+            .filter(|(_, (_, deleted))| *deleted < before)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &purged {
+            self.trash.remove(id);
+            self.revisions.remove(id);
+        }
+        Ok(purged.len())
+    }
+
+    fn update_post_title(
+        &mut self,
+        id: u32,
+        title: &str,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<()> {
+        self.revise(id, author, updated_at, |post, _| {
+            Ok((title.to_owned(), post.msg.clone()))
+        })?;
+        Ok(())
+    }
+
+    fn update_post_msg(
+        &mut self,
+        id: u32,
+        msg: &str,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<()> {
+        self.revise(id, author, updated_at, |post, _| {
+            Ok((post.title.clone(), msg.to_owned()))
+        })?;
+        Ok(())
+    }
+
+    fn update_post(
+        &mut self,
+        id: u32,
+        title: Option<&str>,
+        msg: Option<&str>,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<Post> {
+        self.revise(id, author, updated_at, |post, _| {
+            Ok((
+                title.unwrap_or(&post.title).to_owned(),
+                msg.unwrap_or(&post.msg).to_owned(),
+            ))
+        })
+    }
+
+    fn restore_post(
+        &mut self,
+        id: u32,
+        revision: u32,
+        author: &str,
+        updated_at: u64,
+    ) -> BriefsResult<Post> {
+        self.revise(id, author, updated_at, |_, revisions| {
+            let (title, msg) = revision::content_at(id, revisions, revision)?;
+            Ok((title.to_owned(), msg.to_owned()))
+        })
+    }
+
+    fn revisions(&self, id: u32) -> BriefsResult<Vec<Revision>> {
+        Ok(self.revisions.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn edited(&self, before: u32, since: u64, limit: u32) -> BriefsResult<Vec<Post>> {
+        let mut edits: Vec<(u64, u32)> = self
+            .revisions
+            .range(..before)
+            .filter(|(id, _)| self.posts.contains_key(id))
+            .filter_map(|(id, revisions)| Some((revisions.last()?.date, *id)))
+            .filter(|(edited_at, _)| *edited_at >= since)
+            .collect();
+        edits.sort_unstable_by(|a, b| b.cmp(a));
+        edits.truncate(limit as usize);
+        let mut ids: Vec<u32> = edits.into_iter().map(|(_, id)| id).collect();
+        ids.sort_unstable();
+        Ok(ids.iter().map(|id| self.posts[id].clone()).collect())
+    }
+
+    fn get_post(&self, id: u32) -> BriefsResult<Post> {
+        Ok(self
+            .posts
+            .get(&id)
+            .cloned()
+            .ok_or(BriefsError::InvalidId {})?)
+    }
+
+    fn page(&self, query: &PageQuery, limit: u32) -> BriefsResult<Vec<Post>> {
+        let posts = self
+            .posts
+            .range(query.from_id..)
+            .map(|(_, post)| post)
+            .filter(|post| query.matches(post));
+        let posts: Vec<Post> = if query.backward {
+            let mut posts: Vec<Post> = posts.rev().take(limit as usize).cloned().collect();
+            posts.reverse();
+            posts
+        } else {
+            posts.take(limit as usize).cloned().collect()
+        };
+        Ok(posts)
+    }
+
+    fn count(&self) -> BriefsResult<u64> {
+        Ok(self.posts.len() as u64)
+    }
+
+    fn last_n(&self, n: u32) -> BriefsResult<Vec<Post>> {
+        let mut posts: Vec<Post> = self
+            .posts
+            .values()
+            .rev()
+            .take(n as usize)
+            .cloned()
+            .collect();
+        posts.reverse();
+        Ok(posts)
+    }
+
+    fn search(&self, query: &str, limit: u32, offset: u64) -> BriefsResult<Vec<SearchHit>> {
+        search::search_posts(self.posts.values(), query, limit, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test::{cleanup_db, setup_mock_db};
+
+    fn new_post(id: u32) -> Post {
+        Post::new(id, format!("Post #{id}"), format!("Message of post #{id}")).unwrap()
+    }
+
+    fn ids(posts: &[Post]) -> Vec<u32> {
+        posts.iter().map(|val| val.id().unwrap()).collect()
+    }
+
+    /// Runs the same checks against every backend, so that they behave alike.
+    fn exercise_store<S: PostStore>(store: &mut S) {
+        assert!(store.stream_info().unwrap().is_none());
+        let info = StreamInfo {
+            id: db::DEFAULT_STREAM_ID,
+            name: "ops".into(),
+            archived: false,
+            last_updated: 0,
+            date_of_inception: 0,
+        };
+        store.create_stream(&info).unwrap();
+        assert!(store.create_stream(&info).is_err());
+        assert_eq!(store.stream_info().unwrap(), Some(info));
+
+        assert_eq!(store.count().unwrap(), 0);
+        assert!(store.last_n(5).unwrap().is_empty());
+        assert_eq!(store.next_post_id().unwrap(), 0);
+
+        for id in 0..5 {
+            store.insert_post(&new_post(id), u64::from(id) + 1).unwrap();
+        }
+        assert_eq!(store.stream_info().unwrap().unwrap().last_updated, 5);
+        // A failed mutation leaves last_updated untouched
+        assert!(store.insert_post(&new_post(2), 100).is_err());
+        assert_eq!(store.stream_info().unwrap().unwrap().last_updated, 5);
+        assert_eq!(store.count().unwrap(), 5);
+        assert_eq!(store.next_post_id().unwrap(), 5);
+
+        let range = |from_id, to_id| PageQuery {
+            from_id,
+            to_id: Some(to_id),
+            ..Default::default()
+        };
+        assert_eq!(ids(&store.page(&range(1, 4), 10).unwrap()), vec![1, 2, 3]);
+        assert_eq!(ids(&store.page(&range(1, 5), 2).unwrap()), vec![1, 2]);
+        assert!(store.page(&range(7, 10), 10).unwrap().is_empty());
+        // Backward pages hold the latest posts, still in ascending order
+        let backward = PageQuery {
+            backward: true,
+            ..range(1, 4)
+        };
+        assert_eq!(ids(&store.page(&backward, 2).unwrap()), vec![2, 3]);
+        // Posts are dated when created, so none is older than the first one
+        let date = store.get_post(0).unwrap().date;
+        let older = PageQuery {
+            until: Some(date),
+            ..Default::default()
+        };
+        assert!(store.page(&older, 10).unwrap().is_empty());
+        let newer = PageQuery {
+            since: Some(date),
+            ..Default::default()
+        };
+        assert_eq!(store.page(&newer, 10).unwrap().len(), 5);
+        assert_eq!(ids(&store.last_n(2).unwrap()), vec![3, 4]);
+        assert_eq!(ids(&store.last_n(10).unwrap()), vec![0, 1, 2, 3, 4]);
+
+        store.update_post_title(1, "New title", "ci", 6).unwrap();
+        store.update_post_msg(3, "New message", "ci", 7).unwrap();
+        assert_eq!(store.stream_info().unwrap().unwrap().last_updated, 7);
+        let post = store.get_post(1).unwrap();
+        assert_eq!(post.title, "New title");
+        assert!(post.edited);
+        let post = store.get_post(3).unwrap();
+        assert_eq!(post.msg, "New message");
+        assert!(post.edited);
+        assert!(!store.get_post(2).unwrap().edited);
+
+        // Every edit is a revision, and restoring one is an edit too
+        store
+            .update_post_msg(1, "Second message", "ops", 7)
+            .unwrap();
+        let revisions = store.revisions(1).unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(
+            (revisions[0].revision, revisions[0].author.as_str()),
+            (1, "ci")
+        );
+        assert_eq!(revisions[0].old_title, "Post #1");
+        assert_eq!(revisions[0].new_title, "New title");
+        assert_eq!(revisions[1].old_msg, "Message of post #1");
+        assert_eq!(revisions[1].new_msg, "Second message");
+        assert!(store.revisions(0).unwrap().is_empty());
+
+        let post = store.restore_post(1, 0, "admin", 7).unwrap();
+        assert_eq!(
+            (post.title.as_str(), post.msg.as_str()),
+            ("Post #1", "Message of post #1")
+        );
+        assert_eq!(store.get_post(1).unwrap(), post);
+        let revisions = store.revisions(1).unwrap();
+        assert_eq!(revisions.len(), 3);
+        assert_eq!(revisions[2].author, "admin");
+        assert_eq!(revisions[2].old_msg, "Second message");
+        assert!(matches!(
+            store
+                .restore_post(1, 7, "admin", 7)
+                .unwrap_err()
+                .downcast_ref::<BriefsError>(),
+            Some(BriefsError::UnknownRevision { .. })
+        ));
+        assert_eq!(store.revisions(1).unwrap().len(), 3);
+
+        // Posts are told apart by their latest edit
+        assert_eq!(ids(&store.edited(5, 0, 10).unwrap()), vec![1, 3]);
+        assert_eq!(ids(&store.edited(3, 7, 10).unwrap()), vec![1]);
+        assert_eq!(ids(&store.edited(5, 0, 1).unwrap()), vec![3]);
+        assert!(store.edited(5, 8, 10).unwrap().is_empty());
+
+        store.delete_post(2, 8).unwrap();
+        assert_eq!(store.count().unwrap(), 4);
+        assert_eq!(store.stream_info().unwrap().unwrap().last_updated, 8);
+        assert_eq!(
+            ids(&store.page(&range(0, 5), 10).unwrap()),
+            vec![0, 1, 3, 4]
+        );
+
+        for result in [
+            store.get_post(2).map(|_| ()),
+            store.delete_post(2, 9),
+            store.undelete_post(3, 9).map(|_| ()),
+            store.update_post_title(2, "title", "ci", 9),
+            store.update_post_msg(2, "msg", "ci", 9),
+            store.update_post(2, None, Some("msg"), "ci", 9).map(|_| ()),
+            store.restore_post(2, 0, "ci", 9).map(|_| ()),
+        ] {
+            assert!(matches!(
+                result.unwrap_err().downcast_ref::<BriefsError>(),
+                Some(BriefsError::InvalidId {})
+            ));
+        }
+        assert_eq!(store.stream_info().unwrap().unwrap().last_updated, 8);
+
+        // A deleted post keeps its ID and can be brought back
+        assert!(store.insert_post(&new_post(2), 9).is_err());
+        assert_eq!(store.tombstones(4, 0, 10).unwrap(), vec![2]);
+        assert!(store.tombstones(1, 0, 10).unwrap().is_empty());
+        assert!(store.tombstones(4, 9, 10).unwrap().is_empty());
+        let post = store.undelete_post(2, 9).unwrap();
+        assert_eq!(post, new_post(2));
+        assert_eq!(store.get_post(2).unwrap(), post);
+        assert_eq!(store.count().unwrap(), 5);
+        assert_eq!(store.stream_info().unwrap().unwrap().last_updated, 9);
+        assert!(store.tombstones(4, 0, 10).unwrap().is_empty());
+
+        // Revisions are kept until the post is purged
+        store.delete_post(1, 10).unwrap();
+        store.delete_post(4, 12).unwrap();
+        assert_eq!(store.revisions(1).unwrap().len(), 3);
+        assert_eq!(ids(&store.edited(5, 0, 10).unwrap()), vec![3]);
+        assert_eq!(store.purge_deleted(10).unwrap(), 0);
+        assert_eq!(store.purge_deleted(11).unwrap(), 1);
+        assert!(store.revisions(1).unwrap().is_empty());
+        assert_eq!(store.tombstones(4, 0, 10).unwrap(), vec![4]);
+        store.delete_post(3, 11).unwrap();
+        // The most recent deletes are kept when there are too many
+        assert_eq!(store.tombstones(4, 0, 1).unwrap(), vec![4]);
+        assert_eq!(store.tombstones(4, 0, 10).unwrap(), vec![3, 4]);
+        assert_eq!(store.tombstones(4, 12, 10).unwrap(), vec![4]);
+        store.undelete_post(3, 13).unwrap();
+        // IDs of deleted posts are not given out again, even once purged
+        assert_eq!(store.next_post_id().unwrap(), 5);
+        store.insert_post(&new_post(5), 13).unwrap();
+        assert_eq!(store.next_post_id().unwrap(), 6);
+        assert!(matches!(
+            store
+                .undelete_post(1, 13)
+                .unwrap_err()
+                .downcast_ref::<BriefsError>(),
+            Some(BriefsError::InvalidId {})
+        ));
+
+        // Only live posts are found, and edits are searchable right away
+        let hit_ids = |hits: Vec<SearchHit>| -> Vec<u32> {
+            hits.iter().map(|val| val.post.id().unwrap()).collect()
+        };
+        let mut found = hit_ids(store.search("post", 10, 0).unwrap());
+        let mut pages = hit_ids(store.search("post", 2, 0).unwrap());
+        pages.extend(hit_ids(store.search("post", 2, 2).unwrap()));
+        assert_eq!(pages, found);
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 2, 3, 5]);
+        let mut found = hit_ids(store.search("MESS*", 10, 0).unwrap());
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 2, 3, 5]);
+        let hits = store.search("new message", 10, 0).unwrap();
+        assert_eq!(hit_ids(hits.clone()), vec![3]);
+        assert_eq!(hits[0].post, store.get_post(3).unwrap());
+        assert_eq!(hits[0].title, "Post #3");
+        assert_eq!(hits[0].snippet, "**New** **message**");
+        let hits = store.search("of post", 10, 0).unwrap();
+        assert_eq!(hits[0].title, "**Post** #5");
+        assert_eq!(hits[0].snippet, "Message **of** **post** #5");
+
+        assert!(store.search("deploy", 10, 0).unwrap().is_empty());
+        store.update_post_title(5, "Deploy done", "ci", 14).unwrap();
+        assert_eq!(hit_ids(store.search("deploy", 10, 0).unwrap()), vec![5]);
+        // Matches in the title rank above matches in the message
+        store
+            .update_post_msg(2, "Deploy is next", "ci", 14)
+            .unwrap();
+        assert_eq!(hit_ids(store.search("deploy", 10, 0).unwrap()), vec![5, 2]);
+        store.delete_post(5, 15).unwrap();
+        assert_eq!(hit_ids(store.search("deploy", 10, 0).unwrap()), vec![2]);
+        store.undelete_post(5, 16).unwrap();
+        assert_eq!(hit_ids(store.search("deploy", 10, 0).unwrap()), vec![5, 2]);
+        assert!(store.search(" ", 10, 0).is_err());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise_store(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let path = setup_mock_db();
+        exercise_store(&mut SqliteStore::open(&path).unwrap());
+        cleanup_db(path);
+    }
+}
@@ -1,84 +1,144 @@
-use sqlite::Connection;
-
 use crate::{
-    constant::{PAGINATION_DEFAULT, PAGINATION_LIMIT, STREAM_CACHE_SIZE},
-    db,
-    post::{time_in_sec, Post},
-    state::{CatchUpResponse, StreamMetadata},
+    constant::{
+        DEFAULT_STREAM, FEED_DEFAULT, FEED_LIMIT, PAGINATION_DEFAULT, PAGINATION_LIMIT,
+        SEARCH_DEFAULT, SEARCH_LIMIT, STREAM_CACHE_SIZE,
+    },
+    cursor, db,
+    feed::{self, FeedFormat},
+    post::{time_in_sec, verify_msg, verify_title, Post},
+    state::{CatchUpResponse, Feed, PostHistory, SearchResults, StreamInfo, StreamMetadata},
+    store::{PageQuery, PostStore},
     BriefsError, BriefsResult,
 };
 use std::{collections::VecDeque, fmt::Display, time::SystemTime};
 
-/// A Stream contains all the posts and some metadata.
+/// Scope of the cursors handed out by `Stream::search`.
+const SEARCH_CURSOR: &str = "search";
+/// Scopes of the cursors handed out by `Stream::catchup_page`, to the
+/// newer and the older posts. The position is a post ID.
+const NEXT_CURSOR: &str = "catchup-next";
+const PREV_CURSOR: &str = "catchup-prev";
+
+/// Where a page of `Stream::catchup_page` starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageStart {
+    /// The oldest posts with an ID of at least the given one.
+    From(u32),
+    /// The newest posts with an ID below the given one.
+    Before(u32),
+}
+
+impl PageStart {
+    fn decode(cursor: &str) -> BriefsResult<Self> {
+        let position = |scope| {
+            cursor::decode(scope, cursor)
+                .ok()
+                .and_then(|val| u32::try_from(val).ok())
+        };
+        match (position(NEXT_CURSOR), position(PREV_CURSOR)) {
+            (Some(id), _) => Ok(Self::From(id)),
+            (_, Some(id)) => Ok(Self::Before(id)),
+            _ => Err(BriefsError::InvalidCursor {
+                cursor: cursor.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// A Stream contains all the posts and some metadata. The latest posts
+/// are cached in memory, while all the posts and the metadata are
+/// persisted in the store.
 #[derive(Debug)]
-pub struct Stream {
+pub struct Stream<S: PostStore> {
+    store: S,
     posts: VecDeque<Post>,
     size: usize,
     nposts: u64,
-    last_updated: u64,
-    date_of_inception: u64,
+    info: StreamInfo,
 }
 
-impl Default for Stream {
+impl<S: PostStore + Default> Default for Stream<S> {
     fn default() -> Self {
-        Stream {
-            posts: VecDeque::with_capacity(STREAM_CACHE_SIZE.into()),
-            size: 0,
-            nposts: 0,
-            date_of_inception: time_in_sec(SystemTime::now()).unwrap(),
-            last_updated: time_in_sec(SystemTime::now()).unwrap(),
-        }
+        Stream::open(S::default()).expect("Failed to open a stream on the default store")
     }
 }
 
-impl Stream {
-    pub fn assemble(conn: &mut Connection, last_updated: u64, doi: u64) -> BriefsResult<Self> {
-        println!("» Assembling existing stream");
-        let records = db::query_cache(conn)?;
-        let post_iter = db::sqlite_to_post(records)?.into_iter().rev();
-        let posts = VecDeque::from_iter(post_iter);
-        let size = posts.len();
-        println!("» Found {} sqlite rows", size);
-        let result = db::query_post_count(conn)?.take("count");
-
-        let nposts = match result {
-            sqlite::Value::Integer(val) => val.try_into()?,
-            _ => return Err(BriefsError::custom_error("Post count not an integer".into()).into()),
+impl<S: PostStore> Stream<S> {
+    /// Open the stream persisted in the store. If the store does not
+    /// contain a stream yet, the default stream is created and persisted.
+    pub fn open(mut store: S) -> BriefsResult<Self> {
+        let info = match store.stream_info()? {
+            Some(info) => {
+                println!("» Assembling existing stream");
+                info
+            }
+            None => {
+                let now = time_in_sec(SystemTime::now())?;
+                let info = StreamInfo {
+                    id: db::DEFAULT_STREAM_ID,
+                    name: DEFAULT_STREAM.into(),
+                    archived: false,
+                    last_updated: now,
+                    date_of_inception: now,
+                };
+                store.create_stream(&info)?;
+                info
+            }
         };
+        let posts = VecDeque::from(store.last_n(STREAM_CACHE_SIZE.into())?);
+        let size = posts.len();
+        println!("» Found {} stored posts", size);
+        let nposts = store.count()?;
 
         Ok(Stream {
+            store,
             posts,
             size,
             nposts,
-            last_updated,
-            date_of_inception: doi,
+            info,
         })
     }
 }
 
-impl Stream {
+impl<S: PostStore> Stream<S> {
     // ***
     // Command handlers
     // ***
 
-    /// Adds a new post to the current stream.
-    pub fn add_post(&mut self, conn: &mut Connection, post: Post) -> BriefsResult<()> {
-        db::insert_post(conn, &post)?;
+    /// Creates a post with the next ID of the stream and adds it. Returns
+    /// the new post.
+    pub fn create_post(&mut self, title: String, msg: String) -> BriefsResult<Post> {
+        let post = Post::new(self.store.next_post_id()?, title, msg)?;
+        self.add_post(post.clone())?;
+        Ok(post)
+    }
+
+    /// Adds a new post to the current stream. The ID of the post has to be
+    /// newer than those of the posts already in the stream; see
+    /// `create_post`.
+    pub fn add_post(&mut self, post: Post) -> BriefsResult<()> {
+        self.ensure_writable()?;
+        let now = time_in_sec(SystemTime::now())?;
+        self.store.insert_post(&post, now)?;
         if self.posts.len() == STREAM_CACHE_SIZE as usize {
             self.posts.pop_front();
             self.size -= 1;
         }
         self.posts.push_back(post);
         self.size += 1;
-        self.last_updated = time_in_sec(SystemTime::now())?;
+        self.info.last_updated = now;
         self.increment_post_count()?;
         Ok(())
     }
 
-    /// Removes an existing post from the stream.
-    pub fn remove_post(&mut self, conn: &mut Connection, id: u32) -> BriefsResult<()> {
-        db::delete_post_by_id(conn, id)?;
-        self.last_updated = time_in_sec(SystemTime::now())?;
+    /// Removes an existing post from the stream. The post is kept as a
+    /// tombstone until purged, see `undelete` and `purge_deleted`.
+    pub fn remove_post(&mut self, id: u32) -> BriefsResult<()> {
+        self.ensure_writable()?;
+        let now = time_in_sec(SystemTime::now())?;
+        self.store.delete_post(id, now)?;
+        self.info.last_updated = now;
         self.decrement_post_count()?;
         if !self.id_in_cache(id) {
             return Ok(());
@@ -89,35 +149,47 @@ impl Stream {
         Ok(())
     }
 
-    /// Update an existing post with the new message.
-    pub fn update_msg(
-        &mut self,
-        conn: &mut Connection,
-        id: u32,
-        new_msg: String,
-    ) -> BriefsResult<()> {
-        db::update_post_msg_by_id(conn, id, new_msg.clone())?;
-        self.last_updated = time_in_sec(SystemTime::now())?;
+    /// Bring back a deleted post. Returns the restored post.
+    pub fn undelete(&mut self, id: u32) -> BriefsResult<Post> {
+        self.ensure_writable()?;
+        let now = time_in_sec(SystemTime::now())?;
+        let post = self.store.undelete_post(id, now)?;
+        self.info.last_updated = now;
+        self.increment_post_count()?;
+        // The post may belong anywhere within the cache
+        self.posts = self.store.last_n(STREAM_CACHE_SIZE.into())?.into();
+        self.size = self.posts.len();
+        Ok(post)
+    }
+
+    /// Remove the posts deleted before `before` for good. Returns the
+    /// number of purged posts.
+    pub fn purge_deleted(&mut self, before: u64) -> BriefsResult<usize> {
+        self.store.purge_deleted(before)
+    }
+
+    /// Update an existing post with the new message, on behalf of `author`.
+    pub fn update_msg(&mut self, id: u32, new_msg: String, author: &str) -> BriefsResult<()> {
+        self.ensure_writable()?;
+        verify_msg(&new_msg)?;
+        let now = time_in_sec(SystemTime::now())?;
+        self.store.update_post_msg(id, &new_msg, author, now)?;
+        self.info.last_updated = now;
         if !self.id_in_cache(id) {
             return Ok(());
         }
         let idx = self.post_id_to_idx(id)?;
-        let post = self
-            .posts
-            .get_mut(idx)
-            .ok_or_else(|| BriefsError::InvalidId {})?;
+        let post = self.posts.get_mut(idx).ok_or(BriefsError::InvalidId {})?;
         post.update_msg(new_msg)
     }
 
-    /// Update an existing post with the new title.
-    pub fn update_title(
-        &mut self,
-        conn: &mut Connection,
-        id: u32,
-        new_title: String,
-    ) -> BriefsResult<()> {
-        db::update_post_title_by_id(conn, id, new_title.clone())?;
-        self.last_updated = time_in_sec(SystemTime::now())?;
+    /// Update an existing post with the new title, on behalf of `author`.
+    pub fn update_title(&mut self, id: u32, new_title: String, author: &str) -> BriefsResult<()> {
+        self.ensure_writable()?;
+        verify_title(&new_title)?;
+        let now = time_in_sec(SystemTime::now())?;
+        self.store.update_post_title(id, &new_title, author, now)?;
+        self.info.last_updated = now;
         if !self.id_in_cache(id) {
             return Ok(());
         }
@@ -125,82 +197,244 @@ impl Stream {
         let post = self
             .posts
             .get_mut(post_id)
-            .ok_or_else(|| BriefsError::InvalidId {})?;
+            .ok_or(BriefsError::InvalidId {})?;
         post.update_title(new_title)
     }
 
-    /// Return latest posts since last fetch.
-    pub fn catchup(
+    /// Update the title, the message or both of an existing post in a
+    /// single edit, on behalf of `author`. Returns the updated post.
+    pub fn update(
+        &mut self,
+        id: u32,
+        title: Option<String>,
+        msg: Option<String>,
+        author: &str,
+    ) -> BriefsResult<Post> {
+        self.ensure_writable()?;
+        if title.is_none() && msg.is_none() {
+            return Err(BriefsError::InvalidMessage {
+                msg: "Nothing to update; expected a title or a msg".into(),
+            }
+            .into());
+        }
+        if let Some(title) = &title {
+            verify_title(title)?;
+        }
+        if let Some(msg) = &msg {
+            verify_msg(msg)?;
+        }
+        let now = time_in_sec(SystemTime::now())?;
+        let post = self
+            .store
+            .update_post(id, title.as_deref(), msg.as_deref(), author, now)?;
+        self.info.last_updated = now;
+        if let Ok(idx) = self.post_id_to_idx(id) {
+            self.posts[idx] = post.clone();
+        }
+        Ok(post)
+    }
+
+    /// Restore the title and message of a post as of the given revision,
+    /// on behalf of `author`. Returns the restored post.
+    pub fn restore(&mut self, id: u32, revision: u32, author: &str) -> BriefsResult<Post> {
+        self.ensure_writable()?;
+        let now = time_in_sec(SystemTime::now())?;
+        let post = self.store.restore_post(id, revision, author, now)?;
+        self.info.last_updated = now;
+        if let Ok(idx) = self.post_id_to_idx(id) {
+            self.posts[idx] = post.clone();
+        }
+        Ok(post)
+    }
+
+    /// Return the revisions of a post, oldest first.
+    pub fn history(&self, id: u32) -> BriefsResult<PostHistory> {
+        // Posts that were never edited have no revisions, but do exist
+        if self.get_post(id).is_none() {
+            return Err(BriefsError::InvalidId {}.into());
+        }
+        Ok(PostHistory {
+            id,
+            revisions: self.store.revisions(id)?,
+        })
+    }
+
+    /// Return at most `limit` of the posts with an ID of at least `sid`,
+    /// see `catchup_page`.
+    pub fn catchup(&self, sid: u32, limit: Option<u32>) -> BriefsResult<CatchUpResponse> {
+        self.catchup_page(sid, None, None, None, limit)
+    }
+
+    /// Return a page of at most `limit` posts dated within `since <= date
+    /// < until`, in ascending order of ID, along with the IDs of the
+    /// deleted posts up to the last returned one. The page holds the posts
+    /// with an ID of at least `sid`, unless it continues from a cursor of
+    /// an earlier page; the cursor does not hold the dates, so pass the
+    /// same ones for every page.
+    ///
+    /// The posts older than the page that were edited are returned too.
+    /// Only posts deleted or edited since the reader fetched the posts
+    /// below the start of the page, or since `since`, are reported, and at
+    /// most `PAGINATION_LIMIT` of either.
+    ///
+    /// Pages are bounded by post IDs, so gaps left by deleted posts and
+    /// posts created meanwhile never shift them.
+    pub fn catchup_page(
         &self,
-        conn: &Connection,
         sid: u32,
+        cursor: Option<&str>,
+        since: Option<u64>,
+        until: Option<u64>,
         limit: Option<u32>,
     ) -> BriefsResult<CatchUpResponse> {
-        let mut response = CatchUpResponse {
-            posts: Vec::new(),
-            caught_up: true,
+        let lmt = limit
+            .unwrap_or(PAGINATION_DEFAULT)
+            .clamp(1, PAGINATION_LIMIT);
+        let start = match cursor {
+            Some(val) => PageStart::decode(val)?,
+            None => PageStart::From(sid),
+        };
+        let dates = PageQuery {
+            since,
+            until,
+            ..Default::default()
         };
-        if self.posts.is_empty() {
-            return Ok(response);
-        }
-
-        let mut caught_up = false;
-        let last_id = self.posts.back().unwrap().id().unwrap();
 
-        if last_id < sid {
-            return Ok(response);
-        }
+        // One more post than asked for tells whether there are more
+        let (posts, has_newer, has_older) = match start {
+            PageStart::From(sid) => {
+                let query = PageQuery {
+                    from_id: sid,
+                    ..dates
+                };
+                let mut posts = self.posts_from(&query, lmt + 1)?;
+                let has_newer = posts.len() > lmt as usize;
+                posts.truncate(lmt as usize);
+                let eid = posts.first().map_or(Ok(sid), Post::id)?;
+                let older = PageQuery {
+                    to_id: Some(eid),
+                    backward: true,
+                    ..dates
+                };
+                let has_older = eid > 0 && !self.store.page(&older, 1)?.is_empty();
+                (posts, has_newer, has_older)
+            }
+            PageStart::Before(eid) => {
+                let query = PageQuery {
+                    to_id: Some(eid),
+                    backward: true,
+                    ..dates
+                };
+                let mut posts = self.store.page(&query, lmt + 1)?;
+                let has_older = posts.len() > lmt as usize;
+                if has_older {
+                    posts.remove(0);
+                }
+                let sid = match posts.last() {
+                    Some(post) => post.id()? + 1,
+                    None => eid,
+                };
+                let newer = PageQuery {
+                    from_id: sid,
+                    ..dates
+                };
+                let has_newer = !self.posts_from(&newer, 1)?.is_empty();
+                (posts, has_newer, has_older)
+            }
+        };
 
-        let mut lmt = limit.unwrap_or(PAGINATION_DEFAULT);
-        lmt = std::cmp::min(lmt, PAGINATION_LIMIT);
-        let mut eid = sid + lmt;
-        eid = if last_id <= eid {
-            caught_up = true;
-            last_id
+        // IDs the newer and the older pages start from
+        let (next_id, prev_id) = match (start, posts.first(), posts.last()) {
+            (_, Some(first), Some(last)) => (u64::from(last.id()?) + 1, first.id()?),
+            (PageStart::From(id) | PageStart::Before(id), _, _) => (u64::from(id), id),
+        };
+        let next_cursor = has_newer.then(|| cursor::encode(NEXT_CURSOR, next_id));
+        let prev_cursor = has_older.then(|| cursor::encode(PREV_CURSOR, prev_id.into()));
+        let changed_since = since.unwrap_or(0).max(self.fetched_since(start)?);
+        // Once caught up, also report posts deleted after the last one
+        let until = if has_newer {
+            next_id.checked_sub(1)
         } else {
-            eid
+            Some(u32::MAX.into())
         };
-        if self.id_in_cache(sid) {
-            println!("» Fetching from cache");
-            // use cache
-            let sidx = self.post_id_to_idx(sid)?;
-            let eidx = self.post_id_to_idx(eid)?;
-            response.posts = self.posts.range(sidx..=eidx).cloned().collect();
-            response.caught_up = caught_up;
-            return Ok(response);
-        }
-        // use db
-        let records = db::catchup(conn, sid.try_into()?, eid.try_into()?, lmt)?;
-        response.posts = db::sqlite_to_post(records)?;
-        response.caught_up = caught_up;
-        Ok(response)
+        let deleted = match until {
+            Some(val) => {
+                self.store
+                    .tombstones(u32::try_from(val)?, changed_since, PAGINATION_LIMIT)?
+            }
+            None => Vec::new(),
+        };
+        let edited = self
+            .store
+            .edited(prev_id, changed_since, PAGINATION_LIMIT)?;
+        Ok(CatchUpResponse {
+            posts,
+            next_cursor,
+            prev_cursor,
+            deleted,
+            edited,
+        })
     }
 
     /// Return a specific post.
-    pub fn get_post(&self, conn: &Connection, id: u32) -> Option<Post> {
+    pub fn get_post(&self, id: u32) -> Option<Post> {
         let result = self.post_id_to_idx(id);
         match result {
             Ok(idx) => self.posts.get(idx).cloned(),
-            Err(_) => match db::query_post_by_id(conn, id) {
-                Ok(val) => Post::parse_sqlite_row(val).ok(),
-                Err(_) => None,
-            },
+            Err(_) => self.store.get_post(id).ok(),
         }
     }
 
+    /// Render the latest posts as a feed document.
+    pub fn feed(&self, format: FeedFormat, limit: Option<u32>) -> BriefsResult<Feed> {
+        let limit = std::cmp::min(limit.unwrap_or(FEED_DEFAULT), FEED_LIMIT);
+        let posts = self.store.last_n(limit)?;
+        Ok(Feed {
+            format,
+            document: feed::render(format, &self.info, &posts)?,
+        })
+    }
+
+    /// Return a page of at most `limit` posts matching the query, best
+    /// match first. The page starts where the one that handed out the
+    /// cursor ended.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: Option<u32>,
+        cursor: Option<&str>,
+    ) -> BriefsResult<SearchResults> {
+        let limit = limit.unwrap_or(SEARCH_DEFAULT).clamp(1, SEARCH_LIMIT);
+        let offset = match cursor {
+            Some(val) => cursor::decode(SEARCH_CURSOR, val)?,
+            None => 0,
+        };
+
+        // One more hit than asked for tells whether there is a next page
+        let mut hits = self.store.search(query, limit + 1, offset)?;
+        let next_cursor = (hits.len() > limit as usize)
+            .then(|| cursor::encode(SEARCH_CURSOR, offset + u64::from(limit)));
+        hits.truncate(limit as usize);
+
+        Ok(SearchResults { hits, next_cursor })
+    }
+
     pub fn stream_metadata(&self) -> BriefsResult<StreamMetadata> {
         Ok(StreamMetadata {
+            name: self.info.name.clone(),
+            archived: self.info.archived,
             posts_count: self.nposts as u32,
-            last_updated: self.last_updated,
-            latest_post_id: self.posts.back().map(|val| val.id().unwrap()),
+            last_updated: self.info.last_updated,
+            // The cache may be empty while older posts are in the store
+            latest_post_id: self.store.last_n(1)?.first().map(Post::id).transpose()?,
+            sqlite_version: db::sqlite_version(),
         })
     }
 
-    /// Refresh the internal cache from Db.
-    pub fn refresh_cache(&mut self, conn: &mut Connection) -> BriefsResult<()> {
+    /// Refresh the internal cache from the store.
+    pub fn refresh_cache(&mut self) -> BriefsResult<()> {
         self.posts.clear();
-        let n_posts = db::query_last_n(conn, self.size.try_into()?)?;
-        self.posts = db::sqlite_to_post(n_posts)?.into();
+        self.posts = self.store.last_n(self.size.try_into()?)?.into();
         Ok(())
     }
 
@@ -208,6 +442,16 @@ impl Stream {
     // Helpers
     // ***
 
+    fn ensure_writable(&self) -> BriefsResult<()> {
+        if self.info.archived {
+            return Err(BriefsError::StreamArchived {
+                name: self.info.name.clone(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
     fn increment_post_count(&mut self) -> BriefsResult<()> {
         self.nposts += 1;
         Ok(())
@@ -218,26 +462,73 @@ impl Stream {
         Ok(())
     }
 
-    fn id_in_cache(&self, index: u32) -> bool {
-        if self.posts.is_empty() {
-            false
-        } else if index < self.posts.front().unwrap().id().unwrap_or_default() {
-            false
-        } else if index > self.posts.back().unwrap().id().unwrap_or_default() {
-            false
-        } else {
-            true
+    fn id_in_cache(&self, id: u32) -> bool {
+        self.post_id_to_idx(id).is_ok()
+    }
+
+    /// Return the earliest date a reader can have fetched the posts below
+    /// the start of a page: the date of the newest of them, or 0 if there
+    /// are none.
+    fn fetched_since(&self, start: PageStart) -> BriefsResult<u64> {
+        let (PageStart::From(id) | PageStart::Before(id)) = start;
+        let below = PageQuery {
+            to_id: Some(id),
+            backward: true,
+            ..Default::default()
+        };
+        Ok(self
+            .store
+            .page(&below, 1)?
+            .first()
+            .map_or(0, |post| post.date))
+    }
+
+    /// Return the posts selected by a forward query, from the cache if it
+    /// holds every one of them.
+    fn posts_from(&self, query: &PageQuery, limit: u32) -> BriefsResult<Vec<Post>> {
+        if !self.cache_covers(query.from_id) {
+            return self.store.page(query, limit);
         }
+        println!("» Fetching from cache");
+        let sidx = self
+            .posts
+            .partition_point(|val| val.id().unwrap_or_default() < query.from_id);
+        Ok(self
+            .posts
+            .range(sidx..)
+            .filter(|val| query.matches(val))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    /// Whether the cache holds every post with an ID of at least `id`. The
+    /// cache keeps the latest posts, so that is the case unless `id` is
+    /// older than the oldest cached post.
+    fn cache_covers(&self, id: u32) -> bool {
+        self.posts
+            .front()
+            .is_some_and(|val| id >= val.id().unwrap_or_default())
+    }
+
+    /// Get the metadata persisted alongside the posts
+    pub fn info(&self) -> &StreamInfo {
+        &self.info
+    }
+
+    /// Get the name of the stream
+    pub fn name(&self) -> &str {
+        &self.info.name
     }
 
     /// Get the last time the stream was updated
     pub fn last_updated(&self) -> u64 {
-        self.last_updated.clone()
+        self.info.last_updated
     }
 
     /// Get the number of posts in cache
     pub fn size(&self) -> usize {
-        self.size as usize
+        self.size
     }
 
     /// Get the number of posts in stream
@@ -247,58 +538,535 @@ impl Stream {
 
     /// Get the date of inception/creation of the stream
     pub fn date_of_inception(&self) -> u64 {
-        self.date_of_inception
+        self.info.date_of_inception
+    }
+
+    pub(crate) fn set_name(&mut self, name: String) {
+        self.info.name = name;
+    }
+
+    pub(crate) fn set_archived(&mut self) {
+        self.info.archived = true;
+    }
+
+    /// Get the store backing the stream
+    pub fn store(&self) -> &S {
+        &self.store
     }
 
     /// Returns the index of post, with the associated ID, in the posts vector.
     fn post_id_to_idx(&self, id: u32) -> BriefsResult<usize> {
-        if self.posts.is_empty() {
-            return Err(BriefsError::InvalidId {}.into());
-        }
-        let mut start = 0;
-        let mut end = self.posts.len() - 1;
-        let mut mid = (end + start) / 2;
-        let mut post_id = self
+        // Cached posts are sorted by ID, with gaps where posts were deleted
+        Ok(self
             .posts
-            .get(mid)
-            .ok_or_else(|| BriefsError::InvalidId {})?
-            .id()?;
-
-        while start < end {
-            if id < post_id {
-                end = mid - 1;
-            } else if id > post_id {
-                start = mid + 1;
-            } else {
+            .binary_search_by_key(&id, |val| val.id().unwrap_or_default())
+            .map_err(|_| BriefsError::InvalidId {})?)
+    }
+}
+
+impl<S: PostStore> Display for Stream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for _post in self.posts.iter() {
+            writeln!(f, "{}", _post)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::test::{cleanup_db, setup_mock_db},
+        store::{MemoryStore, SqliteStore},
+    };
+
+    fn stream_with_posts(n: u32) -> Stream<MemoryStore> {
+        let mut stream = Stream::<MemoryStore>::default();
+        for id in 0..n {
+            let post = Post::new(id, format!("Post #{id}"), format!("Message #{id}")).unwrap();
+            stream.add_post(post).unwrap();
+        }
+        stream
+    }
+
+    #[test]
+    fn test_add_post_rolls_cache() {
+        let stream = stream_with_posts(STREAM_CACHE_SIZE as u32 + 5);
+
+        assert_eq!(stream.size(), STREAM_CACHE_SIZE as usize);
+        assert_eq!(stream.nposts(), STREAM_CACHE_SIZE as usize + 5);
+        assert_eq!(stream.store().count().unwrap(), stream.nposts() as u64);
+        // Old posts are still available from the store
+        assert_eq!(stream.get_post(0).unwrap().title, "Post #0");
+    }
+
+    #[test]
+    fn test_update_and_remove_post() {
+        let mut stream = stream_with_posts(3);
+
+        stream
+            .update_title(1, "Updated title".into(), "ci")
+            .unwrap();
+        stream
+            .update_msg(1, "Updated message".into(), "ci")
+            .unwrap();
+        let post = stream.get_post(1).unwrap();
+        assert_eq!(post.title, "Updated title");
+        assert_eq!(post.msg, "Updated message");
+        assert!(post.edited);
+        assert_eq!(stream.store().get_post(1).unwrap().msg, "Updated message");
+
+        // Invalid updates must not reach the store
+        assert!(stream.update_title(1, String::new(), "ci").is_err());
+        assert_eq!(stream.store().get_post(1).unwrap().title, "Updated title");
+        assert_eq!(stream.history(1).unwrap().revisions.len(), 2);
+
+        stream.remove_post(1).unwrap();
+        assert!(stream.get_post(1).is_none());
+        assert_eq!(stream.nposts(), 2);
+        assert!(stream.remove_post(1).is_err());
+        assert_eq!(stream.nposts(), 2);
+    }
+
+    #[test]
+    fn test_metadata_reads_latest_post_from_store() {
+        let total = STREAM_CACHE_SIZE as u32 + 5;
+        let mut stream = stream_with_posts(total);
+        assert_eq!(
+            stream.stream_metadata().unwrap().latest_post_id,
+            Some(total - 1)
+        );
+
+        // Deleting every cached post empties the cache, not the stream
+        for id in 5..total {
+            stream.remove_post(id).unwrap();
+        }
+        assert_eq!(stream.size(), 0);
+        assert_eq!(stream.stream_metadata().unwrap().latest_post_id, Some(4));
+    }
+
+    #[test]
+    fn test_catchup_reports_tombstones() {
+        let mut stream = stream_with_posts(STREAM_CACHE_SIZE as u32 * 2);
+        let last_id = STREAM_CACHE_SIZE as u32 * 2 - 1;
+        for id in [1, 7, last_id] {
+            stream.remove_post(id).unwrap();
+        }
+
+        let response = stream.catchup(0, Some(5)).unwrap();
+        assert!(response.next_cursor.is_some());
+        let ids: Vec<u32> = response.posts.iter().map(|val| val.id().unwrap()).collect();
+        assert_eq!(ids, vec![0, 2, 3, 4, 5]);
+        assert_eq!(response.deleted, vec![1]);
+
+        // Readers that are caught up still learn about every delete
+        let response = stream.catchup(last_id + 1, None).unwrap();
+        assert!(response.next_cursor.is_none());
+        assert!(response.posts.is_empty());
+        assert_eq!(response.deleted, vec![1, 7, last_id]);
+
+        // An undeleted post is back in the cache and no longer reported
+        let post = stream.undelete(last_id).unwrap();
+        assert_eq!(stream.get_post(last_id).unwrap(), post);
+        assert_eq!(stream.nposts(), STREAM_CACHE_SIZE as usize * 2 - 2);
+        assert_eq!(stream.size(), STREAM_CACHE_SIZE as usize);
+        let response = stream.catchup(last_id, None).unwrap();
+        assert_eq!(response.posts, vec![post]);
+        assert_eq!(response.deleted, vec![1, 7]);
+        assert!(stream.undelete(last_id).is_err());
+
+        // Purged posts are gone for good
+        assert_eq!(stream.purge_deleted(u64::MAX).unwrap(), 2);
+        assert!(stream.catchup(last_id, None).unwrap().deleted.is_empty());
+        assert!(stream.undelete(1).is_err());
+    }
+
+    #[test]
+    fn test_catchup_reports_deletes_since_the_reader_position() {
+        let mut stream = stream_with_posts(PAGINATION_LIMIT + 5);
+        for id in 0..PAGINATION_LIMIT + 2 {
+            stream.remove_post(id).unwrap();
+        }
+
+        // Every delete is newer than the posts below the page, but only the
+        // most recent ones are reported
+        let response = stream.catchup(PAGINATION_LIMIT + 5, None).unwrap();
+        assert_eq!(response.deleted.len(), PAGINATION_LIMIT as usize);
+        assert_eq!(response.deleted.last(), Some(&(PAGINATION_LIMIT + 1)));
+
+        // Readers that fetched a post created after the deletes, or that
+        // only ask for newer ones, are not told about them again
+        let mut post = Post::new(PAGINATION_LIMIT + 5, "Later".into(), "Message".into()).unwrap();
+        post.date += 60;
+        stream.add_post(post.clone()).unwrap();
+        let response = stream.catchup(PAGINATION_LIMIT + 6, None).unwrap();
+        assert!(response.deleted.is_empty());
+        let response = stream
+            .catchup_page(0, None, Some(post.date), None, None)
+            .unwrap();
+        assert_eq!(response.posts, vec![post]);
+        assert!(response.deleted.is_empty());
+    }
+
+    fn ids(posts: &[Post]) -> Vec<u32> {
+        posts.iter().map(|val| val.id().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_catchup_reports_edits_older_than_the_page() {
+        let mut stream = stream_with_posts(5);
+        stream.update_title(1, "Edited".into(), "ci").unwrap();
+        stream.update_msg(3, "Edited".into(), "ci").unwrap();
+
+        let response = stream.catchup(3, None).unwrap();
+        assert_eq!(ids(&response.posts), vec![3, 4]);
+        assert_eq!(ids(&response.edited), vec![1]);
+        assert_eq!(response.edited[0].title, "Edited");
+        // Posts of the page itself are up to date already
+        assert!(stream.catchup(0, None).unwrap().edited.is_empty());
+
+        // Readers that fetched a post created after the edits are not told
+        // about them again
+        let mut post = Post::new(5, "Later".into(), "Message".into()).unwrap();
+        post.date += 60;
+        stream.add_post(post).unwrap();
+        assert!(stream.catchup(6, None).unwrap().edited.is_empty());
+    }
+
+    #[test]
+    fn test_create_delete_create_never_reuses_ids() {
+        let mut stream = Stream::<MemoryStore>::default();
+        for id in 0..3 {
+            let post = stream
+                .create_post(format!("Post #{id}"), "Message".into())
+                .unwrap();
+            assert_eq!(post.id().unwrap(), id);
+        }
+
+        // Neither the latest nor older IDs are given out again
+        stream.remove_post(2).unwrap();
+        let post = stream
+            .create_post("Post #3".into(), "Message".into())
+            .unwrap();
+        assert_eq!(post.id().unwrap(), 3);
+        stream.remove_post(0).unwrap();
+        stream.remove_post(1).unwrap();
+        let post = stream
+            .create_post("Post #4".into(), "Message".into())
+            .unwrap();
+        assert_eq!(post.id().unwrap(), 4);
+        assert_eq!(stream.nposts(), 2);
+        assert_eq!(stream.get_post(4).unwrap(), post);
+        assert!(stream.get_post(2).is_none());
+
+        let response = stream.catchup(0, None).unwrap();
+        assert_eq!(ids(&response.posts), vec![3, 4]);
+        assert!(response.next_cursor.is_none());
+        assert_eq!(response.deleted, vec![0, 1, 2]);
+        // Pages count posts rather than IDs, and may start at a gap
+        let response = stream.catchup(1, Some(1)).unwrap();
+        assert_eq!(ids(&response.posts), vec![3]);
+        assert!(response.next_cursor.is_some());
+        assert_eq!(response.deleted, vec![0, 1, 2]);
+        assert!(stream.catchup(5, None).unwrap().next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_catchup_across_gaps_in_cache_and_store() {
+        let cache_size = STREAM_CACHE_SIZE as u32;
+        let mut stream = stream_with_posts(cache_size * 2);
+        for id in (1..cache_size).step_by(2) {
+            stream.remove_post(id).unwrap();
+        }
+        // Every cached post is gone, while older ones remain in the store
+        for id in cache_size..cache_size * 2 {
+            stream.remove_post(id).unwrap();
+        }
+        assert_eq!(stream.size(), 0);
+
+        let response = stream.catchup(0, Some(3)).unwrap();
+        assert_eq!(ids(&response.posts), vec![0, 2, 4]);
+        assert!(response.next_cursor.is_some());
+        assert_eq!(response.deleted, vec![1, 3]);
+        let response = stream.catchup(5, None).unwrap();
+        assert_eq!(ids(&response.posts), vec![6, 8]);
+        assert!(response.next_cursor.is_none());
+        assert_eq!(
+            response.deleted.len(),
+            cache_size as usize / 2 + cache_size as usize
+        );
+        assert_eq!(stream.get_post(8).unwrap().title, "Post #8");
+
+        let post = stream.create_post("New".into(), "Message".into()).unwrap();
+        assert_eq!(post.id().unwrap(), cache_size * 2);
+        assert_eq!(
+            ids(&stream.catchup(9, None).unwrap().posts),
+            vec![cache_size * 2]
+        );
+    }
+
+    #[test]
+    fn test_catchup_pages_with_cursors_and_dates() {
+        let mut stream = Stream::<MemoryStore>::default();
+        for id in 0..30 {
+            let post = Post::from_parts(
+                id,
+                format!("Post #{id}"),
+                "Message".into(),
+                1000 + u64::from(id) * 10,
+                false,
+            );
+            stream.add_post(post).unwrap();
+        }
+        for id in [3, 4, 25] {
+            stream.remove_post(id).unwrap();
+        }
+        let live: Vec<u32> = (0..30).filter(|id| ![3, 4, 25].contains(id)).collect();
+
+        // Forward from the first post
+        let (mut found, mut cursor) = (Vec::new(), None);
+        loop {
+            let page = stream
+                .catchup_page(0, cursor.as_deref(), None, None, Some(10))
+                .unwrap();
+            assert_eq!(page.prev_cursor.is_some(), !found.is_empty());
+            found.extend(ids(&page.posts));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                assert_eq!(page.deleted, vec![3, 4, 25]);
                 break;
             }
-            mid = (end + start) / 2;
-            post_id = self
-                .posts
-                .get(mid)
-                .ok_or_else(|| BriefsError::InvalidId {})?
-                .id()?;
         }
+        assert_eq!(found, live);
 
-        if self
-            .posts
-            .get(mid)
-            .ok_or_else(|| BriefsError::InvalidId {})?
-            .id()?
-            != id
-        {
-            return Err(BriefsError::InvalidId {}.into());
+        // Backward from past the latest post, every page in ascending order
+        let page = stream.catchup(30, None).unwrap();
+        assert!(page.posts.is_empty());
+        assert!(page.next_cursor.is_none());
+        let (mut found, mut cursor) = (Vec::new(), page.prev_cursor);
+        while let Some(val) = cursor {
+            let page = stream
+                .catchup_page(0, Some(&val), None, None, Some(10))
+                .unwrap();
+            assert_eq!(page.next_cursor.is_some(), !found.is_empty());
+            found.splice(0..0, ids(&page.posts));
+            cursor = page.prev_cursor;
         }
+        assert_eq!(found, live);
+
+        // Back and forth from the middle
+        let page = stream.catchup(6, Some(4)).unwrap();
+        assert_eq!(ids(&page.posts), vec![6, 7, 8, 9]);
+        assert_eq!(page.deleted, vec![3, 4]);
+        let prev = page.prev_cursor.unwrap();
+        let page = stream
+            .catchup_page(0, Some(&prev), None, None, Some(4))
+            .unwrap();
+        assert_eq!(ids(&page.posts), vec![0, 1, 2, 5]);
+        assert!(page.prev_cursor.is_none());
+        let next = page.next_cursor.unwrap();
+        let page = stream
+            .catchup_page(0, Some(&next), None, None, Some(4))
+            .unwrap();
+        assert_eq!(ids(&page.posts), vec![6, 7, 8, 9]);
 
-        Ok(mid)
+        // Dates of posts 10 to 19; the bounds hold for every page
+        let page = stream
+            .catchup_page(0, None, Some(1100), Some(1200), Some(6))
+            .unwrap();
+        assert_eq!(ids(&page.posts), (10..16).collect::<Vec<u32>>());
+        assert!(page.prev_cursor.is_none());
+        let next = page.next_cursor.unwrap();
+        let page = stream
+            .catchup_page(0, Some(&next), Some(1100), Some(1200), Some(6))
+            .unwrap();
+        assert_eq!(ids(&page.posts), (16..20).collect::<Vec<u32>>());
+        assert!(page.next_cursor.is_none());
+        assert!(page.prev_cursor.is_some());
+
+        // A cursor keeps its place as posts are created
+        let page = stream.catchup(20, Some(5)).unwrap();
+        let next = page.next_cursor.unwrap();
+        stream.create_post("New".into(), "Message".into()).unwrap();
+        let page = stream
+            .catchup_page(0, Some(&next), None, None, None)
+            .unwrap();
+        assert_eq!(ids(&page.posts), vec![26, 27, 28, 29, 30]);
+
+        let search = cursor::encode(SEARCH_CURSOR, 0);
+        for cursor in ["zz", search.as_str()] {
+            assert!(matches!(
+                stream
+                    .catchup_page(0, Some(cursor), None, None, None)
+                    .unwrap_err()
+                    .downcast_ref::<BriefsError>(),
+                Some(BriefsError::InvalidCursor { .. })
+            ));
+        }
     }
-}
 
-impl Display for Stream {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for _post in self.posts.iter() {
-            writeln!(f, "{}", _post)?;
+    #[test]
+    fn test_restore_updates_cache_and_store() {
+        let mut stream = stream_with_posts(STREAM_CACHE_SIZE as u32 + 1);
+        assert!(stream.history(0).unwrap().revisions.is_empty());
+        assert!(stream.history(99).is_err());
+
+        // Post 0 is only in the store, the last post is in the cache as well
+        for id in [0, STREAM_CACHE_SIZE as u32] {
+            stream.update_msg(id, "Oops".into(), "ci").unwrap();
+            let post = stream.restore(id, 0, "admin").unwrap();
+            assert_eq!(post.msg, format!("Message #{id}"));
+            assert!(post.edited);
+            assert_eq!(stream.get_post(id).unwrap(), post);
+            assert_eq!(stream.store().get_post(id).unwrap(), post);
+
+            let history = stream.history(id).unwrap();
+            let authors: Vec<&str> = history
+                .revisions
+                .iter()
+                .map(|val| val.author.as_str())
+                .collect();
+            assert_eq!(authors, vec!["ci", "admin"]);
         }
-        Ok(())
+    }
+
+    #[test]
+    fn test_update_is_a_single_revision() {
+        let mut stream = stream_with_posts(STREAM_CACHE_SIZE as u32 + 1);
+
+        // Post 0 is only in the store, the last post is in the cache as well
+        for id in [0, STREAM_CACHE_SIZE as u32] {
+            let post = stream
+                .update(id, Some("New".into()), Some("Both".into()), "ci")
+                .unwrap();
+            assert_eq!((post.title.as_str(), post.msg.as_str()), ("New", "Both"));
+            assert!(post.edited);
+            assert_eq!(stream.get_post(id).unwrap(), post);
+            assert_eq!(stream.store().get_post(id).unwrap(), post);
+            assert_eq!(stream.history(id).unwrap().revisions.len(), 1);
+
+            let post = stream.update(id, None, Some("Msg".into()), "ci").unwrap();
+            assert_eq!((post.title.as_str(), post.msg.as_str()), ("New", "Msg"));
+        }
+
+        // Nothing is changed when any part is invalid
+        assert!(stream
+            .update(0, Some("".into()), Some("Ok".into()), "ci")
+            .is_err());
+        assert!(stream.update(0, None, None, "ci").is_err());
+        assert_eq!(stream.get_post(0).unwrap().msg, "Msg");
+        assert_eq!(stream.history(0).unwrap().revisions.len(), 2);
+    }
+
+    #[test]
+    fn test_catchup_from_cache_and_store() {
+        let stream = stream_with_posts(STREAM_CACHE_SIZE as u32 * 2);
+
+        // Served from the cache
+        let response = stream.catchup(STREAM_CACHE_SIZE as u32 + 2, None).unwrap();
+        assert!(response.next_cursor.is_none());
+        assert_eq!(response.posts.len(), STREAM_CACHE_SIZE as usize - 2);
+
+        // Served from the store
+        let response = stream.catchup(0, Some(5)).unwrap();
+        assert!(response.next_cursor.is_some());
+        let ids: Vec<u32> = response.posts.iter().map(|val| val.id().unwrap()).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_feed_holds_latest_posts() {
+        let stream = stream_with_posts(STREAM_CACHE_SIZE as u32 + 5);
+
+        let feed = stream.feed(FeedFormat::Rss, Some(3)).unwrap();
+        assert_eq!(feed.format, FeedFormat::Rss);
+        assert_eq!(feed.document.matches("<item>").count(), 3);
+        let last_id = STREAM_CACHE_SIZE + 4;
+        assert!(feed.document.contains(&format!("Post #{last_id}")));
+        assert!(!feed.document.contains(&format!("Post #{}<", last_id - 3)));
+    }
+
+    #[test]
+    fn test_search_pages_with_cursor() {
+        let mut stream = stream_with_posts(25);
+        stream.remove_post(24).unwrap();
+
+        let mut found = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = stream.search("post", Some(10), cursor.as_deref()).unwrap();
+            assert!(page.hits.len() <= 10);
+            found.extend(page.hits.iter().map(|val| val.post.id().unwrap()));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(found, (0..24).rev().collect::<Vec<u32>>());
+
+        let page = stream.search("#7", None, None).unwrap();
+        assert_eq!(page.hits.len(), 1);
+        assert_eq!(page.hits[0].title, "Post #**7**");
+        assert_eq!(page.next_cursor, None);
+
+        for (query, cursor) in [("post", Some("zz")), ("", None)] {
+            assert!(stream.search(query, None, cursor).is_err());
+        }
+    }
+
+    #[test]
+    fn test_reopen_from_store() {
+        let stream = stream_with_posts(STREAM_CACHE_SIZE as u32 + 1);
+        let (last_updated, doi) = (stream.last_updated(), stream.date_of_inception());
+        let Stream { store, .. } = stream;
+
+        let stream = Stream::open(store).unwrap();
+        assert_eq!(stream.size(), STREAM_CACHE_SIZE as usize);
+        assert_eq!(stream.nposts(), STREAM_CACHE_SIZE as usize + 1);
+        assert_eq!(stream.last_updated(), last_updated);
+        assert_eq!(stream.date_of_inception(), doi);
+    }
+
+    #[test]
+    fn test_metadata_survives_restart() {
+        let path = setup_mock_db();
+        let mut stream = Stream::open(SqliteStore::open(&path).unwrap()).unwrap();
+        let doi = stream.date_of_inception();
+        stream
+            .add_post(Post::new(0, "Title".into(), "Message".into()).unwrap())
+            .unwrap();
+        let last_updated = stream.last_updated();
+        drop(stream);
+
+        let stream = Stream::open(SqliteStore::open(&path).unwrap()).unwrap();
+        assert_eq!(stream.date_of_inception(), doi);
+        assert_eq!(stream.last_updated(), last_updated);
+        assert_eq!(stream.nposts(), 1);
+
+        cleanup_db(path);
+    }
+
+    #[test]
+    fn test_ids_survive_purge_and_restart() {
+        let path = setup_mock_db();
+        let mut stream = Stream::open(SqliteStore::open(&path).unwrap()).unwrap();
+        for _ in 0..2 {
+            stream
+                .create_post("Title".into(), "Message".into())
+                .unwrap();
+        }
+        stream.remove_post(1).unwrap();
+        assert_eq!(stream.purge_deleted(u32::MAX.into()).unwrap(), 1);
+        drop(stream);
+
+        let mut stream = Stream::open(SqliteStore::open(&path).unwrap()).unwrap();
+        let post = stream
+            .create_post("Title".into(), "Message".into())
+            .unwrap();
+        assert_eq!(post.id().unwrap(), 2);
+        assert_eq!(ids(&stream.catchup(0, None).unwrap().posts), vec![0, 2]);
+
+        cleanup_db(path);
     }
 }
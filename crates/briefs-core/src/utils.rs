@@ -1,69 +1,57 @@
-use std::io::Write;
-
 use anyhow::ensure;
-use sqlite::Connection;
 
 use crate::{
     config::BriefsConfig,
-    constant::{DATA_DIR, DATA_FILE},
-    stream::Stream,
+    constant::{DATA_DIR, DATA_FILE, DEFAULT_STREAM},
+    db,
+    state::StreamInfo,
+    store::PostStore,
     BriefsError, BriefsResult,
 };
 
-pub fn save_stream_on_disk(stream: &Stream, config: &BriefsConfig) -> BriefsResult<()> {
-    let data_dir = config.dirpath.join(DATA_DIR);
-    if !std::fs::exists(&data_dir)? {
-        std::fs::create_dir_all(data_dir.clone())?;
-    }
-    let stream_file = data_dir.join(DATA_FILE);
-    let mut fileptr = std::fs::File::create(&stream_file)?;
-
-    let mut content = Vec::with_capacity(16);
-    content.extend(stream.last_updated().to_be_bytes());
-    content.extend(stream.date_of_inception().to_be_bytes());
-
-    // Write data
-    fileptr.write_all(content.as_slice())?;
-
-    Ok(())
-}
-
-pub fn read_stream_from_disk(
-    conn: &mut Connection,
+/// Imports the metadata of the default stream from the legacy `data/stream`
+/// file, which stored `last_updated` and `date_of_inception` as big-endian
+/// bytes.
+///
+/// The import only happens when the store does not contain a stream yet.
+/// Once imported, the file is renamed to `stream.imported` so that it is
+/// never read again. Returns whether the file was imported.
+///
+/// # Errors
+///
+/// This function will return an error if the legacy file is malformed.
+pub fn import_legacy_stream<S: PostStore>(
+    store: &mut S,
     config: &BriefsConfig,
-) -> BriefsResult<Stream> {
-    let data_dir = config.dirpath.join(DATA_DIR);
-    if !std::fs::exists(&data_dir)? {
-        return Err(BriefsError::utils_error("Data directory does not exist".into()).into());
-    }
-    let stream_file = data_dir.join(DATA_FILE);
-    if !std::fs::exists(&stream_file)? {
-        return Err(BriefsError::utils_error("Stream data does not exist".into()).into());
+) -> BriefsResult<bool> {
+    let stream_file = config.dirpath.join(DATA_DIR).join(DATA_FILE);
+    if !std::fs::exists(&stream_file)? || store.stream_info()?.is_some() {
+        return Ok(false);
     }
 
-    let cache = std::fs::read(stream_file)?;
+    let cache = std::fs::read(&stream_file)?;
 
     ensure!(
         cache.len() == 16,
         BriefsError::utils_error("Incorrect cache len".into())
     );
 
-    let mut u64_chunks = cache.chunks_exact(8);
     let mut u64_barray = [0u8; 8];
-    u64_barray.copy_from_slice(
-        u64_chunks
-            .next()
-            .ok_or(BriefsError::utils_error("Cannot parse doi chunk".into()))?,
-    );
+    u64_barray.copy_from_slice(&cache[..8]);
     let last_updated = u64::from_be_bytes(u64_barray);
-    u64_barray.copy_from_slice(
-        u64_chunks
-            .next()
-            .ok_or(BriefsError::utils_error("Cannot parse doi chunk".into()))?,
-    );
-    let doi = u64::from_be_bytes(u64_barray);
-
-    Ok(Stream::assemble(conn, last_updated, doi)?)
+    u64_barray.copy_from_slice(&cache[8..]);
+    let date_of_inception = u64::from_be_bytes(u64_barray);
+
+    store.create_stream(&StreamInfo {
+        id: db::DEFAULT_STREAM_ID,
+        name: DEFAULT_STREAM.into(),
+        archived: false,
+        last_updated,
+        date_of_inception,
+    })?;
+    std::fs::rename(&stream_file, stream_file.with_extension("imported"))?;
+
+    Ok(true)
 }
 
 #[cfg(test)]
@@ -71,7 +59,12 @@ pub mod tests {
     use std::path::PathBuf;
 
     use super::*;
-    use crate::{constant::CONFIG_FILE, db::test::{self, setup_mock_db}};
+    use crate::{
+        constant::CONFIG_FILE,
+        db::test::{self, setup_mock_db},
+        store::{MemoryStore, SqliteStore},
+        stream::Stream,
+    };
     use rand::{prelude::Distribution, thread_rng};
 
     const CONFIG_DIR: &str = "briefs";
@@ -79,7 +72,7 @@ pub mod tests {
     // Create default stream and config. Also, dirpath in briefsconfig
     // will certainly exist. The dirpath will be random, in order to allow
     // test cases to be run concurrently.
-    pub fn get_mocks() -> (Stream, BriefsConfig) {
+    pub fn get_mocks() -> (Stream<MemoryStore>, BriefsConfig) {
         let tmp_dir = std::env::temp_dir();
         let stream = Stream::default();
         let mut config = BriefsConfig::default();
@@ -114,76 +107,74 @@ pub mod tests {
         std::fs::remove_dir_all(dirpath).unwrap();
     }
 
-    /// Returns last_updated & date_of_inception, which are u64 in stream.
-    fn read_stream_byte_cache(file: PathBuf) -> (u64, u64) {
-        let cache = std::fs::read(file).unwrap();
-
-        assert!(
-            cache.len() == 16,
-            "{:?}",
-            BriefsError::utils_error("Incorrect cache len".into())
-        );
-
-        let mut u64_chunks = cache.chunks_exact(8);
-        let mut u64_barray = [0u8; 8];
-        u64_barray.copy_from_slice(
-            u64_chunks
-                .next()
-                .ok_or(BriefsError::utils_error("Cannot parse doi chunk".into()))
-                .unwrap(),
-        );
-        let last_updated = u64::from_be_bytes(u64_barray);
-        u64_barray.copy_from_slice(
-            u64_chunks
-                .next()
-                .ok_or(BriefsError::utils_error("Cannot parse doi chunk".into()))
-                .unwrap(),
-        );
-        let doi = u64::from_be_bytes(u64_barray);
-
-        (last_updated, doi)
+    /// Writes a data file in the legacy format.
+    fn write_legacy_stream(config: &BriefsConfig, last_updated: u64, doi: u64) -> PathBuf {
+        let data_dir = config.dirpath.join(DATA_DIR);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let stream_file = data_dir.join(DATA_FILE);
+
+        let mut content = Vec::with_capacity(16);
+        content.extend(last_updated.to_be_bytes());
+        content.extend(doi.to_be_bytes());
+        std::fs::write(&stream_file, content).unwrap();
+
+        stream_file
     }
 
     #[test]
-    fn test_save_stream_on_disk() {
-        let (stream, config) = get_mocks();
+    fn test_import_legacy_stream() {
+        let (_, config) = get_mocks();
+        let stream_file = write_legacy_stream(&config, 20, 10);
 
-        save_stream_on_disk(&stream, &config).unwrap();
+        let mock_db = setup_mock_db();
+        let mut store = SqliteStore::open(&mock_db).unwrap();
+        assert!(import_legacy_stream(&mut store, &config).unwrap());
+        assert!(!stream_file.exists());
+        assert!(stream_file.with_extension("imported").exists());
 
-        let stream_dir = config.dirpath.join(DATA_DIR);
-        let stream_file = stream_dir.join(DATA_FILE);
-        assert!(stream_dir.exists());
-        assert!(stream_file.exists());
+        let stream = Stream::open(store).unwrap();
+        assert_eq!(stream.last_updated(), 20);
+        assert_eq!(stream.date_of_inception(), 10);
 
-        let (last_updated, doi) = read_stream_byte_cache(stream_file.clone());
-        assert_eq!(stream.last_updated(), last_updated);
-        assert_eq!(stream.date_of_inception(), doi);
+        // Importing is a one-time operation
+        let mut store = SqliteStore::open(&mock_db).unwrap();
+        assert!(!import_legacy_stream(&mut store, &config).unwrap());
 
         cleanup(config.dirpath);
+        test::cleanup_db(mock_db);
     }
 
     #[test]
-    fn test_basic_read_stream_from_disk() {
-        let (stream, config) = get_mocks();
-
-        save_stream_on_disk(&stream, &config).unwrap();
-
-        let stream_dir = config.dirpath.join(DATA_DIR);
-        let stream_file = stream_dir.join(DATA_FILE);
-        assert!(stream_dir.exists());
+    fn test_import_does_not_override_existing_stream() {
+        let (_, config) = get_mocks();
+        let stream_file = write_legacy_stream(&config, 20, 10);
+        let info = StreamInfo {
+            id: db::DEFAULT_STREAM_ID,
+            name: DEFAULT_STREAM.into(),
+            archived: false,
+            last_updated: 2,
+            date_of_inception: 1,
+        };
+        let mut store = MemoryStore::new();
+        store.create_stream(&info).unwrap();
+
+        assert!(!import_legacy_stream(&mut store, &config).unwrap());
         assert!(stream_file.exists());
+        assert_eq!(store.stream_info().unwrap(), Some(info));
 
-        let mock_db = setup_mock_db();
-        let mut conn = sqlite::open(&mock_db).unwrap();
+        cleanup(config.dirpath);
+    }
 
-        let dskstream = read_stream_from_disk(&mut conn, &config).unwrap();
+    #[test]
+    fn test_import_malformed_legacy_stream() {
+        let (_, config) = get_mocks();
+        let stream_file = write_legacy_stream(&config, 20, 10);
+        std::fs::write(&stream_file, [0u8; 10]).unwrap();
 
-        assert_eq!(dskstream.last_updated(), stream.last_updated());
-        assert_eq!(dskstream.date_of_inception(), stream.date_of_inception());
-        assert_eq!(dskstream.size(), stream.size());
-        assert_eq!(dskstream.nposts(), stream.nposts());
+        let mut store = MemoryStore::new();
+        assert!(import_legacy_stream(&mut store, &config).is_err());
+        assert!(store.stream_info().unwrap().is_none());
 
         cleanup(config.dirpath);
-        test::cleanup_db(mock_db);
     }
 }
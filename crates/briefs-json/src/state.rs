@@ -10,7 +10,6 @@ pub enum Command {
     Read { id: isize },
     Update { id: usize },
     Delete { id: usize },
-    Subscribe {},
 }
 
 #[derive(Serialize, Deserialize, Debug)]
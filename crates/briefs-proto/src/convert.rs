@@ -0,0 +1,626 @@
+//! Conversions between the types of `briefs_core` and their binary
+//! counterparts. Decoding is lenient where proto3 is: an empty stream
+//! name targets the default stream, just like a JSON request without one.
+
+use briefs_core::{
+    auth::{Identity, Role},
+    constant::DEFAULT_STREAM,
+    feed::FeedFormat,
+    post,
+    revision::Revision,
+    state::{
+        self, CatchUpResponse, EventKind, Feed, PostHistory, SearchHit, SearchResults, StreamEvent,
+        StreamMetadata,
+    },
+    BriefsError, BriefsResult, Envelope, ErrorReply, Outcome, StreamResponse,
+};
+
+use crate::messages::{self as pb, command::Kind, response::Body};
+
+fn stream_or_default(stream: String) -> String {
+    if stream.is_empty() {
+        DEFAULT_STREAM.into()
+    } else {
+        stream
+    }
+}
+
+impl From<post::Post> for pb::Post {
+    fn from(post: post::Post) -> Self {
+        Self {
+            id: post.id().unwrap_or_default(),
+            title: post.title,
+            msg: post.msg,
+            date: post.date,
+            edited: post.edited,
+        }
+    }
+}
+
+impl From<pb::Post> for post::Post {
+    fn from(post: pb::Post) -> Self {
+        post::Post::from_parts(post.id, post.title, post.msg, post.date, post.edited)
+    }
+}
+
+impl From<CatchUpResponse> for pb::CatchUpResponse {
+    fn from(response: CatchUpResponse) -> Self {
+        Self {
+            posts: response.posts.into_iter().map(Into::into).collect(),
+            deleted: response.deleted,
+            next_cursor: response.next_cursor,
+            prev_cursor: response.prev_cursor,
+            edited: response.edited.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<pb::CatchUpResponse> for CatchUpResponse {
+    fn from(response: pb::CatchUpResponse) -> Self {
+        Self {
+            posts: response.posts.into_iter().map(Into::into).collect(),
+            next_cursor: response.next_cursor,
+            prev_cursor: response.prev_cursor,
+            deleted: response.deleted,
+            edited: response.edited.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<StreamMetadata> for pb::StreamMetadata {
+    fn from(metadata: StreamMetadata) -> Self {
+        Self {
+            name: metadata.name,
+            archived: metadata.archived,
+            latest_post_id: metadata.latest_post_id,
+            last_updated: metadata.last_updated,
+            posts_count: metadata.posts_count,
+            sqlite_version: metadata.sqlite_version,
+        }
+    }
+}
+
+impl From<pb::StreamMetadata> for StreamMetadata {
+    fn from(metadata: pb::StreamMetadata) -> Self {
+        Self {
+            name: metadata.name,
+            archived: metadata.archived,
+            latest_post_id: metadata.latest_post_id,
+            last_updated: metadata.last_updated,
+            posts_count: metadata.posts_count,
+            sqlite_version: metadata.sqlite_version,
+        }
+    }
+}
+
+impl From<state::StreamInfo> for pb::StreamInfo {
+    fn from(info: state::StreamInfo) -> Self {
+        Self {
+            id: info.id,
+            name: info.name,
+            archived: info.archived,
+            last_updated: info.last_updated,
+            date_of_inception: info.date_of_inception,
+        }
+    }
+}
+
+impl From<pb::StreamInfo> for state::StreamInfo {
+    fn from(info: pb::StreamInfo) -> Self {
+        Self {
+            id: info.id,
+            name: info.name,
+            archived: info.archived,
+            last_updated: info.last_updated,
+            date_of_inception: info.date_of_inception,
+        }
+    }
+}
+
+impl From<FeedFormat> for pb::FeedFormat {
+    fn from(format: FeedFormat) -> Self {
+        match format {
+            FeedFormat::Atom => pb::FeedFormat::Atom,
+            FeedFormat::Rss => pb::FeedFormat::Rss,
+            FeedFormat::Json => pb::FeedFormat::Json,
+        }
+    }
+}
+
+fn feed_format(format: i32) -> BriefsResult<FeedFormat> {
+    match pb::FeedFormat::try_from(format) {
+        Ok(pb::FeedFormat::Atom) => Ok(FeedFormat::Atom),
+        Ok(pb::FeedFormat::Rss) => Ok(FeedFormat::Rss),
+        Ok(pb::FeedFormat::Json) => Ok(FeedFormat::Json),
+        Err(_) => Err(BriefsError::InvalidMessage {
+            msg: format!("unknown feed format {format}"),
+        }
+        .into()),
+    }
+}
+
+impl From<Feed> for pb::Feed {
+    fn from(feed: Feed) -> Self {
+        Self {
+            format: pb::FeedFormat::from(feed.format).into(),
+            document: feed.document,
+        }
+    }
+}
+
+impl TryFrom<pb::Feed> for Feed {
+    type Error = anyhow::Error;
+
+    fn try_from(feed: pb::Feed) -> BriefsResult<Self> {
+        Ok(Self {
+            format: feed_format(feed.format)?,
+            document: feed.document,
+        })
+    }
+}
+
+impl From<Revision> for pb::Revision {
+    fn from(revision: Revision) -> Self {
+        Self {
+            revision: revision.revision,
+            author: revision.author,
+            date: revision.date,
+            old_title: revision.old_title,
+            old_msg: revision.old_msg,
+            new_title: revision.new_title,
+            new_msg: revision.new_msg,
+        }
+    }
+}
+
+impl From<pb::Revision> for Revision {
+    fn from(revision: pb::Revision) -> Self {
+        Self {
+            revision: revision.revision,
+            author: revision.author,
+            date: revision.date,
+            old_title: revision.old_title,
+            old_msg: revision.old_msg,
+            new_title: revision.new_title,
+            new_msg: revision.new_msg,
+        }
+    }
+}
+
+impl From<PostHistory> for pb::PostHistory {
+    fn from(history: PostHistory) -> Self {
+        Self {
+            id: history.id,
+            revisions: history.revisions.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<pb::PostHistory> for PostHistory {
+    fn from(history: pb::PostHistory) -> Self {
+        Self {
+            id: history.id,
+            revisions: history.revisions.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<SearchHit> for pb::SearchHit {
+    fn from(hit: SearchHit) -> Self {
+        Self {
+            post: Some(hit.post.into()),
+            score: hit.score,
+            title: hit.title,
+            snippet: hit.snippet,
+        }
+    }
+}
+
+impl TryFrom<pb::SearchHit> for SearchHit {
+    type Error = anyhow::Error;
+
+    fn try_from(hit: pb::SearchHit) -> BriefsResult<Self> {
+        let post = hit.post.ok_or_else(|| BriefsError::InvalidMessage {
+            msg: "search hit without a post".into(),
+        })?;
+        Ok(Self {
+            post: post.into(),
+            score: hit.score,
+            title: hit.title,
+            snippet: hit.snippet,
+        })
+    }
+}
+
+impl From<SearchResults> for pb::SearchResults {
+    fn from(results: SearchResults) -> Self {
+        Self {
+            hits: results.hits.into_iter().map(Into::into).collect(),
+            next_cursor: results.next_cursor,
+        }
+    }
+}
+
+impl TryFrom<pb::SearchResults> for SearchResults {
+    type Error = anyhow::Error;
+
+    fn try_from(results: pb::SearchResults) -> BriefsResult<Self> {
+        Ok(Self {
+            hits: results
+                .hits
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<BriefsResult<_>>()?,
+            next_cursor: results.next_cursor,
+        })
+    }
+}
+
+impl From<Identity> for pb::Identity {
+    fn from(identity: Identity) -> Self {
+        let role = match identity.role {
+            Role::Reader => pb::Role::Reader,
+            Role::Publisher => pb::Role::Publisher,
+            Role::Admin => pb::Role::Admin,
+        };
+        Self {
+            name: identity.name,
+            role: role.into(),
+        }
+    }
+}
+
+impl TryFrom<pb::Identity> for Identity {
+    type Error = anyhow::Error;
+
+    fn try_from(identity: pb::Identity) -> BriefsResult<Self> {
+        let role = match pb::Role::try_from(identity.role) {
+            Ok(pb::Role::Reader) => Role::Reader,
+            Ok(pb::Role::Publisher) => Role::Publisher,
+            Ok(pb::Role::Admin) => Role::Admin,
+            Err(_) => {
+                return Err(BriefsError::InvalidMessage {
+                    msg: format!("unknown role {}", identity.role),
+                }
+                .into())
+            }
+        };
+        Ok(Self {
+            name: identity.name,
+            role,
+        })
+    }
+}
+
+impl From<StreamEvent> for pb::StreamEvent {
+    fn from(event: StreamEvent) -> Self {
+        let kind = match event.kind {
+            EventKind::Created(post) => pb::stream_event::Kind::Created(post.into()),
+            EventKind::Updated(post) => pb::stream_event::Kind::Updated(post.into()),
+            EventKind::Deleted { id } => pb::stream_event::Kind::Deleted(id),
+            EventKind::Undeleted(post) => pb::stream_event::Kind::Undeleted(post.into()),
+        };
+        Self {
+            stream: event.stream,
+            kind: Some(kind),
+        }
+    }
+}
+
+impl TryFrom<pb::StreamEvent> for StreamEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(event: pb::StreamEvent) -> BriefsResult<Self> {
+        let kind = match event.kind {
+            Some(pb::stream_event::Kind::Created(post)) => EventKind::Created(post.into()),
+            Some(pb::stream_event::Kind::Updated(post)) => EventKind::Updated(post.into()),
+            Some(pb::stream_event::Kind::Deleted(id)) => EventKind::Deleted { id },
+            Some(pb::stream_event::Kind::Undeleted(post)) => EventKind::Undeleted(post.into()),
+            None => {
+                return Err(BriefsError::InvalidMessage {
+                    msg: "event without a kind".into(),
+                }
+                .into())
+            }
+        };
+        Ok(Self {
+            stream: event.stream,
+            kind,
+        })
+    }
+}
+
+impl From<briefs_core::Command> for pb::Command {
+    fn from(cmd: briefs_core::Command) -> Self {
+        use briefs_core::Command;
+
+        let kind = match cmd {
+            Command::Catchup {
+                stream,
+                last_fetch_id,
+                cursor,
+                since,
+                until,
+                limit,
+            } => Kind::Catchup(pb::CatchupCommand {
+                stream,
+                last_fetch_id,
+                cursor,
+                since,
+                until,
+                limit,
+            }),
+            Command::Create { stream, title, msg } => {
+                Kind::Create(pb::CreateCommand { stream, title, msg })
+            }
+            Command::UpdateMsg { stream, id, msg } => {
+                Kind::UpdateMsg(pb::UpdateMsgCommand { stream, id, msg })
+            }
+            Command::UpdateTitle { stream, id, title } => {
+                Kind::UpdateTitle(pb::UpdateTitleCommand { stream, id, title })
+            }
+            Command::Delete { stream, id } => Kind::Delete(pb::DeleteCommand { stream, id }),
+            Command::Get { stream, id } => Kind::Get(pb::GetCommand { stream, id }),
+            Command::Metadata { stream } => Kind::Metadata(pb::MetadataCommand { stream }),
+            Command::CreateStream { name } => Kind::CreateStream(pb::CreateStreamCommand { name }),
+            Command::ListStreams {} => Kind::ListStreams(pb::ListStreamsCommand {}),
+            Command::RenameStream { stream, new_name } => {
+                Kind::RenameStream(pb::RenameStreamCommand { stream, new_name })
+            }
+            Command::ArchiveStream { stream } => {
+                Kind::ArchiveStream(pb::ArchiveStreamCommand { stream })
+            }
+            Command::Subscribe {
+                stream,
+                last_seen_id,
+            } => Kind::Subscribe(pb::SubscribeCommand {
+                stream,
+                last_seen_id,
+            }),
+            Command::Feed {
+                stream,
+                format,
+                limit,
+            } => Kind::Feed(pb::FeedCommand {
+                stream,
+                format: pb::FeedFormat::from(format).into(),
+                limit,
+            }),
+            Command::Login { token } => Kind::Login(pb::LoginCommand { token }),
+            Command::History { stream, id } => Kind::History(pb::HistoryCommand { stream, id }),
+            Command::Restore {
+                stream,
+                id,
+                revision,
+            } => Kind::Restore(pb::RestoreCommand {
+                stream,
+                id,
+                revision,
+            }),
+            Command::Undelete { stream, id } => Kind::Undelete(pb::UndeleteCommand { stream, id }),
+            Command::Search {
+                stream,
+                query,
+                limit,
+                cursor,
+            } => Kind::Search(pb::SearchCommand {
+                stream,
+                query,
+                limit,
+                cursor,
+            }),
+            Command::Update {
+                stream,
+                id,
+                title,
+                msg,
+            } => Kind::Update(pb::UpdateCommand {
+                stream,
+                id,
+                title,
+                msg,
+            }),
+        };
+        Self { kind: Some(kind) }
+    }
+}
+
+impl TryFrom<pb::Command> for briefs_core::Command {
+    type Error = anyhow::Error;
+
+    fn try_from(cmd: pb::Command) -> BriefsResult<Self> {
+        use briefs_core::Command;
+
+        let kind = cmd.kind.ok_or_else(|| BriefsError::InvalidMessage {
+            msg: "command without a kind".into(),
+        })?;
+        Ok(match kind {
+            Kind::Catchup(cmd) => Command::Catchup {
+                stream: stream_or_default(cmd.stream),
+                last_fetch_id: cmd.last_fetch_id,
+                cursor: cmd.cursor,
+                since: cmd.since,
+                until: cmd.until,
+                limit: cmd.limit,
+            },
+            Kind::Create(cmd) => Command::Create {
+                stream: stream_or_default(cmd.stream),
+                title: cmd.title,
+                msg: cmd.msg,
+            },
+            Kind::UpdateMsg(cmd) => Command::UpdateMsg {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+                msg: cmd.msg,
+            },
+            Kind::UpdateTitle(cmd) => Command::UpdateTitle {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+                title: cmd.title,
+            },
+            Kind::Delete(cmd) => Command::Delete {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+            },
+            Kind::Get(cmd) => Command::Get {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+            },
+            Kind::Metadata(cmd) => Command::Metadata {
+                stream: stream_or_default(cmd.stream),
+            },
+            Kind::CreateStream(cmd) => Command::CreateStream { name: cmd.name },
+            Kind::ListStreams(_) => Command::ListStreams {},
+            Kind::RenameStream(cmd) => Command::RenameStream {
+                stream: cmd.stream,
+                new_name: cmd.new_name,
+            },
+            Kind::ArchiveStream(cmd) => Command::ArchiveStream { stream: cmd.stream },
+            Kind::Subscribe(cmd) => Command::Subscribe {
+                stream: stream_or_default(cmd.stream),
+                last_seen_id: cmd.last_seen_id,
+            },
+            Kind::Feed(cmd) => Command::Feed {
+                stream: stream_or_default(cmd.stream),
+                format: feed_format(cmd.format)?,
+                limit: cmd.limit,
+            },
+            Kind::Login(cmd) => Command::Login { token: cmd.token },
+            Kind::History(cmd) => Command::History {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+            },
+            Kind::Restore(cmd) => Command::Restore {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+                revision: cmd.revision,
+            },
+            Kind::Undelete(cmd) => Command::Undelete {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+            },
+            Kind::Search(cmd) => Command::Search {
+                stream: stream_or_default(cmd.stream),
+                query: cmd.query,
+                limit: cmd.limit,
+                cursor: cmd.cursor,
+            },
+            Kind::Update(cmd) => Command::Update {
+                stream: stream_or_default(cmd.stream),
+                id: cmd.id,
+                title: cmd.title,
+                msg: cmd.msg,
+            },
+        })
+    }
+}
+
+impl From<briefs_core::Response> for pb::Response {
+    fn from(response: briefs_core::Response) -> Self {
+        use briefs_core::Response;
+
+        let body = match response {
+            Response::Post(post) => Body::Post(post.into()),
+            Response::CatchUp(response) => Body::Catchup(response.into()),
+            Response::Metadata(metadata) => Body::Metadata(metadata.into()),
+            Response::Streams(infos) => Body::Streams(pb::StreamList {
+                streams: infos.into_iter().map(Into::into).collect(),
+            }),
+            Response::Event(event) => Body::Event(event.into()),
+            Response::Feed(feed) => Body::Feed(feed.into()),
+            Response::History(history) => Body::History(history.into()),
+            Response::Search(results) => Body::Search(results.into()),
+            Response::Identity(identity) => Body::Identity(identity.into()),
+            Response::Message(response) => Body::Message(pb::Ack {
+                msg: response.msg().to_owned(),
+            }),
+        };
+        Self { body: Some(body) }
+    }
+}
+
+impl TryFrom<pb::Response> for briefs_core::Response {
+    type Error = anyhow::Error;
+
+    fn try_from(response: pb::Response) -> BriefsResult<Self> {
+        use briefs_core::Response;
+
+        let body = response.body.ok_or_else(|| BriefsError::InvalidMessage {
+            msg: "response without a body".into(),
+        })?;
+        Ok(match body {
+            Body::Post(post) => Response::Post(post.into()),
+            Body::Catchup(response) => Response::CatchUp(response.into()),
+            Body::Metadata(metadata) => Response::Metadata(metadata.into()),
+            Body::Streams(list) => {
+                Response::Streams(list.streams.into_iter().map(Into::into).collect())
+            }
+            Body::Event(event) => Response::Event(event.try_into()?),
+            Body::Feed(feed) => Response::Feed(feed.try_into()?),
+            Body::History(history) => Response::History(history.into()),
+            Body::Search(results) => Response::Search(results.try_into()?),
+            Body::Identity(identity) => Response::Identity(identity.try_into()?),
+            Body::Message(ack) => Response::Message(StreamResponse::new(ack.msg)),
+        })
+    }
+}
+
+impl From<briefs_core::Request> for pb::Request {
+    fn from(request: briefs_core::Request) -> Self {
+        Self {
+            id: request.id,
+            command: Some(request.cmd.into()),
+        }
+    }
+}
+
+impl TryFrom<pb::Request> for briefs_core::Request {
+    type Error = anyhow::Error;
+
+    fn try_from(request: pb::Request) -> BriefsResult<Self> {
+        let cmd = request.command.ok_or_else(|| BriefsError::InvalidMessage {
+            msg: "request without a command".into(),
+        })?;
+        Ok(Self::new(request.id, cmd.try_into()?))
+    }
+}
+
+impl From<Envelope> for pb::Envelope {
+    fn from(envelope: Envelope) -> Self {
+        let outcome = match envelope.outcome {
+            Outcome::Data(response) => pb::envelope::Outcome::Data(response.into()),
+            Outcome::Error(error) => pb::envelope::Outcome::Error(pb::Error {
+                code: error.code.into(),
+                msg: error.msg,
+            }),
+        };
+        Self {
+            id: envelope.id,
+            outcome: Some(outcome),
+        }
+    }
+}
+
+impl TryFrom<pb::Envelope> for Envelope {
+    type Error = anyhow::Error;
+
+    fn try_from(envelope: pb::Envelope) -> BriefsResult<Self> {
+        let outcome = match envelope.outcome {
+            Some(pb::envelope::Outcome::Data(response)) => Outcome::Data(response.try_into()?),
+            Some(pb::envelope::Outcome::Error(error)) => Outcome::Error(ErrorReply {
+                code: u16::try_from(error.code)?,
+                msg: error.msg,
+            }),
+            None => {
+                return Err(BriefsError::InvalidMessage {
+                    msg: "envelope without an outcome".into(),
+                }
+                .into())
+            }
+        };
+        Ok(Self {
+            id: envelope.id,
+            outcome,
+        })
+    }
+}
@@ -0,0 +1,143 @@
+//! Negotiation of the encoding at connection start.
+//!
+//! A client that wants the binary encoding sends a `Hello` as its first
+//! frame, listing the encodings it understands in order of preference.
+//! The server answers with a `HelloAck` carrying the chosen encoding, and
+//! every following frame of the connection uses it. Clients that send a
+//! command straight away are served with JSON, so older clients keep
+//! working unchanged.
+//!
+//! ```text
+//! Hello:    "BRFS" | version: u8 | count: u8 | encoding: u8 * count
+//! HelloAck: "BRFS" | version: u8 | encoding: u8
+//! ```
+
+use briefs_core::{BriefsError, BriefsResult};
+
+use crate::{Encoding, PROTOCOL_VERSION};
+
+/// Starts every handshake frame. A JSON command always starts with `{`,
+/// so the two can never be confused.
+pub const MAGIC: &[u8; 4] = b"BRFS";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub encodings: Vec<Encoding>,
+}
+
+impl Hello {
+    pub fn new(encodings: Vec<Encoding>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            encodings,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(MAGIC.len() + 2 + self.encodings.len());
+        frame.extend_from_slice(MAGIC);
+        frame.push(self.version);
+        frame.push(self.encodings.len() as u8);
+        frame.extend(self.encodings.iter().map(|encoding| encoding.id()));
+        frame
+    }
+
+    /// Returns `None` if the frame is not a handshake, i.e. the client
+    /// skipped the negotiation. Unknown encodings are ignored.
+    pub fn decode(frame: &[u8]) -> BriefsResult<Option<Self>> {
+        let Some(rest) = frame.strip_prefix(MAGIC) else {
+            return Ok(None);
+        };
+        let [version, count, ids @ ..] = rest else {
+            return Err(invalid("truncated hello"));
+        };
+        if ids.len() != *count as usize {
+            return Err(invalid("hello with a wrong number of encodings"));
+        }
+        Ok(Some(Self {
+            version: *version,
+            encodings: ids.iter().filter_map(|id| Encoding::from_id(*id)).collect(),
+        }))
+    }
+
+    /// Picks the first encoding of the client that the server supports;
+    /// JSON if there is none, or if the client speaks another version.
+    pub fn negotiate(&self) -> HelloAck {
+        let encoding = if self.version == PROTOCOL_VERSION {
+            self.encodings.first().copied().unwrap_or(Encoding::Json)
+        } else {
+            Encoding::Json
+        };
+        HelloAck {
+            version: PROTOCOL_VERSION,
+            encoding,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HelloAck {
+    pub version: u8,
+    pub encoding: Encoding,
+}
+
+impl HelloAck {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(MAGIC.len() + 2);
+        frame.extend_from_slice(MAGIC);
+        frame.push(self.version);
+        frame.push(self.encoding.id());
+        frame
+    }
+
+    pub fn decode(frame: &[u8]) -> BriefsResult<Self> {
+        let Some([version, id]) = frame.strip_prefix(MAGIC) else {
+            return Err(invalid("malformed hello ack"));
+        };
+        let encoding = Encoding::from_id(*id).ok_or_else(|| invalid("unknown encoding"))?;
+        Ok(Self {
+            version: *version,
+            encoding,
+        })
+    }
+}
+
+fn invalid(msg: &str) -> anyhow::Error {
+    BriefsError::InvalidMessage { msg: msg.into() }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiation() {
+        let hello = Hello::new(vec![Encoding::Proto, Encoding::Json]);
+        let decoded = Hello::decode(&hello.encode()).unwrap().unwrap();
+        assert_eq!(decoded, hello);
+
+        let ack = decoded.negotiate();
+        assert_eq!(ack.encoding, Encoding::Proto);
+        assert_eq!(HelloAck::decode(&ack.encode()).unwrap(), ack);
+
+        // Unknown encodings are skipped
+        let decoded = Hello::decode(b"BRFS\x01\x02\x09\x00").unwrap().unwrap();
+        assert_eq!(decoded.negotiate().encoding, Encoding::Json);
+
+        // Another version falls back to JSON
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            encodings: vec![Encoding::Proto],
+        };
+        assert_eq!(hello.negotiate().encoding, Encoding::Json);
+    }
+
+    #[test]
+    fn test_not_a_hello() {
+        assert_eq!(Hello::decode(br#"{"ListStreams":{}}"#).unwrap(), None);
+        assert!(Hello::decode(b"BRFS").is_err());
+        assert!(Hello::decode(b"BRFS\x01\x02\x01").is_err());
+        assert!(HelloAck::decode(b"BRFS\x01").is_err());
+    }
+}
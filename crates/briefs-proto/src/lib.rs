@@ -0,0 +1,367 @@
+//! Compact, versioned binary encoding of the briefs wire protocol.
+//!
+//! The messages are protobuf (see `protos/def.proto`), which keeps the
+//! frames small for high-volume readers, while JSON stays available as a
+//! fallback. Which of the two a connection uses is decided by the
+//! handshake in [`handshake`].
+mod convert;
+pub mod handshake;
+pub mod messages;
+
+use std::{fmt::Display, str::FromStr};
+
+use briefs_core::{BriefsError, BriefsResult, Envelope, Request};
+use prost::Message;
+
+/// Version of the binary protocol; bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Encodings a connection can use for commands and responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Proto,
+}
+
+impl Encoding {
+    /// Identifier of the encoding in the handshake.
+    pub fn id(self) -> u8 {
+        match self {
+            Encoding::Json => 0,
+            Encoding::Proto => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Encoding::Json),
+            1 => Some(Encoding::Proto),
+            _ => None,
+        }
+    }
+
+    pub fn encode_request(self, request: Request) -> BriefsResult<Vec<u8>> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(&request)?,
+            Encoding::Proto => messages::Request::from(request).encode_to_vec(),
+        })
+    }
+
+    pub fn decode_request(self, payload: &[u8]) -> BriefsResult<Request> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+            Encoding::Proto => messages::Request::decode(payload)?.try_into(),
+        }
+    }
+
+    pub fn encode_envelope(self, envelope: Envelope) -> BriefsResult<Vec<u8>> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(&envelope)?,
+            Encoding::Proto => messages::Envelope::from(envelope).encode_to_vec(),
+        })
+    }
+
+    pub fn decode_envelope(self, payload: &[u8]) -> BriefsResult<Envelope> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+            Encoding::Proto => messages::Envelope::decode(payload)?.try_into(),
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "json"),
+            Encoding::Proto => write!(f, "proto"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = BriefsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "proto" => Ok(Encoding::Proto),
+            _ => Err(BriefsError::InvalidMessage {
+                msg: format!("unknown encoding '{s}'"),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use briefs_core::{
+        auth::{Identity, Role},
+        feed::FeedFormat,
+        post::Post,
+        revision::Revision,
+        state::{
+            CatchUpResponse, EventKind, Feed, PostHistory, SearchHit, SearchResults, StreamEvent,
+            StreamInfo, StreamMetadata,
+        },
+        Command, Response, StreamResponse,
+    };
+
+    fn commands() -> Vec<Command> {
+        vec![
+            Command::Catchup {
+                stream: "ops".into(),
+                last_fetch_id: 7,
+                cursor: None,
+                since: None,
+                until: None,
+                limit: None,
+            },
+            Command::Catchup {
+                stream: "ops".into(),
+                last_fetch_id: 0,
+                cursor: Some("636174636875702d6e6578743a38".into()),
+                since: Some(1700000000),
+                until: Some(1800000000),
+                limit: Some(5),
+            },
+            Command::Create {
+                stream: "default".into(),
+                title: "नमस्ते".into(),
+                msg: "Hello 😃".into(),
+            },
+            Command::UpdateMsg {
+                stream: "ops".into(),
+                id: 1,
+                msg: "new".into(),
+            },
+            Command::UpdateTitle {
+                stream: "ops".into(),
+                id: 2,
+                title: "new".into(),
+            },
+            Command::Delete {
+                stream: "ops".into(),
+                id: 0,
+            },
+            Command::Get {
+                stream: "ops".into(),
+                id: 3,
+            },
+            Command::Metadata {
+                stream: "ops".into(),
+            },
+            Command::CreateStream { name: "hr".into() },
+            Command::ListStreams {},
+            Command::RenameStream {
+                stream: "hr".into(),
+                new_name: "people".into(),
+            },
+            Command::ArchiveStream {
+                stream: "people".into(),
+            },
+            Command::Subscribe {
+                stream: "ops".into(),
+                last_seen_id: Some(0),
+            },
+            Command::Subscribe {
+                stream: "ops".into(),
+                last_seen_id: None,
+            },
+            Command::Feed {
+                stream: "ops".into(),
+                format: FeedFormat::Json,
+                limit: Some(5),
+            },
+            Command::Login {
+                token: "brf_0123".into(),
+            },
+            Command::History {
+                stream: "ops".into(),
+                id: 3,
+            },
+            Command::Restore {
+                stream: "ops".into(),
+                id: 3,
+                revision: 0,
+            },
+            Command::Undelete {
+                stream: "ops".into(),
+                id: 3,
+            },
+            Command::Search {
+                stream: "ops".into(),
+                query: "deploy*".into(),
+                limit: Some(5),
+                cursor: Some("7365617263683a35".into()),
+            },
+            Command::Search {
+                stream: "ops".into(),
+                query: "नमस्ते".into(),
+                limit: None,
+                cursor: None,
+            },
+            Command::Update {
+                stream: "ops".into(),
+                id: 3,
+                title: Some("Deployed".into()),
+                msg: None,
+            },
+        ]
+    }
+
+    fn responses() -> Vec<Response> {
+        let post = Post::from_parts(3, "Title".into(), "Message".into(), 1700000000, true);
+        vec![
+            Response::Post(post.clone()),
+            Response::CatchUp(CatchUpResponse {
+                posts: vec![post.clone(), post.clone()],
+                next_cursor: None,
+                prev_cursor: Some("636174636875702d707265763a33".into()),
+                deleted: vec![1, 4],
+                edited: vec![post.clone()],
+            }),
+            Response::Metadata(StreamMetadata {
+                name: "ops".into(),
+                archived: false,
+                latest_post_id: None,
+                last_updated: 10,
+                posts_count: 0,
+                sqlite_version: "3.46.0".into(),
+            }),
+            Response::Streams(vec![StreamInfo {
+                id: 1,
+                name: "default".into(),
+                archived: true,
+                last_updated: 2,
+                date_of_inception: 1,
+            }]),
+            Response::Event(StreamEvent {
+                stream: "ops".into(),
+                kind: EventKind::Updated(post.clone()),
+            }),
+            Response::Event(StreamEvent {
+                stream: "ops".into(),
+                kind: EventKind::Deleted { id: 3 },
+            }),
+            Response::Event(StreamEvent {
+                stream: "ops".into(),
+                kind: EventKind::Undeleted(post.clone()),
+            }),
+            Response::Feed(Feed {
+                format: FeedFormat::Rss,
+                document: "<rss version=\"2.0\"/>".into(),
+            }),
+            Response::History(PostHistory {
+                id: 3,
+                revisions: vec![Revision {
+                    revision: 1,
+                    author: "ci".into(),
+                    date: 1700000001,
+                    old_title: "Title".into(),
+                    old_msg: "Mesage".into(),
+                    new_title: "Title".into(),
+                    new_msg: "Message".into(),
+                }],
+            }),
+            Response::History(PostHistory {
+                id: 4,
+                revisions: vec![],
+            }),
+            Response::Search(SearchResults {
+                hits: vec![SearchHit {
+                    post,
+                    score: 1.25,
+                    title: "**Title**".into(),
+                    snippet: "Message".into(),
+                }],
+                next_cursor: Some("7365617263683a35".into()),
+            }),
+            Response::Search(SearchResults {
+                hits: vec![],
+                next_cursor: None,
+            }),
+            Response::Identity(Identity {
+                name: "ci".into(),
+                role: Role::Publisher,
+            }),
+            Response::Message(StreamResponse::new("Succesfully added a new post".into())),
+        ]
+    }
+
+    fn envelopes() -> Vec<Envelope> {
+        let mut envelopes: Vec<Envelope> = responses()
+            .into_iter()
+            .enumerate()
+            .map(|(id, response)| Envelope::new(id as u64, Ok(response)))
+            .collect();
+        envelopes.push(Envelope::new(
+            u64::MAX,
+            Err(BriefsError::StreamArchived { name: "ops".into() }.into()),
+        ));
+        envelopes
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        for encoding in [Encoding::Json, Encoding::Proto] {
+            for (id, (cmd, expected)) in commands().into_iter().zip(commands()).enumerate() {
+                let payload = encoding
+                    .encode_request(Request::new(id as u64, cmd))
+                    .unwrap();
+                let decoded = encoding.decode_request(&payload).unwrap();
+                assert_eq!(decoded, Request::new(id as u64, expected), "{encoding}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        for encoding in [Encoding::Json, Encoding::Proto] {
+            for (envelope, expected) in envelopes().into_iter().zip(envelopes()) {
+                let payload = encoding.encode_envelope(envelope).unwrap();
+                let decoded = encoding.decode_envelope(&payload).unwrap();
+                assert_eq!(decoded, expected, "{encoding}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_proto_is_compact() {
+        for (first, second) in envelopes().into_iter().zip(envelopes()) {
+            let json = Encoding::Json.encode_envelope(first).unwrap();
+            let proto = Encoding::Proto.encode_envelope(second).unwrap();
+            assert!(proto.len() < json.len());
+        }
+    }
+
+    #[test]
+    fn test_empty_stream_targets_default() {
+        let payload = messages::Request {
+            id: 1,
+            command: Some(messages::Command {
+                kind: Some(messages::command::Kind::Metadata(
+                    messages::MetadataCommand::default(),
+                )),
+            }),
+        }
+        .encode_to_vec();
+        assert_eq!(
+            Encoding::Proto.decode_request(&payload).unwrap(),
+            Request::new(
+                1,
+                Command::Metadata {
+                    stream: "default".into()
+                }
+            )
+        );
+
+        let err = Encoding::Proto
+            .decode_request(&messages::Request::default().encode_to_vec())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BriefsError>(),
+            Some(BriefsError::InvalidMessage { .. })
+        ));
+    }
+}
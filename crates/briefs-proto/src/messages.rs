@@ -0,0 +1,465 @@
+//! Message types of the binary encoding, as described in
+//! `protos/def.proto`. They are kept in sync with the schema by hand, so
+//! that building the crate does not require `protoc`.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Post {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+    #[prost(string, tag = "2")]
+    pub title: String,
+    #[prost(string, tag = "3")]
+    pub msg: String,
+    #[prost(uint64, tag = "4")]
+    pub date: u64,
+    #[prost(bool, tag = "5")]
+    pub edited: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CatchUpResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub posts: Vec<Post>,
+    #[prost(uint32, repeated, tag = "3")]
+    pub deleted: Vec<u32>,
+    #[prost(string, optional, tag = "4")]
+    pub next_cursor: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub prev_cursor: Option<String>,
+    #[prost(message, repeated, tag = "6")]
+    pub edited: Vec<Post>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamMetadata {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(bool, tag = "2")]
+    pub archived: bool,
+    #[prost(uint32, optional, tag = "3")]
+    pub latest_post_id: Option<u32>,
+    #[prost(uint64, tag = "4")]
+    pub last_updated: u64,
+    #[prost(uint32, tag = "5")]
+    pub posts_count: u32,
+    #[prost(string, tag = "6")]
+    pub sqlite_version: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamInfo {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(bool, tag = "3")]
+    pub archived: bool,
+    #[prost(uint64, tag = "4")]
+    pub last_updated: u64,
+    #[prost(uint64, tag = "5")]
+    pub date_of_inception: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamList {
+    #[prost(message, repeated, tag = "1")]
+    pub streams: Vec<StreamInfo>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ack {
+    #[prost(string, tag = "1")]
+    pub msg: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CatchupCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub last_fetch_id: u32,
+    #[prost(string, optional, tag = "3")]
+    pub cursor: Option<String>,
+    #[prost(uint64, optional, tag = "4")]
+    pub since: Option<u64>,
+    #[prost(uint64, optional, tag = "5")]
+    pub until: Option<u64>,
+    #[prost(uint32, optional, tag = "6")]
+    pub limit: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(string, tag = "2")]
+    pub title: String,
+    #[prost(string, tag = "3")]
+    pub msg: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateMsgCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+    #[prost(string, tag = "3")]
+    pub msg: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateTitleCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+    #[prost(string, tag = "3")]
+    pub title: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeleteCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MetadataCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CreateStreamCommand {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListStreamsCommand {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RenameStreamCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(string, tag = "2")]
+    pub new_name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ArchiveStreamCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, optional, tag = "2")]
+    pub last_seen_id: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum FeedFormat {
+    Atom = 0,
+    Rss = 1,
+    Json = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FeedCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(enumeration = "FeedFormat", tag = "2")]
+    pub format: i32,
+    #[prost(uint32, optional, tag = "3")]
+    pub limit: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LoginCommand {
+    #[prost(string, tag = "1")]
+    pub token: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HistoryCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RestoreCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+    #[prost(uint32, tag = "3")]
+    pub revision: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UndeleteCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SearchCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(string, tag = "2")]
+    pub query: String,
+    #[prost(uint32, optional, tag = "3")]
+    pub limit: Option<u32>,
+    #[prost(string, optional, tag = "4")]
+    pub cursor: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateCommand {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+    #[prost(string, optional, tag = "3")]
+    pub title: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub msg: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Command {
+    #[prost(
+        oneof = "command::Kind",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19"
+    )]
+    pub kind: Option<command::Kind>,
+}
+
+pub mod command {
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "1")]
+        Catchup(CatchupCommand),
+        #[prost(message, tag = "2")]
+        Create(CreateCommand),
+        #[prost(message, tag = "3")]
+        UpdateMsg(UpdateMsgCommand),
+        #[prost(message, tag = "4")]
+        UpdateTitle(UpdateTitleCommand),
+        #[prost(message, tag = "5")]
+        Delete(DeleteCommand),
+        #[prost(message, tag = "6")]
+        Get(GetCommand),
+        #[prost(message, tag = "7")]
+        Metadata(MetadataCommand),
+        #[prost(message, tag = "8")]
+        CreateStream(CreateStreamCommand),
+        #[prost(message, tag = "9")]
+        ListStreams(ListStreamsCommand),
+        #[prost(message, tag = "10")]
+        RenameStream(RenameStreamCommand),
+        #[prost(message, tag = "11")]
+        ArchiveStream(ArchiveStreamCommand),
+        #[prost(message, tag = "12")]
+        Subscribe(SubscribeCommand),
+        #[prost(message, tag = "13")]
+        Feed(FeedCommand),
+        #[prost(message, tag = "14")]
+        Login(LoginCommand),
+        #[prost(message, tag = "15")]
+        History(HistoryCommand),
+        #[prost(message, tag = "16")]
+        Restore(RestoreCommand),
+        #[prost(message, tag = "17")]
+        Undelete(UndeleteCommand),
+        #[prost(message, tag = "18")]
+        Search(SearchCommand),
+        #[prost(message, tag = "19")]
+        Update(UpdateCommand),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamEvent {
+    #[prost(string, tag = "1")]
+    pub stream: String,
+    #[prost(oneof = "stream_event::Kind", tags = "2, 3, 4, 5")]
+    pub kind: Option<stream_event::Kind>,
+}
+
+pub mod stream_event {
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "2")]
+        Created(Post),
+        #[prost(message, tag = "3")]
+        Updated(Post),
+        #[prost(uint32, tag = "4")]
+        Deleted(u32),
+        #[prost(message, tag = "5")]
+        Undeleted(Post),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Feed {
+    #[prost(enumeration = "FeedFormat", tag = "1")]
+    pub format: i32,
+    #[prost(string, tag = "2")]
+    pub document: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Revision {
+    #[prost(uint32, tag = "1")]
+    pub revision: u32,
+    #[prost(string, tag = "2")]
+    pub author: String,
+    #[prost(uint64, tag = "3")]
+    pub date: u64,
+    #[prost(string, tag = "4")]
+    pub old_title: String,
+    #[prost(string, tag = "5")]
+    pub old_msg: String,
+    #[prost(string, tag = "6")]
+    pub new_title: String,
+    #[prost(string, tag = "7")]
+    pub new_msg: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PostHistory {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+    #[prost(message, repeated, tag = "2")]
+    pub revisions: Vec<Revision>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SearchHit {
+    #[prost(message, optional, tag = "1")]
+    pub post: Option<Post>,
+    #[prost(double, tag = "2")]
+    pub score: f64,
+    #[prost(string, tag = "3")]
+    pub title: String,
+    #[prost(string, tag = "4")]
+    pub snippet: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SearchResults {
+    #[prost(message, repeated, tag = "1")]
+    pub hits: Vec<SearchHit>,
+    #[prost(string, optional, tag = "2")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Role {
+    Reader = 0,
+    Publisher = 1,
+    Admin = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Identity {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(enumeration = "Role", tag = "2")]
+    pub role: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Response {
+    #[prost(oneof = "response::Body", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10")]
+    pub body: Option<response::Body>,
+}
+
+pub mod response {
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Body {
+        #[prost(message, tag = "1")]
+        Message(Ack),
+        #[prost(message, tag = "2")]
+        Post(Post),
+        #[prost(message, tag = "3")]
+        Catchup(CatchUpResponse),
+        #[prost(message, tag = "4")]
+        Metadata(StreamMetadata),
+        #[prost(message, tag = "5")]
+        Streams(StreamList),
+        #[prost(message, tag = "6")]
+        Event(StreamEvent),
+        #[prost(message, tag = "7")]
+        Feed(Feed),
+        #[prost(message, tag = "8")]
+        Identity(Identity),
+        #[prost(message, tag = "9")]
+        History(PostHistory),
+        #[prost(message, tag = "10")]
+        Search(SearchResults),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Request {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(message, optional, tag = "2")]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Error {
+    #[prost(uint32, tag = "1")]
+    pub code: u32,
+    #[prost(string, tag = "2")]
+    pub msg: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Envelope {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(oneof = "envelope::Outcome", tags = "2, 3")]
+    pub outcome: Option<envelope::Outcome>,
+}
+
+pub mod envelope {
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Outcome {
+        #[prost(message, tag = "2")]
+        Data(Response),
+        #[prost(message, tag = "3")]
+        Error(Error),
+    }
+}
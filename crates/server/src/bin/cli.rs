@@ -1,19 +1,25 @@
-use briefs_core::StreamResponse;
-use briefs_core::{state::CatchUpResponse, BriefsResult, Command};
+use briefs_core::auth::{Role, TokenStore};
+use briefs_core::codec::{read_frame, write_frame};
+use briefs_core::constant::DEFAULT_STREAM;
+use briefs_core::feed::FeedFormat;
+use briefs_core::revision;
+use briefs_core::state::{EventKind, PostHistory};
+use briefs_core::{config, db, migrations};
+use briefs_core::{BriefsError, BriefsResult, Command, Request, Response};
+use briefs_proto::handshake::{Hello, HelloAck};
+use briefs_proto::Encoding;
 use clap::{ArgAction, Parser, Subcommand};
+use server::tls;
 use std::io::{Read, Write};
 use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::{net::IpAddr, path::PathBuf};
-use tokio::io::AsyncReadExt;
 use tokio::{io::AsyncWriteExt, net::TcpStream};
 use tokio_rustls::client::TlsStream;
 use tokio_rustls::rustls::pki_types::pem::PemObject;
-use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
 use tokio_rustls::{rustls, TlsConnector};
 
-const BUFFER_SIZE: usize = 10240;
-
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
 struct Cli {
@@ -28,10 +34,31 @@ struct Cli {
     #[arg(long)]
     cafile: Option<PathBuf>,
 
+    #[arg(long, value_name = "FILE", requires = "client_key")]
+    /// Client certificate presented to the server; see `client_ca` in the
+    /// server config
+    client_cert: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE", requires = "client_cert")]
+    /// Private key of the client certificate
+    client_key: Option<PathBuf>,
+
     #[arg(short, long, action = ArgAction::SetTrue)]
     /// Select if the output should be json
     json: bool,
 
+    #[arg(long, default_value = DEFAULT_STREAM)]
+    /// Name of the stream targeted by post commands
+    stream: String,
+
+    #[arg(long, default_value = "proto")]
+    /// Encoding requested from the server: proto or json
+    encoding: Encoding,
+
+    #[arg(long)]
+    /// API token to log in with; see `token create`
+    token: Option<String>,
+
     #[command(subcommand)]
     command: BriefsCommand,
 }
@@ -49,16 +76,37 @@ pub enum BriefsCommand {
     /// briefs with the latest posts
     Catchup {
         idx: Option<u32>,
+        #[arg(long, value_delimiter = ',')]
+        /// Catch up on these streams instead, over a single connection
+        streams: Vec<String>,
+        #[arg(long)]
+        /// Continue from the next or prev cursor printed by a previous catchup
+        cursor: Option<String>,
+        #[arg(long)]
+        /// Only posts dated at or after this unix timestamp
+        since: Option<u64>,
+        #[arg(long)]
+        /// Only posts dated before this unix timestamp
+        until: Option<u64>,
+        #[arg(long)]
+        /// Number of posts per page
+        limit: Option<u32>,
     },
 
     GetPost {
         id: u32,
     },
 
+    /// Move a post to the trash; it can be undeleted until it is purged
     DeletePost {
         id: u32,
     },
 
+    /// Bring back a post from the trash
+    UndeletePost {
+        id: u32,
+    },
+
     UpdateMsg {
         id: u32,
         msg: String,
@@ -69,11 +117,282 @@ pub enum BriefsCommand {
         title: String,
     },
 
+    /// List the edits of a post, along with what they changed
+    History {
+        id: u32,
+    },
+
+    /// Show what changed in a post between two revisions; revision 0 is
+    /// the post as it was created
+    Diff {
+        id: u32,
+        from: u32,
+        to: u32,
+    },
+
+    /// Restore a post as of the given revision; the restore is an edit too
+    Restore {
+        id: u32,
+        revision: u32,
+    },
+
     StreamMetadata {},
+
+    /// Print the events of the stream as they happen, until interrupted
+    Subscribe {
+        #[arg(long)]
+        /// Replay the posts created after this post ID first
+        since: Option<u32>,
+    },
+
+    /// Export the latest posts of the stream as an Atom, RSS or JSON feed
+    Export {
+        #[arg(long, default_value = "atom")]
+        /// Feed format: atom, rss or json
+        format: FeedFormat,
+        #[arg(long)]
+        /// Number of posts in the feed
+        limit: Option<u32>,
+        #[arg(short, long, value_name = "FILE")]
+        /// Write the feed to this file instead of stdout
+        output: Option<PathBuf>,
+    },
+
+    /// Search the title and message of the posts; every word must match,
+    /// and a word ending with '*' matches as a prefix
+    Search {
+        #[arg(required = true, num_args = 1..)]
+        query: Vec<String>,
+        #[arg(long)]
+        /// Number of hits per page
+        limit: Option<u32>,
+        #[arg(long)]
+        /// Continue from the next cursor printed by a previous search
+        cursor: Option<String>,
+    },
+
+    /// Create, list, rename or archive streams
+    Stream {
+        #[command(subcommand)]
+        action: StreamCommand,
+    },
+
+    /// Create, list or revoke the API tokens of the local sqlite Db
+    Token {
+        #[arg(long, value_name = "FILE")]
+        /// Path to sqlite Db; defaults to the Db in config
+        db: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: TokenCommand,
+    },
+
+    /// Generate a self-signed development certificate and set it as the
+    /// `cert` and `pkey` of the config
+    DevCert {
+        #[arg(long, value_name = "DIR")]
+        /// Directory of the certificate; defaults to the config directory
+        dir: Option<PathBuf>,
+    },
+
+    /// Inspect or apply schema migrations of the local sqlite Db
+    Migrate {
+        #[arg(long, value_name = "FILE")]
+        /// Path to sqlite Db; defaults to the Db in config
+        db: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: MigrateCommand,
+    },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum StreamCommand {
+    /// Create a new, empty stream
+    Create { name: String },
+    /// List all the streams, archived ones included
+    List,
+    /// Rename an existing stream
+    Rename { name: String, new_name: String },
+    /// Archive a stream; it can still be read but no longer changed
+    Archive { name: String },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum TokenCommand {
+    /// Create a new token and print it; it is not shown again
+    Create {
+        name: String,
+        #[arg(long, default_value = "reader")]
+        /// Role granted by the token: reader, publisher or admin
+        role: Role,
+    },
+    /// List the names and roles of all the tokens
+    List,
+    /// Revoke a token; it can no longer be used to log in
+    Revoke { name: String },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum MigrateCommand {
+    /// Show the current and latest schema versions
+    Status,
+    /// Apply all pending migrations
+    Up,
+}
+
+/// A connection to the server, along with the encoding negotiated for it.
+struct Session {
+    stream: TlsStream<TcpStream>,
+    encoding: Encoding,
+    next_id: u64,
+}
+
+impl Session {
+    /// Asks the server for the given encoding; the server may fall back
+    /// to JSON.
+    async fn start(mut stream: TlsStream<TcpStream>, encoding: Encoding) -> BriefsResult<Self> {
+        let hello = Hello::new(vec![encoding]);
+        write_frame(&mut stream, &hello.encode()).await?;
+        let ack = read_frame(&mut stream).await?.ok_or_else(|| {
+            BriefsError::custom_error("Server closed the connection during handshake".into())
+        })?;
+        let ack = HelloAck::decode(&ack)?;
+        if ack.encoding != encoding {
+            eprintln!("Server does not support {encoding}, using {}", ack.encoding);
+        }
+        Ok(Self {
+            stream,
+            encoding: ack.encoding,
+            next_id: 1,
+        })
+    }
+
+    /// Logs the connection in; the following commands run with the role
+    /// of the token.
+    async fn login(&mut self, token: String) -> BriefsResult<()> {
+        match self.send_command(Command::Login { token }).await? {
+            Response::Identity(identity) => {
+                eprintln!("✓ Logged in as '{}' ({})", identity.name, identity.role);
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends the command to the server and returns the response, or the
+    /// error sent back by the server. The connection stays open for
+    /// further commands.
+    async fn send_command(&mut self, cmd: Command) -> BriefsResult<Response> {
+        self.send_batch(vec![cmd])
+            .await?
+            .pop()
+            .expect("one result per command")
+    }
+
+    /// Sends all the commands without waiting for the replies in between,
+    /// then collects the replies, which the server may send in any order.
+    /// Returns one result per command, in the order of the commands.
+    async fn send_batch(
+        &mut self,
+        cmds: Vec<Command>,
+    ) -> BriefsResult<Vec<BriefsResult<Response>>> {
+        let first_id = self.next_id;
+        for cmd in cmds {
+            let request = Request::new(self.next_id, cmd);
+            self.next_id += 1;
+            write_frame(&mut self.stream, &self.encoding.encode_request(request)?).await?;
+        }
+
+        let mut results: Vec<Option<BriefsResult<Response>>> =
+            (first_id..self.next_id).map(|_| None).collect();
+        for _ in 0..results.len() {
+            let response = read_frame(&mut self.stream).await?.ok_or_else(|| {
+                BriefsError::custom_error("Server closed the connection without responding".into())
+            })?;
+            // Keep stdout clean for exported feeds
+            eprintln!("Read {} bytes", response.len());
+            let envelope = self.encoding.decode_envelope(&response)?;
+            let slot = envelope
+                .id
+                .checked_sub(first_id)
+                .and_then(|idx| results.get_mut(idx as usize))
+                .filter(|slot| slot.is_none())
+                .ok_or_else(|| {
+                    BriefsError::custom_error(format!(
+                        "Unexpected response to request {}",
+                        envelope.id
+                    ))
+                })?;
+            *slot = Some(envelope.into_result());
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+/// Prints the events pushed by the server for the subscription, until the
+/// connection is closed.
+async fn subscribe(
+    session: &mut Session,
+    stream_name: String,
+    since: Option<u32>,
+    json: bool,
+) -> BriefsResult<()> {
+    let id = session.next_id;
+    session.next_id += 1;
+    let request = Request::new(
+        id,
+        Command::Subscribe {
+            stream: stream_name,
+            last_seen_id: since,
+        },
+    );
+    let payload = session.encoding.encode_request(request)?;
+    write_frame(&mut session.stream, &payload).await?;
+
+    while let Some(payload) = read_frame(&mut session.stream).await? {
+        let envelope = session.encoding.decode_envelope(&payload)?;
+        if envelope.id != id {
+            continue;
+        }
+        match envelope.into_result()? {
+            Response::Message(response) => println!("{}", response),
+            Response::Event(event) if json => print_json(&Response::Event(event))?,
+            Response::Event(event) => match event.kind {
+                EventKind::Created(post) => println!("+ {}", post),
+                EventKind::Updated(post) => println!("~ {}", post),
+                EventKind::Deleted { id } => println!("- Post {id} deleted"),
+                EventKind::Undeleted(post) => println!("+ {}", post),
+            },
+            response => return Err(unexpected(response)),
+        }
+    }
+    Ok(())
+}
+
+/// Prints the response as sent by the server in the JSON encoding.
+fn print_json(response: &Response) -> BriefsResult<()> {
+    println!("{}", serde_json::to_string_pretty(response)?);
+    Ok(())
+}
+
+/// The server answered with another kind of response than requested.
+fn unexpected(response: Response) -> anyhow::Error {
+    BriefsError::custom_error(format!("Unexpected response: {response:?}")).into()
+}
+
+/// Sends a command answered with a plain `StreamResponse` and prints it.
+async fn send_and_print(session: &mut Session, request: Command) -> BriefsResult<()> {
+    match session.send_command(request).await? {
+        Response::Message(response) => println!("{}", response),
+        response => return Err(unexpected(response)),
+    }
+    Ok(())
 }
 
 async fn new_post(
-    mut stream: TlsStream<TcpStream>,
+    session: &mut Session,
+    stream_name: String,
     title: Option<String>,
     msg: Option<String>,
 ) -> BriefsResult<()> {
@@ -97,230 +416,367 @@ async fn new_post(
     });
 
     let request = Command::Create {
+        stream: stream_name,
         title: inner_title,
         msg: inner_msg,
     };
-    let _bytes = stream
-        .write_all(&serde_json::to_vec(&request).unwrap().as_slice())
-        .await
-        .unwrap();
-    // println!("Written {bytes} bytes");
-    //
-    // stream.flush().await.unwrap();
-    // println!("Flushed data");
-    stream.shutdown().await.unwrap();
-    println!("Completed shutdown");
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-    // stream.readable().await.unwrap();
-    match stream.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            let response = serde_json::from_slice::<StreamResponse>(&kb_buffer[..bytes]).unwrap();
-            println!("{}", response);
-        }
-        Err(e) => eprintln!("Error reading from stream: {:?}", e),
-    };
+    send_and_print(session, request).await
+}
 
-    Ok(())
+/// Page and date filters of a catchup.
+struct CatchupPage {
+    cursor: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<u32>,
 }
 
 async fn briefs(
-    mut stream: TlsStream<TcpStream>,
+    session: &mut Session,
+    stream_names: Vec<String>,
     starting_index: u32,
+    page: CatchupPage,
     json: bool,
 ) -> BriefsResult<()> {
-    let request = Command::Catchup {
-        last_fetch_id: starting_index,
-    };
-    stream
-        .write_all(&serde_json::to_vec(&request).unwrap().as_slice())
-        .await
-        .unwrap();
-    stream.shutdown().await.unwrap();
-    // println!("Written {bytes} bytes");
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-    // stream.readable().await.unwrap();
-    match stream.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            // let response = String::from_utf8(kb_buffer[..bytes].to_vec()).map_err(|_| {
-            //     BriefsError::CustomError {
-            //         msg: "Unable to decode UTF-8".into(),
-            //     }
-            // })?;
-            let response = serde_json::from_slice::<crate::CatchUpResponse>(&kb_buffer[..bytes]);
-            if response.is_err() {
-                println!("{:?}", serde_json::from_slice::<crate::StreamResponse>(&kb_buffer[..bytes]));
-            }
-            let response = response.unwrap();
-            if !json {
-                println!("caught_up: {}", response.caught_up);
-                for post in response.posts.into_iter() {
-                    println!("{}", post);
-                }
-            } else {
-                println!("{:#?}", response);
-            }
+    // Every stream is caught up over the same connection
+    let requests = stream_names
+        .iter()
+        .map(|stream_name| Command::Catchup {
+            stream: stream_name.clone(),
+            last_fetch_id: starting_index,
+            cursor: page.cursor.clone(),
+            since: page.since,
+            until: page.until,
+            limit: page.limit,
+        })
+        .collect();
+    let results = session.send_batch(requests).await?;
+    let many = stream_names.len() > 1;
+    for (stream_name, result) in stream_names.into_iter().zip(results) {
+        if many {
+            println!("== {stream_name} ==");
+        }
+        let response = match result? {
+            Response::CatchUp(response) => response,
+            response => return Err(unexpected(response)),
+        };
+        if json {
+            print_json(&Response::CatchUp(response))?;
+            continue;
+        }
+        if !response.deleted.is_empty() {
+            println!("deleted: {:?}", response.deleted);
+        }
+        if !response.edited.is_empty() {
+            let ids: Vec<u32> = response
+                .edited
+                .iter()
+                .filter_map(|post| post.id().ok())
+                .collect();
+            println!("edited: {ids:?}");
+        }
+        for post in response.posts.into_iter() {
+            println!("{}", post);
+        }
+        if let Some(cursor) = response.prev_cursor {
+            println!("prev cursor: {cursor}");
+        }
+        match response.next_cursor {
+            Some(cursor) => println!("next cursor: {cursor}"),
+            None => println!("caught up"),
         }
-        Err(e) => eprintln!("Error reading from stream: {:?}", e),
     }
     Ok(())
 }
 
-async fn get_post(mut stream: TlsStream<TcpStream>, id: u32) -> BriefsResult<()> {
-    let request = Command::Get { id };
-    stream
-        .write_all(&serde_json::to_vec(&request).unwrap().as_slice())
-        .await
-        .unwrap();
-    // println!("Written {bytes} bytes");
-    stream.shutdown().await.unwrap();
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-    // stream.readable().await.unwrap();
-    match stream.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            // let response = String::from_utf8(kb_buffer[..bytes].to_vec()).map_err(|_| {
-            //     BriefsError::CustomError {
-            //         msg: "Unable to decode UTF-8".into(),
-            //     }
-            // })?;
-            let response = serde_json::from_slice::<briefs_core::post::Post>(&kb_buffer[..bytes])?;
-            println!("{:#?}", response);
-        }
-        Err(e) => eprintln!("Error reading from stream: {:?}", e),
+async fn get_post(session: &mut Session, stream_name: String, id: u32) -> BriefsResult<()> {
+    let request = Command::Get {
+        stream: stream_name,
+        id,
+    };
+    let response = match session.send_command(request).await? {
+        Response::Post(post) => post,
+        response => return Err(unexpected(response)),
+    };
+    println!("{:#?}", response);
+    Ok(())
+}
+
+async fn post_history(
+    session: &mut Session,
+    stream_name: String,
+    id: u32,
+) -> BriefsResult<PostHistory> {
+    let request = Command::History {
+        stream: stream_name,
+        id,
+    };
+    match session.send_command(request).await? {
+        Response::History(history) => Ok(history),
+        response => Err(unexpected(response)),
+    }
+}
+
+async fn history(
+    session: &mut Session,
+    stream_name: String,
+    id: u32,
+    json: bool,
+) -> BriefsResult<()> {
+    let history = post_history(session, stream_name, id).await?;
+    if json {
+        return print_json(&Response::History(history));
+    }
+    if history.revisions.is_empty() {
+        println!("Post {id} was never edited");
+    }
+    for revision in history.revisions {
+        println!("{}\n", revision);
     }
     Ok(())
 }
 
-async fn remove_post(mut stream: TlsStream<TcpStream>, id: u32) -> BriefsResult<()> {
-    let request = Command::Delete { id };
-    stream
-        .write(&serde_json::to_vec(&request).unwrap().as_slice())
-        .await
-        .unwrap();
-    stream.shutdown().await.unwrap();
-    // println!("Written {bytes} bytes");
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-    // stream.readable().await.unwrap();
-    match stream.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            // let response = String::from_utf8(kb_buffer[..bytes].to_vec()).map_err(|_| {
-            //     BriefsError::CustomError {
-            //         msg: "Unable to decode UTF-8".into(),
-            //     }
-            // })?;
-            let response = serde_json::from_slice::<StreamResponse>(&kb_buffer[..bytes]).unwrap();
-            println!("{:?}", response);
-        }
-        Err(e) => eprintln!("Error reading from stream: {:?}", e),
+async fn diff(
+    session: &mut Session,
+    stream_name: String,
+    id: u32,
+    from: u32,
+    to: u32,
+) -> BriefsResult<()> {
+    let history = post_history(session, stream_name, id).await?;
+    println!("{}", revision::diff(id, &history.revisions, from, to)?);
+    Ok(())
+}
+
+async fn restore(
+    session: &mut Session,
+    stream_name: String,
+    id: u32,
+    revision: u32,
+) -> BriefsResult<()> {
+    let request = Command::Restore {
+        stream: stream_name,
+        id,
+        revision,
+    };
+    match session.send_command(request).await? {
+        Response::Post(post) => println!("Restored revision {revision}\n{post}"),
+        response => return Err(unexpected(response)),
     }
     Ok(())
 }
 
-async fn update_msg(mut stream: TlsStream<TcpStream>, id: u32, msg: String) -> BriefsResult<()> {
-    let request = Command::UpdateMsg { id, msg };
-    stream
-        .write(&serde_json::to_vec(&request).unwrap().as_slice())
-        .await
-        .unwrap();
-    stream.shutdown().await.unwrap();
-    // println!("Written {bytes} bytes");
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-    // stream.readable().await.unwrap();
-    match stream.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            // let response = String::from_utf8(kb_buffer[..bytes].to_vec()).map_err(|_| {
-            //     BriefsError::CustomError {
-            //         msg: "Unable to decode UTF-8".into(),
-            //     }
-            // })?;
-            let response = serde_json::from_slice::<StreamResponse>(&kb_buffer[..bytes]).unwrap();
-            println!("{}", response);
-        }
-        Err(e) => eprintln!("Error reading from stream: {:?}", e),
+async fn undelete_post(session: &mut Session, stream_name: String, id: u32) -> BriefsResult<()> {
+    let request = Command::Undelete {
+        stream: stream_name,
+        id,
+    };
+    match session.send_command(request).await? {
+        Response::Post(post) => println!("Undeleted post\n{post}"),
+        response => return Err(unexpected(response)),
     }
     Ok(())
 }
 
-async fn update_title(
-    mut stream: TlsStream<TcpStream>,
-    id: u32,
-    title: String,
+async fn search(
+    session: &mut Session,
+    stream_name: String,
+    query: String,
+    limit: Option<u32>,
+    cursor: Option<String>,
+    json: bool,
+) -> BriefsResult<()> {
+    let request = Command::Search {
+        stream: stream_name,
+        query,
+        limit,
+        cursor,
+    };
+    let results = match session.send_command(request).await? {
+        Response::Search(results) => results,
+        response => return Err(unexpected(response)),
+    };
+    if json {
+        return print_json(&Response::Search(results));
+    }
+    if results.hits.is_empty() {
+        println!("No posts found");
+    }
+    for hit in results.hits {
+        let id = hit.post.id()?;
+        println!("#{id} {}\n{}\n", hit.title, hit.snippet);
+    }
+    if let Some(cursor) = results.next_cursor {
+        println!("next cursor: {cursor}");
+    }
+    Ok(())
+}
+
+async fn stream_metadata(session: &mut Session, stream_name: String) -> BriefsResult<()> {
+    let request = Command::Metadata {
+        stream: stream_name,
+    };
+    let response = match session.send_command(request).await? {
+        Response::Metadata(metadata) => metadata,
+        response => return Err(unexpected(response)),
+    };
+    println!("{:#?}", response);
+    Ok(())
+}
+
+async fn list_streams(session: &mut Session, json: bool) -> BriefsResult<()> {
+    let response = match session.send_command(Command::ListStreams {}).await? {
+        Response::Streams(infos) => infos,
+        response => return Err(unexpected(response)),
+    };
+    if json {
+        return print_json(&Response::Streams(response));
+    }
+    for info in response {
+        let archived = if info.archived { " (archived)" } else { "" };
+        println!("{}{}", info.name, archived);
+    }
+    Ok(())
+}
+
+async fn export(
+    session: &mut Session,
+    stream_name: String,
+    format: FeedFormat,
+    limit: Option<u32>,
+    output: Option<PathBuf>,
+) -> BriefsResult<()> {
+    let request = Command::Feed {
+        stream: stream_name,
+        format,
+        limit,
+    };
+    let feed = match session.send_command(request).await? {
+        Response::Feed(feed) => feed,
+        response => return Err(unexpected(response)),
+    };
+    match output {
+        Some(path) => {
+            std::fs::write(&path, feed.document)?;
+            eprintln!("✓ Exported {} feed to '{}'", feed.format, path.display());
+        }
+        None => print!("{}", feed.document),
+    }
+    Ok(())
+}
+
+/// Load config from the given file, or from the env/fallback config dir.
+fn load_config(config_file: Option<PathBuf>) -> BriefsResult<config::BriefsConfig> {
+    let filepath = match config_file {
+        Some(filepath) => filepath,
+        None => config::fetch_config_from_env().or_else(|_| config::fallback_config_dir())?,
+    };
+    config::BriefsConfig::from_file(filepath)
+}
+
+/// Resolves the local Db from the given path or the config, which has to
+/// exist already.
+fn local_db(config_file: Option<PathBuf>, db_path: Option<PathBuf>) -> BriefsResult<PathBuf> {
+    let db_path = match db_path {
+        Some(path) => path,
+        None => load_config(config_file)?.db,
+    };
+    let db_path = db::resolve_db_path(Some(db_path))?;
+    if !db_path.is_file() {
+        return Err(
+            BriefsError::custom_error(format!("Db not found at '{}'", db_path.display())).into(),
+        );
+    }
+    Ok(db_path)
+}
+
+fn dev_cert(config_file: Option<PathBuf>, dir: Option<PathBuf>) -> BriefsResult<()> {
+    let mut config = load_config(config_file)?;
+    let dir = dir.unwrap_or_else(|| config.dirpath.clone());
+    let (cert, pkey) = tls::generate_dev_cert(&dir)?;
+    config.cert = cert.canonicalize()?;
+    config.pkey = pkey.canonicalize()?;
+    config.save()?;
+    println!(
+        "✓ Generated development certificate '{}'",
+        config.cert.display()
+    );
+    println!(
+        "✓ Updated '{}'; connect with `--cafile {}`",
+        config.filepath.display(),
+        config.cert.display()
+    );
+    Ok(())
+}
+
+fn manage_tokens(
+    config_file: Option<PathBuf>,
+    db_path: Option<PathBuf>,
+    action: TokenCommand,
 ) -> BriefsResult<()> {
-    let request = Command::UpdateTitle { id, title };
-    stream
-        .write(&serde_json::to_vec(&request).unwrap().as_slice())
-        .await
-        .unwrap();
-    stream.shutdown().await.unwrap();
-    // println!("Written {bytes} bytes");
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-    // stream.readable().await.unwrap();
-    match stream.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            // let response = String::from_utf8(kb_buffer[..bytes].to_vec()).map_err(|_| {
-            //     BriefsError::CustomError {
-            //         msg: "Unable to decode UTF-8".into(),
-            //     }
-            // })?;
-            let response = serde_json::from_slice::<StreamResponse>(&kb_buffer[..bytes]).unwrap();
-            println!("{}", response);
-        }
-        Err(e) => eprintln!("Error reading from stream: {:?}", e),
+    let db_path = local_db(config_file, db_path)?;
+    let mut conn = sqlite::open(&db_path)?;
+    // Tokens need the latest schema
+    migrations::migrate_up(&mut conn)?;
+    let mut tokens = TokenStore::new(conn);
+
+    match action {
+        TokenCommand::Create { name, role } => {
+            let token = tokens.create(&name, role)?;
+            eprintln!("✓ Created {role} token '{name}'; it is not shown again");
+            println!("{token}");
+        }
+        TokenCommand::List => {
+            for info in tokens.list()? {
+                println!("{} ({})", info.name, info.role);
+            }
+        }
+        TokenCommand::Revoke { name } => {
+            tokens.revoke(&name)?;
+            println!("✓ Revoked token '{name}'");
+        }
     }
     Ok(())
 }
 
-async fn stream_metadata(mut stream: TlsStream<TcpStream>) -> BriefsResult<()> {
-    let request = Command::Metadata {};
-    stream
-        .write(&serde_json::to_vec(&request).unwrap().as_slice())
-        .await
-        .unwrap();
-    stream.shutdown().await.unwrap();
-    // println!("Written {bytes} bytes");
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-    // stream.readable().await.unwrap();
-    match stream.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            // let response = String::from_utf8(kb_buffer[..bytes].to_vec()).map_err(|_| {
-            //     BriefsError::CustomError {
-            //         msg: "Unable to decode UTF-8".into(),
-            //     }
-            // })?;
-            let response =
-                serde_json::from_slice::<briefs_core::state::StreamMetadata>(&kb_buffer[..bytes])?;
-            println!("{:#?}", response);
-        }
-        Err(e) => eprintln!("Error reading from stream: {:?}", e),
+fn migrate(
+    config_file: Option<PathBuf>,
+    db_path: Option<PathBuf>,
+    action: MigrateCommand,
+) -> BriefsResult<()> {
+    let db_path = local_db(config_file, db_path)?;
+    let mut conn = sqlite::open(&db_path)?;
+
+    match action {
+        MigrateCommand::Status => {
+            let status = migrations::status(&conn)?;
+            println!("Db: {}", db_path.display());
+            println!(
+                "Schema version: {} (latest: {})",
+                status.current, status.latest
+            );
+            if status.current > status.latest {
+                println!("✗ Db was migrated by a newer binary");
+            } else if status.pending.is_empty() {
+                println!("✓ Up to date");
+            }
+            for (version, description) in status.pending {
+                println!("  pending {version}: {description}");
+            }
+        }
+        MigrateCommand::Up => {
+            let version = migrations::migrate_up(&mut conn)?;
+            println!("✓ Db migrated to version {version}");
+        }
     }
     Ok(())
 }
 
 fn validate_socket(cli: &Cli) -> Result<SocketAddr, ()> {
     if let Some(socket_addr) = cli.socket_addr {
-        return Ok(socket_addr);
+        Ok(socket_addr)
     } else {
-        return Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080));
+        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080))
     }
     //if let Some(config_file) = cli.config.to_owned() {
     //let config_file_exists = config_file.exists();
@@ -341,6 +797,27 @@ fn validate_socket(cli: &Cli) -> Result<SocketAddr, ()> {
 async fn main() {
     let cli = Cli::parse();
 
+    // Migrations, tokens and certificates are local; no connection to the
+    // server needed.
+    let local = match &cli.command {
+        BriefsCommand::Migrate { db, action } => {
+            Some(migrate(cli.config.clone(), db.clone(), action.clone()))
+        }
+        BriefsCommand::DevCert { dir } => Some(dev_cert(cli.config.clone(), dir.clone())),
+        BriefsCommand::Token { db, action } => Some(manage_tokens(
+            cli.config.clone(),
+            db.clone(),
+            action.clone(),
+        )),
+        _ => None,
+    };
+    if let Some(result) = local {
+        if let Err(e) = result {
+            eprintln!("ERROR: {}", e);
+        }
+        return;
+    }
+
     let socket = validate_socket(&cli).unwrap();
     // !-------
     // Shouldn't be hardcoded
@@ -356,9 +833,18 @@ async fn main() {
         root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     }
 
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
+    let builder = rustls::ClientConfig::builder().with_root_certificates(root_cert_store);
+    let config = match (&cli.client_cert, &cli.client_key) {
+        (Some(cert), Some(key)) => {
+            let certs = CertificateDer::pem_file_iter(cert)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let key = PrivateKeyDer::from_pem_file(key).unwrap();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        }
+        _ => builder.with_no_client_auth(),
+    };
     // !-------
     // Probably don't need Arc here
     // -------!
@@ -366,44 +852,118 @@ async fn main() {
     let stream = TcpStream::connect(socket).await.unwrap();
 
     let stream = connector.connect(domain, stream).await.unwrap();
+    let mut session = match Session::start(stream, cli.encoding).await {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return;
+        }
+    };
+    if let Some(token) = cli.token {
+        if let Err(e) = session.login(token).await {
+            eprintln!("ERROR: {}", e);
+            return;
+        }
+    }
 
-    match cli.command {
-        BriefsCommand::NewPost { title, msg } => new_post(stream, title, msg).await.unwrap(),
-        BriefsCommand::Catchup { idx } => {
-            let result = briefs(stream, idx.unwrap_or_default(), cli.json).await;
-            if result.is_err() {
-                eprintln!("ERROR: {}", result.unwrap_err());
-            }
+    let stream_name = cli.stream;
+    let result = match cli.command {
+        BriefsCommand::NewPost { title, msg } => {
+            new_post(&mut session, stream_name, title, msg).await
         }
-        BriefsCommand::GetPost { id } => {
-            let result = get_post(stream, id).await;
-            if result.is_err() {
-                eprintln!("ERROR: {}", result.unwrap_err());
-            }
+        BriefsCommand::Catchup {
+            idx,
+            streams,
+            cursor,
+            since,
+            until,
+            limit,
+        } => {
+            let streams = if streams.is_empty() {
+                vec![stream_name]
+            } else {
+                streams
+            };
+            let page = CatchupPage {
+                cursor,
+                since,
+                until,
+                limit,
+            };
+            let idx = idx.unwrap_or_default();
+            briefs(&mut session, streams, idx, page, cli.json).await
         }
+        BriefsCommand::GetPost { id } => get_post(&mut session, stream_name, id).await,
         BriefsCommand::DeletePost { id } => {
-            let result = remove_post(stream, id).await;
-            if result.is_err() {
-                eprintln!("ERROR: {}", result.unwrap_err());
-            }
+            let request = Command::Delete {
+                stream: stream_name,
+                id,
+            };
+            send_and_print(&mut session, request).await
         }
+        BriefsCommand::UndeletePost { id } => undelete_post(&mut session, stream_name, id).await,
         BriefsCommand::UpdateMsg { id, msg } => {
-            let result = update_msg(stream, id, msg).await;
-            if result.is_err() {
-                eprintln!("ERROR: {}", result.unwrap_err());
-            }
+            let request = Command::UpdateMsg {
+                stream: stream_name,
+                id,
+                msg,
+            };
+            send_and_print(&mut session, request).await
         }
         BriefsCommand::UpdateTitle { id, title } => {
-            let result = update_title(stream, id, title).await;
-            if result.is_err() {
-                eprintln!("ERROR: {}", result.unwrap_err());
-            }
+            let request = Command::UpdateTitle {
+                stream: stream_name,
+                id,
+                title,
+            };
+            send_and_print(&mut session, request).await
+        }
+        BriefsCommand::History { id } => history(&mut session, stream_name, id, cli.json).await,
+        BriefsCommand::Diff { id, from, to } => diff(&mut session, stream_name, id, from, to).await,
+        BriefsCommand::Restore { id, revision } => {
+            restore(&mut session, stream_name, id, revision).await
         }
-        BriefsCommand::StreamMetadata {} => {
-            let result = stream_metadata(stream).await;
-            if result.is_err() {
-                eprintln!("ERROR: {}", result.unwrap_err());
+        BriefsCommand::Subscribe { since } => {
+            subscribe(&mut session, stream_name, since, cli.json).await
+        }
+        BriefsCommand::StreamMetadata {} => stream_metadata(&mut session, stream_name).await,
+        BriefsCommand::Export {
+            format,
+            limit,
+            output,
+        } => export(&mut session, stream_name, format, limit, output).await,
+        BriefsCommand::Search {
+            query,
+            limit,
+            cursor,
+        } => {
+            let query = query.join(" ");
+            search(&mut session, stream_name, query, limit, cursor, cli.json).await
+        }
+        BriefsCommand::Stream { action } => match action {
+            StreamCommand::Create { name } => {
+                send_and_print(&mut session, Command::CreateStream { name }).await
+            }
+            StreamCommand::List => list_streams(&mut session, cli.json).await,
+            StreamCommand::Rename { name, new_name } => {
+                let request = Command::RenameStream {
+                    stream: name,
+                    new_name,
+                };
+                send_and_print(&mut session, request).await
             }
+            StreamCommand::Archive { name } => {
+                send_and_print(&mut session, Command::ArchiveStream { stream: name }).await
+            }
+        },
+        BriefsCommand::Migrate { .. }
+        | BriefsCommand::Token { .. }
+        | BriefsCommand::DevCert { .. } => {
+            unreachable!("handled before connecting")
         }
+    };
+    if let Err(e) = result {
+        eprintln!("ERROR: {}", e);
     }
+    let _ = session.stream.shutdown().await;
 }
@@ -0,0 +1,952 @@
+//! HTTP/JSON gateway to the stream handler, for clients that cannot speak
+//! the framed TLS protocol. Every route maps to a `Command`, which is sent
+//! to the stream handler over the same channel as the commands of the TLS
+//! connections. The gateway is served over HTTPS only, with the same
+//! certificate as the TLS connections, see `TlsListener`.
+//!
+//! `GET /ws` upgrades to a WebSocket carrying the requests, replies and
+//! subscriptions of the TLS connections, see `crate::ws`. `GET /events`
+//! streams the posts of a stream as server-sent events, see `crate::sse`.
+//!
+//! Requests run with the identity of the API token sent in the
+//! `Authorization: Bearer <token>` header; see `briefs_core::auth`. Only
+//! `GET /events` also takes the token in the `access_token` query
+//! parameter, since `EventSource` cannot set headers. Query parameters end
+//! up in access logs and browser history, so other routes reject it.
+//!
+//! Post routes target the default stream, unless a `stream` query
+//! parameter is given. Successful responses are the JSON of the
+//! `Response`, tagged with its `type`, except for feeds, which are served
+//! as documents of their own media type; errors are an `ErrorReply` along
+//! with a matching status.
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequestParts, Path, Query, State,
+    },
+    http::{header, request::Parts, StatusCode},
+    response::IntoResponse,
+    routing::{get, patch, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use std::{io, net::SocketAddr};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, watch},
+    task::JoinSet,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use briefs_core::{
+    auth::Identity, constant::DEFAULT_STREAM, feed::FeedFormat, state::StreamEvent, BriefsError,
+    Command, ErrorReply, Response, StreamCommand,
+};
+
+use crate::{send_command, shutdown_requested, sse, ws};
+
+/// Path of the server-sent events, the one route taking `access_token`.
+const EVENTS_PATH: &str = "/events";
+
+/// Returns the routes of the gateway. WebSockets and event streams end once
+/// the server shuts down, see `shutdown_requested`.
+pub fn router(
+    tx: mpsc::Sender<StreamCommand>,
+    events: broadcast::Sender<StreamEvent>,
+    shutdown: watch::Receiver<bool>,
+) -> Router {
+    Router::new()
+        .route("/posts", get(catchup).post(create_post))
+        .route(
+            "/posts/{id}",
+            get(get_post).patch(update_post).delete(delete_post),
+        )
+        .route("/posts/{id}/history", get(history))
+        .route("/posts/{id}/restore", post(restore))
+        .route("/posts/{id}/undelete", post(undelete))
+        .route("/metadata", get(metadata))
+        .route("/feed/{format}", get(feed))
+        .route("/search", get(search))
+        .route("/streams", get(list_streams).post(create_stream))
+        .route("/streams/{name}", patch(update_stream))
+        .route("/ws", get(ws::upgrade))
+        .route(EVENTS_PATH, get(sse::events))
+        .with_state(Gateway {
+            tx,
+            events,
+            shutdown,
+        })
+}
+
+/// Serves the gateway over TLS on the listener until the server stops.
+/// Once the server shuts down no new connections are accepted, in-flight
+/// requests are finished, and WebSockets and event streams are closed.
+pub async fn serve_http(
+    listener: TlsListener,
+    tx: mpsc::Sender<StreamCommand>,
+    events: broadcast::Sender<StreamEvent>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let mut stopped = shutdown.clone();
+    let stopped = async move { shutdown_requested(&mut stopped).await };
+    if let Err(e) = axum::serve(listener, router(tx, events, shutdown))
+        .with_graceful_shutdown(stopped)
+        .await
+    {
+        eprintln!("✗ HTTP gateway stopped: {e}");
+    }
+}
+
+/// Accepts the connections of the gateway with the latest acceptor, see
+/// `crate::tls::watch_tls`, so API tokens never travel in cleartext.
+/// Handshakes run on their own tasks; a slow client does not hold up the
+/// others, and failed handshakes are only logged.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: watch::Receiver<TlsAcceptor>,
+    handshakes: JoinSet<(io::Result<TlsStream<TcpStream>>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: watch::Receiver<TlsAcceptor>) -> Self {
+        Self {
+            listener,
+            acceptor,
+            handshakes: JoinSet::new(),
+        }
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                conn = self.listener.accept() => match conn {
+                    Ok((conn, addr)) => {
+                        let acceptor = self.acceptor.borrow().clone();
+                        self.handshakes
+                            .spawn(async move { (acceptor.accept(conn).await, addr) });
+                    }
+                    Err(e) => eprintln!("✗ Unable to accept HTTP connection: {e}"),
+                },
+                Some(Ok((handshake, addr))) = self.handshakes.join_next() => match handshake {
+                    Ok(stream) => return (stream, addr),
+                    Err(e) => eprintln!("✗ TLS handshake failed: {e}"),
+                },
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Gateway {
+    pub(crate) tx: mpsc::Sender<StreamCommand>,
+    pub(crate) events: broadcast::Sender<StreamEvent>,
+    pub(crate) shutdown: watch::Receiver<bool>,
+}
+
+impl Gateway {
+    async fn send(&self, caller: &Option<Identity>, cmd: Command) -> Reply {
+        Ok(Json(send_command(&self.tx, caller.clone(), cmd).await?))
+    }
+}
+
+/// The identity of the client, from its API token. Requests without a
+/// token run without an identity.
+///
+/// Only `EVENTS_PATH` takes the token in the query, for `EventSource`.
+pub(crate) struct Caller(pub(crate) Option<Identity>);
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+impl FromRequestParts<Gateway> for Caller {
+    type Rejection = HttpError;
+
+    async fn from_request_parts(parts: &mut Parts, gateway: &Gateway) -> Result<Self, HttpError> {
+        let token = match parts.headers.get(header::AUTHORIZATION) {
+            Some(value) => {
+                // Anything but a Bearer token is an invalid credential
+                let token = value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .ok_or_else(|| anyhow::Error::from(BriefsError::InvalidToken))?;
+                Some(token.trim().to_owned())
+            }
+            None => {
+                let Query(query) = Query::<TokenQuery>::try_from_uri(&parts.uri)?;
+                match query.access_token {
+                    Some(_) if parts.uri.path() != EVENTS_PATH => {
+                        return Err(invalid(format!(
+                            "access_token is only accepted on {EVENTS_PATH}; \
+                            send an Authorization header instead"
+                        )))
+                    }
+                    token => token,
+                }
+            }
+        };
+        let Some(token) = token else {
+            return Ok(Caller(None));
+        };
+        match send_command(&gateway.tx, None, Command::Login { token }).await? {
+            Response::Identity(identity) => Ok(Caller(Some(identity))),
+            response => Err(anyhow::anyhow!("Unexpected response: {response:?}").into()),
+        }
+    }
+}
+
+/// An error along with the status it is sent with.
+#[derive(Debug)]
+pub(crate) struct HttpError(StatusCode, ErrorReply);
+
+impl From<anyhow::Error> for HttpError {
+    fn from(e: anyhow::Error) -> Self {
+        // Errors relayed from a subscription already carry their code
+        let reply = match e.downcast::<ErrorReply>() {
+            Ok(reply) => reply,
+            Err(e) => ErrorReply::from(&e),
+        };
+        let status = match reply.code {
+            // InvalidId, UnknownStream, UnknownRevision
+            201 | 202 | 207 => StatusCode::NOT_FOUND,
+            // StreamExists, StreamArchived
+            203 | 204 => StatusCode::CONFLICT,
+            // Unauthenticated, InvalidToken
+            400 | 401 => StatusCode::UNAUTHORIZED,
+            // Forbidden
+            402 => StatusCode::FORBIDDEN,
+            code if code < 500 => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self(status, reply)
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> axum::response::Response {
+        (self.0, Json(self.1)).into_response()
+    }
+}
+
+/// Requests that cannot be parsed are answered like the invalid messages
+/// of the TLS connections.
+pub(crate) fn invalid(rejection: impl ToString) -> HttpError {
+    let e = BriefsError::InvalidMessage {
+        msg: rejection.to_string(),
+    };
+    HttpError(
+        StatusCode::BAD_REQUEST,
+        ErrorReply::from(&anyhow::Error::from(e)),
+    )
+}
+
+impl From<JsonRejection> for HttpError {
+    fn from(rejection: JsonRejection) -> Self {
+        invalid(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for HttpError {
+    fn from(rejection: QueryRejection) -> Self {
+        invalid(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for HttpError {
+    fn from(rejection: PathRejection) -> Self {
+        invalid(rejection.body_text())
+    }
+}
+
+type Reply = Result<Json<Response>, HttpError>;
+
+pub(crate) fn default_stream() -> String {
+    DEFAULT_STREAM.into()
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    #[serde(default = "default_stream")]
+    stream: String,
+}
+
+#[derive(Deserialize)]
+struct CatchupQuery {
+    #[serde(default = "default_stream")]
+    stream: String,
+    #[serde(default)]
+    from: u32,
+    #[serde(default)]
+    cursor: Option<String>,
+    #[serde(default)]
+    since: Option<u64>,
+    #[serde(default)]
+    until: Option<u64>,
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    #[serde(default = "default_stream")]
+    stream: String,
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default = "default_stream")]
+    stream: String,
+    q: String,
+    #[serde(default)]
+    limit: Option<u32>,
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewPost {
+    title: String,
+    msg: String,
+}
+
+#[derive(Deserialize)]
+struct PostPatch {
+    title: Option<String>,
+    msg: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RestoreBody {
+    revision: u32,
+}
+
+#[derive(Deserialize)]
+struct NewStream {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct StreamPatch {
+    name: Option<String>,
+    archived: Option<bool>,
+}
+
+/// `GET /posts?from=<id>&cursor=&since=&until=&limit=`
+async fn catchup(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    query: Result<Query<CatchupQuery>, QueryRejection>,
+) -> Reply {
+    let Query(query) = query?;
+    gateway
+        .send(
+            &caller,
+            Command::Catchup {
+                stream: query.stream,
+                last_fetch_id: query.from,
+                cursor: query.cursor,
+                since: query.since,
+                until: query.until,
+                limit: query.limit,
+            },
+        )
+        .await
+}
+
+/// `POST /posts`
+async fn create_post(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+    body: Result<Json<NewPost>, JsonRejection>,
+) -> Result<(StatusCode, Json<Response>), HttpError> {
+    let (Query(query), Json(body)) = (query?, body?);
+    let response = gateway
+        .send(
+            &caller,
+            Command::Create {
+                stream: query.stream,
+                title: body.title,
+                msg: body.msg,
+            },
+        )
+        .await?;
+    Ok((StatusCode::CREATED, response))
+}
+
+/// `GET /posts/{id}`
+async fn get_post(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    id: Result<Path<u32>, PathRejection>,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+) -> Reply {
+    let (Path(id), Query(query)) = (id?, query?);
+    gateway
+        .send(
+            &caller,
+            Command::Get {
+                stream: query.stream,
+                id,
+            },
+        )
+        .await
+}
+
+/// `PATCH /posts/{id}`; updates the title, the message or both, and
+/// returns the updated post.
+async fn update_post(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    id: Result<Path<u32>, PathRejection>,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+    body: Result<Json<PostPatch>, JsonRejection>,
+) -> Reply {
+    let (Path(id), Query(query), Json(body)) = (id?, query?, body?);
+    if body.title.is_none() && body.msg.is_none() {
+        return Err(invalid("Nothing to update; expected a title or a msg"));
+    }
+    gateway
+        .send(
+            &caller,
+            Command::Update {
+                stream: query.stream,
+                id,
+                title: body.title,
+                msg: body.msg,
+            },
+        )
+        .await
+}
+
+/// `DELETE /posts/{id}`
+async fn delete_post(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    id: Result<Path<u32>, PathRejection>,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+) -> Reply {
+    let (Path(id), Query(query)) = (id?, query?);
+    gateway
+        .send(
+            &caller,
+            Command::Delete {
+                stream: query.stream,
+                id,
+            },
+        )
+        .await
+}
+
+/// `GET /posts/{id}/history`
+async fn history(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    id: Result<Path<u32>, PathRejection>,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+) -> Reply {
+    let (Path(id), Query(query)) = (id?, query?);
+    gateway
+        .send(
+            &caller,
+            Command::History {
+                stream: query.stream,
+                id,
+            },
+        )
+        .await
+}
+
+/// `POST /posts/{id}/restore`; restores the revision given in the body and
+/// returns the restored post.
+async fn restore(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    id: Result<Path<u32>, PathRejection>,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+    body: Result<Json<RestoreBody>, JsonRejection>,
+) -> Reply {
+    let (Path(id), Query(query), Json(body)) = (id?, query?, body?);
+    gateway
+        .send(
+            &caller,
+            Command::Restore {
+                stream: query.stream,
+                id,
+                revision: body.revision,
+            },
+        )
+        .await
+}
+
+/// `POST /posts/{id}/undelete`; brings the post back from the trash and
+/// returns it.
+async fn undelete(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    id: Result<Path<u32>, PathRejection>,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+) -> Reply {
+    let (Path(id), Query(query)) = (id?, query?);
+    gateway
+        .send(
+            &caller,
+            Command::Undelete {
+                stream: query.stream,
+                id,
+            },
+        )
+        .await
+}
+
+/// `GET /metadata`
+async fn metadata(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+) -> Reply {
+    let Query(query) = query?;
+    gateway
+        .send(
+            &caller,
+            Command::Metadata {
+                stream: query.stream,
+            },
+        )
+        .await
+}
+
+/// `GET /feed/{format}`; `format` is `atom`, `rss` or `json`.
+async fn feed(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    format: Result<Path<String>, PathRejection>,
+    query: Result<Query<FeedQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, HttpError> {
+    let (Path(format), Query(query)) = (format?, query?);
+    let format: FeedFormat = format.parse().map_err(invalid)?;
+    let cmd = Command::Feed {
+        stream: query.stream,
+        format,
+        limit: query.limit,
+    };
+    match send_command(&gateway.tx, caller, cmd).await? {
+        Response::Feed(feed) => Ok((
+            [(header::CONTENT_TYPE, feed.format.content_type())],
+            feed.document,
+        )),
+        response => Err(anyhow::anyhow!("Unexpected response: {response:?}").into()),
+    }
+}
+
+/// `GET /search?q=<query>`; pass the `next_cursor` of a page as `cursor`
+/// to get the next one.
+async fn search(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    query: Result<Query<SearchQuery>, QueryRejection>,
+) -> Reply {
+    let Query(query) = query?;
+    gateway
+        .send(
+            &caller,
+            Command::Search {
+                stream: query.stream,
+                query: query.q,
+                limit: query.limit,
+                cursor: query.cursor,
+            },
+        )
+        .await
+}
+
+/// `GET /streams`
+async fn list_streams(State(gateway): State<Gateway>, Caller(caller): Caller) -> Reply {
+    gateway.send(&caller, Command::ListStreams {}).await
+}
+
+/// `POST /streams`
+async fn create_stream(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    body: Result<Json<NewStream>, JsonRejection>,
+) -> Result<(StatusCode, Json<Response>), HttpError> {
+    let Json(body) = body?;
+    let response = gateway
+        .send(&caller, Command::CreateStream { name: body.name })
+        .await?;
+    Ok((StatusCode::CREATED, response))
+}
+
+/// `PATCH /streams/{name}`; renames and/or archives the stream. Archived
+/// streams cannot be unarchived.
+async fn update_stream(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    name: Result<Path<String>, PathRejection>,
+    body: Result<Json<StreamPatch>, JsonRejection>,
+) -> Reply {
+    let (Path(mut stream), Json(body)) = (name?, body?);
+    let mut response = None;
+    if let Some(new_name) = body.name {
+        let cmd = Command::RenameStream {
+            stream,
+            new_name: new_name.clone(),
+        };
+        response = Some(gateway.send(&caller, cmd).await?);
+        stream = new_name;
+    }
+    match body.archived {
+        Some(true) => {
+            response = Some(
+                gateway
+                    .send(&caller, Command::ArchiveStream { stream })
+                    .await?,
+            )
+        }
+        Some(false) => return Err(invalid("Archived streams cannot be unarchived")),
+        None => {}
+    }
+    response.ok_or_else(|| invalid("Nothing to update; expected a name or archived"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use briefs_core::{
+        state::{CatchUpResponse, Feed},
+        StreamResponse,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    /// A stream handler that answers every command with its JSON,
+    /// and knows no post but the first one. Listing the streams answers
+    /// with the caller, who logs in with the token `brf_ok`.
+    fn echo_handler() -> mpsc::Sender<StreamCommand> {
+        let (tx, mut rx) = mpsc::channel::<StreamCommand>(16);
+        tokio::spawn(async move {
+            while let Some(StreamCommand { cmd, caller, resp }) = rx.recv().await {
+                let response = match cmd {
+                    Command::Login { token } if token == "brf_ok" => {
+                        Ok(Response::Identity(Identity {
+                            name: "ci".into(),
+                            role: briefs_core::auth::Role::Publisher,
+                        }))
+                    }
+                    Command::Login { .. } => Err(BriefsError::InvalidToken.into()),
+                    Command::ListStreams {} => Ok(Response::Message(StreamResponse::new(
+                        serde_json::to_string(&caller).unwrap(),
+                    ))),
+                    Command::Catchup { .. } => Ok(Response::CatchUp(CatchUpResponse {
+                        posts: vec![],
+                        next_cursor: None,
+                        prev_cursor: None,
+                        deleted: vec![],
+                        edited: vec![],
+                    })),
+                    Command::Get { id, .. } if id > 0 => Err(BriefsError::InvalidId {}.into()),
+                    Command::Feed { stream, format, .. } => Ok(Response::Feed(Feed {
+                        format,
+                        document: format!("<feed of {stream}/>"),
+                    })),
+                    cmd => Ok(Response::Message(StreamResponse::new(
+                        serde_json::to_string(&cmd).unwrap(),
+                    ))),
+                };
+                let _ = resp.unwrap().send(response);
+            }
+        });
+        tx
+    }
+
+    /// Returns what the echo handler answered with, such as the command.
+    fn echoed<T: serde::de::DeserializeOwned>(body: &str) -> T {
+        let Response::Message(message) = serde_json::from_str(body).unwrap() else {
+            panic!("expected a message: {body}");
+        };
+        serde_json::from_str(message.msg()).unwrap()
+    }
+
+    async fn call(method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+        let (status, _, body) = call_with_headers(method, uri, body).await;
+        (status, body)
+    }
+
+    async fn call_with_headers(
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, axum::http::HeaderMap, String) {
+        call_as(None, method, uri, body).await
+    }
+
+    async fn call_as(
+        authorization: Option<&str>,
+        method: &str,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, axum::http::HeaderMap, String) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(value) = authorization {
+            request = request.header(header::AUTHORIZATION, value);
+        }
+        let request = request.body(Body::from(body.to_owned())).unwrap();
+        let (events, _) = broadcast::channel(16);
+        let (_, shutdown) = watch::channel(false);
+        let response = router(echo_handler(), events, shutdown)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_routes_map_to_commands() {
+        let (status, body) = call("GET", "/posts?from=4&since=10&limit=5", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"{"type":"CatchUp","data":{"posts":[],"next_cursor":null,"prev_cursor":null,"deleted":[],"edited":[]}}"#
+        );
+
+        let (status, body) = call(
+            "POST",
+            "/posts?stream=ops",
+            r#"{"title":"Hello","msg":"World"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::Create {
+                stream: "ops".into(),
+                title: "Hello".into(),
+                msg: "World".into(),
+            }
+        );
+
+        let (status, body) = call("DELETE", "/posts/3", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::Delete {
+                stream: DEFAULT_STREAM.into(),
+                id: 3,
+            }
+        );
+
+        let (status, body) = call("GET", "/posts/2/history?stream=ops", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::History {
+                stream: "ops".into(),
+                id: 2,
+            }
+        );
+
+        let (status, body) = call("POST", "/posts/2/restore", r#"{"revision":1}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::Restore {
+                stream: DEFAULT_STREAM.into(),
+                id: 2,
+                revision: 1,
+            }
+        );
+
+        let (status, body) = call("POST", "/posts/3/undelete?stream=ops", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::Undelete {
+                stream: "ops".into(),
+                id: 3,
+            }
+        );
+
+        let (status, body) = call("GET", "/search?q=deploy%20v1.2&limit=5", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::Search {
+                stream: DEFAULT_STREAM.into(),
+                query: "deploy v1.2".into(),
+                limit: Some(5),
+                cursor: None,
+            }
+        );
+
+        let (status, _) = call("GET", "/search?stream=ops", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = call("GET", "/metadata?stream=ops", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::Metadata {
+                stream: "ops".into(),
+            }
+        );
+
+        let (status, body) = call("PATCH", "/posts/0", r#"{"title":"New"}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::Update {
+                stream: DEFAULT_STREAM.into(),
+                id: 0,
+                title: Some("New".into()),
+                msg: None,
+            }
+        );
+
+        let (status, body) = call("PATCH", "/streams/ops", r#"{"archived":true}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            echoed::<Command>(&body),
+            Command::ArchiveStream {
+                stream: "ops".into(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_feeds() {
+        let (status, headers, body) = call_with_headers("GET", "/feed/rss?stream=ops", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/rss+xml");
+        assert_eq!(body, "<feed of ops/>");
+
+        let (_, headers, _) = call_with_headers("GET", "/feed/json", "").await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/feed+json");
+
+        let (status, _) = call("GET", "/feed/xml", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let (status, body) = call("GET", "/posts/5", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let error: ErrorReply = serde_json::from_str(&body).unwrap();
+        assert_eq!(error.code, BriefsError::InvalidId {}.code());
+
+        for (method, uri, body) in [
+            ("POST", "/posts", r#"{"title":"Missing msg"}"#),
+            ("GET", "/posts/not-an-id", ""),
+            ("GET", "/posts?from=-1", ""),
+            ("GET", "/posts?until=yesterday", ""),
+            ("PATCH", "/posts/0", "{}"),
+            ("POST", "/posts/0/restore", r#"{"revision":-1}"#),
+        ] {
+            let (status, body) = call(method, uri, body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{method} {uri}");
+            let error: ErrorReply = serde_json::from_str(&body).unwrap();
+            assert_eq!(error.code, 302, "{method} {uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serves_over_tls() {
+        use std::{path::Path, sync::Arc};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::{
+            rustls::{self, pki_types::ServerName, RootCertStore},
+            TlsConnector,
+        };
+
+        let fixture = |name| {
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../../auth/mtls")
+                .join(name)
+        };
+        let config =
+            crate::tls::server_config(&fixture("server.pem"), &fixture("server-key.pem"), None)
+                .unwrap();
+        let (_acceptor_tx, acceptor) = watch::channel(TlsAcceptor::from(Arc::new(config)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (events, _) = broadcast::channel(16);
+        let listener = TlsListener::new(listener, acceptor);
+        let (_, shutdown) = watch::channel(false);
+        tokio::spawn(serve_http(listener, echo_handler(), events, shutdown));
+
+        // Cleartext requests never reach the routes
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"GET /posts HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        let _ = conn.read_to_end(&mut reply).await;
+        assert!(!reply.starts_with(b"HTTP/1.1"));
+
+        let mut roots = RootCertStore::empty();
+        for cert in crate::tls::load_certs(&fixture("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let domain = ServerName::try_from("localhost").unwrap();
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let mut conn = TlsConnector::from(Arc::new(config))
+            .connect(domain, conn)
+            .await
+            .unwrap();
+        conn.write_all(b"GET /posts HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut reply = String::new();
+        conn.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200 OK"), "{reply}");
+        assert!(reply.ends_with(r#""deleted":[],"edited":[]}}"#), "{reply}");
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token() {
+        let (status, _, body) = call_as(Some("Bearer brf_ok"), "GET", "/streams", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let identity = Identity {
+            name: "ci".into(),
+            role: briefs_core::auth::Role::Publisher,
+        };
+        assert_eq!(echoed::<Option<Identity>>(&body), Some(identity));
+
+        // Tokens in the query are only for EventSource
+        let (status, _, body) = call_as(None, "GET", "/streams?access_token=brf_ok", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("access_token is only accepted on /events"));
+
+        let (_, _, body) = call_as(None, "GET", "/streams", "").await;
+        assert_eq!(echoed::<Option<Identity>>(&body), None);
+
+        for authorization in ["Bearer brf_wrong", "brf_ok"] {
+            let (status, _, _) = call_as(Some(authorization), "GET", "/streams", "").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{authorization}");
+        }
+    }
+}
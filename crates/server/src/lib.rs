@@ -1,23 +1,38 @@
 mod error;
+pub mod http;
 pub mod interfaces;
+mod sse;
+pub mod tls;
+mod ws;
 
 pub use error::ServerError;
 
-use briefs_core::{Command, StreamCommand, db::setup_db};
-use tokio_rustls::server::TlsStream;
-use std::path::PathBuf;
+use briefs_core::{
+    auth::Identity,
+    codec::{read_frame, write_frame},
+    db::setup_db,
+    state::{CatchUpResponse, EventKind, StreamEvent},
+    BriefsError, BriefsResult, Command, Envelope, Request, Response, StreamCommand, StreamResponse,
+};
+use briefs_proto::{handshake::Hello, Encoding};
+use std::{path::PathBuf, sync::Arc};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
 };
+use tokio_rustls::server::TlsStream;
 
-/// 10Kb buffer
-const BUFFER_SIZE: usize = 10240;
 pub const POSTS_TABLE: &str = "posts";
+/// Max number of requests of a connection awaiting a reply.
+pub const MAX_IN_FLIGHT: usize = 32;
+/// Max number of events buffered for a subscriber; a subscriber lagging
+/// further behind catches up from the Db.
+pub const EVENT_BUFFER_SIZE: usize = 256;
 
 pub mod interprocess {
-    use super::oneshot;
+    use super::{oneshot, BriefsResult, Response};
 
     pub enum Status {
         Success,
@@ -52,6 +67,40 @@ pub mod interprocess {
     pub fn respond_with_bytes(responder: oneshot::Sender<Vec<u8>>, msg: Vec<u8>) {
         let _ = responder.send(msg);
     }
+
+    pub fn respond_with_result(
+        responder: oneshot::Sender<BriefsResult<Response>>,
+        response: BriefsResult<Response>,
+    ) {
+        let _ = responder.send(response);
+    }
+}
+
+/// Sends the command to the stream handler on behalf of the caller and
+/// waits for the result.
+pub async fn send_command(
+    tx: &mpsc::Sender<StreamCommand>,
+    caller: Option<Identity>,
+    cmd: Command,
+) -> BriefsResult<Response> {
+    let (responder, receiver) = oneshot::channel();
+    let wrapped_cmd = StreamCommand {
+        cmd,
+        caller,
+        resp: Some(responder),
+    };
+    let unavailable = || BriefsError::custom_error("Stream handler is not running".into());
+    tx.send(wrapped_cmd).await.map_err(|_| unavailable())?;
+    receiver.await.map_err(|_| unavailable())?
+}
+
+/// Resolves once the server is shutting down, i.e. once `true` is sent on
+/// the shutdown channel. Never resolves if the sender is dropped without
+/// that, so servers without a shutdown sender run until the process ends.
+pub async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|val| *val).await.is_err() {
+        std::future::pending::<()>().await;
+    }
 }
 
 pub fn setup_server(db_path: Option<PathBuf>) -> anyhow::Result<()> {
@@ -59,32 +108,818 @@ pub fn setup_server(db_path: Option<PathBuf>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Serves the requests of a single TLS connection, see `serve_conn`.
 pub async fn handle_conn_request(
-    mut conn: TlsStream<TcpStream>,
+    conn: TlsStream<TcpStream>,
     tx: mpsc::Sender<StreamCommand>,
+    events: broadcast::Sender<StreamEvent>,
+    shutdown: watch::Receiver<bool>,
 ) {
-    println!("Succesfully connected with {:?}", conn.get_ref().0.peer_addr());
-
-    // let mut kb_buffer = [0u8; BUFFER_SIZE];
-    let mut kb_buffer = Vec::with_capacity(BUFFER_SIZE);
-
-    match conn.read_to_end(&mut kb_buffer).await {
-        Ok(bytes) => {
-            println!("Read {bytes} bytes");
-            let cmd = serde_json::from_slice::<Command>(&kb_buffer[..bytes]).unwrap();
-            println!("{:?}", cmd);
-            let (responder, sender) = oneshot::channel();
-            let wrapped_cmd = StreamCommand {
-                cmd,
-                resp: Some(responder),
+    println!(
+        "Succesfully connected with {:?}",
+        conn.get_ref().0.peer_addr()
+    );
+    let caller = tls::peer_identity(conn.get_ref().1.peer_certificates());
+    if let Some(identity) = &caller {
+        println!(
+            "Client certificate of '{}' ({})",
+            identity.name, identity.role
+        );
+    }
+    serve_conn(conn, tx, events, caller, shutdown).await;
+}
+
+/// Serves the requests of a single connection. Every request and response
+/// is a frame, so the client can send any number of requests over the
+/// same connection; the handler returns once the client disconnects.
+///
+/// Requests are pipelined: the client does not need to wait for a reply
+/// before sending the next request, and replies are sent as soon as the
+/// stream handler completes them, which may be out of order. Every reply
+/// is an `Envelope` carrying the ID of the request it answers. At most
+/// `MAX_IN_FLIGHT` requests of a connection are pending at a time.
+///
+/// A `Subscribe` request is answered with any number of replies, one per
+/// event, until the client disconnects; see `subscribe`.
+///
+/// Commands run with the identity of the last successful `Login` of the
+/// connection, or else with the given `caller`, as established by the
+/// client certificate; see `briefs_core::auth` and `tls`.
+///
+/// If the first frame is a `Hello`, the encoding chosen for the rest of
+/// the connection is acknowledged; otherwise the connection uses JSON and
+/// the frame is served as the first request.
+///
+/// Once the server shuts down, see `shutdown_requested`, no further request
+/// is read, not even the first one; the replies of pending requests are
+/// sent before the connection is closed.
+pub async fn serve_conn<S>(
+    mut conn: S,
+    tx: mpsc::Sender<StreamCommand>,
+    events: broadcast::Sender<StreamEvent>,
+    caller: Option<Identity>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut encoding = Encoding::Json;
+    let mut pending = None;
+    let first = tokio::select! {
+        frame = read_frame(&mut conn) => frame,
+        // Idle clients do not hold up the shutdown
+        _ = shutdown_requested(&mut shutdown) => return,
+    };
+    match first {
+        Ok(Some(payload)) => match Hello::decode(&payload) {
+            Ok(Some(hello)) => {
+                let ack = hello.negotiate();
+                encoding = ack.encoding;
+                println!("Negotiated {} encoding", encoding);
+                if let Err(e) = write_frame(&mut conn, &ack.encode()).await {
+                    eprintln!("Error writing frame: {}", e);
+                    return;
+                }
+            }
+            Ok(None) => pending = Some(payload),
+            Err(e) => {
+                eprintln!("Invalid handshake: {}", e);
+                let _ = conn.shutdown().await;
+                return;
+            }
+        },
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Error reading frame: {}", e);
+            return;
+        }
+    }
+
+    let (mut reader, writer) = tokio::io::split(conn);
+    let (reply_tx, reply_rx) = mpsc::channel(MAX_IN_FLIGHT);
+    let writer = tokio::spawn(write_replies(writer, reply_rx, encoding));
+    let mut dispatcher = Dispatcher::new(tx, events, reply_tx, encoding, caller);
+
+    loop {
+        let payload = match pending.take() {
+            Some(payload) => payload,
+            None => tokio::select! {
+                frame = read_frame(&mut reader) => match frame {
+                    Ok(Some(payload)) => payload,
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("Error reading frame: {}", e);
+                        break;
+                    }
+                },
+                _ = shutdown_requested(&mut shutdown) => break,
+            },
+        };
+        println!("Read {} bytes", payload.len());
+        if !dispatcher.dispatch(&payload).await {
+            break;
+        }
+    }
+
+    // Pending replies are still sent before the connection is closed
+    dispatcher.close().await;
+    if let Ok(mut writer) = writer.await {
+        let _ = writer.shutdown().await;
+    }
+}
+
+/// Dispatches the requests of a connection to the stream handler, and
+/// queues their replies for the writer of the connection. Requests are
+/// pipelined, see `serve_conn`; transports only move the payloads.
+pub(crate) struct Dispatcher {
+    tx: mpsc::Sender<StreamCommand>,
+    events: broadcast::Sender<StreamEvent>,
+    replies: mpsc::Sender<Envelope>,
+    encoding: Encoding,
+    caller: Option<Identity>,
+    in_flight: Arc<Semaphore>,
+    subscriptions: JoinSet<()>,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(
+        tx: mpsc::Sender<StreamCommand>,
+        events: broadcast::Sender<StreamEvent>,
+        replies: mpsc::Sender<Envelope>,
+        encoding: Encoding,
+        caller: Option<Identity>,
+    ) -> Self {
+        Self {
+            tx,
+            events,
+            replies,
+            encoding,
+            caller,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            subscriptions: JoinSet::new(),
+        }
+    }
+
+    /// Decodes the request and hands it to the stream handler. Returns
+    /// false once the connection has to be closed.
+    pub(crate) async fn dispatch(&mut self, payload: &[u8]) -> bool {
+        if self.replies.is_closed() {
+            return false;
+        }
+        let Ok(permit) = self.in_flight.clone().acquire_owned().await else {
+            return false;
+        };
+
+        let Request { id, cmd } = match self.encoding.decode_request(payload) {
+            Ok(request) => request,
+            Err(e) => {
+                // The ID of a request that cannot be decoded is unknown
+                let error = BriefsError::InvalidMessage { msg: e.to_string() };
+                return self
+                    .replies
+                    .send(Envelope::new(0, Err(error.into())))
+                    .await
+                    .is_ok();
+            }
+        };
+        if let Command::Login { .. } = cmd {
+            // Later requests run with the new identity, so the login has
+            // to complete before they are dispatched.
+            let result = send_command(&self.tx, None, cmd).await;
+            if let Ok(Response::Identity(identity)) = &result {
+                println!("Logged in as '{}' ({})", identity.name, identity.role);
+                self.caller = Some(identity.clone());
+            }
+            drop(permit);
+            return self.replies.send(Envelope::new(id, result)).await.is_ok();
+        }
+        println!("{:?}", cmd);
+
+        if let Command::Subscribe { .. } = cmd {
+            // Subscribe before the stream handler sees the request, so that
+            // no event is missed in between.
+            let subscription = Subscription {
+                id,
+                events: self.events.subscribe(),
+                tx: self.tx.clone(),
+                caller: self.caller.clone(),
+                replies: self.replies.clone(),
+            };
+            self.subscriptions
+                .spawn(subscribe(subscription, cmd, Some(permit)));
+            return true;
+        }
+
+        let (responder, sender) = oneshot::channel();
+        let wrapped_cmd = StreamCommand {
+            cmd,
+            caller: self.caller.clone(),
+            resp: Some(responder),
+        };
+        if self.tx.send(wrapped_cmd).await.is_err() {
+            eprintln!("Stream handler is not running");
+            return false;
+        }
+        let replies = self.replies.clone();
+        tokio::spawn(async move {
+            if let Ok(result) = sender.await {
+                let _ = replies.send(Envelope::new(id, result)).await;
+            }
+            drop(permit);
+        });
+        true
+    }
+
+    /// Ends the subscriptions of the connection. The replies of pending
+    /// requests are still queued.
+    pub(crate) async fn close(mut self) {
+        self.subscriptions.abort_all();
+        while self.subscriptions.join_next().await.is_some() {}
+    }
+}
+
+/// A subscriber of the events of a stream, on behalf of request `id`.
+struct Subscription {
+    id: u64,
+    events: broadcast::Receiver<StreamEvent>,
+    tx: mpsc::Sender<StreamCommand>,
+    caller: Option<Identity>,
+    replies: mpsc::Sender<Envelope>,
+}
+
+impl Subscription {
+    async fn request(&self, cmd: Command) -> BriefsResult<Response> {
+        send_command(&self.tx, self.caller.clone(), cmd).await
+    }
+
+    /// Sends a reply to the subscribe request. Returns false once the
+    /// connection is gone.
+    async fn reply(&self, result: BriefsResult<Response>) -> bool {
+        self.replies
+            .send(Envelope::new(self.id, result))
+            .await
+            .is_ok()
+    }
+
+    /// Pushes the event, unless it is the creation of a post that was
+    /// already pushed. Returns false once the connection is gone.
+    async fn push(&self, event: StreamEvent, last_seen: &mut Option<u32>) -> bool {
+        if let EventKind::Created(post) = &event.kind {
+            let id = post.id().unwrap_or_default();
+            if last_seen.is_some_and(|last_seen| id <= last_seen) {
+                return true;
+            }
+            *last_seen = Some(id);
+        }
+        self.reply(Ok(Response::Event(event))).await
+    }
+
+    /// Pushes the edits and deletions of the posts up to `last_seen`, and
+    /// every post created after it, reading the stream one page at a time.
+    /// Returns false if the subscription has to end.
+    async fn replay(&self, stream: &str, last_seen: &mut Option<u32>) -> bool {
+        let seen = *last_seen;
+        let mut cursor = None;
+        loop {
+            let cmd = Command::Catchup {
+                stream: stream.to_owned(),
+                last_fetch_id: last_seen.map_or(0, |id| id + 1),
+                cursor: cursor.take(),
+                since: None,
+                until: None,
+                limit: None,
+            };
+            let first_page = cursor.is_none();
+            let CatchUpResponse {
+                posts,
+                next_cursor,
+                deleted,
+                edited,
+                ..
+            } = match self.request(cmd).await {
+                Ok(Response::CatchUp(response)) => response,
+                Ok(_) => return false,
+                Err(e) => {
+                    self.reply(Err(e)).await;
+                    return false;
+                }
             };
-            tx.send(wrapped_cmd).await.unwrap();
-            let result = sender.await.unwrap();
-            // println!("CONN:\n{}", result);
-            conn.write_all(result.as_slice()).await.unwrap();
-            conn.shutdown().await.unwrap();
+
+            // Every page reports the changes to the posts seen before it
+            let held = |id: &u32| first_page && seen.is_some_and(|seen| *id <= seen);
+            let deleted = deleted
+                .into_iter()
+                .filter(held)
+                .map(|id| EventKind::Deleted { id });
+            let edited = edited
+                .into_iter()
+                .filter(|post| post.id().is_ok_and(|id| held(&id)))
+                .map(EventKind::Updated);
+            let created = posts.into_iter().map(EventKind::Created);
+
+            let before = *last_seen;
+            for kind in deleted.chain(edited).chain(created) {
+                let event = StreamEvent {
+                    stream: stream.to_owned(),
+                    kind,
+                };
+                if !self.push(event, last_seen).await {
+                    return false;
+                }
+            }
+            if next_cursor.is_none() || *last_seen == before {
+                return true;
+            }
+            cursor = next_cursor;
+        }
+    }
+}
+
+/// Serves a `Subscribe` request until the connection or the stream handler
+/// goes away. The subscription is acknowledged, then the edits and
+/// deletions of the posts up to `last_seen_id` and the posts created after
+/// it are replayed, and then every event of the stream is pushed as it
+/// happens. A subscriber that falls behind the event buffer replays what it
+/// missed the same way, as far as `Catchup` reports it.
+///
+/// Events are matched by stream name, so renaming the stream ends the flow
+/// of events.
+async fn subscribe(
+    mut subscription: Subscription,
+    cmd: Command,
+    permit: Option<OwnedSemaphorePermit>,
+) {
+    let Command::Subscribe {
+        stream,
+        last_seen_id,
+    } = &cmd
+    else {
+        return;
+    };
+    let (stream, last_seen_id) = (stream.clone(), *last_seen_id);
+
+    let metadata = match subscription.request(cmd).await {
+        Ok(Response::Metadata(metadata)) => metadata,
+        Ok(_) => return,
+        Err(e) => {
+            subscription.reply(Err(e)).await;
+            return;
+        }
+    };
+    let ack = StreamResponse::new(format!("Subscribed to stream '{}'", metadata.name));
+    if !subscription.reply(Ok(Response::Message(ack))).await {
+        return;
+    }
+
+    let mut last_seen = last_seen_id.or(metadata.latest_post_id);
+    if last_seen_id.is_some() && !subscription.replay(&stream, &mut last_seen).await {
+        return;
+    }
+    // A subscription is not a pending request
+    drop(permit);
+
+    loop {
+        let received = tokio::select! {
+            received = subscription.events.recv() => received,
+            // Nobody is listening anymore
+            _ = subscription.replies.closed() => return,
+        };
+        match received {
+            Ok(event) if event.stream == stream => {
+                if !subscription.push(event, &mut last_seen).await {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                eprintln!("Subscriber of '{stream}' missed {missed} events");
+                if !subscription.replay(&stream, &mut last_seen).await {
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
         }
-        Err(e) => eprintln!("Error reading into buffer: {:?}", e),
     }
 }
 
+/// Subscribes to a stream on behalf of a transport that only pushes
+/// events, and returns the replies to the `Subscribe` command, see
+/// `subscribe`. The subscription ends once the receiver is dropped.
+pub(crate) fn spawn_subscription(
+    tx: mpsc::Sender<StreamCommand>,
+    events: &broadcast::Sender<StreamEvent>,
+    caller: Option<Identity>,
+    cmd: Command,
+) -> mpsc::Receiver<Envelope> {
+    let (replies, receiver) = mpsc::channel(MAX_IN_FLIGHT);
+    let subscription = Subscription {
+        id: 0,
+        events: events.subscribe(),
+        tx,
+        caller,
+        replies,
+    };
+    tokio::spawn(subscribe(subscription, cmd, None));
+    receiver
+}
+
+/// Encodes and writes the replies of a connection until every sender is
+/// dropped or writing fails. Returns the writer so that it can be shut
+/// down.
+async fn write_replies<W>(
+    mut writer: W,
+    mut replies: mpsc::Receiver<Envelope>,
+    encoding: Encoding,
+) -> W
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(envelope) = replies.recv().await {
+        let result = match encoding.encode_envelope(envelope) {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("Error encoding response: {}", e);
+                break;
+            }
+        };
+        if let Err(e) = write_frame(&mut writer, &result).await {
+            eprintln!("Error writing frame: {}", e);
+            break;
+        }
+    }
+    writer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use briefs_core::{post::Post, state::StreamMetadata, Outcome};
+    use briefs_proto::handshake::HelloAck;
+
+    fn metadata(stream: &str) -> Command {
+        Command::Metadata {
+            stream: stream.into(),
+        }
+    }
+
+    /// Metadata of a mock stream, as sent in reply to a `Subscribe`.
+    pub(crate) fn stream_metadata(
+        name: String,
+        latest_post_id: Option<u32>,
+        posts_count: u32,
+    ) -> StreamMetadata {
+        StreamMetadata {
+            name,
+            archived: false,
+            latest_post_id,
+            last_updated: 0,
+            posts_count,
+            sqlite_version: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_replies_out_of_order() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, mut rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let (_, shutdown) = watch::channel(false);
+        tokio::spawn(serve_conn(server, tx, events, None, shutdown));
+
+        // Answers the second command, and the first one only once the
+        // client has received the reply to the second one.
+        let (replied_tx, replied_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let first: StreamCommand = rx.recv().await.unwrap();
+            let second: StreamCommand = rx.recv().await.unwrap();
+            let reply = |StreamCommand { cmd, resp, .. }: StreamCommand| {
+                let Command::Metadata { stream } = cmd else {
+                    panic!("unexpected command");
+                };
+                let response = Response::Message(StreamResponse::new(stream));
+                resp.unwrap().send(Ok(response)).unwrap();
+            };
+            reply(second);
+            replied_rx.await.unwrap();
+            reply(first);
+        });
+
+        let hello = Hello::new(vec![Encoding::Proto]);
+        write_frame(&mut client, &hello.encode()).await.unwrap();
+        let ack = read_frame(&mut client).await.unwrap().unwrap();
+        let encoding = HelloAck::decode(&ack).unwrap().encoding;
+        assert_eq!(encoding, Encoding::Proto);
+
+        for (id, stream) in [(1, "ops"), (2, "releases")] {
+            let request = encoding
+                .encode_request(Request::new(id, metadata(stream)))
+                .unwrap();
+            write_frame(&mut client, &request).await.unwrap();
+        }
+
+        let mut replies = Vec::new();
+        let mut replied_tx = Some(replied_tx);
+        for _ in 0..2 {
+            let payload = read_frame(&mut client).await.unwrap().unwrap();
+            let envelope = encoding.decode_envelope(&payload).unwrap();
+            let Outcome::Data(Response::Message(msg)) = envelope.outcome else {
+                panic!("unexpected reply");
+            };
+            replies.push((envelope.id, msg.msg().to_owned()));
+            if let Some(replied_tx) = replied_tx.take() {
+                replied_tx.send(()).unwrap();
+            }
+        }
+        assert_eq!(
+            replies,
+            vec![(2, "releases".to_owned()), (1, "ops".to_owned())]
+        );
+
+        client.shutdown().await.unwrap();
+        assert_eq!(read_frame(&mut client).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sends_pending_replies() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, mut rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let conn = tokio::spawn(serve_conn(server, tx, events, None, shutdown_rx));
+
+        let encoding = Encoding::Json;
+        let request = encoding
+            .encode_request(Request::new(1, metadata("ops")))
+            .unwrap();
+        write_frame(&mut client, &request).await.unwrap();
+
+        // The request is queued when the server shuts down
+        let StreamCommand { resp, .. } = rx.recv().await.unwrap();
+        shutdown_tx.send(true).unwrap();
+        let response = Response::Message(StreamResponse::new("ops".into()));
+        resp.unwrap().send(Ok(response)).unwrap();
+
+        let payload = read_frame(&mut client).await.unwrap().unwrap();
+        let envelope = encoding.decode_envelope(&payload).unwrap();
+        assert_eq!(envelope.id, 1);
+        assert!(matches!(
+            envelope.outcome,
+            Outcome::Data(Response::Message(_))
+        ));
+        assert_eq!(read_frame(&mut client).await.unwrap(), None);
+        conn.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_idle_connections() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, _rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let conn = tokio::spawn(serve_conn(server, tx, events, None, shutdown_rx));
+
+        // The client never sends its first frame
+        shutdown_tx.send(true).unwrap();
+        conn.await.unwrap();
+        assert_eq!(read_frame(&mut client).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_json_without_handshake() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, mut rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let (_, shutdown) = watch::channel(false);
+        tokio::spawn(serve_conn(server, tx, events, None, shutdown));
+        tokio::spawn(async move {
+            while let Some(StreamCommand { resp, .. }) = rx.recv().await {
+                let error = BriefsError::UnknownStream { name: "ops".into() };
+                resp.unwrap().send(Err(error.into())).unwrap();
+            }
+        });
+
+        write_frame(&mut client, br#"{"id":5,"Metadata":{"stream":"ops"}}"#)
+            .await
+            .unwrap();
+        write_frame(&mut client, b"not a request").await.unwrap();
+
+        // Replies may come in any order
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let payload = read_frame(&mut client).await.unwrap().unwrap();
+            let envelope = Encoding::Json.decode_envelope(&payload).unwrap();
+            let Outcome::Error(error) = envelope.outcome else {
+                panic!("unexpected reply");
+            };
+            replies.push((envelope.id, error.code));
+        }
+        replies.sort();
+        assert_eq!(replies, vec![(0, 302), (5, 202)]);
+    }
+
+    fn post(id: u32) -> Post {
+        Post::from_parts(id, format!("Post #{id}"), "Message".into(), 0, false)
+    }
+
+    fn created(stream: &str, id: u32) -> StreamEvent {
+        StreamEvent {
+            stream: stream.into(),
+            kind: EventKind::Created(post(id)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_replays_then_pushes() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let (tx, mut rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let (_, shutdown) = watch::channel(false);
+        tokio::spawn(serve_conn(server, tx, events.clone(), None, shutdown));
+
+        // A stream with posts 0 to 2, where the subscriber has seen post 0
+        tokio::spawn(async move {
+            while let Some(StreamCommand { cmd, resp, .. }) = rx.recv().await {
+                let response = match cmd {
+                    Command::Subscribe { stream, .. } => {
+                        Response::Metadata(stream_metadata(stream, Some(2), 3))
+                    }
+                    Command::Catchup { last_fetch_id, .. } => Response::CatchUp(CatchUpResponse {
+                        posts: (last_fetch_id..3).map(post).collect(),
+                        next_cursor: None,
+                        prev_cursor: None,
+                        deleted: vec![],
+                        edited: vec![],
+                    }),
+                    _ => panic!("unexpected command"),
+                };
+                resp.unwrap().send(Ok(response)).unwrap();
+            }
+        });
+
+        let cmd = Command::Subscribe {
+            stream: "ops".into(),
+            last_seen_id: Some(0),
+        };
+        let request = serde_json::to_vec(&Request::new(9, cmd)).unwrap();
+        write_frame(&mut client, &request).await.unwrap();
+
+        let mut next_reply = async || {
+            let payload = read_frame(&mut client).await.unwrap().unwrap();
+            let envelope = Encoding::Json.decode_envelope(&payload).unwrap();
+            assert_eq!(envelope.id, 9);
+            envelope.into_result().unwrap()
+        };
+        assert!(matches!(next_reply().await, Response::Message(_)));
+        assert_eq!(next_reply().await, Response::Event(created("ops", 1)));
+        assert_eq!(next_reply().await, Response::Event(created("ops", 2)));
+
+        // Already replayed, or of another stream
+        events.send(created("ops", 2)).unwrap();
+        events.send(created("releases", 3)).unwrap();
+        events.send(created("ops", 3)).unwrap();
+        let deleted = StreamEvent {
+            stream: "ops".into(),
+            kind: EventKind::Deleted { id: 1 },
+        };
+        events.send(deleted.clone()).unwrap();
+
+        assert_eq!(next_reply().await, Response::Event(created("ops", 3)));
+        assert_eq!(next_reply().await, Response::Event(deleted));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_replays_edits_and_deletions() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let (tx, mut rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let (_, shutdown) = watch::channel(false);
+        tokio::spawn(serve_conn(server, tx, events, None, shutdown));
+
+        // The subscriber has seen posts 0 to 2, then post 0 was deleted,
+        // post 1 edited, and posts 3 and 4 created, of which 3 is deleted
+        let edited = Post::from_parts(1, "Edited".into(), "Message".into(), 0, true);
+        let changed = edited.clone();
+        tokio::spawn(async move {
+            while let Some(StreamCommand { cmd, resp, .. }) = rx.recv().await {
+                let response = match cmd {
+                    Command::Subscribe { stream, .. } => {
+                        Response::Metadata(stream_metadata(stream, Some(4), 3))
+                    }
+                    Command::Catchup { last_fetch_id, .. } => Response::CatchUp(CatchUpResponse {
+                        posts: (last_fetch_id..5).filter(|id| *id != 3).map(post).collect(),
+                        next_cursor: None,
+                        prev_cursor: None,
+                        deleted: vec![0, 3],
+                        edited: vec![changed.clone()],
+                    }),
+                    _ => panic!("unexpected command"),
+                };
+                resp.unwrap().send(Ok(response)).unwrap();
+            }
+        });
+
+        let cmd = Command::Subscribe {
+            stream: "ops".into(),
+            last_seen_id: Some(2),
+        };
+        let request = serde_json::to_vec(&Request::new(9, cmd)).unwrap();
+        write_frame(&mut client, &request).await.unwrap();
+
+        let mut next_reply = async || {
+            let payload = read_frame(&mut client).await.unwrap().unwrap();
+            let envelope = Encoding::Json.decode_envelope(&payload).unwrap();
+            envelope.into_result().unwrap()
+        };
+        let event = |kind| {
+            Response::Event(StreamEvent {
+                stream: "ops".into(),
+                kind,
+            })
+        };
+        assert!(matches!(next_reply().await, Response::Message(_)));
+        // Post 3 was never seen, so its deletion is not replayed
+        assert_eq!(next_reply().await, event(EventKind::Deleted { id: 0 }));
+        assert_eq!(next_reply().await, event(EventKind::Updated(edited)));
+        assert_eq!(next_reply().await, Response::Event(created("ops", 4)));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_pushes_undeleted_posts() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let (tx, mut rx) = mpsc::channel(16);
+        let (events, _) = broadcast::channel(16);
+        let (_, shutdown) = watch::channel(false);
+        tokio::spawn(serve_conn(server, tx, events.clone(), None, shutdown));
+
+        // Deleting and undeleting post 1 publishes both events
+        let publisher = events.clone();
+        tokio::spawn(async move {
+            while let Some(StreamCommand { cmd, resp, .. }) = rx.recv().await {
+                let response = match cmd {
+                    Command::Subscribe { stream, .. } => {
+                        Response::Metadata(stream_metadata(stream, Some(2), 3))
+                    }
+                    Command::Delete { stream, id } => {
+                        let kind = EventKind::Deleted { id };
+                        publisher.send(StreamEvent { stream, kind }).unwrap();
+                        Response::Message(StreamResponse::new("Deleted".into()))
+                    }
+                    Command::Undelete { stream, id } => {
+                        let kind = EventKind::Undeleted(post(id));
+                        publisher.send(StreamEvent { stream, kind }).unwrap();
+                        Response::Post(post(id))
+                    }
+                    _ => panic!("unexpected command"),
+                };
+                resp.unwrap().send(Ok(response)).unwrap();
+            }
+        });
+
+        let mut send = async |id, cmd| {
+            let request = serde_json::to_vec(&Request::new(id, cmd)).unwrap();
+            write_frame(&mut client, &request).await.unwrap();
+            let payload = read_frame(&mut client).await.unwrap().unwrap();
+            Encoding::Json.decode_envelope(&payload).unwrap()
+        };
+        let cmd = Command::Subscribe {
+            stream: "ops".into(),
+            last_seen_id: None,
+        };
+        assert_eq!(send(1, cmd).await.id, 1);
+
+        let cmd = Command::Delete {
+            stream: "ops".into(),
+            id: 1,
+        };
+        let mut replies = Vec::new();
+        replies.push(send(2, cmd).await);
+        let cmd = Command::Undelete {
+            stream: "ops".into(),
+            id: 1,
+        };
+        replies.push(send(3, cmd).await);
+        for _ in 0..2 {
+            let payload = read_frame(&mut client).await.unwrap().unwrap();
+            replies.push(Encoding::Json.decode_envelope(&payload).unwrap());
+        }
+
+        // The undeleted post is older than the last seen one, yet pushed
+        let pushed: Vec<Response> = replies
+            .into_iter()
+            .filter(|envelope| envelope.id == 1)
+            .map(|envelope| envelope.into_result().unwrap())
+            .collect();
+        let event = |kind| {
+            Response::Event(StreamEvent {
+                stream: "ops".into(),
+                kind,
+            })
+        };
+        assert_eq!(
+            pushed,
+            [
+                event(EventKind::Deleted { id: 1 }),
+                event(EventKind::Undeleted(post(1))),
+            ]
+        );
+    }
+}
@@ -1,26 +1,42 @@
 use std::net::{Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
-use tokio::{net::TcpListener, signal::ctrl_c, sync::mpsc};
-use tokio_rustls::{rustls, TlsAcceptor};
+use tokio::{
+    net::TcpListener,
+    signal::ctrl_c,
+    sync::{broadcast, mpsc, watch},
+    task::JoinSet,
+    time::{interval, timeout},
+};
+use tokio_rustls::TlsAcceptor;
 
 use briefs_core::{
+    auth::{self, Identity, TokenStore},
     config,
-    db::generate_temp_db,
-    post,
-    state::CatchUpResponse,
-    stream,
-    utils::{read_stream_from_disk, save_stream_on_disk},
-    Command, StreamCommand, StreamResponse,
+    constant::PURGE_INTERVAL,
+    db::{generate_temp_db, resolve_db_path},
+    registry::StreamRegistry,
+    state::{EventKind, StreamEvent},
+    store::SqliteStore,
+    utils::import_legacy_stream,
+    BriefsError, BriefsResult, Command, Response, StreamCommand, StreamResponse,
 };
 
-use server::{handle_conn_request, interprocess::respond_with_bytes, setup_server};
+use server::{
+    handle_conn_request,
+    http::{serve_http, TlsListener},
+    interprocess::respond_with_result,
+    setup_server, shutdown_requested, tls, EVENT_BUFFER_SIZE,
+};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let (tx, mut rx) = mpsc::channel(16);
+    let (events, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+    let conn_events = events.clone();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
     // Load config or Generate new config
     let config = match config::fetch_config_from_env() {
@@ -36,6 +52,18 @@ async fn main() {
                     let db_path = generate_temp_db();
                     config.db = db_path.clone();
                     config.socket = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080);
+                    match tls::generate_dev_cert(&config.dirpath) {
+                        Ok((cert, pkey)) => {
+                            println!(
+                                "✓ Generated self-signed development certificate '{}'; \
+                                clients need `--cafile` with it",
+                                cert.display()
+                            );
+                            config.cert = cert;
+                            config.pkey = pkey;
+                        }
+                        Err(e) => eprintln!("✗ Unable to generate development certificate: {e}"),
+                    }
                     config.save().unwrap();
                     println!("✓ Saved config to '{}'", config.filepath.display());
                     config
@@ -43,251 +71,394 @@ async fn main() {
             }
         }
     };
-    let socket = config.socket.clone();
+    let socket = config.socket;
+    let drain_timeout = Duration::from_secs(config.drain_timeout);
+    if let Some(client_ca) = &config.client_ca {
+        println!("✓ Verifying client certificates against '{}'", client_ca.display());
+    }
+    let tls_files = tls::TlsFiles::from_config(&config);
+    let tls_config = match tls_files.server_config() {
+        Ok(tls_config) => tls_config,
+        Err(e) => {
+            eprintln!("✗ Unable to set up TLS from '{}': {e}", config.filepath.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    // New connections use the latest acceptor; reloads swap it
+    let (acceptor_tx, acceptor_rx) = watch::channel(TlsAcceptor::from(Arc::new(tls_config)));
+    tokio::spawn(tls::watch_tls(tls_files, acceptor_tx, tls::TLS_RELOAD_INTERVAL));
 
-    let stream_handle = tokio::spawn(async move {
+    let http_handle = config.http.map(|http_socket| {
+        let tx = tx.clone();
+        let events = events.clone();
+        let acceptor = acceptor_rx.clone();
+        let shutdown = shutdown_rx.clone();
+        tokio::spawn(async move {
+            match TcpListener::bind(http_socket).await {
+                Ok(listener) => {
+                    println!("✓ HTTPS gateway listening on {}...", http_socket);
+                    let listener = TlsListener::new(listener, acceptor);
+                    serve_http(listener, tx, events, shutdown).await;
+                }
+                Err(e) => eprintln!("✗ Unable to serve HTTPS gateway on {}: {e}", http_socket),
+            }
+        })
+    });
+
+    let mut stream_shutdown = shutdown_rx.clone();
+    let mut stream_handle = tokio::spawn(async move {
         println!("✓ Stream handle running...");
-        let db_path = config.db.clone();
-        setup_server(Some(db_path.clone().into())).expect("Unable to setup db");
-        let mut conn = sqlite::open(db_path).expect("Unable to open connection");
-        let mut stream = read_stream_from_disk(&mut conn, &config).unwrap_or_else(|_| {
-            eprintln!("✗ No Prexisting stream found");
-            println!("✓ Creating a new stream");
-            let s = stream::Stream::default();
-            save_stream_on_disk(&s, &config).expect("✗ Failed to save stream");
-            s
-        });
+        let db_path = resolve_db_path(Some(config.db.clone())).expect("Invalid db path");
+        setup_server(Some(db_path.clone())).expect("Unable to setup db");
+        let mut store = SqliteStore::open(&db_path).expect("Unable to open connection");
+        match import_legacy_stream(&mut store, &config) {
+            Ok(true) => println!("✓ Imported legacy stream data into the db"),
+            Ok(false) => {}
+            Err(e) => eprintln!("✗ Failed to import legacy stream data: {e}"),
+        }
+        drop(store);
+        let mut registry = StreamRegistry::open(&db_path).expect("Unable to open streams");
+        println!("✓ Serving {} stream(s)", registry.list().len());
+        let tokens = TokenStore::open(&db_path).expect("Unable to open tokens");
+        if tokens.list().map_or(true, |val| val.is_empty()) {
+            eprintln!("✗ No API tokens; create one with `cli token create <name> --role admin`");
+        }
+        let private_reads = config.private_reads;
+        if private_reads {
+            println!("✓ Reading streams needs a reader token");
+        }
 
         //-------
         // Handle requets from conn handler
         //-------
-        while let Some(StreamCommand { cmd, resp }) = rx.recv().await {
-            match cmd {
-                Command::Create { title, msg } => {
-                    let new_post = post::Post::new(stream.nposts() as u32, title, msg);
-                    if new_post.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "ERROR during create: {:?}",
-                                new_post.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-                    let new_post = new_post.unwrap();
-                    let result = stream.add_post(&mut conn, new_post.clone());
-                    if result.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "ERROR during create: {:?}",
-                                result.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-
-                    respond_with_bytes(
-                        resp.unwrap(),
-                        serde_json::to_vec(&StreamResponse::new(format!(
-                            "Succesfully added a new post"
-                        )))
-                        .unwrap(),
-                    );
-                }
-
-                Command::Catchup { last_fetch_id } => {
-                    if stream.size() == 0 || last_fetch_id as usize >= stream.nposts() {
-                        let empty_stream_response = serde_json::to_vec(&CatchUpResponse {
-                            posts: vec![],
-                            caught_up: true,
-                        })
-                        .unwrap();
-                        respond_with_bytes(resp.unwrap(), empty_stream_response);
-                        continue;
-                    };
-
-                    // Catchup
-                    let response = stream.catchup(&mut conn, last_fetch_id, None);
-                    if response.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "An error occured: {:?}",
-                                response.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-
-                    // Serialise the response
-                    let response = serde_json::to_vec(&response.unwrap());
-                    if response.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "An error occured: {:?}",
-                                response.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-
-                    resp.unwrap().send(response.unwrap()).unwrap();
-                }
-
-                Command::Get { id } => {
-                    let result = stream.get_post(&mut conn, id);
-                    if result.is_none() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "ERROR during get: Unable to get post"
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-                    resp.unwrap()
-                        .send(serde_json::to_vec(&result.unwrap()).unwrap_or_default())
-                        .unwrap();
-                }
-
-                Command::Delete { id } => {
-                    let result = stream.remove_post(&mut conn, id);
-                    if result.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "ERROR during delete: {}",
-                                result.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-
-                    respond_with_bytes(resp.unwrap(), format!("Succesfully deleted post").into());
-                }
-
-                Command::UpdateMsg { id, msg } => {
-                    let result = stream.update_msg(&mut conn, id, msg);
-                    if result.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "ERROR during message update: {}",
-                                result.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-                    respond_with_bytes(
-                        resp.unwrap(),
-                        serde_json::to_vec(&StreamResponse::new(format!(
-                            "Succesfully updated post message"
-                        )))
-                        .unwrap(),
-                    );
+        // On shutdown the channel is closed, and the commands already queued
+        // are still handled before the streams are persisted. The trash is
+        // purged in between commands; the first purge runs right away.
+        let trash_retention = Duration::from_secs(config.trash_retention * 24 * 60 * 60);
+        let mut purge = interval(Duration::from_secs(PURGE_INTERVAL));
+        let mut draining = false;
+        loop {
+            let command = tokio::select! {
+                command = rx.recv() => command,
+                _ = purge.tick(), if !draining => {
+                    purge_trash(&mut registry, trash_retention);
+                    continue;
                 }
-
-                Command::UpdateTitle { id, title } => {
-                    let result = stream.update_title(&mut conn, id, title);
-                    if result.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "ERROR during title update: {}",
-                                result.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-                    respond_with_bytes(
-                        resp.unwrap(),
-                        serde_json::to_vec(&StreamResponse::new(format!(
-                            "Succesfully updated post title"
-                        )))
-                        .unwrap(),
-                    );
-                }
-
-                Command::Metadata {} => {
-                    let result = stream.stream_metadata();
-                    if result.is_err() {
-                        respond_with_bytes(
-                            resp.unwrap(),
-                            serde_json::to_vec(&StreamResponse::new(format!(
-                                "ERROR during title update: {}",
-                                result.unwrap_err()
-                            )))
-                            .unwrap(),
-                        );
-                        continue;
-                    }
-                    resp.unwrap()
-                        .send(serde_json::to_vec(&result.unwrap()).unwrap_or_default())
-                        .unwrap();
+                _ = shutdown_requested(&mut stream_shutdown), if !draining => {
+                    draining = true;
+                    rx.close();
+                    println!("✓ Draining {} queued command(s)...", rx.len());
+                    continue;
                 }
+            };
+            let Some(StreamCommand { cmd, caller, resp }) = command else {
+                break;
+            };
+            let response =
+                handle_command(&mut registry, &tokens, &events, private_reads, caller, cmd);
+            if let Some(resp) = resp {
+                respond_with_result(resp, response);
             }
         }
+        drop(tokens);
+        registry.close()
     });
 
+    let mut conn_shutdown = shutdown_rx.clone();
     let conn_handle = tokio::spawn(async move {
         let socket_addr = socket;
-        let server_cert = PathBuf::from("/Users/rishabh/project/briefs/auth/keys/cert.pem");
-        let private_key = PathBuf::from("/Users/rishabh/project/briefs/auth/keys/key.pem");
         println!("✓ Setting up connection handler...");
 
-        let certs = CertificateDer::pem_file_iter(server_cert)
-            .unwrap()
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
-        let key = PrivateKeyDer::from_pem_file(private_key).unwrap();
-
-        let config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .unwrap();
-        let acceptor = TlsAcceptor::from(Arc::new(config));
-
         // !------- ACCEPT CONNECTIONS ON PORT 8080 -------!
         let listener = TcpListener::bind(socket_addr).await.unwrap();
         println!("✓ Listening on {}...", listener.local_addr().unwrap());
 
+        let mut connections = JoinSet::new();
         loop {
+            let conn = tokio::select! {
+                conn = listener.accept() => conn,
+                // Reap finished connections
+                Some(_) = connections.join_next() => continue,
+                _ = shutdown_requested(&mut conn_shutdown) => break,
+            };
             let _tx = tx.clone();
-            let conn = listener.accept().await;
-            let acceptor = acceptor.clone();
+            let events = conn_events.clone();
+            let mut shutdown = shutdown_rx.clone();
+            let acceptor = acceptor_rx.borrow().clone();
 
             if conn.is_ok() {
-                tokio::spawn(async move {
-                    let stream = acceptor.accept(conn.unwrap().0).await.unwrap();
-                    // function signature needs to change for this
-                    handle_conn_request(stream, _tx).await;
+                connections.spawn(async move {
+                    // Clients stuck in the handshake do not hold up the shutdown
+                    let handshake = tokio::select! {
+                        handshake = acceptor.accept(conn.unwrap().0) => handshake,
+                        _ = shutdown_requested(&mut shutdown) => return,
+                    };
+                    match handshake {
+                        Ok(stream) => handle_conn_request(stream, _tx, events, shutdown).await,
+                        Err(e) => eprintln!("✗ TLS handshake failed: {e}"),
+                    }
                 });
             }
         }
-    });
 
-    let safe_exit_handle = tokio::spawn(async move {
-        println!("✓ Press Ctrl-C to stop the server");
-        ctrl_c().await.unwrap();
-        // std::fs::remove_file(db_path_outer).expect("Unable to remove Db file");
-        std::process::exit(0);
+        // Stop accepting, and let the connections send their pending replies
+        drop(listener);
+        drop(tx);
+        while connections.join_next().await.is_some() {}
     });
 
+    println!("✓ Press Ctrl-C to stop the server");
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        _ = &mut stream_handle => {
+            eprintln!("✗ Stream handle stopped unexpectedly");
+            return ExitCode::FAILURE;
+        }
+    }
+
     //-------
-    // Wait for all threads
+    // Drain the connections, then the stream handle
     //-------
-    conn_handle.await.unwrap();
-    stream_handle.await.unwrap();
-    safe_exit_handle.await.unwrap();
+    println!("✓ Shutting down; draining for up to {}s...", drain_timeout.as_secs());
+    let _ = shutdown_tx.send(true);
+    let drained = timeout(drain_timeout, async move {
+        let _ = conn_handle.await;
+        if let Some(http_handle) = http_handle {
+            let _ = http_handle.await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        eprintln!("✗ Drain timed out after {}s", drain_timeout.as_secs());
+    }
+    // The stream handle drains its queue on its own once the channel closes
+    match stream_handle.await {
+        Ok(Ok(count)) => {
+            println!("✓ Persisted {count} stream(s) and closed the Db");
+            if drained.is_ok() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Ok(Err(e)) => {
+            eprintln!("✗ Unable to persist the streams: {e}");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("✗ Stream handle failed: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(e) => {
+                eprintln!("✗ Unable to listen for SIGTERM: {e}");
+                let _ = ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = ctrl_c().await;
+}
+
+/// Removes the posts deleted more than `retention` ago from every stream.
+fn purge_trash(registry: &mut StreamRegistry, retention: Duration) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    match registry.purge_deleted(now.saturating_sub(retention).as_secs()) {
+        Ok(0) => {}
+        Ok(count) => println!("✓ Purged {count} deleted post(s) from the trash"),
+        Err(e) => eprintln!("✗ Unable to purge the trash: {e}"),
+    }
+}
+
+/// Wraps the given message in a `StreamResponse`.
+fn message(msg: String) -> Response {
+    Response::Message(StreamResponse::new(msg))
+}
+
+/// Pushes the event to the subscribers, if there are any.
+fn publish(events: &broadcast::Sender<StreamEvent>, stream: String, kind: EventKind) {
+    let _ = events.send(StreamEvent { stream, kind });
+}
+
+/// Runs the command against the targeted stream and returns the response
+/// for the connection handler, which wraps it in an envelope for the
+/// client. Changes to posts are published to the subscribers of the
+/// stream. Commands the caller is not allowed to run are rejected, see
+/// `auth::required_role`; edits are recorded as made by the caller.
+fn handle_command(
+    registry: &mut StreamRegistry,
+    tokens: &TokenStore,
+    events: &broadcast::Sender<StreamEvent>,
+    private_reads: bool,
+    caller: Option<Identity>,
+    cmd: Command,
+) -> BriefsResult<Response> {
+    auth::authorize(caller.as_ref(), &cmd, private_reads)?;
+    let author = caller.map(|val| val.name).unwrap_or_default();
+    match cmd {
+        Command::Login { token } => Ok(Response::Identity(tokens.login(&token)?)),
+
+        Command::Create {
+            stream: name,
+            title,
+            msg,
+        } => {
+            let new_post = registry.get_mut(&name)?.create_post(title, msg)?;
+            publish(events, name, EventKind::Created(new_post));
+            Ok(message("Succesfully added a new post".into()))
+        }
+
+        Command::Catchup {
+            stream,
+            last_fetch_id,
+            cursor,
+            since,
+            until,
+            limit,
+        } => {
+            let stream = registry.get(&stream)?;
+            let page =
+                stream.catchup_page(last_fetch_id, cursor.as_deref(), since, until, limit)?;
+            Ok(Response::CatchUp(page))
+        }
+
+        Command::Get { stream, id } => {
+            let post = registry
+                .get(&stream)?
+                .get_post(id)
+                .ok_or(BriefsError::InvalidId {})?;
+            Ok(Response::Post(post))
+        }
+
+        Command::Delete { stream, id } => {
+            registry.get_mut(&stream)?.remove_post(id)?;
+            publish(events, stream, EventKind::Deleted { id });
+            Ok(message("Succesfully deleted post".into()))
+        }
+
+        Command::UpdateMsg {
+            stream: name,
+            id,
+            msg,
+        } => {
+            let stream = registry.get_mut(&name)?;
+            stream.update_msg(id, msg, &author)?;
+            if let Some(post) = stream.get_post(id) {
+                publish(events, name, EventKind::Updated(post));
+            }
+            Ok(message("Succesfully updated post message".into()))
+        }
+
+        Command::UpdateTitle {
+            stream: name,
+            id,
+            title,
+        } => {
+            let stream = registry.get_mut(&name)?;
+            stream.update_title(id, title, &author)?;
+            if let Some(post) = stream.get_post(id) {
+                publish(events, name, EventKind::Updated(post));
+            }
+            Ok(message("Succesfully updated post title".into()))
+        }
+
+        Command::Metadata { stream } => Ok(Response::Metadata(
+            registry.get(&stream)?.stream_metadata()?,
+        )),
+
+        Command::CreateStream { name } => {
+            let info = registry.create(&name)?;
+            Ok(message(format!(
+                "Succesfully created stream '{}'",
+                info.name
+            )))
+        }
+
+        Command::ListStreams {} => Ok(Response::Streams(registry.list())),
+
+        Command::RenameStream { stream, new_name } => {
+            let info = registry.rename(&stream, &new_name)?;
+            Ok(message(format!(
+                "Succesfully renamed stream to '{}'",
+                info.name
+            )))
+        }
+
+        // The connection handler streams the events; the stream handler
+        // only tells where the subscription starts.
+        Command::Subscribe { stream, .. } => Ok(Response::Metadata(
+            registry.get(&stream)?.stream_metadata()?,
+        )),
+
+        Command::ArchiveStream { stream } => {
+            let info = registry.archive(&stream)?;
+            Ok(message(format!(
+                "Succesfully archived stream '{}'",
+                info.name
+            )))
+        }
+
+        Command::Feed {
+            stream,
+            format,
+            limit,
+        } => Ok(Response::Feed(registry.get(&stream)?.feed(format, limit)?)),
+
+        Command::History { stream, id } => {
+            Ok(Response::History(registry.get(&stream)?.history(id)?))
+        }
+
+        Command::Search {
+            stream,
+            query,
+            limit,
+            cursor,
+        } => Ok(Response::Search(registry.get(&stream)?.search(
+            &query,
+            limit,
+            cursor.as_deref(),
+        )?)),
+
+        Command::Restore {
+            stream: name,
+            id,
+            revision,
+        } => {
+            let post = registry.get_mut(&name)?.restore(id, revision, &author)?;
+            publish(events, name, EventKind::Updated(post.clone()));
+            Ok(Response::Post(post))
+        }
+
+        Command::Update {
+            stream: name,
+            id,
+            title,
+            msg,
+        } => {
+            let post = registry.get_mut(&name)?.update(id, title, msg, &author)?;
+            publish(events, name, EventKind::Updated(post.clone()));
+            Ok(Response::Post(post))
+        }
+
+        Command::Undelete { stream: name, id } => {
+            let post = registry.get_mut(&name)?.undelete(id)?;
+            publish(events, name, EventKind::Undeleted(post.clone()));
+            Ok(Response::Post(post))
+        }
+    }
 }
@@ -0,0 +1,268 @@
+//! Server-sent events, for dashboards and status pages that only need to
+//! follow a stream. `GET /events` is read-only and works with a plain
+//! `EventSource`: it replays the posts created after the `Last-Event-ID`,
+//! then pushes the changes to the stream as they happen.
+//!
+//! Events are named after their kind, `created`, `updated`, `deleted` or
+//! `undeleted`, and carry the JSON of the post, or `{"id":<id>}` for
+//! deletions; a post brought back from the trash is sent whole. Only
+//! `created` events have an ID, the ID of the post, so that a reconnecting
+//! `EventSource` resumes after the last post it received. The first
+//! connection may pass the ID with the `since` query parameter instead.
+//!
+//! `EventSource` cannot set headers, so this is the one route taking the
+//! API token in the `access_token` query parameter, see `crate::http`.
+//!
+//! The stream ends once the server shuts down; `EventSource` reconnects
+//! by itself, to whichever server takes over.
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream};
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+
+use briefs_core::{
+    state::{EventKind, StreamEvent},
+    Command, Envelope, ErrorReply, Response,
+};
+
+use crate::{
+    http::{default_stream, invalid, Caller, Gateway, HttpError},
+    shutdown_requested, spawn_subscription,
+};
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+#[derive(Deserialize)]
+pub(crate) struct EventsQuery {
+    #[serde(default = "default_stream")]
+    stream: String,
+    #[serde(default)]
+    since: Option<u32>,
+}
+
+/// `GET /events?stream=<name>&since=<id>`
+pub(crate) async fn events(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    headers: HeaderMap,
+    query: Result<Query<EventsQuery>, QueryRejection>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, HttpError> {
+    let Query(query) = query?;
+    let last_seen_id = match headers.get(LAST_EVENT_ID) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(invalid)?
+                .trim()
+                .parse::<u32>()
+                .map_err(|e| invalid(format!("Invalid Last-Event-ID: {e}")))?,
+        ),
+        None => query.since,
+    };
+
+    let cmd = Command::Subscribe {
+        stream: query.stream,
+        last_seen_id,
+    };
+    let mut replies = spawn_subscription(gateway.tx, &gateway.events, caller, cmd);
+    // The first reply acknowledges the subscription, or tells why the
+    // stream cannot be followed.
+    match replies.recv().await {
+        Some(envelope) => {
+            envelope.into_result()?;
+        }
+        None => return Err(anyhow::anyhow!("Stream handler is not running").into()),
+    }
+
+    let events = stream::unfold(Some((replies, gateway.shutdown)), next_event);
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Replies of the subscription, along with the shutdown channel.
+type EventState = Option<(mpsc::Receiver<Envelope>, watch::Receiver<bool>)>;
+
+/// Waits for the next event to send. An error is sent as an `error` event,
+/// which ends the stream; so does the shutdown of the server.
+async fn next_event(state: EventState) -> Option<(Result<Event, Infallible>, EventState)> {
+    let (mut replies, mut shutdown) = state?;
+    loop {
+        let envelope = tokio::select! {
+            envelope = replies.recv() => envelope?,
+            _ = shutdown_requested(&mut shutdown) => return None,
+        };
+        match envelope.into_result() {
+            Ok(Response::Event(event)) => {
+                return Some((Ok(to_sse(event)), Some((replies, shutdown))));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                let error = match e.downcast::<ErrorReply>() {
+                    Ok(error) => error,
+                    Err(e) => ErrorReply::from(&e),
+                };
+                let event = Event::default().event("error").json_data(error);
+                return Some((Ok(event.unwrap_or_default()), None));
+            }
+        }
+    }
+}
+
+fn to_sse(event: StreamEvent) -> Event {
+    let sse = Event::default();
+    let sse = match event.kind {
+        EventKind::Created(post) => sse
+            .event("created")
+            .id(post.id().unwrap_or_default().to_string())
+            .json_data(post),
+        EventKind::Updated(post) => sse.event("updated").json_data(post),
+        EventKind::Deleted { id } => sse
+            .event("deleted")
+            .json_data(serde_json::json!({ "id": id })),
+        EventKind::Undeleted(post) => sse.event("undeleted").json_data(post),
+    };
+    // Posts always serialize
+    sse.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::stream_metadata;
+    use axum::{body::Body, http::StatusCode};
+    use briefs_core::{post::Post, state::CatchUpResponse, BriefsError, StreamCommand};
+    use futures_util::StreamExt;
+    use tokio::sync::broadcast;
+    use tower::ServiceExt;
+
+    fn post(id: u32) -> Post {
+        Post::from_parts(id, format!("Post {id}"), "Hi".into(), 0, false)
+    }
+
+    /// A stream handler knowing the posts 0 to 2 of the stream `ops`.
+    fn handler() -> mpsc::Sender<StreamCommand> {
+        let (tx, mut rx) = mpsc::channel::<StreamCommand>(16);
+        tokio::spawn(async move {
+            while let Some(StreamCommand { cmd, resp, .. }) = rx.recv().await {
+                let response = match cmd {
+                    Command::Subscribe { stream, .. } if stream == "ops" => {
+                        Ok(Response::Metadata(stream_metadata(stream, Some(2), 3)))
+                    }
+                    Command::Subscribe { stream, .. } => {
+                        Err(BriefsError::UnknownStream { name: stream }.into())
+                    }
+                    Command::Catchup { last_fetch_id, .. } => {
+                        Ok(Response::CatchUp(CatchUpResponse {
+                            posts: (last_fetch_id..=2).map(post).collect(),
+                            next_cursor: None,
+                            prev_cursor: None,
+                            deleted: vec![],
+                            edited: vec![],
+                        }))
+                    }
+                    _ => unreachable!(),
+                };
+                let _ = resp.unwrap().send(response);
+            }
+        });
+        tx
+    }
+
+    async fn get(
+        events: broadcast::Sender<StreamEvent>,
+        uri: &str,
+        last_event_id: Option<&str>,
+    ) -> axum::response::Response {
+        let (_, shutdown) = watch::channel(false);
+        get_until(events, shutdown, uri, last_event_id).await
+    }
+
+    async fn get_until(
+        events: broadcast::Sender<StreamEvent>,
+        shutdown: watch::Receiver<bool>,
+        uri: &str,
+        last_event_id: Option<&str>,
+    ) -> axum::response::Response {
+        let mut request = axum::http::Request::builder().uri(uri);
+        if let Some(id) = last_event_id {
+            request = request.header("Last-Event-ID", id);
+        }
+        crate::http::router(handler(), events, shutdown)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Reads the body until it contains `expected`.
+    async fn read_until(body: &mut axum::body::BodyDataStream, text: &mut String, expected: &str) {
+        while !text.contains(expected) {
+            let chunk = body.next().await.unwrap().unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replays_after_last_event_id() {
+        let (events, _) = broadcast::channel(16);
+        let response = get(events.clone(), "/events?stream=ops", Some("0")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+        let mut body = response.into_body().into_data_stream();
+        let mut text = String::new();
+        read_until(&mut body, &mut text, "id: 2\n").await;
+        assert!(!text.contains("id: 0\n"));
+        assert!(text.contains("event: created\n"));
+        assert!(text.contains(r#""title":"Post 1""#));
+
+        // Posts already replayed are not sent twice
+        for id in [2, 3] {
+            let event = StreamEvent {
+                stream: "ops".into(),
+                kind: EventKind::Created(post(id)),
+            };
+            events.send(event).unwrap();
+        }
+        events
+            .send(StreamEvent {
+                stream: "ops".into(),
+                kind: EventKind::Deleted { id: 1 },
+            })
+            .unwrap();
+        read_until(&mut body, &mut text, "event: deleted\n").await;
+        assert!(text.contains("id: 3\n"));
+        assert_eq!(text.matches("id: 2\n").count(), 1);
+        assert!(text.contains(r#"data: {"id":1}"#));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_ends_the_stream() {
+        let (events, _) = broadcast::channel(16);
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let response = get_until(events, shutdown, "/events?stream=ops", Some("0")).await;
+        let mut body = response.into_body().into_data_stream();
+        let mut text = String::new();
+        read_until(&mut body, &mut text, "id: 2\n").await;
+
+        shutdown_tx.send(true).unwrap();
+        while let Some(chunk) = body.next().await {
+            chunk.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let (events, _) = broadcast::channel(16);
+        let response = get(events.clone(), "/events?stream=missing", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = get(events, "/events?stream=ops", Some("latest")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
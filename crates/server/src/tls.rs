@@ -0,0 +1,499 @@
+//! TLS setup of the connection handler. The certificate and private key of
+//! the server are the `cert` and `pkey` of the config; on a fresh host a
+//! self-signed development certificate is generated, see
+//! `generate_dev_cert`.
+//!
+//! When a CA bundle is configured as `client_ca`, clients may present a
+//! certificate signed by one of its CAs (mutual TLS). The common name of
+//! the certificate subject is the name of the client, which is then logged
+//! in as a publisher without a token. Clients without a certificate are
+//! still accepted and log in with an API token, while certificates that do
+//! not verify fail the handshake.
+//!
+//! Certificates can be rotated without a restart: the files are reloaded
+//! when they change, or on `SIGHUP`, see `watch_tls`. Only new connections
+//! use the new certificate; open connections are kept.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use briefs_core::{
+    auth::{Identity, Role},
+    config::BriefsConfig,
+    BriefsError, BriefsResult,
+};
+use rcgen::{CertificateParams, DnType, KeyPair};
+use time::OffsetDateTime;
+use tokio::sync::watch;
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    TlsAcceptor,
+};
+use x509_parser::parse_x509_certificate;
+
+/// File names of the development certificate and its private key.
+pub const DEV_CERT_FILE: &str = "dev-cert.pem";
+pub const DEV_KEY_FILE: &str = "dev-key.pem";
+/// The development certificate is valid for these hosts; `brief.com` is
+/// the server name expected by the CLI.
+const DEV_CERT_NAMES: [&str; 3] = ["brief.com", "localhost", "127.0.0.1"];
+/// Validity of the development certificate, in days.
+const DEV_CERT_DAYS: i64 = 365;
+/// How often the TLS files are checked for changes.
+pub const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reads all the certificates of the PEM file.
+pub fn load_certs(path: &Path) -> BriefsResult<Vec<CertificateDer<'static>>> {
+    let unreadable = |e: &dyn std::fmt::Display| {
+        BriefsError::config_error(format!(
+            "Unable to read certificates from '{}': {e}",
+            path.display()
+        ))
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| unreadable(&e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| unreadable(&e))?;
+    if certs.is_empty() {
+        return Err(BriefsError::config_error(format!(
+            "No certificate found in '{}'",
+            path.display()
+        ))
+        .into());
+    }
+    Ok(certs)
+}
+
+/// Reads the private key of the PEM file.
+pub fn load_key(path: &Path) -> BriefsResult<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| {
+        BriefsError::config_error(format!(
+            "Unable to read private key from '{}': {e}",
+            path.display()
+        ))
+        .into()
+    })
+}
+
+/// Checks that the file set as `key` in the config exists.
+fn configured_file<'a>(key: &str, path: &'a Path) -> BriefsResult<&'a Path> {
+    if path.as_os_str().is_empty() {
+        return Err(BriefsError::config_error(format!(
+            "`{key}` is not set; run `cli dev-cert` to generate a development certificate"
+        ))
+        .into());
+    }
+    if !path.is_file() {
+        return Err(BriefsError::config_error(format!(
+            "`{key}` file '{}' does not exist",
+            path.display()
+        ))
+        .into());
+    }
+    Ok(path)
+}
+
+/// Builds the TLS config of the server from the `cert`, `pkey` and
+/// `client_ca` of the config. Client certificates are verified against the
+/// CA bundle `client_ca`, if given, and not asked for otherwise.
+///
+/// # Errors
+///
+/// This function will return `BriefsError::ConfigError` if a file is not
+/// set, does not exist or cannot be parsed, and an error if the private
+/// key does not match the certificate.
+pub fn server_config(
+    cert: &Path,
+    pkey: &Path,
+    client_ca: Option<&Path>,
+) -> BriefsResult<rustls::ServerConfig> {
+    let certs = load_certs(configured_file("cert", cert)?)?;
+    let key = load_key(configured_file("pkey", pkey)?)?;
+
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(configured_file("client_ca", client_ca)?)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder.with_single_cert(certs, key).map_err(|e| {
+        BriefsError::config_error(format!(
+            "`pkey` '{}' does not match `cert` '{}': {e}",
+            pkey.display(),
+            cert.display()
+        ))
+        .into()
+    })
+}
+
+/// The TLS files of the config, which are reloaded when they change.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub pkey: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    pub fn from_config(config: &BriefsConfig) -> Self {
+        Self {
+            cert: config.cert.clone(),
+            pkey: config.pkey.clone(),
+            client_ca: config.client_ca.clone(),
+        }
+    }
+
+    /// Builds the TLS config of the server from the files; see
+    /// `server_config`.
+    pub fn server_config(&self) -> BriefsResult<rustls::ServerConfig> {
+        server_config(&self.cert, &self.pkey, self.client_ca.as_deref())
+    }
+
+    /// Modification time and size of every file, which change whenever a
+    /// file is rewritten or replaced.
+    fn stamps(&self) -> Vec<Option<(SystemTime, u64)>> {
+        [Some(&self.cert), Some(&self.pkey), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+}
+
+/// Reloads the TLS files whenever they change, checking every `interval`,
+/// or when the server receives `SIGHUP`. The new acceptor replaces the
+/// current one at once, so every new connection uses either the old or the
+/// new certificate. Files that fail to load, e.g. a certificate whose new
+/// key is not written yet, keep the current acceptor until the next change.
+///
+/// Returns once every receiver of the acceptor is dropped.
+pub async fn watch_tls(files: TlsFiles, acceptor: watch::Sender<TlsAcceptor>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    let mut hangup = Hangup::new();
+    let mut stamps = files.stamps();
+    loop {
+        let reason = tokio::select! {
+            _ = ticks.tick() => {
+                let current = files.stamps();
+                if current == stamps {
+                    continue;
+                }
+                stamps = current;
+                "files changed"
+            }
+            _ = hangup.recv() => {
+                stamps = files.stamps();
+                "SIGHUP"
+            }
+            _ = acceptor.closed() => return,
+        };
+        match files.server_config() {
+            Ok(config) => {
+                acceptor.send_replace(TlsAcceptor::from(Arc::new(config)));
+                println!(
+                    "✓ Reloaded TLS certificate '{}' ({reason})",
+                    files.cert.display()
+                );
+            }
+            Err(e) => eprintln!("✗ Keeping the current TLS certificate ({reason}): {e}"),
+        }
+    }
+}
+
+/// `SIGHUP`, the conventional signal to reload; never received on other
+/// platforms.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| eprintln!("✗ Unable to listen for SIGHUP: {e}"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+            self.signal = None;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+/// Returns the publisher identity of the verified client certificates, or
+/// `None` if the client did not present any.
+pub fn peer_identity(certs: Option<&[CertificateDer<'_>]>) -> Option<Identity> {
+    let cert = certs?.first()?;
+    // The certificate was verified by the handshake, so it parses
+    let name = parse_x509_certificate(cert).ok().and_then(|(_, cert)| {
+        let name = cert.subject().iter_common_name().next()?;
+        name.as_str().ok().map(str::to_owned)
+    });
+    match name {
+        Some(name) => Some(Identity {
+            name,
+            role: Role::Publisher,
+        }),
+        None => {
+            eprintln!("✗ Client certificate has no common name; ignoring it");
+            None
+        }
+    }
+}
+
+/// Generates a self-signed certificate for local development, valid for
+/// `brief.com`, `localhost` and `127.0.0.1`, and writes it along with its
+/// private key to `dir`. Returns the paths of the certificate and the key.
+///
+/// Clients trust the certificate by passing it as `--cafile`; it must never
+/// be used in production.
+pub fn generate_dev_cert(dir: &Path) -> BriefsResult<(PathBuf, PathBuf)> {
+    let mut params = CertificateParams::new(DEV_CERT_NAMES.map(String::from))?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "briefs development");
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::days(1);
+    params.not_after = now + time::Duration::days(DEV_CERT_DAYS);
+    let key_pair = KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    fs::create_dir_all(dir)?;
+    let cert_path = dir.join(DEV_CERT_FILE);
+    let key_path = dir.join(DEV_KEY_FILE);
+    fs::write(&cert_path, cert.pem())?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // The key is only readable by its owner
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&key_path)?
+        .write_all(key_pair.serialize_pem().as_bytes())?;
+    Ok((cert_path, key_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_rustls::{rustls::pki_types::ServerName, TlsAcceptor, TlsConnector};
+
+    /// The demo PKI of `auth/mtls`.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../auth/mtls")
+            .join(name)
+    }
+
+    /// Connects to `domain` trusting the certificates of `cafile`, with the
+    /// client certificate, if any, and returns the identity the server
+    /// mapped it to.
+    async fn handshake(
+        config: rustls::ServerConfig,
+        cafile: &Path,
+        domain: &str,
+        client: Option<(&str, &str)>,
+    ) -> BriefsResult<Option<Identity>> {
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(cafile)? {
+            roots.add(cert)?;
+        }
+        let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+        let config = match client {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(load_certs(&fixture(cert))?, load_key(&fixture(key))?)?,
+            None => builder.with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let conn = acceptor.accept(server).await?;
+            Ok::<_, std::io::Error>(peer_identity(conn.get_ref().1.peer_certificates()))
+        });
+        let domain = ServerName::try_from(domain.to_owned())?;
+        // The client is done before the server verified its certificate
+        let _client = connector.connect(domain, client).await?;
+        Ok(server.await??)
+    }
+
+    async fn mtls_handshake(client: Option<(&str, &str)>) -> BriefsResult<Option<Identity>> {
+        let config = server_config(
+            &fixture("server.pem"),
+            &fixture("server-key.pem"),
+            Some(&fixture("ca.pem")),
+        )?;
+        handshake(config, &fixture("ca.pem"), "localhost", client).await
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() {
+        let identity = mtls_handshake(Some(("client.pem", "client-key.pem")))
+            .await
+            .unwrap();
+        assert_eq!(
+            identity,
+            Some(Identity {
+                name: "ci-publisher".into(),
+                role: Role::Publisher,
+            })
+        );
+
+        assert_eq!(mtls_handshake(None).await.unwrap(), None);
+        assert!(mtls_handshake(Some(("rogue.pem", "rogue-key.pem")))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dev_cert() {
+        let dir = std::env::temp_dir().join(format!("briefs-dev-cert-{}", std::process::id()));
+        let (cert, key) = generate_dev_cert(&dir).unwrap();
+        assert_eq!(cert, dir.join(DEV_CERT_FILE));
+
+        // Clients trust the certificate itself
+        for domain in ["brief.com", "localhost", "127.0.0.1"] {
+            let config = server_config(&cert, &key, None).unwrap();
+            handshake(config, &cert, domain, None).await.unwrap();
+        }
+        let config = server_config(&cert, &key, None).unwrap();
+        assert!(handshake(config, &cert, "example.com", None).await.is_err());
+
+        let certs = load_certs(&cert).unwrap();
+        assert_eq!(
+            peer_identity(Some(&certs)).map(|identity| identity.name),
+            Some("briefs development".into())
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_change() {
+        let dir = std::env::temp_dir().join(format!("briefs-reload-{}", std::process::id()));
+        let (cert, key) = generate_dev_cert(&dir).unwrap();
+        let old_cert = dir.join("old-cert.pem");
+        fs::copy(&cert, &old_cert).unwrap();
+        let files = TlsFiles {
+            cert: cert.clone(),
+            pkey: key.clone(),
+            client_ca: None,
+        };
+        let acceptor = TlsAcceptor::from(Arc::new(files.server_config().unwrap()));
+        let (acceptor_tx, mut acceptor_rx) = watch::channel(acceptor);
+        let watcher = tokio::spawn(watch_tls(files, acceptor_tx, Duration::from_millis(10)));
+
+        // A broken certificate keeps the current acceptor
+        fs::write(&cert, "rotating").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!acceptor_rx.has_changed().unwrap());
+
+        // Rotation
+        generate_dev_cert(&dir).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), acceptor_rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let acceptor = acceptor_rx.borrow_and_update().clone();
+        let handshake_with = |cafile: PathBuf| {
+            let acceptor = acceptor.clone();
+            async move {
+                let (client, server) = tokio::io::duplex(16 * 1024);
+                let server = tokio::spawn(async move { acceptor.accept(server).await.is_ok() });
+                let mut roots = RootCertStore::empty();
+                roots.add(load_certs(&cafile).unwrap().remove(0)).unwrap();
+                let config = rustls::ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let domain = ServerName::try_from("localhost").unwrap();
+                let client = TlsConnector::from(Arc::new(config))
+                    .connect(domain, client)
+                    .await;
+                client.is_ok() && server.await.unwrap()
+            }
+        };
+        assert!(handshake_with(cert).await);
+        assert!(!handshake_with(old_cert).await);
+
+        drop(acceptor_rx);
+        tokio::time::timeout(Duration::from_secs(5), watcher)
+            .await
+            .unwrap()
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let error = |cert: &Path, pkey: &Path| {
+            server_config(cert, pkey, None)
+                .unwrap_err()
+                .downcast::<BriefsError>()
+                .unwrap()
+                .to_string()
+        };
+        let key = fixture("server-key.pem");
+        assert!(error(Path::new(""), &key).contains("`cert` is not set"));
+        let missing = fixture("missing.pem");
+        assert!(error(&missing, &key).contains("`cert` file"));
+        assert!(error(&fixture("server.pem"), &missing).contains("`pkey` file"));
+        assert!(error(&key, &key).contains("No certificate found"));
+        assert!(
+            error(&fixture("server.pem"), &fixture("client-key.pem")).contains("does not match")
+        );
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let certs = load_certs(&fixture("server.pem")).unwrap();
+        assert_eq!(
+            peer_identity(Some(&certs)).map(|identity| identity.name),
+            Some("brief.com".into())
+        );
+        let truncated = CertificateDer::from(&certs[0][..40]);
+        assert_eq!(peer_identity(Some(&[truncated])), None);
+        assert_eq!(peer_identity(Some(&[])), None);
+        assert_eq!(peer_identity(None), None);
+    }
+}
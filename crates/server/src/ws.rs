@@ -0,0 +1,301 @@
+//! WebSocket transport, for browsers and the wasm SDK, which cannot open
+//! the raw TLS socket. A WebSocket connection carries the same requests,
+//! replies and subscriptions as a TLS connection, one request or reply per
+//! message; the messages themselves already delimit them, so no frames or
+//! handshake are needed.
+//!
+//! The encoding is picked with the `encoding` query parameter of the
+//! upgrade request: `json`, the default, is sent in text messages and
+//! `proto` in binary messages. A token sent in the `Authorization` header
+//! of the upgrade request logs the socket in, just like a `Login` sent over
+//! it. Browsers cannot set that header, and the token is not taken from the
+//! query, so browser clients log in with a `Login` sent over the socket.
+//!
+//! Once the server shuts down the socket stops taking requests, sends the
+//! pending replies, and is closed with the `1001` going away code.
+
+use axum::{
+    extract::{
+        rejection::QueryRejection,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Query, State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use briefs_core::{auth::Identity, Envelope};
+use briefs_proto::Encoding;
+
+use crate::{
+    http::{invalid, Caller, Gateway, HttpError},
+    shutdown_requested, Dispatcher, MAX_IN_FLIGHT,
+};
+
+#[derive(Deserialize)]
+pub(crate) struct WsQuery {
+    #[serde(default)]
+    encoding: Option<String>,
+}
+
+/// `GET /ws`
+pub(crate) async fn upgrade(
+    State(gateway): State<Gateway>,
+    Caller(caller): Caller,
+    query: Result<Query<WsQuery>, QueryRejection>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, HttpError> {
+    let Query(query) = query?;
+    let encoding = query
+        .encoding
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(invalid)?
+        .unwrap_or(Encoding::Json);
+    Ok(ws.on_upgrade(move |socket| serve_ws(socket, gateway, encoding, caller)))
+}
+
+/// Serves the requests of a WebSocket until the client closes it or the
+/// server shuts down.
+async fn serve_ws(
+    socket: WebSocket,
+    gateway: Gateway,
+    encoding: Encoding,
+    caller: Option<Identity>,
+) {
+    println!("Succesfully upgraded a WebSocket ({} encoding)", encoding);
+    let (sink, mut stream) = socket.split();
+    let (reply_tx, reply_rx) = mpsc::channel(MAX_IN_FLIGHT);
+    let writer = tokio::spawn(write_replies(sink, reply_rx, encoding));
+    let mut shutdown = gateway.shutdown;
+    let mut dispatcher = Dispatcher::new(gateway.tx, gateway.events, reply_tx, encoding, caller);
+
+    let mut close = None;
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = shutdown_requested(&mut shutdown) => {
+                close = Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server is shutting down".into(),
+                });
+                break;
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let payload = match message {
+            Ok(Message::Text(text)) => text.as_bytes().to_vec(),
+            Ok(Message::Binary(bytes)) => bytes.to_vec(),
+            // Pings are answered by the socket itself
+            Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+            Ok(Message::Close(_)) => break,
+            Err(e) => {
+                eprintln!("Error reading WebSocket: {}", e);
+                break;
+            }
+        };
+        if !dispatcher.dispatch(&payload).await {
+            break;
+        }
+    }
+
+    // Pending replies are still sent before the socket is closed
+    dispatcher.close().await;
+    if let Ok(mut sink) = writer.await {
+        let _ = sink.send(Message::Close(close)).await;
+    }
+}
+
+/// Encodes and sends the replies of a WebSocket until every sender is
+/// dropped or sending fails. Returns the sink so that it can be closed.
+async fn write_replies(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut replies: mpsc::Receiver<Envelope>,
+    encoding: Encoding,
+) -> SplitSink<WebSocket, Message> {
+    while let Some(envelope) = replies.recv().await {
+        let payload = match encoding.encode_envelope(envelope) {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("Error encoding response: {}", e);
+                break;
+            }
+        };
+        let message = match encoding {
+            // JSON is always valid UTF-8
+            Encoding::Json => Message::Text(String::from_utf8_lossy(&payload).into_owned().into()),
+            Encoding::Proto => Message::Binary(payload.into()),
+        };
+        if let Err(e) = sink.send(message).await {
+            eprintln!("Error writing WebSocket: {}", e);
+            break;
+        }
+    }
+    sink
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::stream_metadata;
+    use briefs_core::{
+        post::Post,
+        state::{EventKind, StreamEvent},
+        Command, ErrorReply, Request, Response, StreamCommand, StreamResponse,
+    };
+    use tokio::{
+        net::TcpListener,
+        sync::{broadcast, watch},
+    };
+    use tokio_tungstenite::{connect_async, tungstenite};
+
+    /// Serves the gateway with a stream handler that answers every
+    /// command with its JSON, and returns its address.
+    async fn serve(events: broadcast::Sender<StreamEvent>) -> std::net::SocketAddr {
+        let (_, shutdown) = watch::channel(false);
+        serve_until(events, shutdown).await
+    }
+
+    async fn serve_until(
+        events: broadcast::Sender<StreamEvent>,
+        shutdown: watch::Receiver<bool>,
+    ) -> std::net::SocketAddr {
+        let (tx, mut rx) = mpsc::channel::<StreamCommand>(16);
+        tokio::spawn(async move {
+            while let Some(StreamCommand { cmd, resp, .. }) = rx.recv().await {
+                let response = match cmd {
+                    Command::Subscribe { stream, .. } => {
+                        Response::Metadata(stream_metadata(stream, None, 0))
+                    }
+                    cmd => {
+                        Response::Message(StreamResponse::new(serde_json::to_string(&cmd).unwrap()))
+                    }
+                };
+                let _ = resp.unwrap().send(Ok(response));
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = crate::http::router(tx, events, shutdown);
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        addr
+    }
+
+    type Socket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    async fn send_json(socket: &mut Socket, id: u64, cmd: Command) {
+        let text = serde_json::to_string(&Request::new(id, cmd)).unwrap();
+        socket
+            .send(tungstenite::Message::Text(text.into()))
+            .await
+            .unwrap();
+    }
+
+    async fn next_json(socket: &mut Socket) -> Envelope {
+        let tungstenite::Message::Text(text) = socket.next().await.unwrap().unwrap() else {
+            panic!("expected a text message");
+        };
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_requests_and_events() {
+        let (events, _) = broadcast::channel(16);
+        let addr = serve(events.clone()).await;
+        let (mut socket, _) = connect_async(format!("ws://{addr}/ws")).await.unwrap();
+
+        let cmd = Command::Subscribe {
+            stream: "ops".into(),
+            last_seen_id: None,
+        };
+        send_json(&mut socket, 1, cmd).await;
+        let envelope = next_json(&mut socket).await;
+        assert_eq!(envelope.id, 1);
+        assert!(matches!(
+            envelope.into_result().unwrap(),
+            Response::Message(_)
+        ));
+
+        let event = StreamEvent {
+            stream: "ops".into(),
+            kind: EventKind::Created(Post::from_parts(0, "Hi".into(), "There".into(), 0, false)),
+        };
+        events.send(event.clone()).unwrap();
+        let envelope = next_json(&mut socket).await;
+        assert_eq!(envelope.id, 1);
+        assert_eq!(envelope.into_result().unwrap(), Response::Event(event));
+
+        // Other requests share the socket with the subscription
+        send_json(&mut socket, 2, Command::ListStreams {}).await;
+        let envelope = next_json(&mut socket).await;
+        assert_eq!(envelope.id, 2);
+        let Response::Message(message) = envelope.into_result().unwrap() else {
+            panic!("expected a message");
+        };
+        let cmd: Command = serde_json::from_str(message.msg()).unwrap();
+        assert_eq!(cmd, Command::ListStreams {});
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_the_socket() {
+        let (events, _) = broadcast::channel(16);
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let addr = serve_until(events, shutdown).await;
+        let (mut socket, _) = connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        send_json(&mut socket, 1, Command::ListStreams {}).await;
+        assert_eq!(next_json(&mut socket).await.id, 1);
+
+        shutdown_tx.send(true).unwrap();
+        let tungstenite::Message::Close(Some(frame)) = socket.next().await.unwrap().unwrap() else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(
+            frame.code,
+            tungstenite::protocol::frame::coding::CloseCode::Away
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proto_encoding() {
+        let (events, _) = broadcast::channel(16);
+        let addr = serve(events).await;
+        let url = format!("ws://{addr}/ws?encoding=proto");
+        let (mut socket, _) = connect_async(url).await.unwrap();
+
+        let payload = Encoding::Proto
+            .encode_request(Request::new(7, Command::ListStreams {}))
+            .unwrap();
+        socket
+            .send(tungstenite::Message::Binary(payload.into()))
+            .await
+            .unwrap();
+        let tungstenite::Message::Binary(bytes) = socket.next().await.unwrap().unwrap() else {
+            panic!("expected a binary message");
+        };
+        let envelope = Encoding::Proto.decode_envelope(&bytes).unwrap();
+        assert_eq!(envelope.id, 7);
+    }
+
+    #[tokio::test]
+    async fn test_bad_query_is_an_error_reply() {
+        let (events, _) = broadcast::channel(16);
+        let addr = serve(events).await;
+        for query in ["encoding=xml", "encoding=json&encoding=proto"] {
+            let url = format!("ws://{addr}/ws?{query}");
+            let Err(tungstenite::Error::Http(response)) = connect_async(url).await else {
+                panic!("expected an HTTP error");
+            };
+            assert_eq!(response.status(), 400, "{query}");
+            let reply: ErrorReply =
+                serde_json::from_slice(response.body().as_deref().unwrap()).unwrap();
+            assert_eq!(reply.code, 302, "{query}");
+        }
+    }
+}